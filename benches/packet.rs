@@ -0,0 +1,161 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Benchmarks of the NTP packet hot path. Run them with `cargo bench`.
+//!
+//! Before the benchmarks start, the number of heap allocations made by a single call of each
+//! benchmarked function is printed, using a counting global allocator.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use miscreant::aead::Aes128SivAead;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once, RwLock};
+use std::time::SystemTime;
+
+use cfnts::cookie::{make_cookie, CookieKey, NTSKeys};
+use cfnts::key_rotator::{KeyId, KeyRotator};
+use cfnts::ntp::protocol::{
+    ntp_timestamp, parse_ntp_packet, parse_nts_packet, serialize_nts_packet, LeapState,
+    NtpExtension, NtpExtensionType, NtpPacketHeader, NtsPacket, PacketMode,
+};
+use cfnts::ntp::server::bench_response;
+use cfnts::nts_ke::records::KnownAeadAlgorithm;
+
+/// A global allocator counting the number of allocations.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The keys of the association between the client and the server.
+const C2S: [u8; 32] = [0x01; 32];
+const S2C: [u8; 32] = [0x02; 32];
+const AEAD: KnownAeadAlgorithm = KnownAeadAlgorithm::AeadAesSivCmac256;
+
+fn cookie_keys() -> Arc<RwLock<KeyRotator>> {
+    let master_key = CookieKey::from(&[0x07; 32][..]);
+    let rotator = KeyRotator::with_key(master_key, KeyId::new(1), &[0x03; 32]);
+    Arc::new(RwLock::new(rotator))
+}
+
+fn query_header() -> NtpPacketHeader {
+    NtpPacketHeader {
+        leap_indicator: LeapState::NoLeap,
+        version: 4,
+        mode: PacketMode::Client,
+        stratum: 0,
+        poll: 0,
+        precision: 0x20,
+        root_delay: 0,
+        root_dispersion: 0,
+        reference_id: 0,
+        reference_timestamp: 0,
+        origin_timestamp: 0,
+        receive_timestamp: 0,
+        transmit_timestamp: ntp_timestamp(SystemTime::now()),
+    }
+}
+
+/// An NTS query with a valid cookie for `cookie_keys` and a placeholder for one more cookie.
+fn nts_packet() -> NtsPacket {
+    let keys = cookie_keys();
+    let rotator = keys.read().unwrap();
+    let (key_id, key) = rotator.latest_key_value();
+    let cookie = make_cookie(NTSKeys { c2s: C2S, s2c: S2C }, AEAD, key.as_ref(), key_id);
+
+    NtsPacket {
+        header: query_header(),
+        auth_exts: vec![
+            NtpExtension {
+                ext_type: NtpExtensionType::UniqueIdentifier,
+                contents: vec![0x04; 32],
+            },
+            NtpExtension {
+                ext_type: NtpExtensionType::NTSCookiePlaceholder,
+                contents: vec![0; cookie.len()],
+            },
+            NtpExtension {
+                ext_type: NtpExtensionType::NTSCookie,
+                contents: cookie,
+            },
+        ],
+        auth_enc_exts: vec![],
+    }
+}
+
+fn nts_query() -> Vec<u8> {
+    serialize_nts_packet(nts_packet(), &mut Aes128SivAead::new(&C2S))
+}
+
+fn report_allocations<F: FnMut()>(name: &str, mut f: F) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    println!("{}: {} allocations per call", name, after - before);
+}
+
+fn report_all_allocations() {
+    let query = nts_query();
+    let keys = cookie_keys();
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+    report_allocations("parse_ntp_packet", || {
+        parse_ntp_packet(&query).unwrap();
+    });
+    report_allocations("parse_nts_packet", || {
+        parse_nts_packet(&query, &mut Aes128SivAead::new(&C2S)).unwrap();
+    });
+    let packet = nts_packet();
+    report_allocations("serialize_nts_packet", || {
+        serialize_nts_packet(packet.clone(), &mut Aes128SivAead::new(&C2S));
+    });
+    report_allocations("response", || {
+        bench_response(&query, keys.clone(), logger.clone()).unwrap();
+    });
+}
+
+fn bench_packet(c: &mut Criterion) {
+    static REPORT: Once = Once::new();
+    REPORT.call_once(report_all_allocations);
+
+    let query = nts_query();
+    c.bench_function("parse_ntp_packet", move |b| {
+        b.iter(|| parse_ntp_packet(&query).unwrap())
+    });
+
+    let query = nts_query();
+    c.bench_function("parse_nts_packet", move |b| {
+        b.iter(|| parse_nts_packet(&query, &mut Aes128SivAead::new(&C2S)).unwrap())
+    });
+
+    let packet = nts_packet();
+    c.bench_function("serialize_nts_packet", move |b| {
+        b.iter(|| serialize_nts_packet(packet.clone(), &mut Aes128SivAead::new(&C2S)))
+    });
+
+    let query = nts_query();
+    let keys = cookie_keys();
+    let logger = slog::Logger::root(slog::Discard, slog::o!());
+    c.bench_function("response", move |b| {
+        b.iter(|| bench_response(&query, keys.clone(), logger.clone()).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_packet);
+criterion_main!(benches);
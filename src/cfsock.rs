@@ -1,7 +1,8 @@
 use libc::*;
+use net2::unix::UnixUdpBuilderExt;
 use net2::{TcpBuilder, UdpBuilder};
 use std::net::{SocketAddr, SocketAddr::*};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 #[cfg(target_os = "linux")]
 fn set_freebind(fd: c_int) -> Result<(), std::io::Error> {
@@ -29,6 +30,42 @@ fn set_freebind(_fd: c_int) -> Result<(), std::io::Error> {
     Ok(()) // no op for mac build
 }
 
+// From linux/in.h and linux/in6.h.
+#[cfg(target_os = "linux")]
+const IP_MTU_DISCOVER: c_int = 10;
+#[cfg(target_os = "linux")]
+const IPV6_MTU_DISCOVER: c_int = 23;
+#[cfg(target_os = "linux")]
+const PMTUDISC_DO: c_int = 2;
+
+/// Set the don't-fragment bit on the datagrams sent from the socket, so that a datagram larger
+/// than the path MTU fails to be sent instead of being fragmented.
+#[cfg(target_os = "linux")]
+pub fn set_dont_fragment(socket: &std::net::UdpSocket) -> Result<(), std::io::Error> {
+    use std::io::Error;
+    let (level, name) = match socket.local_addr()? {
+        V4(_) => (IPPROTO_IP, IP_MTU_DISCOVER),
+        V6(_) => (IPPROTO_IPV6, IPV6_MTU_DISCOVER),
+    };
+    match unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &PMTUDISC_DO as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>() as u32,
+        )
+    } {
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dont_fragment(_socket: &std::net::UdpSocket) -> Result<(), std::io::Error> {
+    Ok(()) // no op for mac build
+}
+
 pub fn tcp_listener(addr: &SocketAddr) -> Result<std::net::TcpListener, std::io::Error> {
     let builder = match addr {
         V4(_) => TcpBuilder::new_v4()?,
@@ -40,12 +77,121 @@ pub fn tcp_listener(addr: &SocketAddr) -> Result<std::net::TcpListener, std::io:
     builder.listen(128)
 }
 
-pub fn udp_listen(addr: &SocketAddr) -> Result<std::net::UdpSocket, std::io::Error> {
+/// Bind a UDP socket. If `reuse_port` is true, SO_REUSEPORT is set, so that several processes
+/// can bind the same port and the kernel balances the load between them.
+pub fn udp_listen(
+    addr: &SocketAddr,
+    reuse_port: bool,
+) -> Result<std::net::UdpSocket, std::io::Error> {
     let builder = match addr {
         V4(_) => UdpBuilder::new_v4()?,
         V6(_) => UdpBuilder::new_v6()?,
     };
     builder.reuse_address(true)?;
+    builder.reuse_port(reuse_port)?;
     set_freebind(builder.as_raw_fd())?;
     builder.bind(addr)
 }
+
+/// The first file descriptor passed by socket activation. See sd_listen_fds(3).
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Return the UDP socket passed by socket activation, e.g. by systemd, if there is one. Like
+/// sd_listen_fds(3), it checks that `LISTEN_PID` is our process id and `LISTEN_FDS` is at least
+/// 1, and takes the first passed socket.
+pub fn activated_udp_socket() -> Result<Option<std::net::UdpSocket>, std::io::Error> {
+    let var = |name| std::env::var(name).ok().and_then(|value| value.parse::<u32>().ok());
+    match (var("LISTEN_PID"), var("LISTEN_FDS")) {
+        (Some(pid), Some(fds)) if pid == std::process::id() && fds > 0 => {}
+        _ => return Ok(None),
+    }
+    udp_socket_from_fd(LISTEN_FDS_START).map(Some)
+}
+
+/// Take the ownership of the file descriptor, which must be a UDP socket.
+fn udp_socket_from_fd(fd: RawFd) -> Result<std::net::UdpSocket, std::io::Error> {
+    let mut sock_type: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as socklen_t;
+    let res = unsafe {
+        getsockopt(
+            fd,
+            SOL_SOCKET,
+            SO_TYPE,
+            &mut sock_type as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    if res == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if sock_type != SOCK_DGRAM {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("the file descriptor {} is not a UDP socket", fd),
+        ));
+    }
+    Ok(unsafe { std::net::UdpSocket::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_socket_from_fd() {
+        use std::os::unix::io::IntoRawFd;
+
+        let socket = udp_listen(&"127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let socket = udp_socket_from_fd(socket.into_raw_fd()).unwrap();
+        assert_eq!(socket.local_addr().unwrap(), addr);
+
+        // A TCP socket is refused, and stays open.
+        let listener = tcp_listener(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let error = udp_socket_from_fd(listener.as_raw_fd()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        listener.local_addr().unwrap();
+
+        // Nothing was passed to the tests.
+        assert!(activated_udp_socket().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reuse_port() {
+        let first = udp_listen(&"127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = udp_listen(&addr, true).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dont_fragment() {
+        for addr in &["127.0.0.1:0", "[::1]:0"] {
+            let socket = match udp_listen(&addr.parse().unwrap(), false) {
+                Ok(socket) => socket,
+                // The host may not have IPv6.
+                Err(_) => continue,
+            };
+            set_dont_fragment(&socket).unwrap();
+
+            let (level, name) = match socket.local_addr().unwrap() {
+                V4(_) => (IPPROTO_IP, IP_MTU_DISCOVER),
+                V6(_) => (IPPROTO_IPV6, IPV6_MTU_DISCOVER),
+            };
+            let mut value: c_int = 0;
+            let mut len = std::mem::size_of::<c_int>() as socklen_t;
+            let res = unsafe {
+                getsockopt(
+                    socket.as_raw_fd(),
+                    level,
+                    name,
+                    &mut value as *mut c_int as *mut c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(res, 0);
+            assert_eq!(value, PMTUDISC_DO);
+        }
+    }
+}
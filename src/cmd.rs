@@ -6,6 +6,28 @@
 
 use clap::{App, Arg, SubCommand};
 
+/// Validate that an argument is a non-negative integer.
+fn is_number(value: String) -> Result<(), String> {
+    value.parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a non-negative integer", value))
+}
+
+/// Validate that an argument is a fraction from 0 to 1.
+fn is_fraction(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(()),
+        _ => Err(format!("{} is not a fraction from 0 to 1", value)),
+    }
+}
+
+/// Validate that an argument is a port number.
+fn is_port(value: String) -> Result<(), String> {
+    value.parse::<u16>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a port number", value))
+}
+
 /// Create the subcommand `client`.
 fn create_clap_client_subcommand<'a, 'b>() -> App<'a, 'b> {
     // Arguments for `client` subcommand.
@@ -19,11 +41,43 @@ fn create_clap_client_subcommand<'a, 'b>() -> App<'a, 'b> {
         Arg::with_name("port").long("port").short("p").takes_value(true).required(false)
             .help("Specifies NTS server's port. The default port number is 1234."),
         Arg::with_name("cert").long("cert").short("c").takes_value(true).required(false)
-            .help("Specifies a path to the trusted certificate in PEM format."),
+            .help("Specifies a path to the trusted certificates in PEM format: a file, which \
+                   may be a bundle, or a directory of .pem and .crt files."),
+        Arg::with_name("no-system-roots").long("no-system-roots")
+            .help("Refuses to trust the public roots, so a trusted certificate must be given."),
         Arg::with_name("ipv4").long("ipv4").short("4").conflicts_with("ipv6")
             .help("Forces use of IPv4 only"),
         Arg::with_name("ipv6").long("ipv6").short("6").conflicts_with("ipv4")
             .help("Forces use of IPv6 only"),
+        Arg::with_name("proxy").long("proxy").takes_value(true).required(false)
+            .help("Connects to the NTS-KE server through an HTTP CONNECT (http://host:port) or \
+                   SOCKS5 (socks5://host:port) proxy."),
+        Arg::with_name("source-port").long("source-port").takes_value(true).required(false)
+            .validator(is_port)
+            .help("Sends the NTP queries from this local port. Ports below 1024, such as 123, \
+                   need privileges. The default is an ephemeral port."),
+        Arg::with_name("sni").long("sni").takes_value(true).required(false)
+            .help("Sends this name in the SNI and validates the certificate against it, instead \
+                   of the server hostname, e.g. when connecting to an IP address."),
+        Arg::with_name("accept-name").long("accept-name").takes_value(true).multiple(true)
+            .number_of_values(1).required(false)
+            .help("Only accepts a certificate also valid for this name. It can be given more \
+                   than once, to accept any of the names."),
+        Arg::with_name("transcript").long("transcript").takes_value(true).required(false)
+            .help("Writes every record sent and received during the latest key exchange to this \
+                   file, in hex and decoded, e.g. for a bug report. It contains the cookies."),
+        Arg::with_name("json").long("json")
+            .help("Prints the result as JSON"),
+        Arg::with_name("count").long("count").takes_value(true).required(false)
+            .validator(is_number)
+            .help("Specifies the number of queries to send. The default is 1."),
+        Arg::with_name("interval").long("interval").takes_value(true).required(false)
+            .validator(is_number)
+            .help("Specifies the number of seconds between queries. The default is 64."),
+        Arg::with_name("cookie-low-water").long("cookie-low-water").takes_value(true)
+            .required(false).validator(is_fraction)
+            .help("Asks for more cookies in the next query once fewer than this fraction of the \
+                   cookies from the first key exchange are left. The default is 0.5."),
     ];
 
     // Create a new subcommand.
@@ -66,6 +120,30 @@ fn create_clap_ntp_server_subcommand<'a, 'b>() -> App<'a, 'b> {
         .args(&args)
 }
 
+/// Create the subcommand `describe-packet`.
+fn create_clap_describe_packet_subcommand<'a, 'b>() -> App<'a, 'b> {
+    // Arguments for `describe-packet` subcommand.
+    let args = [
+        Arg::with_name("hex").index(1).required_unless_one(&["file", "pcap"])
+            .conflicts_with_all(&["file", "pcap"])
+            .help("The packet in hex"),
+        Arg::with_name("file").long("file").short("f").takes_value(true).required(false)
+            .conflicts_with("pcap")
+            .help("Specifies a path to a file containing the raw packet"),
+        Arg::with_name("pcap").long("pcap").takes_value(true).required(false)
+            .help("Summarizes the NTP packets of a pcap or pcapng capture, one per line"),
+        Arg::with_name("port").long("port").takes_value(true).multiple(true)
+            .number_of_values(1).requires("pcap").validator(is_port)
+            .help("Specifies a UDP port of the NTP packets in the capture. It can be given more \
+                   than once. The default port number is 123."),
+    ];
+
+    // Create a new subcommand.
+    SubCommand::with_name("describe-packet")
+        .about("Prints a captured NTP packet in a human-readable form")
+        .args(&args)
+}
+
 /// Create the whole command-line configuration.
 pub fn create_clap_command() -> App<'static, 'static> {
     App::new(env!("CARGO_PKG_NAME"))
@@ -75,10 +153,16 @@ pub fn create_clap_command() -> App<'static, 'static> {
             Arg::with_name("debug").long("debug").short("d")
                 .help("Turns on debug logging"),
         )
+        .arg(
+            Arg::with_name("unsafe-log-secrets").long("unsafe-log-secrets")
+                .help("Logs the bytes of keys and cookies in debug logging. Only for \
+                       development, never use it in production"),
+        )
         .subcommands(vec![
             // List of all available subcommands.
             create_clap_client_subcommand(),
             create_clap_ke_server_subcommand(),
             create_clap_ntp_server_subcommand(),
+            create_clap_describe_packet_subcommand(),
         ])
 }
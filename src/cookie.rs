@@ -5,38 +5,181 @@
 use miscreant::aead;
 use miscreant::aead::Aead;
 use rand::Rng;
+use ring::digest;
+
+use slog::warn;
 
 use std::convert::TryInto;
+use std::env;
+use std::fmt;
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Read;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use crate::error::WrapError;
 use crate::key_rotator::KeyId;
+use crate::nts_ke::records::KnownAeadAlgorithm;
+
+pub const COOKIE_SIZE: usize = 104;
+/// The plaintext of a cookie is the AEAD algorithm id, two reserved bytes to keep the cookie
+/// word aligned, and the two keys.
+const PLAINTEXT_SIZE: usize = 68;
+/// The size of the cookies made before the AEAD algorithm id was added to the plaintext. We
+/// still accept them, so that the cookies clients got before an upgrade keep working until their
+/// key expires.
+pub const LEGACY_COOKIE_SIZE: usize = 100;
+/// The plaintext of a legacy cookie is only the two keys, for AEAD_AES_SIV_CMAC_256, the only
+/// algorithm there was.
+const LEGACY_PLAINTEXT_SIZE: usize = 64;
+/// The shortest cookie key we accept from a key file.
+pub const MIN_COOKIE_KEY_SIZE: usize = 16;
+
+/// Whether the `Debug` output of keys and cookies shows their bytes. Only for development.
+static LOG_SECRETS: AtomicBool = AtomicBool::new(false);
+
+/// Show the bytes of keys and cookies in their `Debug` output. Don't turn it on in production,
+/// because the secrets will end up in the logs.
+pub fn set_log_secrets(enabled: bool) {
+    LOG_SECRETS.store(enabled, Ordering::Relaxed);
+}
+
+/// A wrapper of secret bytes whose `Debug` output is only the length and a truncated SHA-256
+/// fingerprint, unless `set_log_secrets` is turned on. The fingerprint is enough to tell whether
+/// two logged secrets are the same.
+pub struct Redacted<'a>(pub &'a [u8]);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if LOG_SECRETS.load(Ordering::Relaxed) {
+            return write!(f, "{:x?}", self.0);
+        }
+        let fingerprint = digest::digest(&digest::SHA256, self.0);
+        write!(f, "<{} bytes, sha256:{}>", self.0.len(), hex::encode(&fingerprint.as_ref()[..4]))
+    }
+}
 
-pub const COOKIE_SIZE: usize = 100;
-#[derive(Debug, Copy, Clone)]
+#[derive(Copy, Clone)]
 pub struct NTSKeys {
     pub c2s: [u8; 32],
     pub s2c: [u8; 32],
 }
 
+impl fmt::Debug for NTSKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NTSKeys")
+            .field("c2s", &Redacted(&self.c2s))
+            .field("s2c", &Redacted(&self.s2c))
+            .finish()
+    }
+}
+
+/// Encoding of the content of a cookie key file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CookieKeyEncoding {
+    /// The file contains the key bytes as is.
+    Raw,
+    Hex,
+    Base64,
+}
+
+impl FromStr for CookieKeyEncoding {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> Result<CookieKeyEncoding, io::Error> {
+        match name {
+            "raw" => Ok(CookieKeyEncoding::Raw),
+            "hex" => Ok(CookieKeyEncoding::Hex),
+            "base64" => Ok(CookieKeyEncoding::Base64),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown cookie key encoding: {}", name),
+            )),
+        }
+    }
+}
+
 /// Cookie key.
 #[derive(Clone, Debug)]
 pub struct CookieKey(Vec<u8>);
 
 impl CookieKey {
-    /// Parse a cookie key from a file.
+    /// Parse a cookie key from a file containing the raw key bytes.
     ///
     /// # Errors
     ///
-    /// There will be an error, if we cannot open the file.
+    /// There will be an error, if we cannot open the file or the key is shorter than
+    /// `MIN_COOKIE_KEY_SIZE`.
+    ///
+    pub fn parse(logger: &slog::Logger, filename: &str) -> Result<CookieKey, io::Error> {
+        CookieKey::parse_with_encoding(logger, filename, CookieKeyEncoding::Raw)
+    }
+
+    /// Parse a cookie key from a file with the given encoding. Surrounding whitespace of a hex or
+    /// base64 key is ignored, so that a trailing newline doesn't matter. A world-readable file is
+    /// still parsed, with a warning on `logger`.
     ///
-    pub fn parse(filename: &str) -> Result<CookieKey, io::Error> {
+    /// # Errors
+    ///
+    /// There will be an error, if we cannot open the file, its content cannot be decoded, or the
+    /// decoded key is shorter than `MIN_COOKIE_KEY_SIZE`.
+    ///
+    pub fn parse_with_encoding(
+        logger: &slog::Logger,
+        filename: &str,
+        encoding: CookieKeyEncoding,
+    ) -> Result<CookieKey, io::Error> {
         let mut file = File::open(filename)?;
         let mut buffer = Vec::new();
 
+        warn_if_world_readable(logger, filename, &file.metadata()?);
+
         file.read_to_end(&mut buffer)?;
-        Ok(CookieKey(buffer))
+        CookieKey::decode(buffer, encoding, filename)
+    }
+
+    /// Parse a cookie key from an environment variable with the given encoding, like a key file.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error, if the environment variable is not set, its value cannot be
+    /// decoded, or the decoded key is shorter than `MIN_COOKIE_KEY_SIZE`.
+    ///
+    pub fn from_env(name: &str, encoding: CookieKeyEncoding) -> Result<CookieKey, io::Error> {
+        let value = env::var(name).wrap_err()?;
+        CookieKey::decode(value.into_bytes(), encoding, name)
+    }
+
+    /// Decode the content of a key file or variable, named `source` in the errors, and check the
+    /// length of the key.
+    fn decode(
+        content: Vec<u8>,
+        encoding: CookieKeyEncoding,
+        source: &str,
+    ) -> Result<CookieKey, io::Error> {
+        let bytes = match encoding {
+            CookieKeyEncoding::Raw => content,
+            CookieKeyEncoding::Hex => hex::decode(String::from_utf8_lossy(&content).trim())
+                .wrap_err()?,
+            CookieKeyEncoding::Base64 => base64::decode(String::from_utf8_lossy(&content).trim())
+                .wrap_err()?,
+        };
+
+        if bytes.len() < MIN_COOKIE_KEY_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "cookie key in {} is {} bytes long, it must be at least {} bytes",
+                    source,
+                    bytes.len(),
+                    MIN_COOKIE_KEY_SIZE,
+                ),
+            ));
+        }
+        Ok(CookieKey(bytes))
     }
 
     /// Return a byte slice of a cookie key content.
@@ -45,26 +188,117 @@ impl CookieKey {
     }
 }
 
-// Only used in test.
-#[cfg(test)]
+/// Where the cookie key of a server config is read from, so that it can be read again when the
+/// keys are rotated on request.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CookieKeySource {
+    /// A key file and the encoding of its content.
+    File(String, CookieKeyEncoding),
+    /// An environment variable and the encoding of its value.
+    Env(String, CookieKeyEncoding),
+}
+
+impl CookieKeySource {
+    /// Parse the source of the cookie key of a server config. It's the environment variable named
+    /// by `cookie_key_env`, which is convenient in CI and containers, or else the file named by
+    /// `cookie_key_file`. Both are decoded with `cookie_key_encoding`. By default, a file is raw
+    /// and a variable is hex, because a variable can't hold arbitrary bytes.
+    pub fn from_settings(
+        settings: &config::Config,
+    ) -> Result<CookieKeySource, config::ConfigError> {
+        let encoding = match settings.get_str("cookie_key_encoding") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(name) => Some(name.parse().wrap_err()?),
+        };
+        match settings.get_str("cookie_key_env") {
+            Err(config::ConfigError::NotFound(_)) => {
+                let cookie_key_filename = settings.get_str("cookie_key_file")?;
+                let encoding = encoding.unwrap_or(CookieKeyEncoding::Raw);
+                Ok(CookieKeySource::File(cookie_key_filename, encoding))
+            },
+
+            // If it's other error, for example, unparseable error, it means that the user intended
+            // to enter the variable name but it just fails.
+            Err(error) => Err(error),
+
+            Ok(name) => Ok(CookieKeySource::Env(name, encoding.unwrap_or(CookieKeyEncoding::Hex))),
+        }
+    }
+
+    /// Read the cookie key from the source. The warnings about the key file go to `logger`.
+    pub fn load(&self, logger: &slog::Logger) -> Result<CookieKey, io::Error> {
+        match self {
+            CookieKeySource::File(filename, encoding) => {
+                CookieKey::parse_with_encoding(logger, filename, *encoding)
+            },
+            CookieKeySource::Env(name, encoding) => CookieKey::from_env(name, *encoding),
+        }
+    }
+}
+
+/// Return true if the file with the given metadata can be read by everyone.
+#[cfg(unix)]
+fn is_world_readable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o004 != 0
+}
+
+#[cfg(not(unix))]
+fn is_world_readable(_metadata: &fs::Metadata) -> bool {
+    false // no op for non-unix build
+}
+
+/// Log a warning if a file containing secret key material can be read by everyone.
+pub fn warn_if_world_readable(logger: &slog::Logger, filename: &str, metadata: &fs::Metadata) {
+    if is_world_readable(metadata) {
+        warn!(
+            logger,
+            "the key file {} is world-readable, please restrict its permissions", filename
+        );
+    }
+}
+
+// Only used in tests and benchmarks.
+#[cfg(any(test, feature = "bench"))]
 impl From<&[u8]> for CookieKey {
     fn from(bytes: &[u8]) -> CookieKey {
         CookieKey(Vec::from(bytes))
     }
 }
 
-pub fn make_cookie(keys: NTSKeys, master_key: &[u8], key_id: KeyId) -> Vec<u8> {
+/// Make a cookie carrying the keys and the id of the AEAD algorithm they are used with.
+pub fn make_cookie(
+    keys: NTSKeys,
+    aead: KnownAeadAlgorithm,
+    master_key: &[u8],
+    key_id: KeyId,
+) -> Vec<u8> {
+    make_cookie_with_context(keys, aead, master_key, key_id, &[])
+}
+
+/// Like `make_cookie`, but the cookie is bound to a client context, e.g. the subnet of the
+/// client. The context is authenticated as associated data, so the cookie can only be eaten with
+/// the same context. An empty context makes the same cookies as `make_cookie`.
+pub fn make_cookie_with_context(
+    keys: NTSKeys,
+    aead: KnownAeadAlgorithm,
+    master_key: &[u8],
+    key_id: KeyId,
+    context: &[u8],
+) -> Vec<u8> {
     let mut nonce = [0; 16];
     rand::thread_rng().fill(&mut nonce);
-    let mut plaintext = [0; 64];
+    let mut plaintext = [0; PLAINTEXT_SIZE];
+    plaintext[0..2].copy_from_slice(&aead.as_algorithm_id().to_be_bytes());
     for i in 0..32 {
-        plaintext[i] = keys.c2s[i];
+        plaintext[4 + i] = keys.c2s[i];
     }
     for i in 0..32 {
-        plaintext[32 + i] = keys.s2c[i];
+        plaintext[36 + i] = keys.s2c[i];
     }
     let mut aead = aead::Aes128SivAead::new(&master_key);
-    let mut ciphertext = aead.seal(&nonce, &[], &plaintext);
+    let mut ciphertext = aead.seal(&nonce, context, &plaintext);
     let mut out = Vec::new();
     out.extend(&key_id.to_be_bytes());
     out.extend(&nonce);
@@ -80,39 +314,74 @@ pub fn get_keyid(cookie: &[u8]) -> Option<KeyId> {
     }
 }
 
-fn unpack(pt: Vec<u8>) -> Option<NTSKeys> {
-    if pt.len() != 64 {
-        return None;
-    } else {
-        let mut key = NTSKeys {
-            c2s: [0; 32],
-            s2c: [0; 32],
-        };
-        for i in 0..32 {
-            key.c2s[i] = pt[i];
-            key.s2c[i] = pt[32 + i];
-        }
-        return Some(key);
+fn unpack(pt: Vec<u8>) -> Option<(u16, NTSKeys)> {
+    let (aead, keys) = match pt.len() {
+        PLAINTEXT_SIZE => (u16::from_be_bytes([pt[0], pt[1]]), &pt[4..]),
+        LEGACY_PLAINTEXT_SIZE => (KnownAeadAlgorithm::AeadAesSivCmac256.as_algorithm_id(), &pt[..]),
+        _ => return None,
+    };
+    let mut key = NTSKeys {
+        c2s: [0; 32],
+        s2c: [0; 32],
+    };
+    for i in 0..32 {
+        key.c2s[i] = keys[i];
+        key.s2c[i] = keys[32 + i];
     }
+    Some((aead, key))
 }
 
-pub fn eat_cookie(cookie: &[u8], key: &[u8]) -> Option<NTSKeys> {
+/// Decrypt a cookie, returning the id of the AEAD algorithm and the keys it carries.
+///
+/// Only the length and the key id, which are sent in the clear, are checked before the
+/// decryption, so returning early on them leaks nothing an observer doesn't already know. The
+/// tag is compared by miscreant in constant time, and the plaintext is only looked at once it's
+/// authenticated.
+pub fn eat_cookie(cookie: &[u8], key: &[u8]) -> Option<(u16, NTSKeys)> {
+    eat_cookie_with_context(cookie, key, &[])
+}
+
+/// Like `eat_cookie`, but for cookies made with `make_cookie_with_context`. A cookie made with
+/// another context fails to decrypt, just like a forged one.
+pub fn eat_cookie_with_context(
+    cookie: &[u8],
+    key: &[u8],
+    context: &[u8],
+) -> Option<(u16, NTSKeys)> {
     if cookie.len() < 40 {
         return None;
     }
     let ciphertext = &cookie[4..];
     let mut aead = aead::Aes128SivAead::new(&key);
-    let answer = aead.open(&ciphertext[0..16], &[], &ciphertext[16..]);
+    let answer = aead.open(&ciphertext[0..16], context, &ciphertext[16..]);
     match answer {
         Err(_) => None,
         Ok(buf) => unpack(buf),
     }
 }
 
+/// Return the cookie context of a client binding its cookies to its subnet, i.e. the /24 of an
+/// IPv4 address or the /64 of an IPv6 address. IPv4 clients of a dual stack socket get the
+/// context of their IPv4 address.
+pub fn subnet_context(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(v4) => v4.octets()[..3].to_vec(),
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            v6.octets()[12..15].to_vec()
+        }
+        IpAddr::V6(v6) => v6.octets()[..8].to_vec(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::error::Error;
+    use std::sync::{Arc, Mutex};
+
+    const AEAD: KnownAeadAlgorithm = KnownAeadAlgorithm::AeadAesSivCmac256;
+
     fn check_eq(a: NTSKeys, b: NTSKeys) {
         for i in 0..32 {
             assert_eq!(a.c2s[i], b.c2s[i]);
@@ -129,7 +398,7 @@ mod tests {
 
         let master_key = [0x07; 32];
         let key_id = KeyId::from_be_bytes([0x03; 4]);
-        let mut cookie = make_cookie(test, &master_key, key_id);
+        let mut cookie = make_cookie(test, AEAD, &master_key, key_id);
         let ret = get_keyid(&cookie);
 
         assert_eq!(cookie.len(), COOKIE_SIZE);
@@ -141,7 +410,10 @@ mod tests {
         let ret2 = eat_cookie(&cookie, &master_key);
         match ret2 {
             None => assert!(false),
-            Some(new_key) => check_eq(new_key, test),
+            Some((aead, new_key)) => {
+                assert_eq!(aead, 15);
+                check_eq(new_key, test);
+            }
         }
 
         cookie[9] = 0xff;
@@ -152,4 +424,219 @@ mod tests {
             Some(_) => assert!(false),
         }
     }
+
+    #[test]
+    fn test_legacy_cookie() {
+        let keys = NTSKeys {
+            s2c: [9; 32],
+            c2s: [10; 32],
+        };
+        let master_key = [0x07; 32];
+
+        // A cookie made before the AEAD algorithm id was added: the key id, the nonce and the
+        // two keys, sealed without any associated data.
+        let nonce = [0x05; 16];
+        let mut plaintext = Vec::new();
+        plaintext.extend(&keys.c2s);
+        plaintext.extend(&keys.s2c);
+        let mut cookie = vec![0x03; 4];
+        cookie.extend(&nonce);
+        cookie.append(&mut aead::Aes128SivAead::new(&master_key).seal(&nonce, &[], &plaintext));
+        assert_eq!(cookie.len(), LEGACY_COOKIE_SIZE);
+
+        let (aead, eaten) = eat_cookie(&cookie, &master_key).unwrap();
+        assert_eq!(aead, AEAD.as_algorithm_id());
+        check_eq(eaten, keys);
+    }
+
+    #[test]
+    fn test_cookie_context() {
+        let keys = NTSKeys {
+            s2c: [9; 32],
+            c2s: [10; 32],
+        };
+        let master_key = [0x07; 32];
+        let key_id = KeyId::from_be_bytes([0x03; 4]);
+        let context = subnet_context("192.0.2.1".parse().unwrap());
+        let other = subnet_context("198.51.100.1".parse().unwrap());
+
+        let cookie = make_cookie_with_context(keys, AEAD, &master_key, key_id, &context);
+        assert_eq!(cookie.len(), COOKIE_SIZE);
+        let (aead, eaten) = eat_cookie_with_context(&cookie, &master_key, &context).unwrap();
+        assert_eq!(aead, 15);
+        check_eq(eaten, keys);
+
+        // The cookie is rejected under another context, or without one.
+        assert!(eat_cookie_with_context(&cookie, &master_key, &other).is_none());
+        assert!(eat_cookie(&cookie, &master_key).is_none());
+
+        // A cookie without a context doesn't validate under one either.
+        let cookie = make_cookie(keys, AEAD, &master_key, key_id);
+        assert!(eat_cookie_with_context(&cookie, &master_key, &context).is_none());
+        assert!(eat_cookie_with_context(&cookie, &master_key, &[]).is_some());
+    }
+
+    #[test]
+    fn test_subnet_context() {
+        let context = |ip: &str| subnet_context(ip.parse().unwrap());
+
+        assert_eq!(context("192.0.2.1"), vec![192, 0, 2]);
+        assert_eq!(context("192.0.2.1"), context("192.0.2.200"));
+        assert_ne!(context("192.0.2.1"), context("192.0.3.1"));
+        // An IPv4 client of a dual stack socket.
+        assert_eq!(context("::ffff:192.0.2.1"), context("192.0.2.1"));
+
+        assert_eq!(context("2001:db8::1"), context("2001:db8::ffff:1"));
+        assert_ne!(context("2001:db8::1"), context("2001:db8:0:1::1"));
+    }
+
+    #[test]
+    fn test_redacted_keys() {
+        let keys = NTSKeys {
+            c2s: [0xab; 32],
+            s2c: [0xcd; 32],
+        };
+        let output = format!("{:x?}", keys);
+        assert!(output.contains("32 bytes"));
+        assert!(!output.contains("ab, ab"));
+        assert!(!output.contains("cd, cd"));
+        assert!(!output.contains(&hex::encode(&keys.c2s[..])));
+        assert!(!output.contains(&hex::encode(&keys.s2c[..])));
+    }
+
+    #[test]
+    fn test_cookie_key_from_env() {
+        let expected: Vec<u8> = (0..32).collect();
+        env::set_var("CFNTS_TEST_COOKIE_KEY_HEX", hex::encode(&expected));
+        let key = CookieKey::from_env("CFNTS_TEST_COOKIE_KEY_HEX", CookieKeyEncoding::Hex).unwrap();
+        assert_eq!(key.as_bytes(), &expected[..]);
+
+        // The encoding is not guessed: a hex string is also valid base64, but decodes to other
+        // bytes.
+        let key = CookieKey::from_env("CFNTS_TEST_COOKIE_KEY_HEX", CookieKeyEncoding::Base64);
+        assert_ne!(key.unwrap().as_bytes(), &expected[..]);
+
+        env::set_var("CFNTS_TEST_COOKIE_KEY_B64", base64::encode(&expected));
+        let key = CookieKey::from_env("CFNTS_TEST_COOKIE_KEY_B64", CookieKeyEncoding::Base64);
+        assert_eq!(key.unwrap().as_bytes(), &expected[..]);
+
+        // The decoded key is too short.
+        env::set_var("CFNTS_TEST_COOKIE_KEY_SHORT", "deadbeef");
+        CookieKey::from_env("CFNTS_TEST_COOKIE_KEY_SHORT", CookieKeyEncoding::Hex).unwrap_err();
+
+        CookieKey::from_env("CFNTS_TEST_COOKIE_KEY_UNSET", CookieKeyEncoding::Hex).unwrap_err();
+    }
+
+    fn load_from_settings(settings: &config::Config) -> Result<CookieKey, Box<dyn Error>> {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        Ok(CookieKeySource::from_settings(settings)?.load(&logger)?)
+    }
+
+    #[test]
+    fn test_cookie_key_from_settings() {
+        let expected: Vec<u8> = (0..32).collect();
+        env::set_var("CFNTS_TEST_SETTINGS_COOKIE_KEY", hex::encode(&expected));
+        let path = env::temp_dir().join("cfnts-test-settings-cookie-key");
+        fs::write(&path, &expected).unwrap();
+
+        // Without a variable, the key is read from the file, raw by default.
+        let mut settings = config::Config::new();
+        settings.set("cookie_key_file", path.to_str().unwrap()).unwrap();
+        let key = load_from_settings(&settings).unwrap();
+        assert_eq!(key.as_bytes(), &expected[..]);
+        let filename = String::from(path.to_str().unwrap());
+        let source = CookieKeySource::File(filename, CookieKeyEncoding::Raw);
+        assert_eq!(CookieKeySource::from_settings(&settings).unwrap(), source);
+
+        // The variable takes precedence, hex by default.
+        let name = "CFNTS_TEST_SETTINGS_COOKIE_KEY";
+        settings.set("cookie_key_env", name).unwrap();
+        let key = load_from_settings(&settings).unwrap();
+        assert_eq!(key.as_bytes(), &expected[..]);
+        let source = CookieKeySource::Env(String::from(name), CookieKeyEncoding::Hex);
+        assert_eq!(CookieKeySource::from_settings(&settings).unwrap(), source);
+
+        // An explicit encoding applies to the variable too.
+        settings.set("cookie_key_encoding", "raw").unwrap();
+        let key = load_from_settings(&settings).unwrap();
+        assert_eq!(key.as_bytes(), hex::encode(&expected).as_bytes());
+
+        settings.set("cookie_key_encoding", "base32").unwrap();
+        load_from_settings(&settings).unwrap_err();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cookie_key_encoding() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let expected: Vec<u8> = (0..32).collect();
+        let encoded = [
+            (CookieKeyEncoding::Raw, expected.clone()),
+            (CookieKeyEncoding::Hex, format!("{}\n", hex::encode(&expected)).into_bytes()),
+            (CookieKeyEncoding::Base64, format!("{}\n", base64::encode(&expected)).into_bytes()),
+        ];
+
+        for (encoding, content) in encoded.iter() {
+            let path = env::temp_dir().join(format!("cfnts-test-encoding-{:?}.key", encoding));
+            fs::write(&path, content).unwrap();
+            let filename = path.to_str().unwrap();
+            let key = CookieKey::parse_with_encoding(&logger, filename, *encoding).unwrap();
+            assert_eq!(key.as_bytes(), &expected[..]);
+            fs::remove_file(&path).unwrap();
+        }
+
+        // The decoded key is too short.
+        let path = env::temp_dir().join("cfnts-test-encoding-short.key");
+        fs::write(&path, "deadbeef").unwrap();
+        let filename = path.to_str().unwrap();
+        CookieKey::parse_with_encoding(&logger, filename, CookieKeyEncoding::Hex).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!("hex".parse::<CookieKeyEncoding>().unwrap(), CookieKeyEncoding::Hex);
+        "pem".parse::<CookieKeyEncoding>().unwrap_err();
+    }
+
+    /// A drain keeping the level and the message of every record, to check what was logged.
+    struct Records(Arc<Mutex<Vec<(slog::Level, String)>>>);
+
+    impl slog::Drain for Records {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push((record.level(), record.msg().to_string()));
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_world_readable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let logger = slog::Logger::root(Records(records.clone()), slog::o!());
+        let path = env::temp_dir().join("cfnts-test-world-readable.key");
+        let filename = path.to_str().unwrap();
+        fs::write(&path, &[0x07; 32][..]).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(is_world_readable(&fs::metadata(&path).unwrap()));
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(!is_world_readable(&fs::metadata(&path).unwrap()));
+        CookieKey::parse(&logger, filename).unwrap();
+        assert!(records.lock().unwrap().is_empty());
+
+        // Parsing still succeeds with a world-readable key file. It only warns.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        CookieKey::parse(&logger, filename).unwrap();
+        let records = records.lock().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].0, slog::Level::Warning);
+        assert!(records[0].1.contains(filename));
+        assert!(records[0].1.contains("world-readable"));
+
+        fs::remove_file(&path).unwrap();
+    }
 }
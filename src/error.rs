@@ -2,9 +2,67 @@
 // Copyright (c) 2019, Cloudflare. All rights reserved.
 // See LICENSE for licensing information.
 
-//! Traits for working with errors.
+//! The errors of the servers failing to start, and traits for working with errors.
 
 use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::key_rotator::RotateError;
+
+/// Why a server failed to start. The binary exits with a distinct code for each kind, so that
+/// scripts and supervisors can tell a configuration to fix from, e.g., a port still in use.
+#[derive(Debug)]
+pub enum StartupError {
+    /// The configuration file is missing or invalid.
+    Config(config::ConfigError),
+    /// A listening socket could not be bound, e.g. the port is in use or needs privileges.
+    Bind(io::Error),
+    /// The cookie keys could not be loaded from the key backend, i.e. the Memcached server.
+    KeyBackend(RotateError),
+    /// Any other failure, e.g. a signal handler could not be installed or a bound socket could
+    /// not be set up.
+    Other(Box<dyn Error>),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StartupError::Config(error) => write!(f, "invalid configuration: {}", error),
+            StartupError::Bind(error) => write!(f, "cannot bind the listening socket: {}", error),
+            StartupError::KeyBackend(RotateError::MemcacheError(error)) => {
+                write!(f, "cannot load the cookie keys from memcached: {:?}", error)
+            }
+            StartupError::KeyBackend(RotateError::KeyIdNotFound(key_id)) => {
+                write!(f, "cookie key {:?} not found in memcached", key_id)
+            }
+            StartupError::Other(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for StartupError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StartupError::Config(error) => Some(error),
+            StartupError::Bind(error) => Some(error),
+            StartupError::KeyBackend(_) => None,
+            StartupError::Other(error) => Some(error.as_ref()),
+        }
+    }
+}
+
+impl From<config::ConfigError> for StartupError {
+    fn from(error: config::ConfigError) -> StartupError {
+        StartupError::Config(error)
+    }
+}
+
+impl From<RotateError> for StartupError {
+    fn from(error: RotateError) -> StartupError {
+        StartupError::KeyBackend(error)
+    }
+}
 
 /// `WrapError` allows the implementor to wrap its own error type in another error type.
 pub trait WrapError<T: Error> {
@@ -6,22 +6,31 @@
 
 use lazy_static::lazy_static;
 
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
 #[cfg(not(test))]
 use memcache::MemcacheError;
 
-use prometheus::{opts, register_counter, register_int_counter, IntCounter};
+use prometheus::{
+    opts, register_counter, register_int_counter, register_int_gauge, IntCounter, IntGauge,
+};
 
 use ring::digest;
 use ring::hmac;
 
+use slog::{error, info};
+
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::{Duration, UNIX_EPOCH};
 #[cfg(not(test))]
 use std::time::SystemTime;
 
-use crate::cookie::CookieKey;
+use crate::cookie::{CookieKey, CookieKeySource};
 
 lazy_static! {
     static ref ROTATION_COUNTER: IntCounter =
@@ -31,11 +40,20 @@ lazy_static! {
         "Number of failures in key rotation"
     )
     .unwrap();
+    static ref SINCE_ROTATION_GAUGE: IntGauge = register_int_gauge!(
+        "ntp_key_seconds_since_last_rotation",
+        "Number of seconds since the last successful key rotation"
+    )
+    .unwrap();
+    static ref ACTIVE_KEYS_GAUGE: IntGauge =
+        register_int_gauge!("ntp_key_active_keys", "Number of keys in the rotator").unwrap();
+    static ref LATEST_KEY_ID_GAUGE: IntGauge =
+        register_int_gauge!("ntp_key_latest_key_id", "Key id of the current period").unwrap();
 }
 
 /// Key id for `KeyRotator`.
 // This struct should be `Clone` and `Copy` because the internal representation is just a `u32`.
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct KeyId(u32);
 
 impl KeyId {
@@ -63,6 +81,16 @@ impl KeyId {
     }
 }
 
+/// Error returned from `KeyRotator::check_key_id` method, when a key id is outside the window of
+/// the rotator.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyIdError {
+    /// The key id is older than the backward periods.
+    Expired,
+    /// The key id is newer than the forward periods, which means clock skew or forgery.
+    FromFuture,
+}
+
 /// Error struct returned from `KeyRotator::rotate` method.
 #[derive(Debug)]
 pub enum RotateError {
@@ -79,6 +107,64 @@ impl From<MemcacheError> for RotateError {
     }
 }
 
+/// The length of the periods of a `KeyRotator` and the number of periods around the current one
+/// it caches keys for.
+///
+/// Every server sharing the Memcached server must use the same duration, because the key of each
+/// period is stored under the Epoch time at its beginning.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RotationPeriods {
+    /// Length of each period in seconds.
+    pub duration: u64,
+    /// The number of future periods whose keys are cached, to accept the cookies of servers
+    /// whose clocks are ahead.
+    pub forward_periods: u64,
+    /// The number of previous periods whose keys are cached. Cookies are valid for about
+    /// `duration * backward_periods` seconds.
+    pub backward_periods: u64,
+}
+
+impl Default for RotationPeriods {
+    /// Hourly keys and cookies valid for a day.
+    fn default() -> RotationPeriods {
+        RotationPeriods {
+            duration: 3600,
+            forward_periods: 2,
+            backward_periods: 24,
+        }
+    }
+}
+
+impl RotationPeriods {
+    /// Parse the periods from the `cookie_rotation_secs`, `cookie_forward_periods` and
+    /// `cookie_backward_periods` settings. Every setting defaults to the value of
+    /// `RotationPeriods::default()`.
+    pub fn from_settings(
+        settings: &config::Config,
+    ) -> Result<RotationPeriods, config::ConfigError> {
+        let defaults = RotationPeriods::default();
+        let get = |key: &str, default: u64| match settings.get_int(key) {
+            // If it's a not-found error, we just set it to the default value.
+            Err(config::ConfigError::NotFound(_)) => Ok(default),
+            Err(error) => Err(error),
+            Ok(val) => u64::try_from(val).map_err(|_| config::ConfigError::Message(format!(
+                "the {} must not be negative", key
+            ))),
+        };
+        let periods = RotationPeriods {
+            duration: get("cookie_rotation_secs", defaults.duration)?,
+            forward_periods: get("cookie_forward_periods", defaults.forward_periods)?,
+            backward_periods: get("cookie_backward_periods", defaults.backward_periods)?,
+        };
+        if periods.duration == 0 {
+            return Err(config::ConfigError::Message(String::from(
+                "the cookie rotation must be at least one second"
+            )));
+        }
+        Ok(periods)
+    }
+}
+
 /// Key rotator.
 pub struct KeyRotator {
     /// URL of the Memcached server.
@@ -105,16 +191,21 @@ pub struct KeyRotator {
     /// Cookie key that will be used as a MAC key of the rotator.
     master_key: CookieKey,
 
+    /// Where the master key was read from, if anywhere. It's read again when a rotation is
+    /// requested, so that a replaced key takes effect without a restart.
+    master_key_source: Option<CookieKeySource>,
+
     /// Key id of the current period.
     latest_key_id: KeyId,
 
     /// Cache store.
     cache: HashMap<KeyId, hmac::Signature>,
 
+    /// The timestamp in seconds of the last successful rotation. It's `None` if the rotator has
+    /// never rotated successfully.
+    last_rotation: Option<u64>,
+
     /// Logger.
-    // TODO: since we don't use the logger now, I will put an `allow(dead_code)` here first. I will
-    // remove it when it's used.
-    #[allow(dead_code)]
     logger: slog::Logger,
 }
 
@@ -124,6 +215,7 @@ impl KeyRotator {
         prefix: String,
         memcached_url: String,
         master_key: CookieKey,
+        periods: RotationPeriods,
         logger: slog::Logger,
     ) -> Result<KeyRotator, RotateError> {
         let mut rotator = KeyRotator {
@@ -131,17 +223,16 @@ impl KeyRotator {
             latest_key_id: KeyId::new(0),
             // The cache should never be empty. This is just a temporary value.
             cache: HashMap::new(),
-
-            // It seems that currently we don't have to customize the following three properties,
-            // so I will just put default values.
-            duration: 3600,
-            number_of_forward_periods: 2,
-            number_of_backward_periods: 24,
+            last_rotation: None,
 
             // From parameters.
+            duration: periods.duration,
+            number_of_forward_periods: periods.forward_periods,
+            number_of_backward_periods: periods.backward_periods,
             prefix,
             memcached_url,
             master_key,
+            master_key_source: None,
             logger,
         };
 
@@ -200,15 +291,8 @@ impl KeyRotator {
         // The timestamp at the beginning of the current period.
         let current_epoch = current_period * self.duration;
 
-        // The first period number that we want to iterate through.
-        let first_period = current_period.saturating_sub(self.number_of_backward_periods);
-
-        // The last period number that we want to iterate through.
-        let last_period = current_period.saturating_add(self.number_of_forward_periods);
-
-        let removed_period = first_period.saturating_sub(1);
-        let removed_epoch = removed_period * self.duration;
-        self.cache_remove(KeyId::from_epoch(removed_epoch));
+        // The first and the last period numbers that we want to iterate through.
+        let (first_period, last_period) = self.window(timestamp);
 
         // Connecting to memcached. I have to add [..] because it seems that Rust is not smart
         // enough to do auto-dereference.
@@ -233,7 +317,121 @@ impl KeyRotator {
 
         // Not all of our friends may have gotten the same forwards keys as we did.
         self.latest_key_id = KeyId::from_epoch(current_epoch);
+        self.last_rotation = Some(timestamp);
+
+        self.prune_expired();
+        self.update_metrics();
+
+        Ok(())
+    }
+
+    /// Export the state of the rotation to the gauges. If the rotator has never rotated
+    /// successfully, the seconds since the last rotation count from the Epoch time, so that
+    /// alerts on a stalled rotation fire.
+    ///
+    /// # Panics
+    ///
+    /// If the system time is before the UNIX Epoch time.
+    ///
+    pub fn update_metrics(&self) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("The system time must be after the UNIX Epoch time.")
+            .as_secs();
+
+        let since_rotation = timestamp.saturating_sub(self.last_rotation.unwrap_or(0));
+        SINCE_ROTATION_GAUGE.set(since_rotation as i64);
+        ACTIVE_KEYS_GAUGE.set(self.cache.len() as i64);
+        LATEST_KEY_ID_GAUGE.set(i64::from(self.latest_key_id.0));
+    }
+
+    /// Return the first and the last period numbers that the rotator must cache at the
+    /// timestamp.
+    fn window(&self, timestamp: u64) -> (u64, u64) {
+        let current_period = timestamp / self.duration;
+        (
+            current_period.saturating_sub(self.number_of_backward_periods),
+            current_period.saturating_add(self.number_of_forward_periods),
+        )
+    }
 
+    /// Remove the keys of the periods outside the current window from the cache, so that the
+    /// cache holds at most `number_of_forward_periods + number_of_backward_periods + 1` keys.
+    /// The latest key is always kept, because we must have a key to make cookies with.
+    ///
+    /// # Panics
+    ///
+    /// If the system time is before the UNIX Epoch time.
+    ///
+    pub fn prune_expired(&mut self) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("The system time must be after the UNIX Epoch time.")
+            .as_secs();
+
+        let (first_period, last_period) = self.window(timestamp);
+        let key_ids: Vec<KeyId> = (first_period..=last_period)
+            .map(|period_number| KeyId::from_epoch(period_number * self.duration))
+            .collect();
+        let latest_key_id = self.latest_key_id;
+        self.cache.retain(|key_id, _| *key_id == latest_key_id || key_ids.contains(key_id));
+    }
+
+    /// Return true if the rotator has never rotated successfully or the last successful rotation
+    /// is older than two periods, which means that the rotation is stalled.
+    ///
+    /// # Panics
+    ///
+    /// If the system time is before the UNIX Epoch time.
+    ///
+    pub fn is_stale(&self) -> bool {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("The system time must be after the UNIX Epoch time.")
+            .as_secs();
+
+        match self.last_rotation {
+            Some(last_rotation) => {
+                timestamp.saturating_sub(last_rotation) > self.duration.saturating_mul(2)
+            },
+            None => true,
+        }
+    }
+
+    /// Check that the key id is within the window of the rotator at the current time. The latest
+    /// key id is always valid, because it's the one we make cookies with.
+    ///
+    /// # Panics
+    ///
+    /// If the system time is before the UNIX Epoch time.
+    ///
+    pub fn check_key_id(&self, key_id: KeyId) -> Result<(), KeyIdError> {
+        if key_id == self.latest_key_id {
+            return Ok(());
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("The system time must be after the UNIX Epoch time.")
+            .as_secs();
+
+        let (first_period, last_period) = self.window(timestamp);
+        if key_id < KeyId::from_epoch(first_period * self.duration) {
+            Err(KeyIdError::Expired)
+        } else if key_id > KeyId::from_epoch(last_period * self.duration) {
+            Err(KeyIdError::FromFuture)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Set where the master key is read from again when a rotation is requested.
+    pub fn set_master_key_source(&mut self, source: Option<CookieKeySource>) {
+        self.master_key_source = source;
+    }
+
+    /// Read the master key again from its source, if it has one. The keys made with the old
+    /// master key stay in the cache until the next rotation replaces them.
+    pub fn reload_master_key(&mut self) -> Result<(), io::Error> {
+        if let Some(source) = &self.master_key_source {
+            self.master_key = source.load(&self.logger)?;
+        }
         Ok(())
     }
 
@@ -248,12 +446,6 @@ impl KeyRotator {
         self.cache.insert(key_id, tag);
     }
 
-    /// Remove an entry from the cache.
-    // It should be private. Don't make it public.
-    fn cache_remove(&mut self, key_id: KeyId) {
-        self.cache.remove(&key_id);
-    }
-
     /// Return the latest key id and hmac tag of the rotator.
     pub fn latest_key_value(&self) -> (KeyId, &hmac::Signature) {
         // This unwrap cannot panic because the HashMap will always contain the latest key id.
@@ -266,12 +458,93 @@ impl KeyRotator {
     }
 }
 
+// Only used in tests and benchmarks.
+#[cfg(any(test, feature = "bench"))]
+impl KeyRotator {
+    /// Create a rotator which already has a single key, without connecting to the Memcached
+    /// server. The key will be the latest key of the rotator.
+    pub fn with_key(master_key: CookieKey, key_id: KeyId, value: &[u8]) -> KeyRotator {
+        use sloggers::Build;
+        use sloggers::null::NullLoggerBuilder;
+
+        let mut rotator = KeyRotator {
+            memcached_url: String::from("unused"),
+            prefix: String::from("test"),
+            duration: 3600,
+            number_of_forward_periods: 2,
+            number_of_backward_periods: 24,
+            master_key,
+            master_key_source: None,
+            latest_key_id: key_id,
+            cache: HashMap::new(),
+            last_rotation: None,
+            logger: NullLoggerBuilder.build().unwrap(),
+        };
+        rotator.cache_insert(key_id, value);
+        rotator
+    }
+}
+
+/// Whether a rotation was requested out of band with `request_rotation`.
+static ROTATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask the thread of `periodic_rotate` to rotate the keys right away, instead of at the end of
+/// the current period. It only sets a flag, so it's safe to call from a signal handler.
+pub fn request_rotation() {
+    ROTATION_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_rotation_signal(_: libc::c_int) {
+    request_rotation();
+}
+
+/// Request a rotation whenever the process receives SIGUSR1, e.g. with `kill -USR1`.
+pub fn rotate_on_sigusr1() -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_rotation_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safe because the handler only stores to an atomic.
+    unsafe { sigaction(Signal::SIGUSR1, &action) }?;
+    Ok(())
+}
+
+/// Read the master key again and rotate the keys if a rotation was requested, and log the new
+/// latest key id. Within a period, the rotation fetches the same keys from the Memcached server,
+/// so it's the new master key which changes the keys we use. It returns whether it rotated.
+fn rotate_if_requested(rotor: &Arc<RwLock<KeyRotator>>) -> bool {
+    if !ROTATION_REQUESTED.swap(false, Ordering::SeqCst) {
+        return false;
+    }
+    let mut rotator = rotor.write().unwrap();
+    if let Err(error) = rotator.reload_master_key() {
+        error!(rotator.logger, "failure to reload the master key on request: {:?}", error);
+    }
+    match rotator.rotate() {
+        Ok(()) => {
+            info!(rotator.logger, "rotated the keys on request, latest key id {:?}",
+                  rotator.latest_key_id)
+        }
+        Err(error) => error!(rotator.logger, "failure to rotate the keys on request: {:?}", error),
+    }
+    true
+}
+
 pub fn periodic_rotate(rotor: Arc<RwLock<KeyRotator>>) {
     let mut rotor = rotor.clone();
+    // The requested rotations run in this thread too, so they never race with the scheduled
+    // ones. Sleep a second at a time, so that a request doesn't wait for the end of the period
+    // and the seconds since the last rotation stay current.
     thread::spawn(move || loop {
         inner(&mut rotor);
         let restlen = read_sleep(&rotor);
-        thread::sleep(Duration::from_secs(restlen as u64));
+        for _ in 0..restlen {
+            thread::sleep(Duration::from_secs(1));
+            if !rotate_if_requested(&rotor) {
+                rotor.read().unwrap().update_metrics();
+            }
+        }
     });
 }
 
@@ -295,6 +568,8 @@ fn read_sleep(rotor: &Arc<RwLock<KeyRotator>>) -> u64 {
 mod test {
     use super::*;
 
+    use crate::cookie::CookieKeyEncoding;
+
     use ::memcache::MemcacheError;
     use lazy_static::lazy_static;
     use sloggers::Build;
@@ -324,6 +599,8 @@ mod test {
     // Mocking SystemTime.
     lazy_static! {
         pub static ref NOW: Mutex<u64> = Mutex::new(0);
+        // The tests share the mocked time, so they must not run at the same time.
+        static ref SERIAL: Mutex<()> = Mutex::new(());
     }
     pub struct SystemTime;
     impl SystemTime {
@@ -334,10 +611,34 @@ mod test {
         }
     }
 
+    /// A rotator which has never rotated, with a zero master key.
+    fn test_rotator(
+        prefix: &str,
+        duration: u64,
+        forward_periods: u64,
+        backward_periods: u64,
+        latest_key_id: KeyId,
+    ) -> KeyRotator {
+        KeyRotator {
+            memcached_url: String::from("unused"),
+            prefix: String::from(prefix),
+            duration,
+            number_of_forward_periods: forward_periods,
+            number_of_backward_periods: backward_periods,
+            master_key: CookieKey::from(&[0; 32][..]),
+            master_key_source: None,
+            latest_key_id,
+            cache: HashMap::new(),
+            last_rotation: None,
+            logger: NullLoggerBuilder.build().unwrap(),
+        }
+    }
+
     #[test]
     fn test_rotation() {
         use self::memcache::HASH_MAP;
 
+        let _serial = SERIAL.lock().unwrap();
         let mut hash_map = HASH_MAP.lock().unwrap();
         hash_map.insert("test/1".to_string(), vec![1; 32]);
         hash_map.insert("test/2".to_string(), vec![2; 32]);
@@ -352,11 +653,16 @@ mod test {
             number_of_forward_periods: 1,
             number_of_backward_periods: 1,
             master_key: CookieKey::from(&[0, 32][..]),
+            master_key_source: None,
             latest_key_id: KeyId::from_be_bytes([1, 2, 3, 4]),
             cache: HashMap::new(),
+            last_rotation: None,
             logger: NullLoggerBuilder.build().unwrap(),
         };
 
+        // The rotator never rotates, so it must be stale.
+        assert!(rotator.is_stale());
+
         *NOW.lock().unwrap() = 2;
         // No error because the hash map has "test/1", "test/2", and "test/3".
         rotator.rotate().unwrap();
@@ -369,6 +675,8 @@ mod test {
 
         // The key id should change.
         assert_ne!(old_latest, new_latest);
+        // The rotator has just rotated.
+        assert!(!rotator.is_stale());
 
         *NOW.lock().unwrap() = 1;
         // Return error because the hash map doesn't have "test/0".
@@ -377,5 +685,192 @@ mod test {
         *NOW.lock().unwrap() = 4;
         // Return error because the hash map doesn't have "test/5".
         rotator.rotate().unwrap_err();
+
+        *NOW.lock().unwrap() = 6;
+        // The last successful rotation was at 3, which is more than two periods ago.
+        assert!(rotator.is_stale());
+    }
+
+    #[test]
+    fn test_connect_periods() {
+        use self::memcache::HASH_MAP;
+
+        let _serial = SERIAL.lock().unwrap();
+        let mut hash_map = HASH_MAP.lock().unwrap();
+        for epoch in (0..=200).step_by(10) {
+            hash_map.insert(format!("periods/{}", epoch), vec![epoch as u8; 32]);
+        }
+        drop(hash_map);
+
+        let periods = RotationPeriods {
+            duration: 10,
+            forward_periods: 1,
+            backward_periods: 4,
+        };
+        *NOW.lock().unwrap() = 105;
+        let rotator = KeyRotator::connect(
+            String::from("periods"),
+            String::from("unused"),
+            CookieKey::from(&[0; 32][..]),
+            periods,
+            NullLoggerBuilder.build().unwrap(),
+        ).unwrap();
+
+        assert_eq!(rotator.duration, 10);
+        assert_eq!(rotator.number_of_forward_periods, 1);
+        assert_eq!(rotator.number_of_backward_periods, 4);
+        // The keys of 60 to 110.
+        let mut key_ids: Vec<KeyId> = rotator.cache.keys().cloned().collect();
+        key_ids.sort();
+        let expected: Vec<KeyId> = (6..=11).map(|period| KeyId::from_epoch(period * 10)).collect();
+        assert_eq!(key_ids, expected);
+        assert_eq!(rotator.latest_key_value().0, KeyId::from_epoch(100));
+    }
+
+    #[test]
+    fn test_prune_expired() {
+        use self::memcache::HASH_MAP;
+
+        let _serial = SERIAL.lock().unwrap();
+        let mut hash_map = HASH_MAP.lock().unwrap();
+        for epoch in 0..=1000 {
+            hash_map.insert(format!("prune/{}", epoch), vec![epoch as u8; 32]);
+        }
+        drop(hash_map);
+
+        let mut rotator = test_rotator("prune", 10, 2, 3, KeyId::new(0));
+
+        // Skip a few periods between the rotations, as if the rotation were stalled from time
+        // to time.
+        for now in (100..900).step_by(35) {
+            *NOW.lock().unwrap() = now;
+            rotator.rotate().unwrap();
+            assert!(rotator.cache.len() <= 2 + 3 + 1);
+        }
+
+        let mut key_ids: Vec<KeyId> = rotator.cache.keys().cloned().collect();
+        key_ids.sort();
+        // The last rotation is at 870, so the window is from 840 to 890.
+        let expected: Vec<KeyId> = (84..=89).map(|period| KeyId::from_epoch(period * 10)).collect();
+        assert_eq!(key_ids, expected);
+        assert_eq!(rotator.latest_key_value().0, KeyId::from_epoch(870));
+    }
+
+    #[test]
+    fn test_requested_rotation() {
+        use self::memcache::HASH_MAP;
+
+        let _serial = SERIAL.lock().unwrap();
+        let mut hash_map = HASH_MAP.lock().unwrap();
+        for epoch in 0..=5 {
+            hash_map.insert(format!("request/{}", epoch), vec![epoch as u8; 32]);
+        }
+        drop(hash_map);
+
+        let path = std::env::temp_dir().join("cfnts-test-requested-rotation.key");
+        std::fs::write(&path, &[1; 32][..]).unwrap();
+        let source = CookieKeySource::File(
+            String::from(path.to_str().unwrap()),
+            CookieKeyEncoding::Raw,
+        );
+
+        let mut rotator = test_rotator("request", 1, 1, 1, KeyId::new(0));
+        rotator.set_master_key_source(Some(source));
+        let rotor = Arc::new(RwLock::new(rotator));
+        let latest_value = || {
+            let rotator = rotor.read().unwrap();
+            let (key_id, value) = rotator.latest_key_value();
+            (key_id, Vec::from(value.as_ref()))
+        };
+
+        *NOW.lock().unwrap() = 2;
+        rotor.write().unwrap().rotate().unwrap();
+        let (old_key_id, old_value) = latest_value();
+
+        // Nothing happens until a rotation is requested, even if the key file is replaced.
+        std::fs::write(&path, &[2; 32][..]).unwrap();
+        assert!(!rotate_if_requested(&rotor));
+        assert_eq!(latest_value(), (old_key_id, old_value.clone()));
+
+        // Within the same period, the requested rotation makes new keys with the new master key.
+        request_rotation();
+        assert!(rotate_if_requested(&rotor));
+        let (new_key_id, new_value) = latest_value();
+        assert_eq!(new_key_id, old_key_id);
+        assert_ne!(new_value, old_value);
+
+        // The request is consumed.
+        assert!(!rotate_if_requested(&rotor));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rotation_metrics() {
+        use self::memcache::HASH_MAP;
+
+        let _serial = SERIAL.lock().unwrap();
+        let mut hash_map = HASH_MAP.lock().unwrap();
+        for epoch in (0..=100).step_by(10) {
+            hash_map.insert(format!("metrics/{}", epoch), vec![epoch as u8; 32]);
+        }
+        drop(hash_map);
+
+        let mut rotator = test_rotator("metrics", 10, 1, 2, KeyId::new(0));
+
+        // The rotator never rotates, so the seconds count from the Epoch time.
+        *NOW.lock().unwrap() = 55;
+        rotator.update_metrics();
+        assert_eq!(SINCE_ROTATION_GAUGE.get(), 55);
+        assert_eq!(ACTIVE_KEYS_GAUGE.get(), 0);
+
+        rotator.rotate().unwrap();
+        assert_eq!(SINCE_ROTATION_GAUGE.get(), 0);
+        // The keys of 30, 40, 50, and 60.
+        assert_eq!(ACTIVE_KEYS_GAUGE.get(), 4);
+        assert_eq!(LATEST_KEY_ID_GAUGE.get(), 50);
+
+        *NOW.lock().unwrap() = 62;
+        rotator.update_metrics();
+        assert_eq!(SINCE_ROTATION_GAUGE.get(), 7);
+        assert_eq!(LATEST_KEY_ID_GAUGE.get(), 50);
+
+        rotator.rotate().unwrap();
+        assert_eq!(SINCE_ROTATION_GAUGE.get(), 0);
+        assert_eq!(LATEST_KEY_ID_GAUGE.get(), 60);
+    }
+
+    #[test]
+    fn test_check_key_id() {
+        let _serial = SERIAL.lock().unwrap();
+        let rotator = test_rotator("unused", 10, 2, 3, KeyId::from_epoch(300));
+
+        // The window is from 470 to 520.
+        *NOW.lock().unwrap() = 505;
+        assert_eq!(rotator.check_key_id(KeyId::from_epoch(470)), Ok(()));
+        assert_eq!(rotator.check_key_id(KeyId::from_epoch(500)), Ok(()));
+        assert_eq!(rotator.check_key_id(KeyId::from_epoch(520)), Ok(()));
+        assert_eq!(rotator.check_key_id(KeyId::from_epoch(460)), Err(KeyIdError::Expired));
+        assert_eq!(rotator.check_key_id(KeyId::from_epoch(530)), Err(KeyIdError::FromFuture));
+
+        // The latest key id is valid even if the rotation is stalled.
+        assert_eq!(rotator.check_key_id(KeyId::from_epoch(300)), Ok(()));
+    }
+
+    #[test]
+    fn test_rotation_periods_from_settings() {
+        let mut settings = config::Config::new();
+        assert_eq!(RotationPeriods::from_settings(&settings).unwrap(), RotationPeriods::default());
+
+        settings.set("cookie_rotation_secs", 600i64).unwrap();
+        settings.set("cookie_backward_periods", 6i64).unwrap();
+        let periods = RotationPeriods::from_settings(&settings).unwrap();
+        assert_eq!(periods.duration, 600);
+        assert_eq!(periods.forward_periods, 2);
+        assert_eq!(periods.backward_periods, 6);
+
+        settings.set("cookie_rotation_secs", 0i64).unwrap();
+        RotationPeriods::from_settings(&settings).unwrap_err();
+        settings.set("cookie_rotation_secs", -1i64).unwrap();
+        RotationPeriods::from_settings(&settings).unwrap_err();
     }
 }
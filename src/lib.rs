@@ -0,0 +1,23 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The library of cfnts. The `cfnts` binary is a thin wrapper around it, while the library
+//! also lets the benchmarks reach the packet processing code.
+
+extern crate lazy_static;
+extern crate log;
+// The register macros of prometheus expand to its hidden helper macros, which must be in scope.
+#[macro_use]
+extern crate prometheus;
+extern crate slog;
+
+pub mod cfsock;
+pub mod cmd;
+pub mod cookie;
+pub mod error;
+pub mod key_rotator;
+pub mod metrics;
+pub mod ntp;
+pub mod nts_ke;
+pub mod sub_command;
@@ -2,23 +2,13 @@
 // Copyright (c) 2019, Cloudflare. All rights reserved.
 // See LICENSE for licensing information.
 
-extern crate lazy_static;
-extern crate log;
-extern crate prometheus;
 extern crate slog;
 extern crate slog_scope;
 extern crate slog_stdlog;
 extern crate sloggers;
 
-mod cfsock;
-mod cmd;
-mod cookie;
-mod error;
-mod key_rotator;
-mod metrics;
-mod ntp;
-mod nts_ke;
-mod sub_command;
+use cfnts::error::StartupError;
+use cfnts::{cmd, cookie, sub_command};
 
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
@@ -46,6 +36,23 @@ fn create_logger<'a>(matches: &clap::ArgMatches<'a>) -> slog::Logger {
     builder.build().expect("BUG: TerminalLoggerBuilder::build shouldn't return an error.")
 }
 
+/// Return the exit code of a server failing to start, so that scripts and supervisors can tell
+/// the failures apart:
+///
+/// * 1: any other failure, e.g. a signal handler could not be installed,
+/// * 2: the configuration file is missing or invalid,
+/// * 3: a listening socket could not be bound, e.g. the port is in use or needs privileges,
+/// * 4: the cookie keys could not be loaded from memcached.
+///
+fn exit_code(error: &StartupError) -> i32 {
+    match error {
+        StartupError::Other(_) => 1,
+        StartupError::Config(_) => 2,
+        StartupError::Bind(_) => 3,
+        StartupError::KeyBackend(_) => 4,
+    }
+}
+
 /// The entry point of cfnts.
 fn main() {
     // According to the documentation of `get_matches`, if the parsing fails, an error will be
@@ -54,6 +61,9 @@ fn main() {
 
     let logger = create_logger(&matches);
 
+    // Keys and cookies are redacted in the logs, unless the user explicitly asks for them.
+    cookie::set_log_secrets(matches.is_present("unsafe-log-secrets"));
+
     // After calling this, slog_stdlog will forward all the `log` crate logging to
     // `slog_scope::logger()`.
     //
@@ -67,18 +77,27 @@ fn main() {
     let _scope_guard = slog_scope::set_global_logger(logger.clone());
 
     if matches.subcommand.is_none() {
-        eprintln!("please specify a valid subcommand: only client, ke-server, and ntp-server \
-                   are supported.");
+        eprintln!("please specify a valid subcommand: only client, ke-server, ntp-server, and \
+                   describe-packet are supported.");
         process::exit(1);
     }
 
     if let Some(ke_server_matches) = matches.subcommand_matches("ke-server") {
-        sub_command::ke_server::run(ke_server_matches);
+        if let Err(error) = sub_command::ke_server::run(ke_server_matches) {
+            eprintln!("starting NTS-KE server failed: {}", error);
+            process::exit(exit_code(&error));
+        }
     }
     if let Some(ntp_server_matches) = matches.subcommand_matches("ntp-server") {
-        sub_command::ntp_server::run(ntp_server_matches);
+        if let Err(error) = sub_command::ntp_server::run(ntp_server_matches) {
+            eprintln!("starting NTP server failed: {}", error);
+            process::exit(exit_code(&error));
+        }
     }
     if let Some(client_matches) = matches.subcommand_matches("client") {
         sub_command::client::run(client_matches);
     }
+    if let Some(describe_matches) = matches.subcommand_matches("describe-packet") {
+        sub_command::describe_packet::run(describe_matches);
+    }
 }
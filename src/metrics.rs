@@ -1,22 +1,66 @@
 // Our goal is to shove data at prometheus in response to requests.
 use lazy_static::lazy_static;
-use prometheus::{
-    self, register_int_gauge, Encoder, __register_gauge, labels, opts,
-};
+use prometheus::{self, register_int_gauge, Encoder, labels, opts};
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::net;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+use ring::constant_time;
 
 use slog::{error};
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct MetricsConfig {
     pub port: u16,
     pub addr: String,
+    /// If given, the metrics are only served to requests with an `Authorization: Bearer <token>`
+    /// header. The health check stays open, so that load balancers don't need the token.
+    pub token: Option<String>,
+}
+
+// The config is logged in debug mode, so the token must not be printed.
+impl std::fmt::Debug for MetricsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MetricsConfig")
+            .field("port", &self.port)
+            .field("addr", &self.addr)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
 }
 
+impl MetricsConfig {
+    /// Return true if the metrics listener would take the TCP address, i.e. they have the same
+    /// port, and either the same IP address or one of them is a wildcard covering the other. A
+    /// listener address given as a host name is never reported, because we don't resolve it
+    /// here.
+    pub fn overlaps(&self, addr: net::SocketAddr) -> bool {
+        let metrics_ip: net::IpAddr = match self.addr.parse() {
+            Ok(ip) => ip,
+            Err(_) => return false,
+        };
+        if self.port != addr.port() {
+            return false;
+        }
+        let covers = |wildcard: net::IpAddr, ip: net::IpAddr| {
+            // An IPv6 wildcard takes the IPv4 addresses too on dual stack sockets.
+            wildcard.is_unspecified() && (wildcard.is_ipv6() || ip.is_ipv4())
+        };
+        metrics_ip == addr.ip() || covers(metrics_ip, addr.ip()) || covers(addr.ip(), metrics_ip)
+    }
+}
+
+/// A readiness check used by the `/healthz` endpoint. It returns true only when the server is
+/// ready to serve traffic.
+pub type HealthCheck = Arc<dyn Fn() -> bool + Send + Sync>;
+
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const HEALTH_PATH: &'static str = "/healthz";
+const REQUEST_BUF_SIZE: usize = 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
 
 lazy_static! {
     static ref VERSION_INFO: prometheus::IntGauge = register_int_gauge!(opts!(
@@ -38,8 +82,90 @@ fn scrape_result() -> String {
         + &String::from_utf8(buffer).unwrap()
 }
 
-fn serve_metrics(mut dest: net::TcpStream, logger: slog::Logger) -> () {
-    if let Err(e) = dest.write(&scrape_result().as_bytes()) {
+fn unauthorized_result() -> String {
+    "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Type: text/plain\r\n\r\n\
+     unauthorized\n"
+        .to_owned()
+}
+
+fn health_result(health: &HealthCheck) -> String {
+    if health() {
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nok\n".to_owned()
+    } else {
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/plain\r\n\r\nnot ready\n"
+            .to_owned()
+    }
+}
+
+/// Return the path in the request line of an HTTP request, if there is one.
+fn request_path(request: &str) -> Option<&str> {
+    let request_line = request.lines().next()?;
+    // The request line looks like "GET /path HTTP/1.1".
+    request_line.split_whitespace().nth(1)
+}
+
+/// Return the token in the `Authorization: Bearer <token>` header of an HTTP request, if there
+/// is one.
+fn bearer_token(request: &str) -> Option<&str> {
+    // The headers follow the request line, up to the first empty line.
+    for header in request.lines().skip(1).take_while(|line| !line.is_empty()) {
+        let mut parts = header.splitn(2, ':');
+        let name = parts.next()?.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            let value = parts.next()?.trim();
+            let mut credentials = value.splitn(2, ' ');
+            if credentials.next()?.eq_ignore_ascii_case("bearer") {
+                return credentials.next().map(str::trim);
+            }
+        }
+    }
+    None
+}
+
+/// Return true if the request carries the expected token, or if no token is expected.
+fn is_authorized(request: &str, token: Option<&str>) -> bool {
+    match token {
+        None => true,
+        Some(token) => match bearer_token(request) {
+            Some(given) => {
+                constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes()).is_ok()
+            }
+            None => false,
+        },
+    }
+}
+
+/// Compute the HTTP response for the given raw request. Everything other than the health check
+/// path is answered with the metrics, so that the existing scrapers keep working. If `token` is
+/// given, the metrics require it.
+fn http_response(request: &str, health: &HealthCheck, token: Option<&str>) -> String {
+    match request_path(request) {
+        Some(HEALTH_PATH) => health_result(health),
+        _ if !is_authorized(request, token) => unauthorized_result(),
+        _ => scrape_result(),
+    }
+}
+
+fn serve_metrics(
+    mut dest: net::TcpStream,
+    health: HealthCheck,
+    token: Option<String>,
+    logger: slog::Logger,
+) -> () {
+    let mut buffer = [0; REQUEST_BUF_SIZE];
+    if let Err(e) = dest.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+        error!(logger, "TcpStream set_read_timeout failed with error: {:?}", e);
+    }
+    // We only need the request line, so a single read is enough.
+    let request = match dest.read(&mut buffer) {
+        Ok(size) => String::from_utf8_lossy(&buffer[..size]).into_owned(),
+        Err(e) => {
+            error!(logger, "read from TcpStream failed with error: {:?}", e);
+            String::new()
+        }
+    };
+    let response = http_response(&request, &health, token.as_ref().map(String::as_str));
+    if let Err(e) = dest.write(&response.as_bytes()) {
         error!(logger, "write to TcpStream failed with error: {:?}, unable to serve metrics", e);
     }
     if let Err(e) = dest.shutdown(net::Shutdown::Write) {
@@ -47,8 +173,10 @@ fn serve_metrics(mut dest: net::TcpStream, logger: slog::Logger) -> () {
     }
 }
 
-/// Runs the metric server on the address and port set in config
+/// Runs the metric server on the address and port set in config. The same server also answers
+/// the `/healthz` readiness endpoint using `health`.
 pub fn run_metrics(conf: MetricsConfig,
+                   health: HealthCheck,
                    logger: &slog::Logger) -> Result<(), std::io::Error> {
     VERSION_INFO.set(1);
     let accept = net::TcpListener::bind((conf.addr.as_str(), conf.port))?;
@@ -56,8 +184,10 @@ pub fn run_metrics(conf: MetricsConfig,
         match stream {
             Ok(conn) => {
                 let log_metrics = logger.new(slog::o!("component"=>"serve_metrics"));
+                let health = health.clone();
+                let token = conf.token.clone();
                 thread::spawn(move || {
-                    serve_metrics(conn, log_metrics);
+                    serve_metrics(conn, health, token, log_metrics);
                 });
             }
             Err(err) => return Err(err),
@@ -65,3 +195,102 @@ pub fn run_metrics(conf: MetricsConfig,
     }
     return Err(io::Error::new(io::ErrorKind::Other, "unreachable"));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_overlaps() {
+        let metrics = |addr: &str| MetricsConfig {
+            port: 8001,
+            addr: String::from(addr),
+            token: None,
+        };
+        let overlaps = |metrics_addr, addr: &str| {
+            metrics(metrics_addr).overlaps(addr.parse().unwrap())
+        };
+
+        assert!(overlaps("127.0.0.1", "127.0.0.1:8001"));
+        assert!(!overlaps("127.0.0.1", "127.0.0.1:1234"));
+        assert!(!overlaps("127.0.0.1", "127.0.0.2:8001"));
+        assert!(overlaps("0.0.0.0", "192.0.2.1:8001"));
+        assert!(overlaps("192.0.2.1", "0.0.0.0:8001"));
+        assert!(overlaps("::", "192.0.2.1:8001"));
+        assert!(overlaps("2001:db8::1", "[::]:8001"));
+        assert!(!overlaps("0.0.0.0", "[2001:db8::1]:8001"));
+        // Host names are left to the bind.
+        assert!(!overlaps("localhost", "127.0.0.1:8001"));
+    }
+
+    #[test]
+    fn test_healthz() {
+        let ready = Arc::new(AtomicBool::new(false));
+        let health: HealthCheck = {
+            let ready = ready.clone();
+            Arc::new(move || ready.load(Ordering::SeqCst))
+        };
+        let request = "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+        // Before the keys are initialized.
+        assert!(http_response(request, &health, None).starts_with("HTTP/1.1 503"));
+
+        // After the keys are initialized.
+        ready.store(true, Ordering::SeqCst);
+        assert!(http_response(request, &health, None).starts_with("HTTP/1.1 200"));
+
+        // The health check doesn't need the token.
+        assert!(http_response(request, &health, Some("secret")).starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn test_metrics_token() {
+        let health: HealthCheck = Arc::new(|| true);
+        let request = |authorization: &str| {
+            format!("GET /metrics HTTP/1.1\r\nHost: localhost\r\n{}\r\n", authorization)
+        };
+
+        // Without a token configured, anyone gets the metrics.
+        let response = http_response(&request(""), &health, None);
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        for authorization in &[
+            "",
+            "Authorization: Bearer wrong\r\n",
+            "Authorization: Bearer secret-but-longer\r\n",
+            "Authorization: Basic secret\r\n",
+            "X-Authorization: Bearer secret\r\n",
+        ] {
+            let response = http_response(&request(authorization), &health, Some("secret"));
+            assert!(response.starts_with("HTTP/1.1 401"), "{:?}", authorization);
+            assert!(!response.contains("build_info"));
+        }
+
+        // The header name and the scheme are case-insensitive.
+        for authorization in &[
+            "Authorization: Bearer secret\r\n",
+            "authorization: bearer secret\r\n",
+        ] {
+            let response = http_response(&request(authorization), &health, Some("secret"));
+            assert!(response.starts_with("HTTP/1.1 200"), "{:?}", authorization);
+        }
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let request = "GET / HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer abc \r\n\r\n";
+        assert_eq!(bearer_token(request), Some("abc"));
+        // Headers end at the first empty line.
+        assert_eq!(bearer_token("GET / HTTP/1.1\r\n\r\nAuthorization: Bearer abc\r\n"), None);
+        assert_eq!(bearer_token("GET / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("GET /healthz HTTP/1.1\r\n"), Some("/healthz"));
+        assert_eq!(request_path("GET /metrics HTTP/1.1\r\n"), Some("/metrics"));
+        assert_eq!(request_path(""), None);
+    }
+}
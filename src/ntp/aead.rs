@@ -0,0 +1,123 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Creating the AEADs protecting NTS packets.
+//!
+//! The rest of the NTP code only sees `NegotiatedAead` values through the `NtsAead` trait, so that
+//! adding an algorithm, or caching the key schedule of an expensive one, only touches this module.
+//! They aren't boxed, because the server creates a pair for every request it answers.
+
+use miscreant::aead::{Aead, Aes128SivAead};
+
+use crate::cookie::NTSKeys;
+use crate::nts_ke::records::KnownAeadAlgorithm;
+
+/// An AEAD sealing and opening NTS authenticators. Unlike miscreant's `Aead`, it can be used as a
+/// trait object.
+pub trait NtsAead {
+    fn seal(&mut self, nonce: &[u8], associated_data: &[u8], plaintext: &[u8]) -> Vec<u8>;
+
+    /// Open the ciphertext. It returns `None` if the authentication fails.
+    fn open(&mut self, nonce: &[u8], associated_data: &[u8], ciphertext: &[u8])
+        -> Option<Vec<u8>>;
+
+    /// The number of bytes the ciphertext is longer than the plaintext.
+    fn tag_len(&self) -> usize;
+}
+
+impl<T: Aead> NtsAead for T {
+    fn seal(&mut self, nonce: &[u8], associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        Aead::seal(self, nonce, associated_data, plaintext)
+    }
+
+    fn open(&mut self, nonce: &[u8], associated_data: &[u8], ciphertext: &[u8])
+        -> Option<Vec<u8>>
+    {
+        Aead::open(self, nonce, associated_data, ciphertext).ok()
+    }
+
+    fn tag_len(&self) -> usize {
+        // Miscreant only implements SIV modes, which prepend a 16-byte synthetic IV.
+        16
+    }
+}
+
+/// The AEAD of one direction of an association, for any of the algorithms we know.
+pub enum NegotiatedAead {
+    /// Miscreant calls Aes128SivAead what IANA calls AEAD_AES_SIV_CMAC_256.
+    AeadAesSivCmac256(Aes128SivAead),
+}
+
+impl NtsAead for NegotiatedAead {
+    fn seal(&mut self, nonce: &[u8], associated_data: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        match self {
+            NegotiatedAead::AeadAesSivCmac256(aead) => {
+                NtsAead::seal(aead, nonce, associated_data, plaintext)
+            }
+        }
+    }
+
+    fn open(&mut self, nonce: &[u8], associated_data: &[u8], ciphertext: &[u8])
+        -> Option<Vec<u8>>
+    {
+        match self {
+            NegotiatedAead::AeadAesSivCmac256(aead) => {
+                NtsAead::open(aead, nonce, associated_data, ciphertext)
+            }
+        }
+    }
+
+    fn tag_len(&self) -> usize {
+        match self {
+            NegotiatedAead::AeadAesSivCmac256(aead) => NtsAead::tag_len(aead),
+        }
+    }
+}
+
+/// The AEADs of an association, one for each direction.
+pub struct NtsAeads {
+    /// The AEAD keyed with the client-to-server key, protecting requests.
+    pub c2s: NegotiatedAead,
+    /// The AEAD keyed with the server-to-client key, protecting responses.
+    pub s2c: NegotiatedAead,
+}
+
+/// Create the AEADs of the given algorithm for the keys of an association.
+pub fn new_aeads(algorithm: KnownAeadAlgorithm, keys: &NTSKeys) -> NtsAeads {
+    match algorithm {
+        KnownAeadAlgorithm::AeadAesSivCmac256 => NtsAeads {
+            c2s: NegotiatedAead::AeadAesSivCmac256(Aes128SivAead::new(&keys.c2s)),
+            s2c: NegotiatedAead::AeadAesSivCmac256(Aes128SivAead::new(&keys.s2c)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_aeads() {
+        let keys = NTSKeys {
+            c2s: [1; 32],
+            s2c: [2; 32],
+        };
+        let nonce = [3; 16];
+        for &algorithm in &[KnownAeadAlgorithm::AeadAesSivCmac256] {
+            // The two ends of the association create their AEADs independently.
+            let mut client = new_aeads(algorithm, &keys);
+            let mut server = new_aeads(algorithm, &keys);
+
+            let request = client.c2s.seal(&nonce, b"header", b"request");
+            assert_eq!(request.len(), b"request".len() + client.c2s.tag_len());
+            assert_eq!(server.c2s.open(&nonce, b"header", &request).unwrap(), b"request");
+            let response = server.s2c.seal(&nonce, b"header", b"response");
+            assert_eq!(client.s2c.open(&nonce, b"header", &response).unwrap(), b"response");
+
+            // Each direction has its own key, and the associated data is authenticated.
+            assert!(client.s2c.open(&nonce, b"header", &request).is_none());
+            assert!(server.c2s.open(&nonce, b"tampered", &request).is_none());
+        }
+    }
+}
@@ -1,41 +1,103 @@
 use crate::nts_ke::client::NtsKeResult;
+use crate::nts_ke::records::KnownAeadAlgorithm;
 
-use miscreant::aead::Aead;
-use miscreant::aead::Aes128SivAead;
+use chrono::{DateTime, SecondsFormat, Utc};
 use rand::Rng;
-use slog::{debug};
+use serde::Serialize;
+use slog::{debug, warn};
+use std::cmp;
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::iter;
+use std::thread;
 
-use std::net::{UdpSocket, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket, ToSocketAddrs};
 use std::time::{Duration, SystemTime};
 
+use super::aead::new_aeads;
+use super::protocol::ntp_timestamp;
 use super::protocol::parse_nts_packet;
 use super::protocol::serialize_nts_packet;
 use super::protocol::LeapState;
+use super::protocol::MAX_COOKIE_PLACEHOLDERS;
 use super::protocol::NtpExtension;
 use super::protocol::NtpExtensionType::*;
+use super::protocol::NtpPacket;
 use super::protocol::NtpPacketHeader;
+use super::protocol::NtpTimestamp;
 use super::protocol::NtsPacket;
 use super::protocol::PacketMode::Client;
-use super::protocol::TWO_POW_32;
+use super::protocol::ProtocolError;
 use super::protocol::UNIX_OFFSET;
 
 use self::NtpClientError::*;
 
 const BUFF_SIZE: usize = 2048;
 const TIMEOUT: Duration = Duration::from_secs(10);
+const TWO_POW_16: f64 = 65536.0;
 
+/// When fewer cookies than this are left after a query, the client loop runs the key exchange
+/// again, so that it never runs out of cookies.
+pub const LOW_COOKIE_THRESHOLD: usize = 2;
+
+/// The fraction of a full cookie pool below which the client loop asks for more cookies, if it's
+/// not given.
+pub const DEFAULT_LOW_WATER_MARK: f64 = 0.5;
+
+/// The measurement of the client. It can be serialized to JSON for scripting and monitoring.
+#[derive(Debug, Clone, Serialize)]
 pub struct NtpResult {
+    pub server: SocketAddr,
+    pub nts: bool,
     pub stratum: u8,
-    pub time_diff: f64,
+    pub leap: u8,
+    pub reference_id: u32,
+    /// The clock offset in seconds.
+    pub offset: f64,
+    /// The round-trip delay in seconds. The offset is off by at most half of it, so a high delay
+    /// points at a poor path to the server, and the offset is less trustworthy.
+    pub delay: f64,
+    /// The root dispersion of the server in seconds.
+    pub dispersion: f64,
+    /// The precision of the server clock as a power of two seconds, e.g. -20 for about a
+    /// microsecond. Offsets smaller than that are noise.
+    pub precision: i8,
+    /// The interval between queries the server suggests, as a power of two seconds.
+    pub poll: i8,
+    pub receive_timestamp: ResultTimestamp,
+    pub transmit_timestamp: ResultTimestamp,
+    /// The number of cookies left in the pool after the query.
+    pub cookies_remaining: usize,
+}
+
+/// A timestamp rendered both as the raw NTP value and in ISO 8601.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResultTimestamp {
+    pub ntp: u64,
+    pub iso8601: String,
+}
+
+impl ResultTimestamp {
+    /// Render the raw NTP timestamp in the era closest to the pivot.
+    fn new(ntp: u64, pivot: SystemTime) -> ResultTimestamp {
+        let time = DateTime::<Utc>::from(NtpTimestamp::from(ntp).to_system_time_near(pivot));
+        ResultTimestamp {
+            ntp,
+            iso8601: time.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum NtpClientError {
     NoIpv4AddrFound,
     NoIpv6AddrFound,
-    InvalidUid
+    InvalidUid,
+    NoCookie,
+    UnknownAeadAlgorithm,
+    /// Binding to the source port is not permitted, which needs privileges below 1024.
+    SourcePortNotPermitted(u16),
 }
 
 impl std::error::Error for NtpClientError {
@@ -51,7 +113,34 @@ impl std::error::Error for NtpClientError {
 
 impl std::fmt::Display for NtpClientError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Ntp Client Error ")
+        match self {
+            SourcePortNotPermitted(port) => write!(
+                f,
+                "not permitted to send from port {}, ports below 1024 need privileges",
+                port,
+            ),
+            _ => write!(f, "Ntp Client Error "),
+        }
+    }
+}
+
+/// Bind the socket the queries are sent from, on the source port if given, or an ephemeral port
+/// otherwise.
+fn bind_socket(ipv6: bool, source_port: Option<u16>) -> Result<UdpSocket, Box<dyn Error>> {
+    let ip: IpAddr = if ipv6 {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+    let port = source_port.unwrap_or(0);
+    UdpSocket::bind((ip, port)).map_err(|error| bind_error(error, port))
+}
+
+/// Explain a failure to bind the source port, if it's a lack of privileges.
+fn bind_error(error: io::Error, port: u16) -> Box<dyn Error> {
+    match error.kind() {
+        io::ErrorKind::PermissionDenied => Box::new(SourcePortNotPermitted(port)),
+        _ => Box::new(error),
     }
 }
 
@@ -63,18 +152,157 @@ fn system_to_ntpfloat(time: SystemTime) -> f64 {
     epoch_time.as_secs() as f64 + (epoch_time.subsec_nanos() as f64) / 1.0e9
 }
 
-/// Returns a float representing the ntp timestamp
-fn timestamp_to_float(time: u64) -> f64 {
-    let ts_secs = time >> 32;
-    let ts_frac = time - (ts_secs << 32);
-    (ts_secs as f64) + (ts_frac as f64) / TWO_POW_32
+/// Returns a float representing the ntp timestamp, in the era closest to the pivot
+fn timestamp_to_float(time: u64, pivot: SystemTime) -> f64 {
+    system_to_ntpfloat(NtpTimestamp::from(time).to_system_time_near(pivot))
+}
+
+/// Build a plain NTPv4 client request, i.e. one without any NTS extension, which can be used as
+/// a minimal SNTP client. See RFC 4330 Section 5.
+pub fn build_client_request(transmit_time: SystemTime) -> NtpPacket {
+    NtpPacket {
+        header: NtpPacketHeader {
+            leap_indicator: LeapState::NoLeap,
+            version: 4,
+            mode: Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: 0,
+            reference_timestamp: 0,
+            origin_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp: ntp_timestamp(transmit_time),
+        },
+        exts: vec![],
+    }
+}
+
+/// Compute the clock offset and the round-trip delay in seconds from the time the request was
+/// sent, the response header, and the time the response arrived. See RFC 5905 Section 8.
+pub fn compute_offset_delay(
+    req_tx: SystemTime,
+    resp: &NtpPacketHeader,
+    resp_rx: SystemTime,
+) -> (f64, f64) {
+    let t1 = system_to_ntpfloat(req_tx);
+    let t2 = timestamp_to_float(resp.receive_timestamp, req_tx);
+    let t3 = timestamp_to_float(resp.transmit_timestamp, req_tx);
+    let t4 = system_to_ntpfloat(resp_rx);
+
+    let offset = ((t2 - t1) + (t3 - t4)) / 2.0;
+    let delay = (t4 - t1) - (t3 - t2);
+    (offset, delay)
+}
+
+/// Take the next cookie out of the pool. Each cookie is used only once, so that the requests
+/// cannot be linked to each other.
+///
+/// Once the pool is empty, the cached cookies take its place together with their keys, which
+/// the server answers them with.
+fn take_cookie(state: &mut NtsKeResult) -> Option<Vec<u8>> {
+    if state.cookies.is_empty() {
+        let cached = state.cached.take()?;
+        state.keys = cached.keys;
+        state.cookies = cached.cookies;
+    }
+    if state.cookies.is_empty() {
+        None
+    } else {
+        Some(state.cookies.remove(0))
+    }
+}
+
+/// Put the new cookies the server sent in the encrypted extensions into the pool.
+fn refill_cookies(state: &mut NtsKeResult, packet: &NtsPacket) {
+    for ext in packet.auth_enc_exts.iter() {
+        if ext.ext_type == NTSCookie {
+            state.cookies.push(ext.contents.clone());
+        }
+    }
+}
+
+/// Return the number of placeholders the next request needs to refill the pool of `pool` cookies
+/// up to `pool_size`, if it's below `low_water_mark` times that size, and none otherwise. The
+/// cookie used by the request is replaced by the server in any case.
+fn refill_placeholders(pool: usize, pool_size: usize, low_water_mark: f64) -> usize {
+    if (pool as f64) < low_water_mark * pool_size as f64 {
+        cmp::min(pool_size.saturating_sub(pool), MAX_COOKIE_PLACEHOLDERS)
+    } else {
+        0
+    }
+}
+
+/// Build an NTS request carrying the cookie and asking for `placeholders` more cookies, up to
+/// `MAX_COOKIE_PLACEHOLDERS`. The server replaces the cookie in any case. It fails if the cookie
+/// is too long for an extension.
+fn nts_request(
+    unique_id: Vec<u8>,
+    cookie: Vec<u8>,
+    placeholders: usize,
+) -> Result<NtsPacket, ProtocolError> {
+    // Strict servers drop requests without a transmit timestamp. A random one serves as well as
+    // the time, and doesn't reveal the client's clock.
+    let mut transmit_timestamp = 0;
+    while transmit_timestamp == 0 {
+        transmit_timestamp = rand::thread_rng().gen();
+    }
+    let header = NtpPacketHeader {
+        leap_indicator: LeapState::NoLeap,
+        version: 4,
+        mode: Client,
+        stratum: 0,
+        poll: 0,
+        precision: 0x20,
+        root_delay: 0,
+        root_dispersion: 0,
+        reference_id: 0,
+        reference_timestamp: 0xdeadbeef,
+        origin_timestamp: 0,
+        receive_timestamp: 0,
+        transmit_timestamp,
+    };
+    // A placeholder must be as long as the cookie, so that the response is no larger than the
+    // request. Servers ignore shorter ones to avoid amplification.
+    let placeholder = NtpExtension::new(NTSCookiePlaceholder, vec![0; cookie.len()])?;
+    // The cookie goes out as the server gave it, since the server must find it unchanged.
+    let mut exts = vec![
+        NtpExtension::new(UniqueIdentifier, unique_id)?,
+        NtpExtension {
+            ext_type: NTSCookie,
+            contents: cookie,
+        },
+    ];
+    exts.extend(iter::repeat(placeholder).take(cmp::min(placeholders, MAX_COOKIE_PLACEHOLDERS)));
+    Ok(NtsPacket {
+        header: header,
+        auth_exts: exts,
+        auth_enc_exts: vec![],
+    })
 }
 
-/// Run the NTS client with the given data from key exchange
+/// Run the NTS client with the given data from key exchange. A cookie is taken out of the pool
+/// in `state` and the new cookies from the server are put back into it.
 pub fn run_nts_ntp_client(
     logger: &slog::Logger,
-    state: NtsKeResult,
+    state: &mut NtsKeResult,
+) -> Result<NtpResult, Box<dyn Error>> {
+    run_nts_ntp_client_with_placeholders(logger, state, 0)
+}
+
+/// Run the NTS client like `run_nts_ntp_client`, and ask for `placeholders` more cookies than
+/// the one replacing the cookie used, up to `MAX_COOKIE_PLACEHOLDERS`. It lets a client low on
+/// cookies refill its pool without running the key exchange again.
+pub fn run_nts_ntp_client_with_placeholders(
+    logger: &slog::Logger,
+    state: &mut NtsKeResult,
+    placeholders: usize,
 ) -> Result<NtpResult, Box<dyn Error>> {
+    let algorithm =
+        KnownAeadAlgorithm::from_algorithm_id(state.aead_scheme).ok_or(UnknownAeadAlgorithm)?;
+    let cookie = take_cookie(state).ok_or(NoCookie)?;
 
     let mut ip_addrs = (state.next_server.as_str(), state.next_port).to_socket_addrs()?;
     let addr;
@@ -86,73 +314,45 @@ pub fn run_nts_ntp_client(
             if addr == None {
                 return Err(Box::new(NoIpv4AddrFound));
             }
-            socket = UdpSocket::bind("0.0.0.0:0");
+            socket = bind_socket(false, state.source_port);
         } else {
             // mandated to use ipv6
             addr = ip_addrs.find(|&x| x.is_ipv6());
             if addr == None {
                 return Err(Box::new(NoIpv6AddrFound));
             }
-            socket = UdpSocket::bind("[::]:0");
+            socket = bind_socket(true, state.source_port);
         }
     } else {
         // sniff whichever one is supported
         addr = ip_addrs.next();
         // check if this address is ipv4 or ipv6
         if addr.unwrap().is_ipv6() {
-            socket = UdpSocket::bind("[::]:0");
+            socket = bind_socket(true, state.source_port);
         } else {
-            socket = UdpSocket::bind("0.0.0.0:0");
+            socket = bind_socket(false, state.source_port);
         }
     }
 
-    let socket = socket.unwrap();
+    let socket = socket?;
     socket.set_read_timeout(Some(TIMEOUT))?;
     socket.set_write_timeout(Some(TIMEOUT))?;
-    let mut send_aead = Aes128SivAead::new(&state.keys.c2s);
-    let mut recv_aead = Aes128SivAead::new(&state.keys.s2c);
-    let header = NtpPacketHeader {
-        leap_indicator: LeapState::NoLeap,
-        version: 4,
-        mode: Client,
-        stratum: 0,
-        poll: 0,
-        precision: 0x20,
-        root_delay: 0,
-        root_dispersion: 0,
-        reference_id: 0,
-        reference_timestamp: 0xdeadbeef,
-        origin_timestamp: 0,
-        receive_timestamp: 0,
-        transmit_timestamp: 0,
-    };
+    let mut aeads = new_aeads(algorithm, &state.keys);
     let mut unique_id: Vec<u8> = vec![0; 32];
     rand::thread_rng().fill(&mut unique_id[..]);
-    let exts = vec![
-        NtpExtension {
-            ext_type: UniqueIdentifier,
-            contents: unique_id.clone(),
-        },
-        NtpExtension {
-            ext_type: NTSCookie,
-            contents: state.cookies[0].clone(),
-        },
-    ];
-    let packet = NtsPacket {
-        header: header,
-        auth_exts: exts,
-        auth_enc_exts: vec![],
-    };
+    let packet = nts_request(unique_id.clone(), cookie, placeholders)?;
+    // Fail here rather than get a Kiss of Death back.
+    packet.validate_request()?;
     socket.connect(addr.unwrap())?;
-    let wire_packet = &serialize_nts_packet::<Aes128SivAead>(packet, &mut send_aead);
-    let t1 = system_to_ntpfloat(SystemTime::now());
+    let wire_packet = &serialize_nts_packet(packet, &mut aeads.c2s);
+    let t1 = SystemTime::now();
     socket.send(wire_packet)?;
     debug!(logger, "transmitting packet");
     let mut buff = [0; BUFF_SIZE];
     let (size, _origin) = socket.recv_from(&mut buff)?;
-    let t4 = system_to_ntpfloat(SystemTime::now());
+    let t4 = SystemTime::now();
     debug!(logger, "received packet");
-    let received = parse_nts_packet::<Aes128SivAead>(&buff[0..size], &mut recv_aead);
+    let received = parse_nts_packet(&buff[0..size], &mut aeads.s2c);
     match received {
         Err(x) => Err(Box::new(x)),
         Ok(packet) => {
@@ -163,12 +363,452 @@ pub fn run_nts_ntp_client(
                 return Err(Box::new(InvalidUid));
             }
 
-            Ok(NtpResult {
-                stratum: packet.header.stratum,
-                time_diff: ((timestamp_to_float(packet.header.receive_timestamp) - t1)
-                    + (timestamp_to_float(packet.header.transmit_timestamp) - t4))
-                    / 2.0,
-            })
+            refill_cookies(state, &packet);
+
+            Ok(nts_result(addr.unwrap(), &packet.header, t1, t4, state.cookies.len()))
         },
     }
 }
+
+/// Build the result of an NTS query from the response header, the time the request was sent
+/// and the time the response arrived.
+fn nts_result(
+    server: SocketAddr,
+    header: &NtpPacketHeader,
+    t1: SystemTime,
+    t4: SystemTime,
+    cookies_remaining: usize,
+) -> NtpResult {
+    let (offset, delay) = compute_offset_delay(t1, header, t4);
+    NtpResult {
+        server,
+        nts: true,
+        stratum: header.stratum,
+        leap: header.leap_indicator as u8,
+        reference_id: header.reference_id,
+        offset,
+        delay,
+        dispersion: f64::from(header.root_dispersion) / TWO_POW_16,
+        precision: header.precision,
+        poll: header.poll,
+        receive_timestamp: ResultTimestamp::new(header.receive_timestamp, t1),
+        transmit_timestamp: ResultTimestamp::new(header.transmit_timestamp, t1),
+        cookies_remaining,
+    }
+}
+
+/// Run `rounds` queries with `query`, `interval` apart, and pass each result to `on_result`.
+/// A full pool is as many cookies as the first key exchange gave. Once fewer than `low_water_mark`
+/// times that many are left, `query` is asked to send placeholders for the missing ones, so that
+/// the pool is refilled before it runs out.
+/// The key exchange is run with `key_exchange` at the start, and again whenever fewer than
+/// `LOW_COOKIE_THRESHOLD` cookies are left, in case the server stops sending new cookies.
+pub fn run_nts_client_loop<K, Q, F>(
+    logger: &slog::Logger,
+    rounds: u64,
+    interval: Duration,
+    low_water_mark: f64,
+    mut key_exchange: K,
+    mut query: Q,
+    mut on_result: F,
+) -> Result<(), Box<dyn Error>>
+where
+    K: FnMut() -> Result<NtsKeResult, Box<dyn Error>>,
+    Q: FnMut(&mut NtsKeResult, usize) -> Result<NtpResult, Box<dyn Error>>,
+    F: FnMut(&NtpResult),
+{
+    let mut state = key_exchange()?;
+    let pool_size = state.cookies.len();
+    for round in 0..rounds {
+        if round > 0 {
+            thread::sleep(interval);
+        }
+        let placeholders = refill_placeholders(state.cookies.len(), pool_size, low_water_mark);
+        if placeholders > 0 {
+            debug!(
+                logger,
+                "only {} cookies left, asking for {} more", state.cookies.len(), placeholders
+            );
+        }
+        let result = query(&mut state, placeholders)?;
+        on_result(&result);
+
+        if result.cookies_remaining < LOW_COOKIE_THRESHOLD && round + 1 < rounds {
+            warn!(
+                logger,
+                "only {} cookies left, running the key exchange again", result.cookies_remaining
+            );
+            state = key_exchange()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cookie::{make_cookie, CookieKey, NTSKeys, COOKIE_SIZE};
+    use crate::key_rotator::{KeyId, KeyRotator};
+    use crate::ntp::server::bench_response;
+    use crate::nts_ke::client::{AddrFamily, CachedCookies, NegotiationSource};
+
+    use miscreant::aead::{Aead, Aes128SivAead};
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn test_build_client_request() {
+        let transmit_time = SystemTime::UNIX_EPOCH + Duration::new(1_000_000, 500_000_000);
+        let request = build_client_request(transmit_time);
+
+        assert_eq!(request.header.mode, Client);
+        assert_eq!(request.header.version, 4);
+        assert_eq!(request.header.transmit_timestamp, ntp_timestamp(transmit_time));
+        assert!(request.exts.is_empty());
+    }
+
+    #[test]
+    fn test_bind_socket() {
+        // Find a free port.
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let socket = bind_socket(false, Some(port)).unwrap();
+        assert_eq!(socket.local_addr().unwrap().port(), port);
+        assert!(socket.local_addr().unwrap().is_ipv4());
+
+        // The port is taken now.
+        bind_socket(false, Some(port)).unwrap_err();
+
+        let socket = bind_socket(false, None).unwrap();
+        assert_ne!(socket.local_addr().unwrap().port(), 0);
+    }
+
+    #[test]
+    fn test_bind_error() {
+        let error = bind_error(io::Error::from(io::ErrorKind::PermissionDenied), 123);
+        assert_eq!(
+            error.to_string(),
+            "not permitted to send from port 123, ports below 1024 need privileges",
+        );
+
+        let error = bind_error(io::Error::from(io::ErrorKind::AddrInUse), 123);
+        assert!(error.downcast_ref::<io::Error>().is_some());
+    }
+
+    #[test]
+    fn test_compute_offset_delay() {
+        // The server clock is 5 seconds ahead. The request takes 0.75 seconds to arrive, the
+        // server takes 0.5 seconds to respond, and the response takes 0.75 seconds to arrive.
+        let t1 = SystemTime::UNIX_EPOCH + Duration::new(1_000_000, 0);
+        let t2 = t1 + Duration::new(5, 750_000_000);
+        let t3 = t2 + Duration::new(0, 500_000_000);
+        let t4 = t1 + Duration::new(2, 0);
+
+        let mut resp = build_client_request(t3).header;
+        resp.receive_timestamp = ntp_timestamp(t2);
+
+        let (offset, delay) = compute_offset_delay(t1, &resp, t4);
+        assert!((offset - 5.0).abs() < 1e-6);
+        assert!((delay - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_result_json() {
+        let t1 = SystemTime::UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+        let t2 = t1 + Duration::new(0, 250_000_000);
+        let result = NtpResult {
+            server: "127.0.0.1:123".parse().unwrap(),
+            nts: true,
+            stratum: 2,
+            leap: 0,
+            reference_id: 0x7f000001,
+            offset: 0.5,
+            delay: 0.25,
+            dispersion: 0.125,
+            precision: -20,
+            poll: 6,
+            receive_timestamp: ResultTimestamp::new(ntp_timestamp(t1), t1),
+            transmit_timestamp: ResultTimestamp::new(ntp_timestamp(t2), t1),
+            cookies_remaining: 7,
+        };
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["server"], "127.0.0.1:123");
+        assert_eq!(json["nts"], true);
+        assert_eq!(json["stratum"], 2);
+        assert_eq!(json["leap"], 0);
+        assert_eq!(json["reference_id"], 0x7f000001);
+        assert_eq!(json["offset"], 0.5);
+        assert_eq!(json["delay"], 0.25);
+        assert_eq!(json["dispersion"], 0.125);
+        assert_eq!(json["precision"], -20);
+        assert_eq!(json["poll"], 6);
+        assert_eq!(json["receive_timestamp"]["ntp"], ntp_timestamp(t1));
+        assert_eq!(json["receive_timestamp"]["iso8601"], "2017-07-14T02:40:00.000000000Z");
+        assert_eq!(json["transmit_timestamp"]["ntp"], ntp_timestamp(t2));
+        assert_eq!(json["transmit_timestamp"]["iso8601"], "2017-07-14T02:40:00.250000000Z");
+        assert_eq!(json["cookies_remaining"], 7);
+    }
+
+    #[test]
+    fn test_nts_result() {
+        let t1 = SystemTime::UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+        let t4 = t1 + Duration::new(0, 500_000_000);
+        let mut resp = build_client_request(t1 + Duration::new(0, 250_000_000)).header;
+        resp.receive_timestamp = resp.transmit_timestamp;
+        resp.stratum = 2;
+        resp.precision = -20;
+        resp.poll = 6;
+
+        let result = nts_result("127.0.0.1:123".parse().unwrap(), &resp, t1, t4, 3);
+        assert_eq!(result.precision, -20);
+        assert_eq!(result.poll, 6);
+        assert_eq!(result.stratum, 2);
+        assert!((result.delay - 0.5).abs() < 1e-6);
+        assert!(result.offset.abs() < 1e-6);
+        assert_eq!(result.cookies_remaining, 3);
+    }
+
+    #[test]
+    fn test_compute_offset_delay_era_rollover() {
+        // The request is sent just before the 2036 rollover and the server answers after it.
+        let era_1 = SystemTime::UNIX_EPOCH + Duration::new((1 << 32) - UNIX_OFFSET, 0);
+        let t1 = era_1 - Duration::new(1, 0);
+        let t2 = t1 + Duration::new(5, 750_000_000);
+        let t3 = t2 + Duration::new(0, 500_000_000);
+        let t4 = t1 + Duration::new(2, 0);
+
+        let mut resp = build_client_request(t3).header;
+        resp.receive_timestamp = ntp_timestamp(t2);
+
+        let (offset, delay) = compute_offset_delay(t1, &resp, t4);
+        assert!((offset - 5.0).abs() < 1e-6);
+        assert!((delay - 1.5).abs() < 1e-6);
+    }
+
+    fn test_ke_result(cookie_count: u8) -> NtsKeResult {
+        NtsKeResult {
+            cookies: (0..cookie_count).map(|i| vec![i; 4]).collect(),
+            next_protocols: vec![0],
+            aead_scheme: 15,
+            next_server: String::from("localhost"),
+            next_port: 123,
+            next_server_source: NegotiationSource::Default,
+            next_port_source: NegotiationSource::Default,
+            keys: NTSKeys {
+                c2s: [0; 32],
+                s2c: [0; 32],
+            },
+            use_ipv4: None,
+            ke_addr_family: AddrFamily::Ipv4,
+            source_port: None,
+            warnings: Vec::new(),
+            cached: None,
+        }
+    }
+
+    fn test_result(cookies_remaining: usize) -> NtpResult {
+        let t1 = SystemTime::UNIX_EPOCH + Duration::new(1_500_000_000, 0);
+        NtpResult {
+            server: "127.0.0.1:123".parse().unwrap(),
+            nts: true,
+            stratum: 2,
+            leap: 0,
+            reference_id: 0,
+            offset: 0.0,
+            delay: 0.0,
+            dispersion: 0.0,
+            precision: 0,
+            poll: 0,
+            receive_timestamp: ResultTimestamp::new(ntp_timestamp(t1), t1),
+            transmit_timestamp: ResultTimestamp::new(ntp_timestamp(t1), t1),
+            cookies_remaining,
+        }
+    }
+
+    #[test]
+    fn test_cookie_placeholders() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let rotator = KeyRotator::with_key(CookieKey::from(&[7; 32][..]), KeyId::new(1), &[1; 32]);
+        let (key_id, key) = rotator.latest_key_value();
+        let keys = NTSKeys {
+            c2s: [1; 32],
+            s2c: [2; 32],
+        };
+        let cookie = make_cookie(keys, KnownAeadAlgorithm::AeadAesSivCmac256, key.as_ref(), key_id);
+        let cookie_keys = Arc::new(RwLock::new(rotator));
+
+        for &(placeholders, fresh_cookies) in &[(0, 1), (3, 4), (7, 8), (20, 8)] {
+            let request = nts_request(vec![0; 32], cookie.clone(), placeholders).unwrap();
+            let placeholder_count = request
+                .auth_exts
+                .iter()
+                .filter(|ext| ext.ext_type == NTSCookiePlaceholder)
+                .inspect(|ext| assert_eq!(ext.contents.len(), COOKIE_SIZE))
+                .count();
+            assert_eq!(placeholder_count, fresh_cookies - 1);
+
+            let query = serialize_nts_packet(request, &mut Aes128SivAead::new(&keys.c2s));
+            let response = bench_response(&query, cookie_keys.clone(), logger.clone()).unwrap();
+            let response = parse_nts_packet(&response, &mut Aes128SivAead::new(&keys.s2c)).unwrap();
+
+            // The server replaces the cookie used, and sends one for each placeholder.
+            let mut state = test_ke_result(0);
+            refill_cookies(&mut state, &response);
+            assert_eq!(state.cookies.len(), fresh_cookies);
+        }
+    }
+
+    #[test]
+    fn test_refill_cookies() {
+        let mut state = test_ke_result(2);
+        assert_eq!(take_cookie(&mut state), Some(vec![0; 4]));
+
+        let packet = NtsPacket {
+            header: build_client_request(SystemTime::now()).header,
+            auth_exts: vec![],
+            auth_enc_exts: vec![
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: vec![5; 4],
+                },
+                NtpExtension {
+                    ext_type: NTSCookiePlaceholder,
+                    contents: vec![6; 4],
+                },
+            ],
+        };
+        refill_cookies(&mut state, &packet);
+        assert_eq!(state.cookies, vec![vec![1; 4], vec![5; 4]]);
+
+        assert_eq!(take_cookie(&mut state), Some(vec![1; 4]));
+        assert_eq!(take_cookie(&mut state), Some(vec![5; 4]));
+        assert_eq!(take_cookie(&mut state), None);
+    }
+
+    #[test]
+    fn test_take_cached_cookie() {
+        let mut state = test_ke_result(1);
+        let keys = NTSKeys {
+            c2s: [1; 32],
+            s2c: [2; 32],
+        };
+        state.cached = Some(CachedCookies {
+            keys,
+            cookies: vec![vec![7; 4]],
+        });
+
+        // The fresh cookies are used first, with the keys of the exchange.
+        assert_eq!(take_cookie(&mut state), Some(vec![0; 4]));
+        assert_eq!(state.keys.c2s, [0; 32]);
+
+        // Then the cached ones, with the keys they were issued with.
+        assert_eq!(take_cookie(&mut state), Some(vec![7; 4]));
+        assert_eq!(state.keys.c2s, [1; 32]);
+        assert_eq!(state.keys.s2c, [2; 32]);
+        assert!(state.cached.is_none());
+        assert_eq!(take_cookie(&mut state), None);
+    }
+
+    #[test]
+    fn test_rekey_when_cookies_low() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut key_exchanges = 0;
+        let mut results = Vec::new();
+
+        // The server never sends a new cookie, so each query uses up one cookie of the pool.
+        run_nts_client_loop(
+            &logger,
+            20,
+            Duration::from_secs(0),
+            DEFAULT_LOW_WATER_MARK,
+            || {
+                key_exchanges += 1;
+                Ok(test_ke_result(4))
+            },
+            |state, _| {
+                take_cookie(state).expect("the pool must never be empty");
+                Ok(test_result(state.cookies.len()))
+            },
+            |result| results.push(result.cookies_remaining),
+        )
+        .unwrap();
+
+        assert_eq!(results.len(), 20);
+        // Each key exchange gives 4 cookies, and it runs again when only one is left.
+        assert_eq!(&results[..6], &[3, 2, 1, 3, 2, 1]);
+        assert_eq!(key_exchanges, 7);
+    }
+
+    #[test]
+    fn test_refill_placeholders() {
+        assert_eq!(refill_placeholders(8, 8, DEFAULT_LOW_WATER_MARK), 0);
+        assert_eq!(refill_placeholders(4, 8, DEFAULT_LOW_WATER_MARK), 0);
+        assert_eq!(refill_placeholders(3, 8, DEFAULT_LOW_WATER_MARK), 5);
+        // The pool is refilled up to the size the key exchange gave.
+        assert_eq!(refill_placeholders(2, 4, DEFAULT_LOW_WATER_MARK), 0);
+        assert_eq!(refill_placeholders(1, 4, DEFAULT_LOW_WATER_MARK), 3);
+        // It's bounded by the most placeholders a request carries.
+        assert_eq!(refill_placeholders(0, 8, DEFAULT_LOW_WATER_MARK), MAX_COOKIE_PLACEHOLDERS);
+        // A mark of zero never refills, and a mark of one keeps the pool full.
+        assert_eq!(refill_placeholders(1, 8, 0.0), 0);
+        assert_eq!(refill_placeholders(7, 8, 1.0), 1);
+    }
+
+    #[test]
+    fn test_refill_when_cookies_low() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let rotator = KeyRotator::with_key(CookieKey::from(&[7; 32][..]), KeyId::new(1), &[1; 32]);
+        let (key_id, key) = rotator.latest_key_value();
+        let keys = NTSKeys {
+            c2s: [1; 32],
+            s2c: [2; 32],
+        };
+        let cookie = make_cookie(keys, KnownAeadAlgorithm::AeadAesSivCmac256, key.as_ref(), key_id);
+        let cookie_keys = Arc::new(RwLock::new(rotator));
+        let mut key_exchanges = 0;
+        let mut sent_placeholders = Vec::new();
+        let mut results = Vec::new();
+
+        // The key exchange gives a pool of 8 cookies, and five of them are lost after the first
+        // query, which leaves 3, below the low-water mark of 4.
+        run_nts_client_loop(
+            &logger,
+            4,
+            Duration::from_secs(0),
+            DEFAULT_LOW_WATER_MARK,
+            || {
+                key_exchanges += 1;
+                let mut state = test_ke_result(0);
+                state.cookies = vec![cookie.clone(); 8];
+                state.keys = keys;
+                Ok(state)
+            },
+            |state, placeholders| {
+                if sent_placeholders.is_empty() {
+                    state.cookies.truncate(3);
+                }
+                let cookie = take_cookie(state).expect("the pool must never be empty");
+                let request = nts_request(vec![0; 32], cookie, placeholders)?;
+                sent_placeholders.push(
+                    request
+                        .auth_exts
+                        .iter()
+                        .filter(|ext| ext.ext_type == NTSCookiePlaceholder)
+                        .count(),
+                );
+                let query = serialize_nts_packet(request, &mut Aes128SivAead::new(&keys.c2s));
+                let response = bench_response(&query, cookie_keys.clone(), logger.clone())?;
+                let response = parse_nts_packet(&response, &mut Aes128SivAead::new(&keys.s2c))?;
+                refill_cookies(state, &response);
+                Ok(test_result(state.cookies.len()))
+            },
+            |result| results.push(result.cookies_remaining),
+        )
+        .unwrap();
+
+        // The second request asks for the 5 missing cookies, and the pool stays full afterwards.
+        assert_eq!(sent_placeholders, vec![0, 5, 0, 0]);
+        assert_eq!(results, vec![3, 8, 8, 8]);
+        assert_eq!(key_exchanges, 1);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod aead;
 pub mod client;
+pub mod pcap;
 pub mod protocol;
 pub mod server;
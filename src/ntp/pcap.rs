@@ -0,0 +1,357 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Summaries of the NTP packets in a pcap or pcapng capture, for offline analysis of the traffic
+//! of a client or a server.
+
+use std::cmp;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use super::protocol::{
+    has_extension, parse_ntp_packet, parse_packet_header, NtpExtensionType, PacketMode,
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_NANOSECOND_MAGIC: u32 = 0xa1b2_3c4d;
+const PCAP_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+
+// See https://www.tcpdump.org/linktypes.html.
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_UDP: u8 = 17;
+const UDP_HEADER_SIZE: usize = 8;
+
+/// The summary of an NTP packet found in a capture.
+#[derive(Clone, Debug)]
+pub struct PacketSummary {
+    /// The number of the frame in the capture, counting from 1 like Wireshark.
+    pub frame: usize,
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// The mode, unless the header is malformed.
+    pub mode: Option<PacketMode>,
+    /// The types of the extensions, in order. The encrypted ones can't be seen.
+    pub extensions: Vec<NtpExtensionType>,
+    /// Whether the packet is authenticated with NTS.
+    pub nts: bool,
+    /// Why the packet couldn't be parsed, if it couldn't.
+    pub malformed: Option<String>,
+}
+
+/// One line per packet, e.g. `1: 50000 -> 123, Client, NTS, extensions [...]`.
+impl fmt::Display for PacketSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.frame, self.src_port, self.dst_port)?;
+        if let Some(mode) = self.mode {
+            write!(f, ", {:?}", mode)?;
+        }
+        if self.nts {
+            write!(f, ", NTS")?;
+        }
+        if !self.extensions.is_empty() {
+            write!(f, ", extensions {:?}", self.extensions)?;
+        }
+        if let Some(ref reason) = self.malformed {
+            write!(f, ", malformed: {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a pcap or pcapng capture, and summarize the UDP payloads from or to one of `ports` as
+/// NTP packets. Other frames are skipped, and so are the IP fragments after the first and the
+/// IPv6 packets with extension headers. Only Ethernet, Linux cooked, loopback and raw IP
+/// captures are understood.
+pub fn analyze_pcap<P: AsRef<Path>>(path: P, ports: &[u16]) -> Result<Vec<PacketSummary>, Error> {
+    let capture = fs::read(path)?;
+    let frames = read_frames(&capture)?;
+    let summaries = frames
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &(linktype, frame))| {
+            let (src_port, dst_port, payload) = udp_payload(linktype, frame)?;
+            if !ports.contains(&src_port) && !ports.contains(&dst_port) {
+                return None;
+            }
+            Some(summarize(i + 1, src_port, dst_port, payload))
+        })
+        .collect();
+    Ok(summaries)
+}
+
+fn summarize(frame: usize, src_port: u16, dst_port: u16, payload: &[u8]) -> PacketSummary {
+    let mut summary = PacketSummary {
+        frame,
+        src_port,
+        dst_port,
+        mode: None,
+        extensions: Vec::new(),
+        nts: false,
+        malformed: None,
+    };
+    match parse_packet_header(payload) {
+        Ok(header) => summary.mode = Some(header.mode),
+        Err(error) => {
+            summary.malformed = Some(error.to_string());
+            return summary;
+        }
+    }
+    match parse_ntp_packet(payload) {
+        Ok(packet) => {
+            summary.extensions = packet.exts.iter().map(|ext| ext.ext_type).collect();
+            summary.nts = has_extension(&packet, NtpExtensionType::NTSAuthenticator);
+        }
+        Err(error) => summary.malformed = Some(error.to_string()),
+    }
+    summary
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason)
+}
+
+fn truncated() -> Error {
+    invalid("truncated capture")
+}
+
+fn u16_at(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let bytes = [*data.get(offset)?, *data.get(offset + 1)?];
+    Some(if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    })
+}
+
+fn u32_at(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let high = u32::from(u16_at(data, offset, big_endian)?);
+    let low = u32::from(u16_at(data, offset + 2, big_endian)?);
+    Some(if big_endian {
+        (high << 16) | low
+    } else {
+        (low << 16) | high
+    })
+}
+
+/// Split the capture into frames, with the link type of each.
+fn read_frames(capture: &[u8]) -> Result<Vec<(u32, &[u8])>, Error> {
+    // The block type of a section header reads the same in both byte orders.
+    match u32_at(capture, 0, true) {
+        Some(PCAPNG_SECTION_HEADER) => read_pcapng(capture),
+        Some(_) => read_pcap(capture),
+        None => Err(truncated()),
+    }
+}
+
+fn read_pcap(capture: &[u8]) -> Result<Vec<(u32, &[u8])>, Error> {
+    let is_magic = |big_endian| match u32_at(capture, 0, big_endian) {
+        Some(PCAP_MAGIC) | Some(PCAP_NANOSECOND_MAGIC) => true,
+        _ => false,
+    };
+    let big_endian = if is_magic(true) {
+        true
+    } else if is_magic(false) {
+        false
+    } else {
+        return Err(invalid("not a pcap or pcapng capture"));
+    };
+    // The upper bits may carry the FCS length.
+    let linktype = u32_at(capture, 20, big_endian).ok_or_else(truncated)? & 0xffff;
+
+    let mut frames = Vec::new();
+    let mut offset = PCAP_HEADER_SIZE;
+    while offset < capture.len() {
+        let captured = u32_at(capture, offset + 8, big_endian).ok_or_else(truncated)? as usize;
+        let start = offset + PCAP_RECORD_HEADER_SIZE;
+        let frame = capture.get(start..start + captured).ok_or_else(truncated)?;
+        frames.push((linktype, frame));
+        offset = start + captured;
+    }
+    Ok(frames)
+}
+
+fn read_pcapng(capture: &[u8]) -> Result<Vec<(u32, &[u8])>, Error> {
+    let mut big_endian = true;
+    // The link types of the interfaces of the current section, by interface id.
+    let mut linktypes = Vec::new();
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while offset < capture.len() {
+        let block_type = u32_at(capture, offset, big_endian).ok_or_else(truncated)?;
+        if block_type == PCAPNG_SECTION_HEADER {
+            // Each section has its own byte order and interfaces.
+            big_endian = match u32_at(capture, offset + 8, true) {
+                Some(PCAPNG_BYTE_ORDER_MAGIC) => true,
+                Some(magic) if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => false,
+                _ => return Err(invalid("invalid pcapng section header")),
+            };
+            linktypes.clear();
+        }
+        let len = u32_at(capture, offset + 4, big_endian).ok_or_else(truncated)? as usize;
+        if len < 12 {
+            return Err(invalid("invalid pcapng block length"));
+        }
+        let body = capture.get(offset + 8..offset + len - 4).ok_or_else(truncated)?;
+        match block_type {
+            PCAPNG_INTERFACE_DESCRIPTION => {
+                linktypes.push(u32::from(u16_at(body, 0, big_endian).ok_or_else(truncated)?));
+            }
+            PCAPNG_ENHANCED_PACKET => {
+                let interface = u32_at(body, 0, big_endian).ok_or_else(truncated)? as usize;
+                let captured = u32_at(body, 12, big_endian).ok_or_else(truncated)? as usize;
+                let frame = body.get(20..20 + captured).ok_or_else(truncated)?;
+                frames.push((interface_linktype(&linktypes, interface)?, frame));
+            }
+            PCAPNG_SIMPLE_PACKET => {
+                // The frame is padded to the block, so only its original length tells its end.
+                let original = u32_at(body, 0, big_endian).ok_or_else(truncated)? as usize;
+                let frame = body.get(4..).ok_or_else(truncated)?;
+                let frame = &frame[..cmp::min(original, frame.len())];
+                frames.push((interface_linktype(&linktypes, 0)?, frame));
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(frames)
+}
+
+fn interface_linktype(linktypes: &[u32], interface: usize) -> Result<u32, Error> {
+    linktypes
+        .get(interface)
+        .cloned()
+        .ok_or_else(|| invalid("packet of an unknown pcapng interface"))
+}
+
+/// Return the ports and the payload of the UDP datagram in the frame, if there is one.
+fn udp_payload(linktype: u32, frame: &[u8]) -> Option<(u16, u16, &[u8])> {
+    let ip = match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            while u16_at(frame, offset, true)? == ETHERTYPE_VLAN {
+                offset += 4;
+            }
+            ip_packet(u16_at(frame, offset, true)?, frame.get(offset + 2..)?)?
+        }
+        LINKTYPE_LINUX_SLL => ip_packet(u16_at(frame, 14, true)?, frame.get(16..)?)?,
+        // The address family is in the byte order of the capturing host, so rely on the
+        // version of the IP packet instead.
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_RAW => frame,
+        _ => return None,
+    };
+    let udp = match ip.first()? >> 4 {
+        4 => ipv4_payload(ip)?,
+        6 => ipv6_payload(ip)?,
+        _ => return None,
+    };
+    let src_port = u16_at(udp, 0, true)?;
+    let dst_port = u16_at(udp, 2, true)?;
+    // The payload may be cut short by the snapshot length.
+    let len = cmp::max(u16_at(udp, 4, true)? as usize, UDP_HEADER_SIZE);
+    Some((src_port, dst_port, udp.get(UDP_HEADER_SIZE..cmp::min(len, udp.len()))?))
+}
+
+fn ip_packet(ethertype: u16, packet: &[u8]) -> Option<&[u8]> {
+    match ethertype {
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => Some(packet),
+        _ => None,
+    }
+}
+
+/// Return the UDP datagram in the IPv4 packet. Ethernet pads short frames, so the end is given
+/// by the total length.
+fn ipv4_payload(ip: &[u8]) -> Option<&[u8]> {
+    let header_len = usize::from(ip[0] & 0x0f) * 4;
+    let total_len = u16_at(ip, 2, true)? as usize;
+    let fragment_offset = u16_at(ip, 6, true)? & 0x1fff;
+    if *ip.get(9)? != IPPROTO_UDP || fragment_offset != 0 {
+        return None;
+    }
+    ip.get(header_len..cmp::min(total_len, ip.len()))
+}
+
+fn ipv6_payload(ip: &[u8]) -> Option<&[u8]> {
+    let payload_len = u16_at(ip, 4, true)? as usize;
+    if *ip.get(6)? != IPPROTO_UDP {
+        return None;
+    }
+    ip.get(40..cmp::min(40 + payload_len, ip.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ntp::protocol::NtpExtensionType::*;
+
+    #[test]
+    fn test_pcap() {
+        let summaries = analyze_pcap("tests/captures/ntp.pcap", &[123]).unwrap();
+        // The DNS query in the third frame is skipped.
+        assert_eq!(summaries.len(), 3);
+
+        let request = &summaries[0];
+        assert_eq!((request.frame, request.src_port, request.dst_port), (1, 50000, 123));
+        assert_eq!(request.mode, Some(PacketMode::Client));
+        assert!(request.nts);
+        assert_eq!(
+            request.extensions,
+            vec![UniqueIdentifier, NTSCookie, NTSCookiePlaceholder, NTSCookiePlaceholder,
+                 NTSAuthenticator],
+        );
+        assert_eq!(request.malformed, None);
+
+        let response = &summaries[1];
+        assert_eq!((response.frame, response.src_port, response.dst_port), (2, 123, 50000));
+        assert_eq!(response.mode, Some(PacketMode::Server));
+        assert!(response.nts);
+        assert_eq!(response.extensions, vec![UniqueIdentifier, NTSAuthenticator]);
+        assert_eq!(response.to_string(),
+                   "2: 123 -> 50000, Server, NTS, extensions [UniqueIdentifier, NTSAuthenticator]");
+
+        let truncated = &summaries[2];
+        assert_eq!(truncated.frame, 4);
+        assert_eq!(truncated.mode, None);
+        assert!(!truncated.nts);
+        assert_eq!(truncated.malformed.as_ref().map(String::as_str), Some("Too short"));
+
+        // Only the given ports are looked at.
+        assert!(analyze_pcap("tests/captures/ntp.pcap", &[4460]).unwrap().is_empty());
+        assert_eq!(analyze_pcap("tests/captures/ntp.pcap", &[53]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_pcapng() {
+        // A plain request over IPv6 behind a VLAN tag.
+        let summaries = analyze_pcap("tests/captures/ntp.pcapng", &[123]).unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].mode, Some(PacketMode::Client));
+        assert!(!summaries[0].nts);
+        assert!(summaries[0].extensions.is_empty());
+        assert_eq!(summaries[0].to_string(), "1: 50000 -> 123, Client");
+    }
+
+    #[test]
+    fn test_not_a_capture() {
+        let error = analyze_pcap("tests/ca.pem", &[123]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,180 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Networks whose requests the server drops, e.g. to mitigate abuse, or the only networks it
+//! serves, for private deployments.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+/// A block of IPv4 or IPv6 addresses in CIDR notation, e.g. `192.0.2.0/24` or `2001:db8::/32`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    /// Return true if the address is in the network. IPv4 addresses mapped into IPv6, as a dual
+    /// stack socket receives them, are matched against the IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmap(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = io::Error;
+
+    fn from_str(cidr: &str) -> Result<IpNetwork, io::Error> {
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid network: {}", cidr))
+        };
+        let mut parts = cidr.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        // A single address is a network of its own.
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+/// Turn an IPv4-mapped IPv6 address back into the IPv4 address.
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            let octets = v6.octets();
+            IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+        }
+        ip => ip,
+    }
+}
+
+/// Return true if the first `prefix_len` bits of the two addresses are equal.
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    let rest = prefix_len % 8;
+    if rest == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// The networks a server serves.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    /// If it's not empty, only these networks are served.
+    pub allow: Vec<IpNetwork>,
+    /// These networks are never served, even if they are in an allowed network.
+    pub deny: Vec<IpNetwork>,
+}
+
+impl Acl {
+    /// Return true if requests from the address are served. A denied network wins over an
+    /// allowed one, whichever is more specific.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|network| network.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn test_parse() {
+        let network: IpNetwork = "192.0.2.0/24".parse().unwrap();
+        assert_eq!(network.prefix_len, 24);
+        let network: IpNetwork = "2001:db8::/32".parse().unwrap();
+        assert_eq!(network.addr, IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0)));
+        let network: IpNetwork = "192.0.2.1".parse().unwrap();
+        assert_eq!(network.prefix_len, 32);
+
+        for cidr in &["192.0.2.0/33", "2001:db8::/129", "192.0.2.0/", "example.com/8", ""] {
+            cidr.parse::<IpNetwork>().unwrap_err();
+        }
+    }
+
+    fn networks(cidrs: &[&str]) -> Vec<IpNetwork> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_contains() {
+        let acl = Acl {
+            allow: vec![],
+            deny: networks(&["192.0.2.0/24", "198.51.100.128/25", "2001:db8::/32"]),
+        };
+        let denied = |ip: &str| !acl.permits(ip.parse().unwrap());
+
+        assert!(denied("192.0.2.1"));
+        assert!(denied("192.0.2.255"));
+        assert!(!denied("192.0.3.1"));
+        assert!(denied("198.51.100.200"));
+        assert!(!denied("198.51.100.100"));
+        assert!(denied("2001:db8:1::1"));
+        assert!(!denied("2001:db9::1"));
+        // An IPv4 client of a dual stack socket.
+        assert!(denied("::ffff:192.0.2.1"));
+        assert!(!denied("::ffff:203.0.113.1"));
+
+        // The whole address space.
+        let everything: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("203.0.113.1".parse().unwrap()));
+        assert!(!everything.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_allow() {
+        // Everything is served by default.
+        let acl = Acl::default();
+        assert!(acl.permits("203.0.113.1".parse().unwrap()));
+
+        // Only the allowed networks are served.
+        let acl = Acl {
+            allow: networks(&["192.0.2.0/24", "2001:db8::/32"]),
+            deny: vec![],
+        };
+        let permits = |ip: &str| acl.permits(ip.parse().unwrap());
+        assert!(permits("192.0.2.1"));
+        assert!(permits("::ffff:192.0.2.1"));
+        assert!(permits("2001:db8::1"));
+        assert!(!permits("203.0.113.1"));
+        assert!(!permits("2001:db9::1"));
+
+        // A denied network wins, whether it's inside or around an allowed one.
+        let acl = Acl {
+            allow: networks(&["192.0.2.0/24", "198.51.100.7"]),
+            deny: networks(&["192.0.2.128/25", "198.51.100.0/24"]),
+        };
+        let permits = |ip: &str| acl.permits(ip.parse().unwrap());
+        assert!(permits("192.0.2.1"));
+        assert!(!permits("192.0.2.200"));
+        assert!(!permits("198.51.100.7"));
+        assert!(!permits("203.0.113.1"));
+    }
+}
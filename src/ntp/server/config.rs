@@ -10,10 +10,272 @@ use sloggers::Build;
 use std::convert::TryFrom;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 
-use crate::cookie::CookieKey;
+use super::acl::IpNetwork;
+use super::ext_policy::{ExtensionAction, ExtensionPolicy};
+use super::interleaved;
+use crate::cookie::{CookieKey, CookieKeySource};
 use crate::error::WrapError;
+use crate::key_rotator::RotationPeriods;
 use crate::metrics::MetricsConfig;
+use crate::ntp::protocol::{DEFAULT_MAX_EXTENSIONS, MAX_COOKIE_PLACEHOLDERS, MAX_POLL, MIN_POLL};
+use crate::nts_ke::records::KnownAeadAlgorithm;
+
+/// The poll exponent advertised in responses, if it's not specified. The poll interval is
+/// 2**7 = 128 seconds.
+pub const DEFAULT_POLL: i8 = 7;
+
+/// The largest UDP payload the server sends, if it's not specified. It's the minimum IPv6 MTU,
+/// so responses are never fragmented, although it leaves out the IP and UDP headers.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 1280;
+
+/// How often the upstream is queried, if it's not specified.
+pub const DEFAULT_UPSTREAM_POLL: Duration = Duration::from_secs(1);
+
+/// The reference id of the reference clock, if it's not specified.
+pub const DEFAULT_REFCLOCK_REFID: u32 = 0x4750_5300; // "GPS"
+
+/// The smallest maximum response size we accept. It leaves room for a few cookies.
+const MIN_RESPONSE_SIZE: usize = 512;
+
+/// The smallest maximum number of extensions in a request we accept. It leaves room for the
+/// unique identifier, the cookie, the placeholders we honor and the authenticator.
+const MIN_MAX_EXTENSIONS: usize = MAX_COOKIE_PLACEHOLDERS + 3;
+
+/// Parse the poll exponent from the config and validate that it's within the range from
+/// `MIN_POLL` to `MAX_POLL`.
+fn get_poll(settings: &config::Config) -> Result<i8, config::ConfigError> {
+    match settings.get_int("poll") {
+        // If it's a not-found error, we just set it to the default value.
+        Err(config::ConfigError::NotFound(_)) => Ok(DEFAULT_POLL),
+
+        // If it's other error, for example, unparseable error, it means that the user intended
+        // to enter the poll but it just fails.
+        Err(error) => Err(error),
+
+        Ok(val) if val >= i64::from(MIN_POLL) && val <= i64::from(MAX_POLL) => Ok(val as i8),
+
+        Ok(_) => Err(config::ConfigError::Message(format!(
+            "the poll must be an exponent between {} and {}", MIN_POLL, MAX_POLL
+        ))),
+    }
+}
+
+/// The AEAD algorithms accepted in cookies, if they are not specified.
+fn default_accepted_aead() -> Vec<u16> {
+    vec![KnownAeadAlgorithm::AeadAesSivCmac256.as_algorithm_id()]
+}
+
+/// Parse the ids of the AEAD algorithms accepted in cookies from the config and validate that
+/// they are all known algorithms.
+fn get_accepted_aead(settings: &config::Config) -> Result<Vec<u16>, config::ConfigError> {
+    let values = match settings.get_array("accepted_aead") {
+        // If it's a not-found error, we just set it to the default value.
+        Err(config::ConfigError::NotFound(_)) => return Ok(default_accepted_aead()),
+        Err(error) => return Err(error),
+        Ok(values) => values,
+    };
+
+    let mut accepted_aead = Vec::new();
+    for value in values {
+        let id = value.into_int()?;
+        match u16::try_from(id).ok().and_then(KnownAeadAlgorithm::from_algorithm_id) {
+            Some(algorithm) => accepted_aead.push(algorithm.as_algorithm_id()),
+            None => return Err(config::ConfigError::Message(format!(
+                "{} is not a known AEAD algorithm id", id
+            ))),
+        }
+    }
+    if accepted_aead.is_empty() {
+        return Err(config::ConfigError::Message(
+            String::from("at least one AEAD algorithm must be accepted")
+        ));
+    }
+    Ok(accepted_aead)
+}
+
+/// Parse an optional socket address from the config. It returns `None` if the key is not found.
+fn get_optional_addr(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Option<SocketAddr>, config::ConfigError> {
+    match settings.get_str(key) {
+        // If it's a not-found error, we can just leave it empty.
+        Err(config::ConfigError::NotFound(_)) => Ok(None),
+
+        // If it's other error, for example, unparseable error, it means that the user intended
+        // to enter the address but it just fails.
+        Err(error) => Err(error),
+
+        // Parse SocketAddr from a string.
+        Ok(addr) => Ok(Some(addr.parse().wrap_err()?)),
+    }
+}
+
+/// Parse the maximum size of a response from the config and validate that it's at least
+/// `MIN_RESPONSE_SIZE` and fits in a UDP datagram.
+fn get_max_response_size(settings: &config::Config) -> Result<usize, config::ConfigError> {
+    match settings.get_int("max_response_size") {
+        // If it's a not-found error, we just set it to the default value.
+        Err(config::ConfigError::NotFound(_)) => Ok(DEFAULT_MAX_RESPONSE_SIZE),
+        Err(error) => Err(error),
+        Ok(val) if val >= MIN_RESPONSE_SIZE as i64 && val <= i64::from(u16::max_value()) => {
+            Ok(val as usize)
+        }
+        Ok(_) => Err(config::ConfigError::Message(format!(
+            "the maximum response size must be between {} and {}",
+            MIN_RESPONSE_SIZE, u16::max_value()
+        ))),
+    }
+}
+
+/// Parse the maximum number of extensions parsed in a request from the config. It must be at least
+/// `MIN_MAX_EXTENSIONS`, so that a full NTS request is still parsed.
+fn get_max_extensions(settings: &config::Config) -> Result<usize, config::ConfigError> {
+    match settings.get_int("max_extensions") {
+        // If it's a not-found error, we just set it to the default value.
+        Err(config::ConfigError::NotFound(_)) => Ok(DEFAULT_MAX_EXTENSIONS),
+        Err(error) => Err(error),
+        Ok(val) if val >= MIN_MAX_EXTENSIONS as i64 => usize::try_from(val).map_err(|_| {
+            config::ConfigError::Message(String::from("the maximum of extensions is too large"))
+        }),
+        Ok(_) => Err(config::ConfigError::Message(format!(
+            "the maximum number of extensions must be at least {}",
+            MIN_MAX_EXTENSIONS
+        ))),
+    }
+}
+
+/// Parse the number of responses remembered for interleaved mode from the config. It must be at
+/// least one.
+fn get_interleaved_capacity(settings: &config::Config) -> Result<usize, config::ConfigError> {
+    match settings.get_int("interleaved_capacity") {
+        // If it's a not-found error, we just set it to the default value.
+        Err(config::ConfigError::NotFound(_)) => Ok(interleaved::DEFAULT_CAPACITY),
+        Err(error) => Err(error),
+        Ok(val) if val >= 1 => usize::try_from(val).map_err(|_| config::ConfigError::Message(
+            String::from("the interleaved capacity is too large")
+        )),
+        Ok(_) => Err(config::ConfigError::Message(String::from(
+            "the interleaved capacity must be at least one"
+        ))),
+    }
+}
+
+/// Parse the interval between the upstream queries from the config, given in seconds. It must be
+/// at least one second.
+fn get_upstream_poll(settings: &config::Config) -> Result<Duration, config::ConfigError> {
+    match settings.get_int("upstream_poll_secs") {
+        // If it's a not-found error, we just set it to the default value.
+        Err(config::ConfigError::NotFound(_)) => Ok(DEFAULT_UPSTREAM_POLL),
+        Err(error) => Err(error),
+        Ok(val) if val >= 1 => Ok(Duration::from_secs(val as u64)),
+        Ok(_) => Err(config::ConfigError::Message(String::from(
+            "the upstream poll interval must be at least one second"
+        ))),
+    }
+}
+
+/// Parse the cookie key rotation from the config, see `RotationPeriods::from_settings`. The
+/// cookies must stay valid for at least the poll interval advertised with the `poll` exponent,
+/// because a client polling at that interval uses a cookie it got a poll interval ago.
+fn get_cookie_rotation(
+    settings: &config::Config,
+    poll: i8,
+) -> Result<RotationPeriods, config::ConfigError> {
+    let periods = RotationPeriods::from_settings(settings)?;
+    let lifetime = periods.duration.saturating_mul(periods.backward_periods);
+    let poll_interval = 1u64 << poll;
+    if lifetime < poll_interval {
+        return Err(config::ConfigError::Message(format!(
+            "the cookies are valid for {} seconds, less than the poll interval of {} seconds",
+            lifetime, poll_interval
+        )));
+    }
+    Ok(periods)
+}
+
+/// Parse a list of networks given in CIDR notation, e.g. the allowed or the denied networks. It's
+/// empty if the key is not found.
+fn get_networks(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Vec<IpNetwork>, config::ConfigError> {
+    let values = match settings.get_array(key) {
+        Err(config::ConfigError::NotFound(_)) => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+        Ok(values) => values,
+    };
+    let mut networks = Vec::new();
+    for value in values {
+        networks.push(value.into_str()?.parse().wrap_err()?);
+    }
+    Ok(networks)
+}
+
+/// Parse the policy for the extensions the server doesn't handle itself. The action for the
+/// types not listed in `echo_extensions`, `ignore_extensions` or `reject_extensions` is given by
+/// `unknown_extensions`, and they are ignored if it's not found.
+fn get_extension_policy(
+    settings: &config::Config,
+) -> Result<ExtensionPolicy, config::ConfigError> {
+    let mut policy = ExtensionPolicy::default();
+    policy.default = match settings.get_str("unknown_extensions") {
+        Err(config::ConfigError::NotFound(_)) => ExtensionAction::default(),
+        Err(error) => return Err(error),
+        Ok(name) => name.parse().wrap_err()?,
+    };
+
+    let lists = [
+        ("echo_extensions", ExtensionAction::Echo),
+        ("ignore_extensions", ExtensionAction::Ignore),
+        ("reject_extensions", ExtensionAction::Reject),
+    ];
+    for &(key, action) in lists.iter() {
+        let values = match settings.get_array(key) {
+            Err(config::ConfigError::NotFound(_)) => continue,
+            Err(error) => return Err(error),
+            Ok(values) => values,
+        };
+        for value in values {
+            let ext_type = value.into_int()?;
+            let ext_type = u16::try_from(ext_type).map_err(|_| {
+                config::ConfigError::Message(format!("{} is not an extension type", ext_type))
+            })?;
+            if policy.actions.iter().any(|(listed_type, _)| *listed_type == ext_type) {
+                return Err(config::ConfigError::Message(format!(
+                    "extension type {} is listed more than once", ext_type
+                )));
+            }
+            policy.actions.push((ext_type, action));
+        }
+    }
+    Ok(policy)
+}
+
+/// Parse a reference id, e.g. the one marking plain responses on listeners serving NTS or the
+/// one of the reference clock. It's given as an ASCII string of at most four characters, like the
+/// reference ids of stratum 1 servers, and padded with zeros.
+fn get_ascii_refid(
+    settings: &config::Config,
+    key: &str,
+) -> Result<Option<u32>, config::ConfigError> {
+    let name = match settings.get_str(key) {
+        // If it's a not-found error, the caller picks the default.
+        Err(config::ConfigError::NotFound(_)) => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(name) => name,
+    };
+    if name.is_empty() || name.len() > 4 || !name.is_ascii() {
+        return Err(config::ConfigError::Message(format!(
+            "the {} must be one to four ASCII characters, not {:?}", key, name
+        )));
+    }
+    let mut refid = [0; 4];
+    refid[..name.len()].copy_from_slice(name.as_bytes());
+    Ok(Some(u32::from_be_bytes(refid)))
+}
 
 fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     let mut metrics = None;
@@ -21,7 +283,8 @@ fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
         if let Ok(port) = settings.get_int("metrics_port") {
             metrics = Some(MetricsConfig {
                 port: port as u16,
-                addr
+                addr,
+                token: settings.get_str("metrics_token").ok(),
             });
         }
     }
@@ -37,6 +300,10 @@ pub struct NtpServerConfig {
 
     pub cookie_key: CookieKey,
 
+    /// Where the cookie key was read from, if anywhere. It's read again when the keys are
+    /// rotated on request.
+    pub cookie_key_source: Option<CookieKeySource>,
+
     /// The logger that will be used throughout the application, while the server is running.
     /// This property is mandatory because logging is very important for debugging.
     logger: slog::Logger,
@@ -44,6 +311,84 @@ pub struct NtpServerConfig {
     pub memcached_url: String,
     pub metrics_config: Option<MetricsConfig>,
     pub upstream_addr: Option<SocketAddr>,
+
+    /// The interval between the queries to the upstream.
+    pub upstream_poll: Duration,
+
+    /// Address and port dedicated to plain NTP. Requests carrying NTS extensions on this address
+    /// will get a Kiss of Death.
+    pub plain_ntp_addr: Option<SocketAddr>,
+
+    /// Address and port dedicated to NTS.
+    pub nts_ntp_addr: Option<SocketAddr>,
+
+    /// If it's true, plain NTP requests on `nts_ntp_addr` will get a Kiss of Death.
+    pub require_nts: bool,
+
+    /// The poll exponent advertised in responses. The poll interval is 2**poll seconds.
+    pub poll: i8,
+
+    /// The ids of the AEAD algorithms we honor in cookies. Requests with cookies for any other
+    /// algorithm will get a Kiss of Death.
+    pub accepted_aead: Vec<u16>,
+
+    /// If it's true, the sockets are bound with SO_REUSEPORT, so that several server processes
+    /// can share the same ports.
+    pub reuse_port: bool,
+
+    /// If it's true, the receive timestamps are taken by the kernel with SO_TIMESTAMP, which
+    /// leaves out the scheduling delay of the server.
+    pub kernel_timestamps: bool,
+
+    /// If it's set, plain NTP responses on the listeners also serving NTS carry this reference
+    /// id instead of the one of the upstream, so that monitoring probes can tell they were
+    /// answered as plain NTP.
+    pub plain_probe_refid: Option<u32>,
+
+    /// The largest UDP payload the server sends. NTS responses carry fewer cookies than asked
+    /// for, if they wouldn't fit otherwise.
+    pub max_response_size: usize,
+    /// The most extensions the server parses in a request. Requests with more are dropped, and
+    /// NTS requests with more in their encrypted part get a Kiss of Death.
+    pub max_extensions: usize,
+    /// If it's true, the server answers requests asking for interleaved mode with the time its
+    /// previous response to the client was actually sent.
+    pub interleaved: bool,
+    /// The number of responses whose transmit time is remembered for interleaved mode. A client
+    /// falls back to basic mode if its previous response was forgotten.
+    pub interleaved_capacity: usize,
+
+    /// If it's not empty, only requests from these networks are served, the others are dropped
+    /// without an answer.
+    pub allow_networks: Vec<IpNetwork>,
+
+    /// Requests from these networks are dropped without an answer, even if they are in an
+    /// allowed network.
+    pub deny_networks: Vec<IpNetwork>,
+
+    /// What to do with the extensions of NTS requests the server doesn't handle itself: echo
+    /// them back, leave them out of the response, or answer with a Kiss of Death.
+    pub extension_policy: ExtensionPolicy,
+
+    /// The length of the cookie key periods and the number of periods the keys are kept for.
+    pub cookie_rotation: RotationPeriods,
+
+    /// If it's true, the cookies are bound to the subnet of the client, so that a cookie stolen
+    /// from one client cannot be used from another network. It must be the same as in the NTS-KE
+    /// server, and clients changing networks need a new key exchange.
+    pub bind_cookies_to_subnet: bool,
+
+    /// If it's true, requests with a zero transmit timestamp or of a version newer than the one
+    /// we speak get a Kiss of Death. By default, they are answered for broad compatibility.
+    pub strict: bool,
+
+    /// A file rewritten by the process disciplining the clock with a local reference clock,
+    /// holding the Unix time of its last update. The server is then stratum 1, and it can't
+    /// have an upstream.
+    pub refclock_file: Option<String>,
+
+    /// The reference id of the reference clock, e.g. "GPS" or "PPS" in ASCII.
+    pub refclock_refid: u32,
 }
 
 /// We decided to make NtpServerConfig mutable so that you can add more address after you parse
@@ -70,9 +415,33 @@ impl NtpServerConfig {
 
             // From parameters.
             cookie_key,
+            cookie_key_source: None,
             memcached_url,
             metrics_config,
             upstream_addr,
+            upstream_poll: DEFAULT_UPSTREAM_POLL,
+
+            // The dedicated addresses are not set by default. The users can set them later.
+            plain_ntp_addr: None,
+            nts_ntp_addr: None,
+            require_nts: false,
+            poll: DEFAULT_POLL,
+            accepted_aead: default_accepted_aead(),
+            reuse_port: false,
+            kernel_timestamps: true,
+            plain_probe_refid: None,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            max_extensions: DEFAULT_MAX_EXTENSIONS,
+            interleaved: false,
+            interleaved_capacity: interleaved::DEFAULT_CAPACITY,
+            allow_networks: Vec::new(),
+            deny_networks: Vec::new(),
+            extension_policy: ExtensionPolicy::default(),
+            cookie_rotation: RotationPeriods::default(),
+            bind_cookies_to_subnet: false,
+            strict: false,
+            refclock_file: None,
+            refclock_refid: DEFAULT_REFCLOCK_REFID,
         }
     }
 
@@ -113,6 +482,12 @@ impl NtpServerConfig {
     /// following cases:
     ///
     /// * The upstream port in the configuration file is a valid `i64` but not a valid `u16`.
+    /// * The poll in the configuration file is not between `MIN_POLL` and `MAX_POLL`.
+    /// * The upstream poll interval in the configuration file is less than a second.
+    /// * The cookie rotation in the configuration file is zero seconds, or the cookies wouldn't
+    ///   stay valid for the poll interval.
+    /// * The accepted AEAD algorithms in the configuration file are empty or unknown.
+    /// * Both an upstream and a reference clock are given.
     ///
     // Returning a `Message` object here is not a good practice. I will figure out a good practice
     // later.
@@ -125,6 +500,9 @@ impl NtpServerConfig {
         // Resolves metrics configuration.
         let metrics_config = get_metrics_config(&settings);
 
+        let poll = get_poll(&settings)?;
+        let accepted_aead = get_accepted_aead(&settings)?;
+
         // XXX: The code of parsing a next port here is quite ugly due to the `get_int` interface.
         // Please don't be surprised :)
         let upstream_port = match settings.get_int("upstream_port") {
@@ -178,8 +556,9 @@ impl NtpServerConfig {
         // Note that all of the file reading stuffs should be at the end of the function so that
         // all the not-file-related stuffs can fail fast.
 
-        let cookie_key_filename = settings.get_str("cookie_key_file")?;
-        let cookie_key = CookieKey::parse(&cookie_key_filename).wrap_err()?;
+        let cookie_key_source = CookieKeySource::from_settings(&settings)?;
+        // The config has no logger yet, so the warnings about the key file go to the global one.
+        let cookie_key = cookie_key_source.load(&slog_scope::logger()).wrap_err()?;
 
         let mut config = NtpServerConfig::new(
             cookie_key,
@@ -188,7 +567,72 @@ impl NtpServerConfig {
             upstream_sock_addr,
         );
 
-        let addrs = settings.get_array("addr")?;
+        config.cookie_key_source = Some(cookie_key_source);
+        config.poll = poll;
+        config.upstream_poll = get_upstream_poll(&settings)?;
+        config.cookie_rotation = get_cookie_rotation(&settings, poll)?;
+        config.accepted_aead = accepted_aead;
+        config.plain_ntp_addr = get_optional_addr(&settings, "plain_ntp_addr")?;
+        config.nts_ntp_addr = get_optional_addr(&settings, "nts_ntp_addr")?;
+        config.require_nts = match settings.get_bool("require_nts") {
+            // If it's a not-found error, we serve plain requests on the NTS address by default.
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(val) => val,
+        };
+        config.reuse_port = match settings.get_bool("reuse_port") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(val) => val,
+        };
+        config.kernel_timestamps = match settings.get_bool("kernel_timestamps") {
+            Err(config::ConfigError::NotFound(_)) => true,
+            Err(error) => return Err(error),
+            Ok(val) => val,
+        };
+        // If it's not found, plain responses carry the refid of the upstream.
+        config.plain_probe_refid = get_ascii_refid(&settings, "plain_probe_refid")?;
+        config.allow_networks = get_networks(&settings, "allow_networks")?;
+        config.deny_networks = get_networks(&settings, "deny_networks")?;
+        config.extension_policy = get_extension_policy(&settings)?;
+        config.max_response_size = get_max_response_size(&settings)?;
+        config.max_extensions = get_max_extensions(&settings)?;
+        config.interleaved = match settings.get_bool("interleaved") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(val) => val,
+        };
+        config.interleaved_capacity = get_interleaved_capacity(&settings)?;
+        config.bind_cookies_to_subnet = match settings.get_bool("bind_cookies_to_subnet") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(val) => val,
+        };
+        config.strict = match settings.get_bool("strict") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(val) => val,
+        };
+        config.refclock_file = match settings.get_str("refclock_file") {
+            Err(config::ConfigError::NotFound(_)) => None,
+            Err(error) => return Err(error),
+            Ok(path) => Some(path),
+        };
+        if config.refclock_file.is_some() && config.upstream_addr.is_some() {
+            return Err(config::ConfigError::Message(String::from(
+                "the server can't have both an upstream and a reference clock"
+            )));
+        }
+        config.refclock_refid =
+            get_ascii_refid(&settings, "refclock_refid")?.unwrap_or(DEFAULT_REFCLOCK_REFID);
+
+        // The list of addresses serving both NTS and plain NTP can be omitted, if the dedicated
+        // addresses are used instead.
+        let addrs = match settings.get_array("addr") {
+            Err(config::ConfigError::NotFound(_)) => Vec::new(),
+            Err(error) => return Err(error),
+            Ok(addrs) => addrs,
+        };
         for addr in addrs {
             // Parse SocketAddr from a string.
             let sock_addr = addr.to_string().parse().wrap_err()?;
@@ -198,3 +642,176 @@ impl NtpServerConfig {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_poll(&settings).unwrap(), DEFAULT_POLL);
+
+        settings.set("poll", 10i64).unwrap();
+        assert_eq!(get_poll(&settings).unwrap(), 10);
+
+        settings.set("poll", 18i64).unwrap();
+        get_poll(&settings).unwrap_err();
+
+        settings.set("poll", -1i64).unwrap();
+        get_poll(&settings).unwrap_err();
+    }
+
+    #[test]
+    fn test_accepted_aead() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_accepted_aead(&settings).unwrap(), vec![15]);
+
+        settings.set("accepted_aead", vec![15i64]).unwrap();
+        assert_eq!(get_accepted_aead(&settings).unwrap(), vec![15]);
+
+        // AEAD_AES_128_GCM is not supported.
+        settings.set("accepted_aead", vec![15i64, 1i64]).unwrap();
+        get_accepted_aead(&settings).unwrap_err();
+
+        settings.set("accepted_aead", Vec::<i64>::new()).unwrap();
+        get_accepted_aead(&settings).unwrap_err();
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_max_response_size(&settings).unwrap(), 1280);
+
+        settings.set("max_response_size", 1472i64).unwrap();
+        assert_eq!(get_max_response_size(&settings).unwrap(), 1472);
+
+        settings.set("max_response_size", 100i64).unwrap();
+        get_max_response_size(&settings).unwrap_err();
+
+        settings.set("max_response_size", 70000i64).unwrap();
+        get_max_response_size(&settings).unwrap_err();
+    }
+
+    #[test]
+    fn test_max_extensions() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_max_extensions(&settings).unwrap(), DEFAULT_MAX_EXTENSIONS);
+
+        settings.set("max_extensions", 64i64).unwrap();
+        assert_eq!(get_max_extensions(&settings).unwrap(), 64);
+
+        settings.set("max_extensions", MIN_MAX_EXTENSIONS as i64).unwrap();
+        assert_eq!(get_max_extensions(&settings).unwrap(), MIN_MAX_EXTENSIONS);
+
+        settings.set("max_extensions", MIN_MAX_EXTENSIONS as i64 - 1).unwrap();
+        get_max_extensions(&settings).unwrap_err();
+    }
+
+    #[test]
+    fn test_interleaved_capacity() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_interleaved_capacity(&settings).unwrap(), interleaved::DEFAULT_CAPACITY);
+
+        settings.set("interleaved_capacity", 100i64).unwrap();
+        assert_eq!(get_interleaved_capacity(&settings).unwrap(), 100);
+
+        settings.set("interleaved_capacity", 0i64).unwrap();
+        get_interleaved_capacity(&settings).unwrap_err();
+    }
+
+    #[test]
+    fn test_upstream_poll() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_upstream_poll(&settings).unwrap(), Duration::from_secs(1));
+
+        settings.set("upstream_poll_secs", 16i64).unwrap();
+        assert_eq!(get_upstream_poll(&settings).unwrap(), Duration::from_secs(16));
+
+        settings.set("upstream_poll_secs", 0i64).unwrap();
+        get_upstream_poll(&settings).unwrap_err();
+    }
+
+    #[test]
+    fn test_cookie_rotation() {
+        let mut settings = config::Config::new();
+        let periods = get_cookie_rotation(&settings, DEFAULT_POLL).unwrap();
+        assert_eq!(periods, RotationPeriods::default());
+
+        settings.set("cookie_rotation_secs", 600i64).unwrap();
+        settings.set("cookie_forward_periods", 1i64).unwrap();
+        settings.set("cookie_backward_periods", 36i64).unwrap();
+        let periods = get_cookie_rotation(&settings, DEFAULT_POLL).unwrap();
+        assert_eq!(periods.duration, 600);
+        assert_eq!(periods.forward_periods, 1);
+        assert_eq!(periods.backward_periods, 36);
+
+        // Six hours are shorter than the poll interval of 2**17 seconds.
+        get_cookie_rotation(&settings, MAX_POLL).unwrap_err();
+        // Nor are the cookies valid for any time without backward periods.
+        settings.set("cookie_backward_periods", 0i64).unwrap();
+        get_cookie_rotation(&settings, MIN_POLL).unwrap_err();
+
+        settings.set("cookie_backward_periods", 24i64).unwrap();
+        settings.set("cookie_rotation_secs", 0i64).unwrap();
+        get_cookie_rotation(&settings, MIN_POLL).unwrap_err();
+        settings.set("cookie_rotation_secs", -3600i64).unwrap();
+        get_cookie_rotation(&settings, MIN_POLL).unwrap_err();
+    }
+
+    #[test]
+    fn test_networks() {
+        let mut settings = config::Config::new();
+        assert!(get_networks(&settings, "deny_networks").unwrap().is_empty());
+
+        settings.set("deny_networks", vec!["192.0.2.0/24", "2001:db8::/32"]).unwrap();
+        let networks = get_networks(&settings, "deny_networks").unwrap();
+        assert_eq!(networks.len(), 2);
+        assert!(networks[0].contains("192.0.2.1".parse().unwrap()));
+        assert!(networks[1].contains("2001:db8::1".parse().unwrap()));
+
+        settings.set("deny_networks", vec!["192.0.2.0/40"]).unwrap();
+        get_networks(&settings, "deny_networks").unwrap_err();
+    }
+
+    #[test]
+    fn test_extension_policy() {
+        let mut settings = config::Config::new();
+        let policy = get_extension_policy(&settings).unwrap();
+        assert_eq!(policy.action(0xff01), ExtensionAction::Ignore);
+
+        settings.set("unknown_extensions", "reject").unwrap();
+        settings.set("echo_extensions", vec![0xff01i64]).unwrap();
+        let policy = get_extension_policy(&settings).unwrap();
+        assert_eq!(policy.action(0xff01), ExtensionAction::Echo);
+        assert_eq!(policy.action(0xff02), ExtensionAction::Reject);
+
+        settings.set("ignore_extensions", vec![0xff01i64]).unwrap();
+        get_extension_policy(&settings).unwrap_err();
+
+        settings.set("ignore_extensions", vec![0x10000i64]).unwrap();
+        get_extension_policy(&settings).unwrap_err();
+
+        settings.set("ignore_extensions", Vec::<i64>::new()).unwrap();
+        settings.set("unknown_extensions", "drop").unwrap();
+        get_extension_policy(&settings).unwrap_err();
+    }
+
+    #[test]
+    fn test_plain_probe_refid() {
+        let mut settings = config::Config::new();
+        assert_eq!(get_ascii_refid(&settings, "plain_probe_refid").unwrap(), None);
+
+        settings.set("plain_probe_refid", "PLN").unwrap();
+        assert_eq!(get_ascii_refid(&settings, "plain_probe_refid").unwrap(), Some(0x504c4e00));
+
+        settings.set("plain_probe_refid", "PLAIN").unwrap();
+        get_ascii_refid(&settings, "plain_probe_refid").unwrap_err();
+
+        settings.set("plain_probe_refid", "").unwrap();
+        get_ascii_refid(&settings, "plain_probe_refid").unwrap_err();
+
+        settings.set("refclock_refid", "PPS").unwrap();
+        assert_eq!(get_ascii_refid(&settings, "refclock_refid").unwrap(), Some(0x50505300));
+    }
+}
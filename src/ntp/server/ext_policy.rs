@@ -0,0 +1,85 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! What the server does with the extensions of an NTS request it doesn't handle itself, e.g. a
+//! vendor or debug extension. The Unique Identifier, NTS Cookie, NTS Cookie Placeholder and NTS
+//! Authenticator extensions are always handled by the server.
+
+use std::io;
+use std::str::FromStr;
+
+/// What to do with an extension of a request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExtensionAction {
+    /// Send it back in the same part of the response, authenticated or encrypted, as it came in.
+    Echo,
+    /// Leave it out of the response.
+    Ignore,
+    /// Answer the request with a Kiss of Death with the RSTR code.
+    Reject,
+}
+
+impl Default for ExtensionAction {
+    fn default() -> ExtensionAction {
+        ExtensionAction::Ignore
+    }
+}
+
+impl FromStr for ExtensionAction {
+    type Err = io::Error;
+
+    fn from_str(name: &str) -> Result<ExtensionAction, io::Error> {
+        match name {
+            "echo" => Ok(ExtensionAction::Echo),
+            "ignore" => Ok(ExtensionAction::Ignore),
+            "reject" => Ok(ExtensionAction::Reject),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown extension action: {}", name),
+            )),
+        }
+    }
+}
+
+/// The action for each type of the extensions the server doesn't handle itself. By default,
+/// they are all ignored.
+#[derive(Clone, Debug, Default)]
+pub struct ExtensionPolicy {
+    /// The action for the types which are not in `actions`.
+    pub default: ExtensionAction,
+    /// The actions by extension type. The first one of a type wins.
+    pub actions: Vec<(u16, ExtensionAction)>,
+}
+
+impl ExtensionPolicy {
+    /// Return the action for the extension type.
+    pub fn action(&self, ext_type: u16) -> ExtensionAction {
+        self.actions
+            .iter()
+            .find(|(action_type, _)| *action_type == ext_type)
+            .map_or(self.default, |(_, action)| *action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action() {
+        let policy = ExtensionPolicy::default();
+        assert_eq!(policy.action(0xff01), ExtensionAction::Ignore);
+
+        let policy = ExtensionPolicy {
+            default: ExtensionAction::Reject,
+            actions: vec![(0xff01, ExtensionAction::Echo), (0xff01, ExtensionAction::Ignore)],
+        };
+        assert_eq!(policy.action(0xff01), ExtensionAction::Echo);
+        assert_eq!(policy.action(0xff02), ExtensionAction::Reject);
+
+        assert_eq!("echo".parse::<ExtensionAction>().unwrap(), ExtensionAction::Echo);
+        assert_eq!("reject".parse::<ExtensionAction>().unwrap(), ExtensionAction::Reject);
+        "drop".parse::<ExtensionAction>().unwrap_err();
+    }
+}
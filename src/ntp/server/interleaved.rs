@@ -0,0 +1,155 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Interleaved mode, see draft-ietf-ntp-interleaved-modes.
+//!
+//! In basic mode, the transmit timestamp of a response is taken before the response is sent. In
+//! interleaved mode, the server remembers when each response actually left, and reports it in
+//! the response to the next request of the same client. The client asks for it by setting the
+//! origin timestamp of its request to the receive timestamp of the previous response.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+use crate::ntp::protocol::NtpPacketHeader;
+
+/// The number of responses remembered by default. The oldest ones are forgotten first.
+pub const DEFAULT_CAPACITY: usize = 4096;
+
+/// The transmit timestamps of the latest responses.
+pub struct InterleavedState {
+    /// The client and the transmit timestamp of each response, by its receive timestamp.
+    responses: HashMap<u64, (IpAddr, u64)>,
+    /// The receive timestamps in the order they were saved.
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl InterleavedState {
+    /// Create a state remembering up to `capacity` responses.
+    pub fn new(capacity: usize) -> InterleavedState {
+        InterleavedState {
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Remember that the response to the request of `client` received at `receive_timestamp`
+    /// was sent at `transmit_timestamp`.
+    pub fn save(&mut self, client: IpAddr, receive_timestamp: u64, transmit_timestamp: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(response) = self.responses.get_mut(&receive_timestamp) {
+            // It's already in `order`, so it keeps its place there.
+            *response = (client, transmit_timestamp);
+            return;
+        }
+        while self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.responses.insert(receive_timestamp, (client, transmit_timestamp));
+        self.order.push_back(receive_timestamp);
+    }
+
+    /// Return the transmit timestamp of the previous response to `client`, if the request asks
+    /// for interleaved mode and we remember the response it refers to.
+    pub fn previous_transmit(&self, client: IpAddr, query: &NtpPacketHeader) -> Option<u64> {
+        // Basic mode clients set the origin timestamp to zero or to a random value, which
+        // doesn't match any receive timestamp of ours.
+        if query.origin_timestamp == 0 {
+            return None;
+        }
+        match self.responses.get(&query.origin_timestamp) {
+            Some(&(saved_client, transmit_timestamp)) if saved_client == client => {
+                Some(transmit_timestamp)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::ntp::protocol::{LeapState, PacketMode};
+
+    fn query(origin_timestamp: u64) -> NtpPacketHeader {
+        NtpPacketHeader {
+            leap_indicator: LeapState::NoLeap,
+            version: 4,
+            mode: PacketMode::Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: 0,
+            reference_timestamp: 0,
+            origin_timestamp,
+            receive_timestamp: 0,
+            transmit_timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn test_previous_transmit() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let mut state = InterleavedState::new(DEFAULT_CAPACITY);
+
+        // Nothing was sent yet.
+        assert_eq!(state.previous_transmit(client, &query(100)), None);
+
+        state.save(client, 100, 105);
+        assert_eq!(state.previous_transmit(client, &query(100)), Some(105));
+        // Only the client the response was sent to can ask for it.
+        assert_eq!(state.previous_transmit(other, &query(100)), None);
+        // A basic request doesn't match.
+        assert_eq!(state.previous_transmit(client, &query(0)), None);
+        assert_eq!(state.previous_transmit(client, &query(101)), None);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let mut state = InterleavedState::new(2);
+
+        state.save(client, 100, 105);
+        state.save(client, 200, 205);
+        state.save(client, 300, 305);
+
+        // The oldest response is forgotten.
+        assert_eq!(state.previous_transmit(client, &query(100)), None);
+        assert_eq!(state.previous_transmit(client, &query(200)), Some(205));
+        assert_eq!(state.previous_transmit(client, &query(300)), Some(305));
+
+        let mut state = InterleavedState::new(0);
+        state.save(client, 100, 105);
+        assert_eq!(state.previous_transmit(client, &query(100)), None);
+    }
+
+    #[test]
+    fn test_save_twice() {
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut state = InterleavedState::new(2);
+
+        // Two requests received at the same timestamp take a single place.
+        state.save(client, 100, 105);
+        state.save(client, 100, 106);
+        state.save(client, 200, 205);
+        assert_eq!(state.order.len(), 2);
+        assert_eq!(state.previous_transmit(client, &query(100)), Some(106));
+        assert_eq!(state.previous_transmit(client, &query(200)), Some(205));
+
+        // So the next one only evicts the oldest.
+        state.save(client, 300, 305);
+        assert_eq!(state.previous_transmit(client, &query(100)), None);
+        assert_eq!(state.previous_transmit(client, &query(200)), Some(205));
+    }
+}
@@ -4,8 +4,15 @@
 
 //! NTP server implementation.
 
+mod acl;
 mod config;
+mod ext_policy;
+mod interleaved;
+mod refclock;
 mod server;
 
-pub use self::server::start_ntp_server;
+pub use self::server::{start_ntp_server, start_ntp_server_with_socket};
+#[cfg(any(test, feature = "bench"))]
+#[doc(hidden)]
+pub use self::server::bench_response;
 pub use self::config::NtpServerConfig;
@@ -0,0 +1,71 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A local reference clock, e.g. a GPS receiver with PPS, for a stratum 1 server. Like with the
+//! ntpd SHM driver, an external process disciplines the system clock and tells us when it last
+//! did, here by rewriting a file.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Read the time of the last update of the reference clock from the file. It holds the Unix time
+/// in seconds with an optional fraction, e.g. `1500000000.25`.
+pub fn read_refclock<P: AsRef<Path>>(path: P) -> Result<SystemTime, Error> {
+    parse_refclock_time(&fs::read_to_string(path)?)
+}
+
+fn parse_refclock_time(text: &str) -> Result<SystemTime, Error> {
+    let text = text.trim();
+    let invalid = || {
+        Error::new(ErrorKind::InvalidData, format!("invalid reference clock time: {:?}", text))
+    };
+    let mut parts = text.splitn(2, '.');
+    let secs: u64 = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+    // The fraction is parsed as digits rather than as a float, so that no precision is lost.
+    let nanos = match parts.next() {
+        None => 0,
+        Some(fraction) if !fraction.is_empty() && fraction.bytes().all(|b| b.is_ascii_digit()) => {
+            let mut digits = fraction.bytes().take(9).map(|b| u32::from(b - b'0'));
+            (0..9).fold(0, |nanos, _| nanos * 10 + digits.next().unwrap_or(0))
+        }
+        Some(_) => return Err(invalid()),
+    };
+    Ok(SystemTime::UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn test_parse_refclock_time() {
+        let epoch = SystemTime::UNIX_EPOCH;
+        assert_eq!(parse_refclock_time("1500000000").unwrap(),
+                   epoch + Duration::from_secs(1_500_000_000));
+        assert_eq!(parse_refclock_time("1500000000.25\n").unwrap(),
+                   epoch + Duration::new(1_500_000_000, 250_000_000));
+        // Digits beyond nanoseconds are dropped.
+        assert_eq!(parse_refclock_time("1.0000000019").unwrap(), epoch + Duration::new(1, 1));
+
+        for text in &["", "1500000000.", ".5", "-1", "1.5e3", "1.-5", "now"] {
+            let error = parse_refclock_time(text).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_read_refclock() {
+        let path = env::temp_dir().join("cfnts-test-refclock");
+        fs::write(&path, "1500000000.5\n").unwrap();
+        let updated = read_refclock(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(updated, SystemTime::UNIX_EPOCH + Duration::new(1_500_000_000, 500_000_000));
+
+        assert_eq!(read_refclock(&path).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+}
@@ -1,20 +1,29 @@
 use crate::cfsock;
 use super::config::NtpServerConfig;
-use crate::cookie::{eat_cookie, get_keyid, make_cookie, NTSKeys, COOKIE_SIZE};
+use crate::cookie::{
+    eat_cookie_with_context, get_keyid, make_cookie_with_context, subnet_context, NTSKeys,
+    COOKIE_SIZE, LEGACY_COOKIE_SIZE,
+};
+use crate::error::StartupError;
 use crate::metrics;
-use crate::key_rotator::{periodic_rotate, KeyRotator};
+use crate::key_rotator::{periodic_rotate, rotate_on_sigusr1, KeyIdError, KeyRotator};
+use crate::nts_ke::records::KnownAeadAlgorithm;
 
 use lazy_static::lazy_static;
-use prometheus::{opts, register_counter, register_int_counter, IntCounter};
-use slog::{error, info};
+use prometheus::{
+    exponential_buckets, opts, register_counter, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec,
+};
+use slog::{debug, error, info, warn};
 
+use std::cmp;
 use std::io::{Error, ErrorKind};
 use std::net::{
-    SocketAddr,
+    IpAddr, SocketAddr,
     ToSocketAddrs, UdpSocket,
 };
 use std::os::unix::io::AsRawFd;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time;
 use std::time::{Duration, SystemTime};
@@ -22,30 +31,55 @@ use std::vec;
 
 use crossbeam::sync::WaitGroup;
 use libc::{in6_pktinfo, in_pktinfo};
-/// Miscreant calls Aes128SivAead what IANA calls AEAD_AES_SIV_CMAC_256
-use miscreant::aead::Aead;
-use miscreant::aead::Aes128SivAead;
 use nix::sys::socket::{
-    recvmsg, sendmsg, setsockopt, sockopt, CmsgSpace, ControlMessage, MsgFlags,
+    recvmsg, sendmsg, setsockopt, sockopt, CmsgSpace, ControlMessage, MsgFlags, SockAddr,
 };
-use nix::sys::time::{TimeVal, TimeValLike};
+use nix::sys::time::TimeVal;
 use nix::sys::uio::IoVec;
 
+use super::acl::Acl;
+use super::ext_policy::{ExtensionAction, ExtensionPolicy};
+use super::refclock::read_refclock;
+use super::interleaved::InterleavedState;
+use crate::ntp::aead::new_aeads;
 use crate::ntp::protocol;
 use crate::ntp::protocol::{
-    extract_extension, has_extension, is_nts_packet, parse_ntp_packet, parse_nts_packet,
-    serialize_header, serialize_ntp_packet, serialize_nts_packet, LeapState, LeapState::*,
-    NtpExtension, NtpExtensionType::NTSCookie, NtpExtensionType::UniqueIdentifier, NtpPacket,
-    NtpPacketHeader, NtsPacket, PacketMode, PHI, UNIX_OFFSET,
+    extract_extension, has_extension, is_nts_packet, nts_packet_len, parse_ntp_packet,
+    parse_ntp_packet_strict_with_max_extensions, parse_ntp_packet_with_max_extensions,
+    parse_nts_packet_with_max_extensions, parse_packet_header, serialize_header,
+    serialize_ntp_packet, serialize_nts_packet, LeapState, LeapState::*, NtpExtension,
+    NtpExtensionType::NTSAuthenticator, NtpExtensionType::NTSCookie,
+    NtpExtensionType::NTSCookiePlaceholder, NtpExtensionType::UniqueIdentifier, NtpPacket,
+    NtpPacketHeader, NtsPacket, PacketMode, ntp_short_from_secs, ntp_short_to_secs, ntp_timestamp,
+    MAX_COOKIE_PLACEHOLDERS, PHI,
 };
 
 const BUF_SIZE: usize = 1280; // Anything larger might fragment.
-const TWO_POW_32: f64 = 4294967296.0;
-const TWO_POW_16: f64 = 65536.0;
+/// The maximum number of extensions in an NTS request we are willing to process.
+const MAX_NTS_EXTENSIONS: usize = 16;
+const TWO_POW_32: f64 = 4_294_967_296.0;
+/// The precision of our clock, as an exponent of two seconds.
+const PRECISION: i8 = -18;
+/// The highest stratum of a synchronized server.
+const MAX_STRATUM: u8 = 15;
+/// The stratum advertised while we are not synchronized.
+const UNSYNCHRONIZED_STRATUM: u8 = 16;
+/// The kiss code telling the client to run the key exchange again, which only helps if its
+/// cookie or the authentication of its request failed.
+const KISS_NTSN: u32 = 0x4e54534e;
+/// The kiss code telling the client we don't serve its requests by our policy (RFC 5905 Section
+/// 7.4).
+const KISS_RSTR: u32 = 0x52535452;
 
 lazy_static! {
     static ref QUERY_COUNTER: IntCounter =
         register_int_counter!("ntp_queries_total", "Number of NTP queries").unwrap();
+    static ref VERSION_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "ntp_query_versions_total",
+        "Number of NTP queries by the version of the client",
+        &["version"]
+    )
+    .unwrap();
     static ref NTS_COUNTER: IntCounter = register_int_counter!(
         "ntp_nts_queries_total",
         "Number of queries we thought were NTS"
@@ -63,6 +97,26 @@ lazy_static! {
         "Number of packets without valid ntp headers"
     )
     .unwrap();
+    static ref TRUNCATED_PACKET_COUNTER: IntCounter = register_int_counter!(
+        "ntp_truncated_packet_total",
+        "Number of packets dropped because they were larger than the receive buffer"
+    )
+    .unwrap();
+    static ref DENIED_PACKET_COUNTER: IntCounter = register_int_counter!(
+        "ntp_denied_packet_total",
+        "Number of packets dropped because their source is not allowed or in a denied network"
+    )
+    .unwrap();
+    static ref EXPIRED_KEY_COUNTER: IntCounter = register_int_counter!(
+        "ntp_expired_key_total",
+        "Number of cookies with keys older than the rotation window"
+    )
+    .unwrap();
+    static ref FUTURE_KEY_COUNTER: IntCounter = register_int_counter!(
+        "ntp_future_key_total",
+        "Number of cookies with keys newer than the rotation window"
+    )
+    .unwrap();
     static ref MISSING_KEY_COUNTER: IntCounter =
         register_int_counter!("ntp_missing_key_total", "Number of keys we could not find").unwrap();
     static ref UNDECRYPTABLE_COOKIE_COUNTER: IntCounter = register_int_counter!(
@@ -70,6 +124,21 @@ lazy_static! {
         "Number of cookies we could not decrypt"
     )
     .unwrap();
+    static ref REJECTED_AEAD_COUNTER: IntCounter = register_int_counter!(
+        "ntp_rejected_aead_total",
+        "Number of cookies for AEAD algorithms we don't accept"
+    )
+    .unwrap();
+    static ref REJECTED_EXTENSION_COUNTER: IntCounter = register_int_counter!(
+        "ntp_rejected_extension_total",
+        "Number of NTS requests rejected for an extension the policy rejects"
+    )
+    .unwrap();
+    static ref TRIMMED_COOKIE_COUNTER: IntCounter = register_int_counter!(
+        "ntp_trimmed_cookies_total",
+        "Number of cookies left out of responses to fit the maximum response size"
+    )
+    .unwrap();
     static ref UPSTREAM_QUERY_COUNTER: IntCounter = register_int_counter!(
         "ntp_upstream_queries_total",
         "Number of upstream queries sent"
@@ -80,13 +149,85 @@ lazy_static! {
         "Number of failed upstream queries"
     )
     .unwrap();
+    static ref REFCLOCK_FAILURE_COUNTER: IntCounter = register_int_counter!(
+        "ntp_refclock_failures_total",
+        "Number of failed reads of the reference clock"
+    )
+    .unwrap();
+    // The buckets go from a microsecond to about 30 milliseconds, as the default ones start at
+    // 5 milliseconds, far above what an AEAD operation on a packet takes.
+    static ref AEAD_SECONDS: HistogramVec = register_histogram_vec!(
+        HistogramOpts::new(
+            "ntp_nts_aead_seconds",
+            "Time to decrypt NTS requests and their cookies, and to make the new cookies and \
+             encrypt the responses"
+        )
+        .buckets(exponential_buckets(1e-6, 2.0, 16).unwrap()),
+        &["operation"]
+    )
+    .unwrap();
+}
+
+/// The kind of requests served on a listening socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ListenerKind {
+    /// Serve both NTS and plain NTP requests.
+    Mixed,
+    /// Serve only plain NTP requests. Requests carrying NTS extensions will get a Kiss of Death.
+    PlainOnly,
+    /// Serve only NTS requests. Plain NTP requests will get a Kiss of Death.
+    NtsOnly,
+}
+
+/// The options of a listening socket, which apply to every request it receives.
+#[derive(Clone)]
+struct ListenerConfig {
+    kind: ListenerKind,
+    /// The ids of the AEAD algorithms we honor in cookies.
+    accepted_aead: Vec<u16>,
+    /// The networks whose requests are dropped.
+    acl: Acl,
+    /// What to do with the extensions of NTS requests we don't handle ourselves.
+    ext_policy: ExtensionPolicy,
+    /// The transmit timestamps of the previous responses, if interleaved mode is served. It's
+    /// shared by the listeners, as a client may send its requests to any of them.
+    interleaved: Option<Arc<Mutex<InterleavedState>>>,
+}
+
+/// What we know about a request besides its bytes.
+#[derive(Clone, Copy, Debug)]
+struct RequestContext {
+    /// When the request was received.
+    received: SystemTime,
+    /// The address of the client, if the socket gave us one.
+    client_ip: Option<IpAddr>,
+    /// The time the previous response to the client was sent, if the request asks for
+    /// interleaved mode and we have it.
+    previous_transmit: Option<u64>,
+}
+
+/// The options an NTS response is made with.
+struct NtsOptions<'a> {
+    /// The largest UDP payload we send. Cookies are left out of the response to fit it.
+    max_response_size: usize,
+    /// The most extensions we parse in the request.
+    max_extensions: usize,
+    /// The transmit timestamp of the response, in interleaved mode.
+    previous_transmit: Option<u64>,
+    /// The client context the cookies are bound to. It's empty if they are not bound.
+    cookie_context: &'a [u8],
+    ext_policy: &'a ExtensionPolicy,
 }
 
 #[derive(Clone, Copy, Debug)]
 struct ServerState {
     leap: LeapState,
     stratum: u8,
+    /// The highest version we speak. Responses are in the version of the client, up to this one.
     version: u8,
+    /// The lowest version of the clients we answer. Older clients get a Kiss of Death.
+    min_version: u8,
+    /// The poll exponent, i.e. the poll interval is 2**poll seconds.
     poll: i8,
     precision: i8,
     root_delay: u32,
@@ -94,20 +235,37 @@ struct ServerState {
     refid: u32,
     refstamp: u64,
     taken: SystemTime,
+    /// The reference id of plain responses on mixed listeners, if they have to be told apart
+    /// from NTS responses.
+    plain_probe_refid: Option<u32>,
+    /// The largest UDP payload we send. NTS responses are trimmed to fit it.
+    max_response_size: usize,
+    /// The most extensions we parse in a request.
+    max_extensions: usize,
+    /// Whether the cookies are bound to the subnet of the client.
+    bind_cookies_to_subnet: bool,
+    /// If it's true, requests with a zero transmit timestamp or of a version newer than ours get
+    /// a Kiss of Death.
+    strict: bool,
+}
+
+/// Convert the time carried in an SCM_TIMESTAMP control message.
+fn timeval_to_system_time(time: TimeVal) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::new(time.tv_sec() as u64, time.tv_usec() as u32 * 1000)
 }
 
 /// run_server runs the ntp server on the given socket.
-/// The caller has to set up the socket options correctly
+/// The caller has to set up the socket options correctly. If SO_TIMESTAMP is set, the receive
+/// timestamps are taken by the kernel, otherwise they are taken after the packet is received.
 fn run_server(
     socket: UdpSocket,
     keys: Arc<RwLock<KeyRotator>>,
     servstate: Arc<RwLock<ServerState>>,
     logger: slog::Logger,
     ipv4: bool,
+    listener: ListenerConfig,
 ) -> Result<(), std::io::Error> {
     let sockfd = socket.as_raw_fd();
-    setsockopt(sockfd, sockopt::ReceiveTimestamp, &true)
-        .expect("setsockopt failed; can't run ntp server");
     if ipv4 {
         setsockopt(sockfd, sockopt::Ipv4PacketInfo, &true)
             .expect("setsockopt failed; can't run ntp server");
@@ -118,9 +276,11 @@ fn run_server(
     // The following is adapted from the example in the nix crate docs:
     // https://docs.rs/nix/0.13.0/nix/sys/socket/enum.ControlMessage.html#variant.ScmTimestamp
     // Most of these functions are documented in manpages, and nix is a thin wrapper around them.
+    // The buffer is reused for every request. This is safe because recvmsg overwrites the
+    // received bytes and we only ever look at those.
+    let mut buf = [0; BUF_SIZE];
     loop {
         // Receive and respond to packets
-        let mut buf = [0; BUF_SIZE];
         let flags = MsgFlags::empty();
         let mut cmsgspace: CmsgSpace<(TimeVal, CmsgSpace<(in_pktinfo, CmsgSpace<in6_pktinfo>)>)> =
             CmsgSpace::new();
@@ -136,14 +296,35 @@ fn run_server(
             continue;
         }
         let src = r.address.unwrap();
+        let client_ip = match src {
+            SockAddr::Inet(ref addr) => Some(addr.to_std().ip()),
+            _ => None,
+        };
+        // Clients we don't serve get no answer at all, not even a Kiss of Death.
+        if let Some(ip) = client_ip {
+            if !listener.acl.permits(ip) {
+                DENIED_PACKET_COUNTER.inc();
+                debug!(logger, "dropping a packet from denied address {}", ip);
+                continue;
+            }
+        }
+        // The kernel silently cuts a datagram larger than the buffer. Don't parse what is left of
+        // it, it's not the packet the client sent.
+        if r.flags.contains(MsgFlags::MSG_TRUNC) {
+            TRUNCATED_PACKET_COUNTER.inc();
+            warn!(logger, "dropping a packet larger than {} bytes from {}", BUF_SIZE, src);
+            continue;
+        }
         // We should only have a single cmsg of known type.
         // The nix crate implements a typesafe interface to cmsg,
         // hence some of the matching here.
-        let mut r_time = TimeVal::nanoseconds(0);
+        let mut r_time = None;
         let mut msgs: Vec<ControlMessage> = Vec::new();
         for msg in r.cmsgs() {
             match msg {
-                ControlMessage::ScmTimestamp(&r_timestamp) => r_time = r_timestamp,
+                ControlMessage::ScmTimestamp(&r_timestamp) => {
+                    r_time = Some(timeval_to_system_time(r_timestamp))
+                }
                 ControlMessage::Ipv4PacketInfo(_inf) => {
                     if ipv4 {
                         msgs.push(msg);
@@ -167,17 +348,26 @@ fn run_server(
             }
         }
 
-        let r_system = SystemTime::UNIX_EPOCH
-            + Duration::new(r_time.tv_sec() as u64, r_time.tv_usec() as u32 * 1000);
-        let t_system = SystemTime::now();
-        // We now have the receive times and the current time as SystemTimes
+        // Fall back to the current time, if the kernel didn't give us the receive time.
+        let r_system = r_time.unwrap_or_else(SystemTime::now);
+        let previous_transmit = match (&listener.interleaved, client_ip) {
+            (Some(state), Some(ip)) => parse_packet_header(&buf[..r.bytes])
+                .ok()
+                .and_then(|header| state.lock().unwrap().previous_transmit(ip, &header)),
+            _ => None,
+        };
+        let request = RequestContext {
+            received: r_system,
+            client_ip,
+            previous_transmit,
+        };
         let resp = response(
             &buf[..r.bytes],
-            r_system,
-            t_system,
+            &request,
             keys.clone(),
             servstate.clone(),
             logger.clone(),
+            &listener,
         );
         match resp {
             Ok(data) => {
@@ -188,8 +378,10 @@ fn run_server(
                     flags,
                     Some(&src),
                 );
-                if let Err(err) = resp {
-                    error!(logger, "error sending response: {:}", err);
+                match resp {
+                    Err(err) => error!(logger, "error sending response: {:}", err),
+                    // The response is gone, so now we know when it was actually sent.
+                    Ok(_) => save_transmit(&listener.interleaved, client_ip, r_system),
                 }
             }
             Err(_) => {
@@ -200,50 +392,136 @@ fn run_server(
     }
 }
 
+/// Remember when the response to the request received at `received` was sent, if interleaved
+/// mode is enabled.
+fn save_transmit(
+    interleaved: &Option<Arc<Mutex<InterleavedState>>>,
+    client_ip: Option<IpAddr>,
+    received: SystemTime,
+) {
+    if let (Some(state), Some(ip)) = (interleaved, client_ip) {
+        let transmit_timestamp = ntp_timestamp(SystemTime::now());
+        state.lock().unwrap().save(ip, ntp_timestamp(received), transmit_timestamp);
+    }
+}
+
 /// start_ntp_server runs the ntp server with the config specified in config_filename
 pub fn start_ntp_server(
     config: NtpServerConfig,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), StartupError> {
+    // Addresses in the `addr` list serve both kinds of requests. The dedicated addresses serve
+    // only their own kind, except that the NTS address also serves plain NTP when NTS is not
+    // required.
+    let mut listeners: Vec<(SocketAddr, ListenerKind)> = config.addrs()
+        .iter()
+        .map(|addr| (*addr, ListenerKind::Mixed))
+        .collect();
+    if let Some(addr) = config.plain_ntp_addr {
+        listeners.push((addr, ListenerKind::PlainOnly));
+    }
+    if let Some(addr) = config.nts_ntp_addr {
+        listeners.push((addr, nts_listener_kind(&config)));
+    }
+
+    let mut sockets = Vec::new();
+    for (addr, kind) in listeners {
+        let addr = addr.to_socket_addrs().unwrap().next().unwrap();
+        let socket = cfsock::udp_listen(&addr, config.reuse_port).map_err(StartupError::Bind)?;
+        sockets.push((socket, kind));
+    }
+    start_ntp_server_on_sockets(config, sockets)
+}
+
+/// Run the ntp server like `start_ntp_server`, but serve NTS requests on the given socket, which
+/// is already bound, instead of binding the addresses of the config. Like the NTS address, the
+/// socket serves plain NTP requests too, unless NTS is required. It's meant for socket
+/// activation, e.g. by systemd, and privilege separation.
+pub fn start_ntp_server_with_socket(
+    config: NtpServerConfig,
+    socket: UdpSocket,
+) -> Result<(), StartupError> {
+    let kind = nts_listener_kind(&config);
+    start_ntp_server_on_sockets(config, vec![(socket, kind)])
+}
+
+/// Return the kind of a listener for NTS requests.
+fn nts_listener_kind(config: &NtpServerConfig) -> ListenerKind {
+    if config.require_nts {
+        ListenerKind::NtsOnly
+    } else {
+        ListenerKind::Mixed
+    }
+}
+
+fn start_ntp_server_on_sockets(
+    config: NtpServerConfig,
+    sockets: Vec<(UdpSocket, ListenerKind)>,
+) -> Result<(), StartupError> {
     let logger = config.logger().clone();
 
     info!(logger, "Initializing keys with memcached");
 
-    let key_rotator = KeyRotator::connect(
+    let mut key_rotator = KeyRotator::connect(
         String::from("/nts/nts-keys"), // prefix
         config.memcached_url.clone(), // memcached_url
         config.cookie_key.clone(), // master_key
+        config.cookie_rotation, // periods
         logger.clone(), // logger
-    ).expect("error connecting to the memcached server");
+    )?;
+    key_rotator.set_master_key_source(config.cookie_key_source.clone());
 
     let keys = Arc::new(RwLock::new(key_rotator));
     periodic_rotate(keys.clone());
+    rotate_on_sigusr1().map_err(|error| StartupError::Other(Box::new(error)))?;
 
     let servstate_struct = ServerState {
         leap: Unknown,
         stratum: 16,
         version: protocol::VERSION,
-        poll: 7,
-        precision: -18,
+        min_version: protocol::MIN_VERSION,
+        poll: config.poll,
+        precision: PRECISION,
         root_delay: 10,
         root_dispersion: 10,
         refid: 0,
         refstamp: 0,
         taken: SystemTime::now(),
+        plain_probe_refid: config.plain_probe_refid,
+        max_response_size: config.max_response_size,
+        max_extensions: config.max_extensions,
+        bind_cookies_to_subnet: config.bind_cookies_to_subnet,
+        strict: config.strict,
     };
 
     let servstate = Arc::new(RwLock::new(servstate_struct));
-    match config.upstream_addr.clone() {
-        Some(upstream_addr) => {
+    match (config.upstream_addr.clone(), config.refclock_file.clone()) {
+        (Some(upstream_addr), _) => {
             info!(logger, "connecting to upstream");
             let servstate = servstate.clone();
             let rot_logger = logger.new(slog::o!("task"=>"refereshing servstate"));
-            let socket = UdpSocket::bind("127.0.0.1:0")?; // we only go to local
-            socket.set_read_timeout(Some(time::Duration::from_secs(1)))?;
+            let socket = UdpSocket::bind("127.0.0.1:0") // we only go to local
+                .and_then(|socket| {
+                    socket.set_read_timeout(Some(time::Duration::from_secs(1)))?;
+                    Ok(socket)
+                })
+                .map_err(|error| StartupError::Other(Box::new(error)))?;
+            let poll = config.upstream_poll;
             thread::spawn(move || {
-                refresh_servstate(servstate, rot_logger, socket, &upstream_addr);
+                refresh_servstate(servstate, rot_logger, socket, &upstream_addr, poll);
             });
         }
-        None => {
+        (None, Some(refclock_file)) => {
+            // We aren't synchronized until the reference clock is read.
+            info!(logger, "reading the reference clock from {}", refclock_file);
+            let servstate = servstate.clone();
+            let refclock_logger = logger.new(slog::o!("task"=>"reading the reference clock"));
+            let refid = config.refclock_refid;
+            let poll = config.upstream_poll;
+            thread::spawn(move || {
+                refresh_refclock(servstate, refclock_logger, &refclock_file, refid, poll);
+            });
+        }
+        (None, None) => {
             let mut state_guard = servstate.write().unwrap();
             info!(logger, "setting stratum to 1");
             (*state_guard).leap = NoLeap;
@@ -254,27 +532,70 @@ pub fn start_ntp_server(
     if let Some(metrics_config) = config.metrics_config.clone() {
         info!(logger, "spawning metrics");
         let log_metrics = logger.new(slog::o!("component"=>"metrics"));
+        // The server is healthy as long as the cookie keys keep rotating.
+        let health: metrics::HealthCheck = {
+            let keys = keys.clone();
+            Arc::new(move || !keys.read().unwrap().is_stale())
+        };
         thread::spawn(move || {
-            metrics::run_metrics(metrics_config, &log_metrics)
+            metrics::run_metrics(metrics_config, health, &log_metrics)
                 .expect("metrics could not be run; starting ntp server failed");
         });
     }
 
+    serve(&config, sockets, keys, servstate)
+}
+
+/// Serve the requests on each socket in its own thread. It returns once all the threads stop.
+fn serve(
+    config: &NtpServerConfig,
+    sockets: Vec<(UdpSocket, ListenerKind)>,
+    keys: Arc<RwLock<KeyRotator>>,
+    servstate: Arc<RwLock<ServerState>>,
+) -> Result<(), StartupError> {
+    let logger = config.logger().clone();
+    // The sockets are already bound, so setting them up fails for other reasons.
+    let other = |error: std::io::Error| StartupError::Other(Box::new(error));
+
+    // The state is shared by the listeners, as a client may send its requests to any of them.
+    let interleaved = if config.interleaved {
+        info!(logger, "serving interleaved mode");
+        Some(Arc::new(Mutex::new(InterleavedState::new(config.interleaved_capacity))))
+    } else {
+        None
+    };
+
     let wg = WaitGroup::new();
-    for addr in config.addrs() {
-        let addr = addr.to_socket_addrs().unwrap().next().unwrap();
-        let socket = cfsock::udp_listen(&addr)?;
+    for (socket, kind) in sockets {
+        let addr = socket.local_addr().map_err(other)?;
+        // Responses are bounded by the maximum response size, so a response too large for the
+        // path should fail loudly rather than be fragmented.
+        cfsock::set_dont_fragment(&socket).map_err(other)?;
+        if config.kernel_timestamps {
+            setsockopt(socket.as_raw_fd(), sockopt::ReceiveTimestamp, &true)
+                .map_err(|error| StartupError::Other(Box::new(error)))?;
+        }
         let wg = wg.clone();
         let logger = logger.new(slog::o!("listen_addr"=>addr));
         let keys = keys.clone();
         let servstate = servstate.clone();
-        info!(logger, "Listening on: {}", socket.local_addr()?);
+        let listener = ListenerConfig {
+            kind,
+            accepted_aead: config.accepted_aead.clone(),
+            acl: Acl {
+                allow: config.allow_networks.clone(),
+                deny: config.deny_networks.clone(),
+            },
+            ext_policy: config.extension_policy.clone(),
+            interleaved: interleaved.clone(),
+        };
+        info!(logger, "Listening on: {}", addr);
         let mut use_ipv4 = true;
         if let SocketAddr::V6(_) = addr {
             use_ipv4 = false;
         }
         thread::spawn(move || {
-            run_server(socket, keys, servstate, logger, use_ipv4)
+            run_server(socket, keys, servstate, logger, use_ipv4, listener)
                 .expect("server could not be run");
             drop(wg);
         });
@@ -283,44 +604,55 @@ pub fn start_ntp_server(
     Ok(())
 }
 
-/// Compute the current dispersion to within 1 ULP.
+/// Compute the current dispersion to within 1 ULP: the dispersion when the upstream was last
+/// measured, plus the drift the local clock may have accumulated since then.
 fn fix_dispersion(disp: u32, now: SystemTime, taken: SystemTime) -> u32 {
-    let disp_frac = (disp & 0x0000ffff) as f64;
-    let disp_secs = ((disp & 0xffff0000) >> 16) as f64;
-    let dispf = disp_secs + disp_frac / TWO_POW_16;
-    let diff = now.duration_since(taken);
-    match diff {
-        Ok(secs) => {
-            let curdispf = dispf + (secs.as_secs() as f64) * PHI;
-            let curdisp_secs = curdispf.floor() as u32;
-            let curdisp_frac = (curdispf * 65336.0).floor() as u32;
-            let curdisp = (curdisp_secs << 16) + curdisp_frac;
-            curdisp
-        }
+    match now.duration_since(taken) {
+        Ok(elapsed) => ntp_short_from_secs(ntp_short_to_secs(disp) + secs(elapsed) * PHI),
         Err(_) => disp,
     }
 }
 
-fn ntp_timestamp(time: SystemTime) -> u64 {
-    let unix_time = time.duration_since(SystemTime::UNIX_EPOCH).unwrap(); // Safe absent time machines
-    let unix_offset = Duration::new(UNIX_OFFSET, 0);
-    let epoch_time = unix_offset + unix_time;
-    let ts_secs = epoch_time.as_secs();
-    let ts_nanos = epoch_time.subsec_nanos() as f64;
-    let ts_frac = ((ts_nanos * TWO_POW_32) / 1.0e9).round() as u32;
-    // RFC 5905  Figure 3
-    (ts_secs << 32) + ts_frac as u64
+/// Return a duration in seconds.
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// Return the difference between two raw NTP timestamps in seconds.
+fn timestamp_diff(later: u64, earlier: u64) -> f64 {
+    later.wrapping_sub(earlier) as i64 as f64 / TWO_POW_32
+}
+
+/// Compute the round trip delay to the upstream from its response, i.e. the time the request and
+/// the response spent on the network, without the time the upstream took to answer. See RFC 5905
+/// Section 8.
+fn upstream_delay(header: &NtpPacketHeader, round_trip: Duration) -> f64 {
+    let processing = timestamp_diff(header.transmit_timestamp, header.receive_timestamp);
+    (secs(round_trip) - processing).max(0.0)
 }
 
+/// Compute our root delay from the one of the upstream and the delay to it.
+fn root_delay(header: &NtpPacketHeader, delay: f64) -> u32 {
+    ntp_short_from_secs(ntp_short_to_secs(header.root_delay) + delay)
+}
+
+/// Compute our root dispersion from the one of the upstream and the delay to it: the upstream's
+/// root dispersion, the precision of both clocks and the drift during the round trip. See RFC
+/// 5905 Section 8. The drift since the measurement is added by `fix_dispersion`.
+fn root_dispersion(header: &NtpPacketHeader, delay: f64) -> u32 {
+    let precision = 2f64.powi(i32::from(header.precision)) + 2f64.powi(i32::from(PRECISION));
+    ntp_short_from_secs(ntp_short_to_secs(header.root_dispersion) + precision + PHI * delay)
+}
+
+/// Create the header of a response. The transmit timestamp is left equal to the receive
+/// timestamp, use `stamp_transmit` to set it right before the response is serialized.
 fn create_header(
     query_packet: &NtpPacket,
     received: SystemTime,
-    transmit: SystemTime,
     servstate: Arc<RwLock<ServerState>>,
 ) -> NtpPacketHeader {
     let servstate = servstate.read().unwrap();
     let receive_timestamp = ntp_timestamp(received);
-    let transmit_timestamp = ntp_timestamp(transmit);
     NtpPacketHeader {
         leap_indicator: servstate.leap,
         version: servstate.version,
@@ -329,49 +661,161 @@ fn create_header(
         precision: servstate.precision,
         stratum: servstate.stratum,
         root_delay: servstate.root_delay,
-        root_dispersion: fix_dispersion(servstate.root_dispersion, transmit, servstate.taken),
+        root_dispersion: fix_dispersion(servstate.root_dispersion, received, servstate.taken),
         reference_id: servstate.refid,
         reference_timestamp: servstate.refstamp,
         origin_timestamp: query_packet.header.transmit_timestamp,
         receive_timestamp: receive_timestamp,
-        transmit_timestamp: transmit_timestamp,
+        transmit_timestamp: receive_timestamp,
     }
 }
 
+/// Set the transmit timestamp to the current time, so that the client sees how long the server
+/// took to process the request. In interleaved mode, it's set to the time the previous response
+/// was sent instead. For NTS responses, it has to be done before the encryption, because the
+/// header is authenticated.
+fn stamp_transmit(header: &mut NtpPacketHeader, previous_transmit: Option<u64>) {
+    header.transmit_timestamp =
+        previous_transmit.unwrap_or_else(|| ntp_timestamp(SystemTime::now()));
+}
+
 fn response(
     query: &[u8],
-    r_time: SystemTime,
-    t_time: SystemTime,
+    request: &RequestContext,
     cookie_keys: Arc<RwLock<KeyRotator>>,
     servstate: Arc<RwLock<ServerState>>,
     logger: slog::Logger,
+    listener: &ListenerConfig,
 ) -> Result<Vec<u8>, std::io::Error> {
-    let query_packet = parse_ntp_packet(query)?; // Should try to send a KOD if this happens
-    let resp_header = create_header(&query_packet, r_time, t_time, servstate);
+    let (
+        plain_probe_refid,
+        max_response_size,
+        max_extensions,
+        min_version,
+        bind_cookies_to_subnet,
+        strict,
+    ) = {
+        let state = servstate.read().unwrap();
+        (
+            state.plain_probe_refid,
+            state.max_response_size,
+            state.max_extensions,
+            state.min_version,
+            state.bind_cookies_to_subnet,
+            state.strict,
+        )
+    };
+    // Packets of a version or mode no client sends are dropped right away.
+    let query_packet = parse_ntp_packet_strict_with_max_extensions(query, max_extensions)?;
+    // The NTS-KE server made the cookies with the same context.
+    let cookie_context = match request.client_ip {
+        Some(ip) if bind_cookies_to_subnet => subnet_context(ip),
+        _ => Vec::new(),
+    };
+    let mut resp_header = create_header(&query_packet, request.received, servstate);
+    if request.previous_transmit.is_some() {
+        // An interleaved response echoes the receive timestamp of the request, so that the
+        // client can tell it from a basic one.
+        resp_header.origin_timestamp = query_packet.header.receive_timestamp;
+    }
 
     QUERY_COUNTER.inc();
 
     if query_packet.header.mode != PacketMode::Client {
         return Err(Error::new(ErrorKind::InvalidData, "not client mode"));
     }
+    let client_version = query_packet.header.version;
+    VERSION_COUNTER.with_label_values(&[&client_version.to_string()]).inc();
+    if client_version < min_version {
+        debug!(logger, "query of unsupported version {}", client_version);
+        return send_kiss(query_packet, KISS_RSTR);
+    }
+    if strict {
+        // The client cannot match the origin timestamp of the response to its request.
+        if query_packet.header.transmit_timestamp == 0 {
+            debug!(logger, "strict mode: query without a transmit timestamp");
+            return send_kiss(query_packet, KISS_RSTR);
+        }
+        if client_version > resp_header.version {
+            debug!(logger, "strict mode: query of newer version {}", client_version);
+            return send_kiss(query_packet, KISS_RSTR);
+        }
+    }
+    // Answer in the version of the client, as long as we speak it.
+    resp_header.version = cmp::min(client_version, resp_header.version);
+    match listener.kind {
+        ListenerKind::PlainOnly if carries_nts_extensions(&query_packet) => {
+            error!(logger, "NTS request on the plain NTP address");
+            return send_kiss_of_death(query_packet);
+        }
+        ListenerKind::NtsOnly if !is_nts_packet(&query_packet) => {
+            error!(logger, "plain NTP request on the NTS-only address");
+            return send_kiss_of_death(query_packet);
+        }
+        _ => {}
+    }
     if is_nts_packet(&query_packet) {
+        // Every rejection below gets the same Kiss of Death, so the response doesn't tell why
+        // a cookie was rejected. The checks before the decryption only look at the length and
+        // the key id, which are public, and the decryption compares the tag in constant time.
         NTS_COUNTER.inc();
         let cookie = extract_extension(&query_packet, NTSCookie).unwrap();
+        // We only issue cookies of one size, and accept the legacy ones for a while, so don't
+        // bother decrypting anything else.
+        let cookie_size = cookie.contents.len();
+        if cookie_size != COOKIE_SIZE && cookie_size != LEGACY_COOKIE_SIZE {
+            MALFORMED_COOKIE_COUNTER.inc();
+            error!(logger, "cookie of unexpected size {}", cookie.contents.len());
+            return send_kiss_of_death(query_packet);
+        }
         let keyid_maybe = get_keyid(&cookie.contents);
         match keyid_maybe {
             Some(keyid) => {
                 let point = cookie_keys.read().unwrap();
+                match (*point).check_key_id(keyid) {
+                    Err(KeyIdError::Expired) => {
+                        EXPIRED_KEY_COUNTER.inc();
+                        error!(logger, "expired key {:x?}", keyid);
+                        return send_kiss_of_death(query_packet);
+                    }
+                    Err(KeyIdError::FromFuture) => {
+                        FUTURE_KEY_COUNTER.inc();
+                        error!(logger, "key {:x?} from the future", keyid);
+                        return send_kiss_of_death(query_packet);
+                    }
+                    Ok(()) => {}
+                }
                 let key_maybe = (*point).get(keyid);
                 match key_maybe {
                     Some(key) => {
-                        let nts_keys = eat_cookie(&cookie.contents, key.as_ref());
+                        let nts_keys = timed("eat_cookie", || {
+                            eat_cookie_with_context(
+                                &cookie.contents,
+                                key.as_ref(),
+                                &cookie_context,
+                            )
+                        });
                         match nts_keys {
-                            Some(nts_dir_keys) => {
+                            Some((aead, _)) if !listener.accepted_aead.contains(&aead) => {
+                                REJECTED_AEAD_COUNTER.inc();
+                                error!(logger, "cookie for AEAD {} not accepted", aead);
+                                send_kiss_of_death(query_packet)
+                            },
+                            Some((aead, nts_dir_keys)) => {
+                                let options = NtsOptions {
+                                    max_response_size,
+                                    max_extensions,
+                                    previous_transmit: request.previous_transmit,
+                                    cookie_context: &cookie_context,
+                                    ext_policy: &listener.ext_policy,
+                                };
                                 Ok(process_nts(
                                     resp_header,
+                                    aead,
                                     nts_dir_keys,
                                     cookie_keys.clone(),
                                     query,
+                                    &options,
                                 ))
                             },
                             None => {
@@ -395,48 +839,183 @@ fn response(
             }
         }
     } else {
+        if listener.kind == ListenerKind::Mixed {
+            // Monitoring probes send plain requests to the NTS address to check that the server
+            // is up, so let them tell the plain response apart.
+            debug!(logger, "plain NTP request on an NTS address");
+            if let Some(refid) = plain_probe_refid {
+                resp_header.reference_id = refid;
+            }
+        }
+        stamp_transmit(&mut resp_header, request.previous_transmit);
         Ok(serialize_header(resp_header))
     }
 }
 
+/// Run the response path of a mixed listener with a fixed server state, as `run_server` would
+/// for a query received just now. It's only exposed for the benchmarks.
+#[cfg(any(test, feature = "bench"))]
+#[doc(hidden)]
+pub fn bench_response(
+    query: &[u8],
+    cookie_keys: Arc<RwLock<KeyRotator>>,
+    logger: slog::Logger,
+) -> Result<Vec<u8>, std::io::Error> {
+    let servstate = Arc::new(RwLock::new(ServerState {
+        leap: NoLeap,
+        stratum: 1,
+        version: protocol::VERSION,
+        min_version: protocol::MIN_VERSION,
+        poll: protocol::MIN_POLL,
+        precision: -18,
+        root_delay: 10,
+        root_dispersion: 10,
+        refid: 0,
+        refstamp: 0,
+        taken: SystemTime::now(),
+        plain_probe_refid: None,
+        max_response_size: super::config::DEFAULT_MAX_RESPONSE_SIZE,
+        max_extensions: protocol::DEFAULT_MAX_EXTENSIONS,
+        bind_cookies_to_subnet: false,
+        strict: false,
+    }));
+    let request = RequestContext {
+        received: SystemTime::now(),
+        client_ip: None,
+        previous_transmit: None,
+    };
+    let listener = ListenerConfig {
+        kind: ListenerKind::Mixed,
+        accepted_aead: vec![KnownAeadAlgorithm::AeadAesSivCmac256.as_algorithm_id()],
+        acl: Acl::default(),
+        ext_policy: ExtensionPolicy::default(),
+        interleaved: None,
+    };
+    response(query, &request, cookie_keys, servstate, logger, &listener)
+}
+
+/// Return true if the packet carries any of the NTS extensions.
+fn carries_nts_extensions(packet: &NtpPacket) -> bool {
+    has_extension(packet, NTSCookie)
+        || has_extension(packet, NTSCookiePlaceholder)
+        || has_extension(packet, NTSAuthenticator)
+}
+
 fn process_nts(
     resp_header: NtpPacketHeader,
+    aead: u16,
     keys: NTSKeys,
     cookie_keys: Arc<RwLock<KeyRotator>>,
     query_raw: &[u8],
+    options: &NtsOptions,
 ) -> Vec<u8> {
-    let mut recv_aead = Aes128SivAead::new(&keys.c2s);
-    let mut send_aead = Aes128SivAead::new(&keys.s2c);
-    let query = parse_nts_packet::<Aes128SivAead>(query_raw, &mut recv_aead);
+    // The caller parsed the query with the same limit, so it parses again.
+    let query_packet =
+        || parse_ntp_packet_with_max_extensions(query_raw, options.max_extensions).unwrap();
+    // The caller only passes accepted algorithms, and we only accept known ones.
+    let algorithm = match KnownAeadAlgorithm::from_algorithm_id(aead) {
+        Some(algorithm) => algorithm,
+        None => return serialize_ntp_packet(kiss_of_death(query_packet())),
+    };
+    let mut aeads = new_aeads(algorithm, &keys);
+    let query = timed("decrypt", || {
+        parse_nts_packet_with_max_extensions(query_raw, &mut aeads.c2s, options.max_extensions)
+    });
     match query {
-        Ok(packet) => serialize_nts_packet(
-            nts_response(packet, resp_header, keys, cookie_keys),
-            &mut send_aead,
-        ),
-        Err(_) => serialize_ntp_packet(kiss_of_death(parse_ntp_packet(query_raw).unwrap())),
+        // Bound the amount of work we do for a single request.
+        Ok(ref packet)
+            if packet.auth_exts.len() + packet.auth_enc_exts.len() > MAX_NTS_EXTENSIONS =>
+        {
+            serialize_ntp_packet(kiss_of_death(query_packet()))
+        }
+        Ok(ref packet) if rejects_extension(packet, options.ext_policy) => {
+            REJECTED_EXTENSION_COUNTER.inc();
+            serialize_ntp_packet(kiss(query_packet(), KISS_RSTR))
+        }
+        Ok(packet) => {
+            let mut resp =
+                nts_response(packet, resp_header, algorithm, keys, cookie_keys, options);
+            trim_cookies(&mut resp, options.max_response_size, aeads.s2c.tag_len());
+            stamp_transmit(&mut resp.header, options.previous_transmit);
+            timed("encrypt", || serialize_nts_packet(resp, &mut aeads.s2c))
+        }
+        Err(_) => serialize_ntp_packet(kiss_of_death(query_packet())),
+    }
+}
+
+/// Run `operation`, and record how long it took in the AEAD histogram under `label`. It tells
+/// whether a busy server is bound by the CPU or by the network.
+fn timed<T, F: FnOnce() -> T>(label: &str, operation: F) -> T {
+    let start = time::Instant::now();
+    let result = operation();
+    AEAD_SECONDS.with_label_values(&[label]).observe(secs(start.elapsed()));
+    result
+}
+
+/// Return the action of the policy for the extension, or `None` if the server handles the
+/// extension itself.
+fn extension_action(ext: &NtpExtension, ext_policy: &ExtensionPolicy) -> Option<ExtensionAction> {
+    match ext.ext_type {
+        protocol::NtpExtensionType::Unknown(ext_type) => Some(ext_policy.action(ext_type)),
+        _ => None,
+    }
+}
+
+/// Return true if the request carries an extension, authenticated or encrypted, which the policy
+/// rejects.
+fn rejects_extension(packet: &NtsPacket, ext_policy: &ExtensionPolicy) -> bool {
+    packet
+        .auth_exts
+        .iter()
+        .chain(packet.auth_enc_exts.iter())
+        .any(|ext| extension_action(ext, ext_policy) == Some(ExtensionAction::Reject))
+}
+
+/// Leave out cookies from the end of the response until it fits in `max_size` bytes.
+fn trim_cookies(resp: &mut NtsPacket, max_size: usize, tag_len: usize) {
+    while nts_packet_len(resp, tag_len) > max_size && resp.auth_enc_exts.pop().is_some() {
+        TRIMMED_COOKIE_COUNTER.inc();
     }
 }
 
 fn nts_response(
     query: NtsPacket,
     header: NtpPacketHeader,
+    aead: KnownAeadAlgorithm,
     keys: NTSKeys,
     cookie_keys: Arc<RwLock<KeyRotator>>,
+    options: &NtsOptions,
 ) -> NtsPacket {
     let mut resp_packet = NtsPacket {
         header: header,
         auth_exts: vec![],
         auth_enc_exts: vec![],
     };
+    // Encrypted extensions are echoed encrypted. They go before the cookies, so that only
+    // cookies are left out of a response too large.
+    for ext in query.auth_enc_exts {
+        if extension_action(&ext, options.ext_policy) == Some(ExtensionAction::Echo) {
+            resp_packet.auth_enc_exts.push(ext);
+        }
+    }
+    let mut placeholders = 0;
     for ext in query.auth_exts {
         match ext.ext_type {
             protocol::NtpExtensionType::UniqueIdentifier => resp_packet.auth_exts.push(ext),
+            protocol::NtpExtensionType::Unknown(_) => {
+                if extension_action(&ext, options.ext_policy) == Some(ExtensionAction::Echo) {
+                    resp_packet.auth_exts.push(ext);
+                }
+            }
             protocol::NtpExtensionType::NTSCookiePlaceholder => {
-                if ext.contents.len() >= COOKIE_SIZE {
+                if ext.contents.len() >= COOKIE_SIZE && placeholders < MAX_COOKIE_PLACEHOLDERS {
+                    placeholders += 1;
                     // Avoid amplification
                     let keymaker = cookie_keys.read().unwrap();
                     let (key_id, curr_key) = keymaker.latest_key_value();
-                    let cookie = make_cookie(keys, curr_key.as_ref(), key_id);
+                    let cookie = timed("make_cookie", || make_cookie_with_context(
+                        keys, aead, curr_key.as_ref(), key_id, options.cookie_context,
+                    ));
                     resp_packet.auth_enc_exts.push(NtpExtension {
                         ext_type: NTSCookie,
                         contents: cookie,
@@ -449,7 +1028,9 @@ fn nts_response(
     // This is a free cookie to replace the one consumed in the packet
     let keymaker = cookie_keys.read().unwrap();
     let (key_id, curr_key) = keymaker.latest_key_value();
-    let cookie = make_cookie(keys, curr_key.as_ref(), key_id);
+    let cookie = timed("make_cookie", || {
+        make_cookie_with_context(keys, aead, curr_key.as_ref(), key_id, options.cookie_context)
+    });
     resp_packet.auth_enc_exts.push(NtpExtension {
         ext_type: NTSCookie,
         contents: cookie,
@@ -458,13 +1039,22 @@ fn nts_response(
 }
 
 fn send_kiss_of_death(query_packet: NtpPacket) -> Result<Vec<u8>, std::io::Error> {
-    let resp = kiss_of_death(query_packet);
+    send_kiss(query_packet, KISS_NTSN)
+}
+
+fn send_kiss(query_packet: NtpPacket, kiss_code: u32) -> Result<Vec<u8>, std::io::Error> {
+    let resp = kiss(query_packet, kiss_code);
     Ok(serialize_ntp_packet(resp))
 }
 
 /// The kiss of death tells the client it has done something wrong.
 /// draft-ietf-ntp-using-nts-for-ntp-18 and RFC 5905 specify the format.
 fn kiss_of_death(query_packet: NtpPacket) -> NtpPacket {
+    kiss(query_packet, KISS_NTSN)
+}
+
+/// A Kiss-o'-Death with the given kiss code in the reference id.
+fn kiss(query_packet: NtpPacket, kiss_code: u32) -> NtpPacket {
     KOD_COUNTER.inc();
     let kod_header = NtpPacketHeader {
         leap_indicator: LeapState::Unknown,
@@ -475,7 +1065,7 @@ fn kiss_of_death(query_packet: NtpPacket) -> NtpPacket {
         stratum: 0,
         root_delay: 0,
         root_dispersion: 0,
-        reference_id: 0x4e54534e, // NTSN
+        reference_id: kiss_code,
         reference_timestamp: 0,
         origin_timestamp: query_packet.header.transmit_timestamp,
         receive_timestamp: 0,
@@ -494,11 +1084,77 @@ fn kiss_of_death(query_packet: NtpPacket) -> NtpPacket {
     kod_packet
 }
 
+/// Take over the state of the upstream from its response, measured with a round trip `delay` in
+/// seconds. If the upstream isn't synchronized, or its stratum leaves no room for ours, we aren't
+/// synchronized either.
+fn apply_upstream(
+    state: &mut ServerState,
+    header: &NtpPacketHeader,
+    taken: SystemTime,
+    delay: f64,
+) {
+    state.leap = header.leap_indicator;
+    state.version = 4;
+    state.poll = protocol::clamp_poll(header.poll);
+    state.precision = header.precision;
+    state.stratum = header.stratum.saturating_add(1);
+    state.root_delay = root_delay(header, delay);
+    state.root_dispersion = root_dispersion(header, delay);
+    state.refid = header.reference_id;
+    state.refstamp = header.reference_timestamp;
+    state.taken = taken;
+    // Our stratum is one more than the one of the upstream, which must not pass the highest
+    // synchronized stratum. A stratum of 0 is a Kiss of Death or unspecified.
+    if header.stratum == 0 || state.stratum > MAX_STRATUM {
+        state.leap = LeapState::Unknown;
+        state.stratum = UNSYNCHRONIZED_STRATUM;
+    }
+}
+
+/// Serve the time of a reference clock last updated at `updated` as stratum 1. The dispersion
+/// grows from the update on, so clients notice a reference clock that stops updating.
+fn apply_refclock(state: &mut ServerState, refid: u32, updated: SystemTime) {
+    state.leap = NoLeap;
+    state.stratum = 1;
+    state.precision = PRECISION;
+    state.root_delay = 0;
+    state.root_dispersion = ntp_short_from_secs(2f64.powi(i32::from(PRECISION)));
+    state.refid = refid;
+    state.refstamp = ntp_timestamp(updated);
+    state.taken = updated;
+}
+
+/// Read the time of the last update of the reference clock every `poll` and serve it.
+fn refresh_refclock(
+    servstate: Arc<RwLock<ServerState>>,
+    logger: slog::Logger,
+    refclock_file: &str,
+    refid: u32,
+    poll: Duration,
+) {
+    loop {
+        match read_refclock(refclock_file) {
+            Ok(updated) => {
+                let mut state = servstate.write().unwrap();
+                apply_refclock(&mut state, refid, updated);
+                debug!(logger, "reference clock updated at {:?}", updated);
+            }
+            Err(err) => {
+                REFCLOCK_FAILURE_COUNTER.inc();
+                error!(logger, "failure to read the reference clock: {}", err);
+            }
+        }
+        thread::sleep(poll);
+    }
+}
+
+/// Query the upstream every `poll` and take over its state.
 fn refresh_servstate(
     servstate: Arc<RwLock<ServerState>>,
     logger: slog::Logger,
     sock: std::net::UdpSocket,
     addr: &SocketAddr,
+    poll: Duration,
 ) {
     loop {
         let query_packet = NtpPacket {
@@ -515,12 +1171,13 @@ fn refresh_servstate(
                 reference_timestamp: 0,
                 origin_timestamp: 0,
                 receive_timestamp: 0,
-                transmit_timestamp: 0,
+                transmit_timestamp: ntp_timestamp(SystemTime::now()),
             },
             exts: vec![],
         };
         sock.connect(addr)
             .expect("socket connection to server failed, failed to refresh server state");
+        let sent = time::Instant::now();
         sock.send(&serialize_ntp_packet(query_packet))
             .expect("sending ntp packet to server failed, failed to refresh server state");
         UPSTREAM_QUERY_COUNTER.inc();
@@ -531,17 +1188,9 @@ fn refresh_servstate(
                 let response = parse_ntp_packet(&buff[0..size]);
                 match response {
                     Ok(packet) => {
+                        let delay = upstream_delay(&packet.header, sent.elapsed());
                         let mut state = servstate.write().unwrap();
-                        state.leap = packet.header.leap_indicator;
-                        state.version = 4;
-                        state.poll = packet.header.poll;
-                        state.precision = packet.header.precision;
-                        state.stratum = packet.header.stratum;
-                        state.root_delay = packet.header.root_delay;
-                        state.root_dispersion = packet.header.root_dispersion;
-                        state.refid = packet.header.reference_id;
-                        state.refstamp = packet.header.reference_timestamp;
-                        state.taken = SystemTime::now();
+                        apply_upstream(&mut state, &packet.header, SystemTime::now(), delay);
                         info!(logger, "set server state with stratum {:}", state.stratum);
                     }
                     Err(err) => {
@@ -555,6 +1204,1173 @@ fn refresh_servstate(
                 error!(logger, "read error: {}", err);
             }
         }
-        thread::sleep(time::Duration::from_secs(1));
+        thread::sleep(poll);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use miscreant::aead::{Aead, Aes128SivAead};
+    use prometheus::core::Metric;
+    use sloggers::Build;
+    use sloggers::null::NullLoggerBuilder;
+
+    use crate::cookie::{make_cookie, CookieKey};
+    use crate::key_rotator::KeyId;
+    use crate::ntp::protocol::{parse_nts_packet, NtpTimestamp, DEFAULT_MAX_EXTENSIONS};
+    use crate::ntp::server::config::DEFAULT_MAX_RESPONSE_SIZE;
+    use crate::ntp::server::interleaved::DEFAULT_CAPACITY;
+
+    use std::env;
+    use std::fs;
+
+    /// AEAD_AES_SIV_CMAC_256
+    const AEAD: u16 = 15;
+
+    fn test_keys() -> Arc<RwLock<KeyRotator>> {
+        let rotator = KeyRotator::with_key(
+            CookieKey::from(&[0x07; 32][..]),
+            KeyId::new(1),
+            &[0x01; 32],
+        );
+        Arc::new(RwLock::new(rotator))
+    }
+
+    fn test_servstate() -> Arc<RwLock<ServerState>> {
+        Arc::new(RwLock::new(ServerState {
+            leap: NoLeap,
+            stratum: 1,
+            version: protocol::VERSION,
+            min_version: protocol::MIN_VERSION,
+            poll: 7,
+            precision: -18,
+            root_delay: 10,
+            root_dispersion: 10,
+            refid: 0,
+            refstamp: 0,
+            taken: SystemTime::now(),
+            plain_probe_refid: None,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            max_extensions: DEFAULT_MAX_EXTENSIONS,
+            bind_cookies_to_subnet: false,
+            strict: false,
+        }))
+    }
+
+    fn test_request() -> RequestContext {
+        RequestContext { received: SystemTime::now(), client_ip: None, previous_transmit: None }
+    }
+
+    fn test_listener(kind: ListenerKind) -> ListenerConfig {
+        ListenerConfig {
+            kind,
+            accepted_aead: vec![AEAD],
+            acl: Acl::default(),
+            ext_policy: ExtensionPolicy::default(),
+            interleaved: None,
+        }
+    }
+
+    fn test_query_header() -> NtpPacketHeader {
+        NtpPacketHeader {
+            leap_indicator: NoLeap,
+            version: 4,
+            mode: PacketMode::Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_id: 0,
+            reference_timestamp: 0,
+            origin_timestamp: 0,
+            receive_timestamp: 0,
+            transmit_timestamp: 0x0102030405060708,
+        }
+    }
+
+    fn test_response_received(
+        query: &[u8],
+        kind: ListenerKind,
+        received: SystemTime,
+    ) -> NtpPacket {
+        let logger = NullLoggerBuilder.build().unwrap();
+        let request = RequestContext { received, ..test_request() };
+        let resp = response(
+            query, &request, test_keys(), test_servstate(), logger, &test_listener(kind),
+        );
+        parse_ntp_packet(&resp.unwrap()).unwrap()
+    }
+
+    fn test_response(query: &[u8], kind: ListenerKind) -> NtpPacket {
+        test_response_received(query, kind, SystemTime::now())
+    }
+
+    fn test_cookie() -> Vec<u8> {
+        let keys = test_keys();
+        let rotator = keys.read().unwrap();
+        let (key_id, key) = rotator.latest_key_value();
+        let nts_keys = NTSKeys {
+            c2s: [1; 32],
+            s2c: [2; 32],
+        };
+        make_cookie(nts_keys, KnownAeadAlgorithm::AeadAesSivCmac256, key.as_ref(), key_id)
+    }
+
+    /// A valid NTS request with the given cookie and the keys in `test_cookie`.
+    fn test_nts_query(cookie: Vec<u8>) -> Vec<u8> {
+        test_nts_query_with_placeholders(cookie, 0)
+    }
+
+    /// Like `test_nts_query`, but also asking for more cookies with placeholders.
+    fn test_nts_query_with_placeholders(cookie: Vec<u8>, placeholders: usize) -> Vec<u8> {
+        let mut packet = NtsPacket {
+            header: test_query_header(),
+            auth_exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: vec![0; 32],
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: cookie,
+                },
+            ],
+            auth_enc_exts: vec![],
+        };
+        for _ in 0..placeholders {
+            packet.auth_exts.push(NtpExtension {
+                ext_type: NTSCookiePlaceholder,
+                contents: vec![0; COOKIE_SIZE],
+            });
+        }
+        serialize_nts_packet(packet, &mut Aes128SivAead::new(&[1; 32]))
+    }
+
+    fn is_kiss_of_death(packet: &NtpPacket) -> bool {
+        is_kiss(packet, KISS_NTSN)
+    }
+
+    fn is_kiss(packet: &NtpPacket, kiss_code: u32) -> bool {
+        packet.header.stratum == 0 && packet.header.reference_id == kiss_code
+    }
+
+    #[test]
+    fn test_aead_timing() {
+        let samples = |operation| {
+            let metric = AEAD_SECONDS.with_label_values(&[operation]).metric();
+            let histogram = metric.get_histogram();
+            (histogram.get_sample_count(), histogram.get_sample_sum())
+        };
+        let (decrypts, decrypt_secs) = samples("decrypt");
+        let (encrypts, _) = samples("encrypt");
+        let (eaten_cookies, _) = samples("eat_cookie");
+        let (made_cookies, _) = samples("make_cookie");
+
+        let query = test_nts_query(test_cookie());
+        assert!(!is_kiss_of_death(&test_response(&query, ListenerKind::Mixed)));
+
+        // Other tests may run queries at the same time.
+        let (decrypts_after, decrypt_secs_after) = samples("decrypt");
+        assert!(decrypts_after > decrypts);
+        assert!(decrypt_secs_after > decrypt_secs);
+        assert!(samples("encrypt").0 > encrypts);
+        assert!(samples("eat_cookie").0 > eaten_cookies);
+        assert!(samples("make_cookie").0 > made_cookies);
+    }
+
+    #[test]
+    fn test_plain_probe_refid() {
+        let query = serialize_ntp_packet(NtpPacket {
+            header: test_query_header(),
+            exts: vec![],
+        });
+        let servstate = test_servstate();
+        servstate.write().unwrap().plain_probe_refid = Some(0x504c4e00);
+
+        let logger = NullLoggerBuilder.build().unwrap();
+        let respond = |query: &[u8], kind| {
+            let resp = response(
+                query, &test_request(), test_keys(), servstate.clone(), logger.clone(),
+                &test_listener(kind),
+            );
+            parse_ntp_packet(&resp.unwrap()).unwrap()
+        };
+
+        // A plain probe on the NTS address gets a plain response carrying the marker.
+        let resp = respond(&query, ListenerKind::Mixed);
+        assert_eq!(resp.header.mode, PacketMode::Server);
+        assert_eq!(resp.header.stratum, 1);
+        assert_eq!(resp.header.reference_id, 0x504c4e00);
+        assert_eq!(resp.header.origin_timestamp, 0x0102030405060708);
+        assert!(resp.exts.is_empty());
+
+        // The dedicated plain address keeps the reference id of the upstream.
+        assert_eq!(respond(&query, ListenerKind::PlainOnly).header.reference_id, 0);
+
+        // NTS responses are not marked.
+        let query = test_nts_query(test_cookie());
+        assert_eq!(respond(&query, ListenerKind::Mixed).header.reference_id, 0);
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        let query = test_nts_query_with_placeholders(test_cookie(), MAX_COOKIE_PLACEHOLDERS);
+        let respond = |max_response_size| {
+            let servstate = test_servstate();
+            servstate.write().unwrap().max_response_size = max_response_size;
+            let logger = NullLoggerBuilder.build().unwrap();
+            let listener = test_listener(ListenerKind::Mixed);
+            let resp = response(&query, &test_request(), test_keys(), servstate, logger, &listener);
+            resp.unwrap()
+        };
+
+        // All the cookies asked for fit in the default size.
+        let resp = respond(DEFAULT_MAX_RESPONSE_SIZE);
+        assert!(resp.len() <= DEFAULT_MAX_RESPONSE_SIZE);
+        let packet = parse_nts_packet(&resp, &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        assert_eq!(packet.auth_enc_exts.len(), MAX_COOKIE_PLACEHOLDERS + 1);
+
+        // With a smaller size, the response carries as many cookies as fit.
+        let resp = respond(600);
+        assert!(resp.len() <= 600);
+        let packet = parse_nts_packet(&resp, &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        assert_eq!(packet.auth_enc_exts.len(), 4);
+        assert!(resp.len() + 4 + COOKIE_SIZE > 600);
+    }
+
+    #[test]
+    fn test_max_extensions() {
+        // The unique identifier, the cookie, the placeholders and the authenticator.
+        let query = test_nts_query_with_placeholders(test_cookie(), MAX_COOKIE_PLACEHOLDERS);
+        let respond = |max_extensions| {
+            let servstate = test_servstate();
+            servstate.write().unwrap().max_extensions = max_extensions;
+            let logger = NullLoggerBuilder.build().unwrap();
+            let listener = test_listener(ListenerKind::Mixed);
+            response(&query, &test_request(), test_keys(), servstate, logger, &listener)
+        };
+
+        let resp = respond(MAX_COOKIE_PLACEHOLDERS + 3).unwrap();
+        let packet = parse_nts_packet(&resp, &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        assert_eq!(packet.auth_enc_exts.len(), MAX_COOKIE_PLACEHOLDERS + 1);
+
+        // A request with more extensions is dropped.
+        respond(MAX_COOKIE_PLACEHOLDERS + 2).unwrap_err();
+    }
+
+    #[test]
+    fn test_unique_identifier_echo() {
+        // A Unique Identifier longer than the minimum, so that a length mismatch would show.
+        let unique_id: Vec<u8> = (0..36).collect();
+        let packet = NtsPacket {
+            header: test_query_header(),
+            auth_exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: unique_id.clone(),
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: test_cookie(),
+                },
+                NtpExtension {
+                    ext_type: NTSCookiePlaceholder,
+                    contents: vec![0; COOKIE_SIZE],
+                },
+            ],
+            auth_enc_exts: vec![],
+        };
+        let query = serialize_nts_packet(packet, &mut Aes128SivAead::new(&[1; 32]));
+
+        let logger = NullLoggerBuilder.build().unwrap();
+        let listener = test_listener(ListenerKind::Mixed);
+        let resp = response(
+            &query, &test_request(), test_keys(), test_servstate(), logger, &listener,
+        ).unwrap();
+
+        // On the wire, the Unique Identifier is the first extension, in plaintext, and the
+        // cookies are inside the authenticator after it.
+        let plain = parse_ntp_packet(&resp).unwrap();
+        assert_eq!(plain.exts.len(), 2);
+        assert_eq!(plain.exts[0].ext_type, UniqueIdentifier);
+        assert_eq!(plain.exts[0].contents, unique_id);
+        assert_eq!(plain.exts[1].ext_type, protocol::NtpExtensionType::NTSAuthenticator);
+
+        // It's authenticated but not encrypted, and it's byte-identical to the request's.
+        let packet = parse_nts_packet(&resp, &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        assert_eq!(packet.auth_exts.len(), 1);
+        assert_eq!(packet.auth_exts[0].ext_type, UniqueIdentifier);
+        assert_eq!(packet.auth_exts[0].contents, unique_id);
+        assert_eq!(packet.auth_enc_exts.len(), 2);
+        assert!(packet.auth_enc_exts.iter().all(|ext| ext.ext_type == NTSCookie));
+    }
+
+    #[test]
+    fn test_extension_policy() {
+        let unknown = protocol::NtpExtensionType::Unknown;
+        let packet = NtsPacket {
+            header: test_query_header(),
+            auth_exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: vec![0; 32],
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: test_cookie(),
+                },
+                NtpExtension {
+                    ext_type: unknown(0xff01),
+                    contents: vec![1; 8],
+                },
+            ],
+            auth_enc_exts: vec![NtpExtension {
+                ext_type: unknown(0xff02),
+                contents: vec![2; 8],
+            }],
+        };
+        let query = serialize_nts_packet(packet, &mut Aes128SivAead::new(&[1; 32]));
+
+        let logger = NullLoggerBuilder.build().unwrap();
+        let respond = |default: ExtensionAction| {
+            let listener = ListenerConfig {
+                ext_policy: ExtensionPolicy { default, actions: Vec::new() },
+                ..test_listener(ListenerKind::Mixed)
+            };
+            let resp = response(
+                &query, &test_request(), test_keys(), test_servstate(), logger.clone(), &listener,
+            );
+            resp.unwrap()
+        };
+
+        // By default, the unknown extensions are left out of the response.
+        let resp = respond(ExtensionAction::default());
+        let packet = parse_nts_packet(&resp, &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        assert_eq!(packet.auth_exts.len(), 1);
+        assert_eq!(packet.auth_exts[0].ext_type, UniqueIdentifier);
+        assert_eq!(packet.auth_enc_exts.len(), 1);
+        assert_eq!(packet.auth_enc_exts[0].ext_type, NTSCookie);
+
+        // Echoed, they are sent back in the same part of the response as they came in.
+        let resp = respond(ExtensionAction::Echo);
+        let packet = parse_nts_packet(&resp, &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        assert_eq!(packet.auth_exts.len(), 2);
+        assert_eq!(packet.auth_exts[1].ext_type, unknown(0xff01));
+        assert_eq!(packet.auth_exts[1].contents, vec![1; 8]);
+        assert_eq!(packet.auth_enc_exts.len(), 2);
+        assert_eq!(packet.auth_enc_exts[0].ext_type, unknown(0xff02));
+        assert_eq!(packet.auth_enc_exts[0].contents, vec![2; 8]);
+        assert_eq!(packet.auth_enc_exts[1].ext_type, NTSCookie);
+
+        // Rejected, the request gets a Kiss of Death refusing it by policy.
+        let resp = respond(ExtensionAction::Reject);
+        assert!(is_kiss(&parse_ntp_packet(&resp).unwrap(), KISS_RSTR));
+    }
+
+    #[test]
+    fn test_plain_request_on_nts_only_address() {
+        let query = serialize_ntp_packet(NtpPacket {
+            header: test_query_header(),
+            exts: vec![],
+        });
+
+        let resp = test_response(&query, ListenerKind::NtsOnly);
+        assert!(is_kiss_of_death(&resp));
+        assert_eq!(resp.header.origin_timestamp, 0x0102030405060708);
+
+        // The same request is served on the other kinds of addresses.
+        assert!(!is_kiss_of_death(&test_response(&query, ListenerKind::Mixed)));
+        assert!(!is_kiss_of_death(&test_response(&query, ListenerKind::PlainOnly)));
+    }
+
+    #[test]
+    fn test_reused_buffer() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let logger = NullLoggerBuilder.build().unwrap();
+            let (keys, state) = (test_keys(), test_servstate());
+            run_server(server, keys, state, logger, true, test_listener(ListenerKind::Mixed))
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0; BUF_SIZE];
+
+        // A large NTS request with a bogus cookie gets a Kiss of Death.
+        let large = serialize_ntp_packet(NtpPacket {
+            header: test_query_header(),
+            exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: vec![0; 32],
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: vec![0; COOKIE_SIZE],
+                },
+                NtpExtension {
+                    ext_type: NTSAuthenticator,
+                    contents: vec![0; 36],
+                },
+            ],
+        });
+        client.send_to(&large, server_addr).unwrap();
+        let (amt, _) = client.recv_from(&mut buf).unwrap();
+        assert!(is_kiss_of_death(&parse_ntp_packet(&buf[..amt]).unwrap()));
+
+        // A following plain request must not see the extensions left in the buffer.
+        let small = serialize_header(test_query_header());
+        client.send_to(&small, server_addr).unwrap();
+        let (amt, _) = client.recv_from(&mut buf).unwrap();
+        let resp = parse_ntp_packet(&buf[..amt]).unwrap();
+        assert_eq!(amt, small.len());
+        assert!(!is_kiss_of_death(&resp));
+        assert_eq!(resp.header.origin_timestamp, test_query_header().transmit_timestamp);
+    }
+
+    #[test]
+    fn test_interleaved_response() {
+        let mut query_header = test_query_header();
+        query_header.origin_timestamp = 0x1000;
+        query_header.receive_timestamp = 0x2000;
+        let received = SystemTime::now();
+        let respond = |query: &[u8]| {
+            let logger = NullLoggerBuilder.build().unwrap();
+            let request =
+                RequestContext { received, previous_transmit: Some(0x3000), ..test_request() };
+            let listener = test_listener(ListenerKind::Mixed);
+            let resp = response(query, &request, test_keys(), test_servstate(), logger, &listener);
+            resp.unwrap()
+        };
+
+        // The origin timestamp is the receive timestamp of the request, and the transmit
+        // timestamp is the one of the previous response.
+        let resp = parse_ntp_packet(&respond(&serialize_header(query_header))).unwrap();
+        assert_eq!(resp.header.origin_timestamp, 0x2000);
+        assert_eq!(resp.header.receive_timestamp, ntp_timestamp(received));
+        assert_eq!(resp.header.transmit_timestamp, 0x3000);
+
+        // The same goes for NTS, where the header is authenticated.
+        let packet = NtsPacket {
+            header: query_header,
+            auth_exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: vec![0; 32],
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: test_cookie(),
+                },
+            ],
+            auth_enc_exts: vec![],
+        };
+        let query = serialize_nts_packet(packet, &mut Aes128SivAead::new(&[1; 32]));
+        let resp = parse_nts_packet(&respond(&query), &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        assert_eq!(resp.header.origin_timestamp, 0x2000);
+        assert_eq!(resp.header.transmit_timestamp, 0x3000);
+    }
+
+    #[test]
+    fn test_interleaved_exchange() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let logger = NullLoggerBuilder.build().unwrap();
+            let (keys, state) = (test_keys(), test_servstate());
+            let interleaved = Some(Arc::new(Mutex::new(InterleavedState::new(DEFAULT_CAPACITY))));
+            let listener = ListenerConfig { interleaved, ..test_listener(ListenerKind::Mixed) };
+            run_server(server, keys, state, logger, true, listener)
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0; BUF_SIZE];
+        let mut exchange = |query: NtpPacketHeader| {
+            client.send_to(&serialize_header(query), server_addr).unwrap();
+            let (amt, _) = client.recv_from(&mut buf).unwrap();
+            parse_ntp_packet(&buf[..amt]).unwrap().header
+        };
+
+        // The first request is in basic mode.
+        let first_query = test_query_header();
+        let first = exchange(first_query);
+        assert_eq!(first.origin_timestamp, first_query.transmit_timestamp);
+
+        // The second one refers to the first response, which gets its actual transmit time.
+        let mut second_query = test_query_header();
+        second_query.origin_timestamp = first.receive_timestamp;
+        second_query.receive_timestamp = 0x1111;
+        second_query.transmit_timestamp = 0x2222;
+        let second = exchange(second_query);
+        assert_eq!(second.origin_timestamp, 0x1111);
+        assert!(second.receive_timestamp >= first.receive_timestamp);
+        assert!(second.transmit_timestamp >= first.transmit_timestamp);
+        assert!(second.transmit_timestamp <= second.receive_timestamp);
+
+        // The next request may go on in interleaved mode, or go back to basic mode.
+        let mut third_query = test_query_header();
+        third_query.origin_timestamp = second.receive_timestamp;
+        third_query.receive_timestamp = 0x3333;
+        let third = exchange(third_query);
+        assert_eq!(third.origin_timestamp, 0x3333);
+        assert!(third.transmit_timestamp >= second.receive_timestamp);
+
+        let mut basic_query = test_query_header();
+        basic_query.origin_timestamp = 0x4444;
+        let basic = exchange(basic_query);
+        assert_eq!(basic.origin_timestamp, basic_query.transmit_timestamp);
+        assert!(basic.transmit_timestamp >= basic.receive_timestamp);
+    }
+
+    #[test]
+    fn test_oversized_packet() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let logger = NullLoggerBuilder.build().unwrap();
+            let (keys, state) = (test_keys(), test_servstate());
+            run_server(server, keys, state, logger, true, test_listener(ListenerKind::Mixed))
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0; BUF_SIZE];
+
+        // Cut to the size of the buffer, the request would still parse as a plain request.
+        let mut header = test_query_header();
+        header.transmit_timestamp = 1;
+        let oversized = serialize_ntp_packet(NtpPacket {
+            header,
+            exts: vec![NtpExtension {
+                ext_type: protocol::NtpExtensionType::Unknown(0x1234),
+                contents: vec![0; 2000 - 48 - 4],
+            }],
+        });
+        assert_eq!(oversized.len(), 2000);
+        client.send_to(&oversized, server_addr).unwrap();
+
+        // The first response is to the following request, the oversized one is dropped.
+        let small = serialize_header(test_query_header());
+        client.send_to(&small, server_addr).unwrap();
+        let (amt, _) = client.recv_from(&mut buf).unwrap();
+        let resp = parse_ntp_packet(&buf[..amt]).unwrap();
+        assert_eq!(resp.header.origin_timestamp, test_query_header().transmit_timestamp);
+    }
+
+    #[test]
+    fn test_deny_networks() {
+        let serve = |acl: Acl| {
+            let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let server_addr = server.local_addr().unwrap();
+            thread::spawn(move || {
+                let logger = NullLoggerBuilder.build().unwrap();
+                let (keys, state) = (test_keys(), test_servstate());
+                let listener = ListenerConfig { acl, ..test_listener(ListenerKind::Mixed) };
+                run_server(server, keys, state, logger, true, listener)
+            });
+            server_addr
+        };
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let mut buf = [0; BUF_SIZE];
+        let query = serialize_header(test_query_header());
+        let transmit_timestamp = test_query_header().transmit_timestamp;
+
+        let networks = |cidrs: &[&str]| -> Vec<_> {
+            cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+        };
+        let mut served = |acl: Acl| {
+            client.send_to(&query, serve(acl)).unwrap();
+            match client.recv_from(&mut buf) {
+                Ok((amt, _)) => {
+                    let resp = parse_ntp_packet(&buf[..amt]).unwrap();
+                    assert_eq!(resp.header.origin_timestamp, transmit_timestamp);
+                    true
+                }
+                Err(_) => false,
+            }
+        };
+
+        // The client is outside of the denied networks.
+        assert!(served(Acl {
+            allow: vec![],
+            deny: networks(&["10.0.0.0/8", "::1"]),
+        }));
+
+        // The client is in a denied network, so it gets no answer.
+        let before = DENIED_PACKET_COUNTER.get();
+        assert!(!served(Acl {
+            allow: vec![],
+            deny: networks(&["127.0.0.0/8"]),
+        }));
+        assert!(DENIED_PACKET_COUNTER.get() > before);
+
+        // With an allowlist, only the allowed networks are served.
+        assert!(served(Acl {
+            allow: networks(&["127.0.0.1"]),
+            deny: vec![],
+        }));
+        assert!(!served(Acl {
+            allow: networks(&["10.0.0.0/8"]),
+            deny: vec![],
+        }));
+
+        // The denylist wins over the allowlist.
+        assert!(!served(Acl {
+            allow: networks(&["127.0.0.0/8"]),
+            deny: networks(&["127.0.0.1"]),
+        }));
+    }
+
+    #[test]
+    fn test_client_version() {
+        let query_version = |version| {
+            let mut header = test_query_header();
+            header.version = version;
+            test_response(&serialize_header(header), ListenerKind::Mixed)
+        };
+        let before = VERSION_COUNTER.with_label_values(&["1"]).get();
+
+        // A new key exchange wouldn't help the client, so it gets RSTR rather than NTSN.
+        let resp = query_version(1);
+        assert!(is_kiss(&resp, KISS_RSTR));
+        assert_eq!(VERSION_COUNTER.with_label_values(&["1"]).get(), before + 1);
+
+        for &version in &[3, 4] {
+            let resp = query_version(version);
+            assert!(!is_kiss_of_death(&resp));
+            assert_eq!(resp.header.version, version);
+        }
+
+        // A newer client gets the version we speak.
+        let resp = query_version(5);
+        assert!(!is_kiss_of_death(&resp));
+        assert_eq!(resp.header.version, protocol::VERSION);
+    }
+
+    /// Decode a regression fixture from `tests/fixtures`. See `tests/fixtures/generate.py` for how
+    /// they are made.
+    fn read_fixture(text: &str) -> Vec<u8> {
+        hex::decode(text.split_whitespace().collect::<String>()).unwrap()
+    }
+
+    /// Check a response the way SNTP clients do before using it (RFC 4330 Section 5). A response
+    /// failing any of these is dropped by them, however well it parses.
+    fn check_sntp_response(
+        request: &NtpPacketHeader,
+        response: &NtpPacketHeader,
+    ) -> Result<(), &'static str> {
+        if response.leap_indicator == Unknown {
+            return Err("the server clock is not synchronized");
+        }
+        if response.version != request.version {
+            return Err("the version is not the one of the request");
+        }
+        if response.mode != PacketMode::Server {
+            return Err("the mode is not server");
+        }
+        if response.stratum == 0 || response.stratum > 15 {
+            return Err("the stratum is not between 1 and 15");
+        }
+        if response.transmit_timestamp == 0 {
+            return Err("the transmit timestamp is zero");
+        }
+        if response.origin_timestamp != request.transmit_timestamp {
+            return Err("the origin timestamp is not the transmit timestamp of the request");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sntp_fixture_response() {
+        // The response is our own fixture, not one from an SNTP server, so this only checks that
+        // check_sntp_response accepts a well-formed response and rejects each broken field.
+        let request = read_fixture(include_str!("../../../tests/fixtures/sntp-request.hex"));
+        let request = parse_ntp_packet(&request).unwrap().header;
+        let response = read_fixture(include_str!("../../../tests/fixtures/sntp-response.hex"));
+        let response = parse_ntp_packet(&response).unwrap().header;
+        check_sntp_response(&request, &response).unwrap();
+
+        // Every check rejects what it should, so that the checks below mean something.
+        let broken: [fn(&mut NtpPacketHeader); 7] = [
+            |header| header.leap_indicator = Unknown,
+            |header| header.version = 3,
+            |header| header.mode = PacketMode::Client,
+            |header| header.stratum = 0,
+            |header| header.stratum = 16,
+            |header| header.transmit_timestamp = 0,
+            |header| header.origin_timestamp += 1,
+        ];
+        for breaks in broken.iter() {
+            let mut response = response;
+            breaks(&mut response);
+            check_sntp_response(&request, &response).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_sntp_compatibility() {
+        let requests = [
+            read_fixture(include_str!("../../../tests/fixtures/sntp-request.hex")),
+            read_fixture(include_str!("../../../tests/fixtures/sntp-request-v3.hex")),
+        ];
+        for request in requests.iter() {
+            let request_header = parse_ntp_packet(request).unwrap().header;
+            for &kind in &[ListenerKind::Mixed, ListenerKind::PlainOnly] {
+                let response = test_response(request, kind);
+                assert!(response.exts.is_empty());
+                if let Err(error) = check_sntp_response(&request_header, &response.header) {
+                    panic!("an SNTP client would drop the response to {:x?}: {}", request, error);
+                }
+            }
+        }
+    }
+
+    /// Serve a pre-bound socket the way `start_ntp_server_with_socket` does, and return the
+    /// response to a plain request.
+    fn query_pre_bound_socket(require_nts: bool) -> NtpPacket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let mut config = NtpServerConfig::new(
+            CookieKey::from(&[0x07; 32][..]),
+            String::from("memcache://127.0.0.1:11211"),
+            None,
+            None,
+        );
+        config.set_logger(NullLoggerBuilder.build().unwrap());
+        config.require_nts = require_nts;
+        thread::spawn(move || {
+            let sockets = vec![(socket, nts_listener_kind(&config))];
+            serve(&config, sockets, test_keys(), test_servstate()).unwrap();
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let query = serialize_ntp_packet(NtpPacket {
+            header: test_query_header(),
+            exts: vec![],
+        });
+        client.send_to(&query, addr).unwrap();
+
+        let mut buf = [0; BUF_SIZE];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, addr);
+        parse_ntp_packet(&buf[..len]).unwrap()
+    }
+
+    #[test]
+    fn test_serve_pre_bound_socket() {
+        let resp = query_pre_bound_socket(false);
+        assert!(!is_kiss_of_death(&resp));
+        assert_eq!(resp.header.mode, PacketMode::Server);
+        assert_eq!(resp.header.origin_timestamp, test_query_header().transmit_timestamp);
+
+        // When NTS is required, the socket is as strict as the NTS address.
+        assert!(is_kiss_of_death(&query_pre_bound_socket(true)));
+    }
+
+    #[test]
+    fn test_unsupported_version_and_mode() {
+        let logger = NullLoggerBuilder.build().unwrap();
+        let respond = |first: u8| {
+            let mut query = serialize_header(test_query_header());
+            query[0] = first;
+            let (listener, request) = (test_listener(ListenerKind::Mixed), test_request());
+            response(&query, &request, test_keys(), test_servstate(), logger.clone(), &listener)
+        };
+        // Version 0 and mode 0 are dropped without an answer, not even a Kiss of Death.
+        for &first in &[0x03, 0x20] {
+            let error = respond(first).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+        assert!(respond(0x23).is_ok());
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut header = test_query_header();
+        header.transmit_timestamp = 0;
+        let zero_transmit = serialize_header(header);
+        header.transmit_timestamp = 0x0102030405060708;
+        header.version = 5;
+        let newer_version = serialize_header(header);
+        let valid = serialize_header(test_query_header());
+
+        let respond = |query: &[u8], strict| {
+            let servstate = test_servstate();
+            servstate.write().unwrap().strict = strict;
+            let logger = NullLoggerBuilder.build().unwrap();
+            let listener = test_listener(ListenerKind::Mixed);
+            let resp = response(query, &test_request(), test_keys(), servstate, logger, &listener);
+            parse_ntp_packet(&resp.unwrap()).unwrap()
+        };
+
+        // By default, both requests are answered.
+        assert_eq!(respond(&zero_transmit, false).header.stratum, 1);
+        assert_eq!(respond(&newer_version, false).header.stratum, 1);
+
+        // In strict mode, they get a Kiss of Death, but a valid request is still answered.
+        assert!(is_kiss(&respond(&zero_transmit, true), KISS_RSTR));
+        assert!(is_kiss(&respond(&newer_version, true), KISS_RSTR));
+        assert_eq!(respond(&valid, true).header.stratum, 1);
+        assert_eq!(respond(&test_nts_query(test_cookie()), true).header.stratum, 1);
+    }
+
+    #[test]
+    fn test_transmit_timestamp() {
+        // The request was received a while ago, as if the server took that long to process it.
+        let received = SystemTime::now() - Duration::from_millis(100);
+
+        let plain = serialize_header(test_query_header());
+        let nts = test_nts_query(test_cookie());
+        for query in [plain, nts].iter() {
+            let resp = test_response_received(query, ListenerKind::Mixed, received);
+            assert!(!is_kiss_of_death(&resp));
+            assert_eq!(resp.header.receive_timestamp, ntp_timestamp(received));
+
+            let receive = NtpTimestamp::from(resp.header.receive_timestamp).to_system_time();
+            let transmit = NtpTimestamp::from(resp.header.transmit_timestamp).to_system_time();
+            let residence = transmit.duration_since(receive).unwrap();
+            assert!(residence >= Duration::from_millis(99));
+        }
+    }
+
+    #[test]
+    fn test_accepted_aead() {
+        let query = test_nts_query(test_cookie());
+        let resp = test_response(&query, ListenerKind::Mixed);
+        assert!(!is_kiss_of_death(&resp));
+        assert_eq!(resp.header.origin_timestamp, test_query_header().transmit_timestamp);
+
+        // A cookie for an algorithm which is not accepted.
+        let logger = NullLoggerBuilder.build().unwrap();
+        let listener =
+            ListenerConfig { accepted_aead: Vec::new(), ..test_listener(ListenerKind::Mixed) };
+        let resp =
+            response(&query, &test_request(), test_keys(), test_servstate(), logger, &listener);
+        assert!(is_kiss_of_death(&parse_ntp_packet(&resp.unwrap()).unwrap()));
+    }
+
+    #[test]
+    fn test_cookies_bound_to_subnet() {
+        let keys = test_keys();
+        let (key_id, key) = {
+            let rotator = keys.read().unwrap();
+            let (key_id, key) = rotator.latest_key_value();
+            (key_id, *key)
+        };
+        let nts_keys = NTSKeys {
+            c2s: [1; 32],
+            s2c: [2; 32],
+        };
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let context = subnet_context(client);
+        let aead = KnownAeadAlgorithm::AeadAesSivCmac256;
+        let cookie = make_cookie_with_context(nts_keys, aead, key.as_ref(), key_id, &context);
+        let servstate = test_servstate();
+        servstate.write().unwrap().bind_cookies_to_subnet = true;
+        let respond = |query: &[u8], ip: &str| {
+            let logger = NullLoggerBuilder.build().unwrap();
+            let client_ip = Some(ip.parse().unwrap());
+            let request = RequestContext { client_ip, ..test_request() };
+            let listener = test_listener(ListenerKind::Mixed);
+            let servstate = servstate.clone();
+            let resp = response(query, &request, keys.clone(), servstate, logger, &listener);
+            resp.unwrap()
+        };
+
+        // The cookie is accepted from the subnet it was made for, and the new cookie is bound
+        // to the same subnet.
+        let resp = respond(&test_nts_query(cookie.clone()), "192.0.2.7");
+        assert!(!is_kiss_of_death(&parse_ntp_packet(&resp).unwrap()));
+        let packet = parse_nts_packet(&resp, &mut Aes128SivAead::new(&[2; 32])).unwrap();
+        let new_cookie = &packet.auth_enc_exts[0].contents;
+        assert!(eat_cookie_with_context(new_cookie, key.as_ref(), &context).is_some());
+        assert!(eat_cookie_with_context(new_cookie, key.as_ref(), &[]).is_none());
+
+        // A cookie presented from another subnet is rejected.
+        let resp = respond(&test_nts_query(cookie), "198.51.100.1");
+        assert!(is_kiss_of_death(&parse_ntp_packet(&resp).unwrap()));
+
+        // So is a cookie which is not bound to any subnet.
+        let resp = respond(&test_nts_query(test_cookie()), "192.0.2.1");
+        assert!(is_kiss_of_death(&parse_ntp_packet(&resp).unwrap()));
+    }
+
+    #[test]
+    fn test_rejections_look_alike() {
+        let logger = NullLoggerBuilder.build().unwrap();
+        let reject = |query: &[u8], accepted_aead: Vec<u16>| {
+            let listener = ListenerConfig { accepted_aead, ..test_listener(ListenerKind::Mixed) };
+            let resp = response(
+                query, &test_request(), test_keys(), test_servstate(), logger.clone(), &listener,
+            )
+            .unwrap();
+            assert!(is_kiss_of_death(&parse_ntp_packet(&resp).unwrap()));
+            resp
+        };
+        let with_key_id = |key_id: KeyId| {
+            let mut cookie = test_cookie();
+            cookie[..4].copy_from_slice(&key_id.to_be_bytes());
+            cookie
+        };
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let period = now / 3600 * 3600;
+        let mut undecryptable = test_cookie();
+        undecryptable[20] ^= 1;
+        let wrong_keys = NtsPacket {
+            header: test_query_header(),
+            auth_exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: vec![0; 32],
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: test_cookie(),
+                },
+            ],
+            auth_enc_exts: vec![],
+        };
+
+        let queries = vec![
+            // A cookie of the wrong size.
+            test_nts_query(vec![0; COOKIE_SIZE - 8]),
+            // An expired key, a key from the future, and a key we don't have.
+            test_nts_query(with_key_id(KeyId::new(2))),
+            test_nts_query(with_key_id(KeyId::from_epoch(period + 10 * 3600))),
+            test_nts_query(with_key_id(KeyId::from_epoch(period))),
+            // A cookie failing the authentication.
+            test_nts_query(undecryptable),
+            // A valid cookie, but a request failing the authentication.
+            serialize_nts_packet(wrong_keys, &mut Aes128SivAead::new(&[3; 32])),
+        ];
+        // Whatever the reason, the response is the same, byte for byte.
+        let mut responses: Vec<Vec<u8>> =
+            queries.iter().map(|query| reject(query, vec![AEAD])).collect();
+        // A cookie for an algorithm we don't accept.
+        responses.push(reject(&test_nts_query(test_cookie()), Vec::new()));
+        for resp in responses.iter() {
+            assert_eq!(resp, &responses[0]);
+        }
+    }
+
+    #[test]
+    fn test_short_cookie() {
+        let query = serialize_ntp_packet(NtpPacket {
+            header: test_query_header(),
+            exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: vec![0; 32],
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: vec![0; 4],
+                },
+                NtpExtension {
+                    ext_type: NTSAuthenticator,
+                    contents: vec![0; 36],
+                },
+            ],
+        });
+
+        let malformed = MALFORMED_COOKIE_COUNTER.get();
+        assert!(is_kiss_of_death(&test_response(&query, ListenerKind::Mixed)));
+        // The cookie is rejected as malformed before any decryption is attempted.
+        assert!(MALFORMED_COOKIE_COUNTER.get() > malformed);
+    }
+
+    #[test]
+    fn test_enormous_ciphertext() {
+        // The authenticator claims the largest possible ciphertext.
+        let mut authenticator = vec![0x00, 0x10, 0xff, 0xfc];
+        authenticator.extend_from_slice(&[0; 32]);
+
+        let query = serialize_ntp_packet(NtpPacket {
+            header: test_query_header(),
+            exts: vec![
+                NtpExtension {
+                    ext_type: UniqueIdentifier,
+                    contents: vec![0; 32],
+                },
+                NtpExtension {
+                    ext_type: NTSCookie,
+                    contents: test_cookie(),
+                },
+                NtpExtension {
+                    ext_type: NTSAuthenticator,
+                    contents: authenticator,
+                },
+            ],
+        });
+
+        assert!(is_kiss_of_death(&test_response(&query, ListenerKind::Mixed)));
+    }
+
+    #[test]
+    fn test_nts_request_on_plain_only_address() {
+        let query = serialize_ntp_packet(NtpPacket {
+            header: test_query_header(),
+            exts: vec![NtpExtension {
+                ext_type: NTSCookie,
+                contents: vec![0; COOKIE_SIZE],
+            }],
+        });
+
+        assert!(is_kiss_of_death(&test_response(&query, ListenerKind::PlainOnly)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_kernel_timestamp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        setsockopt(server.as_raw_fd(), sockopt::ReceiveTimestamp, &true).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let before = SystemTime::now();
+        client.send_to(&[0; 48], server.local_addr().unwrap()).unwrap();
+
+        let mut buf = [0; BUF_SIZE];
+        let mut cmsgspace: CmsgSpace<TimeVal> = CmsgSpace::new();
+        let iov = [IoVec::from_mut_slice(&mut buf)];
+        let r = recvmsg(server.as_raw_fd(), &iov, Some(&mut cmsgspace), MsgFlags::empty())
+            .unwrap();
+        let after = SystemTime::now();
+
+        let mut r_time = None;
+        for msg in r.cmsgs() {
+            if let ControlMessage::ScmTimestamp(&r_timestamp) = msg {
+                r_time = Some(timeval_to_system_time(r_timestamp));
+            }
+        }
+        let r_time = r_time.expect("no timestamp control message");
+
+        // The timestamp has a microsecond resolution.
+        let resolution = Duration::from_micros(1);
+        assert!(r_time + resolution >= before);
+        assert!(r_time <= after);
+    }
+
+    fn upstream_header(stratum: u8) -> NtpPacketHeader {
+        let mut header = test_query_header();
+        header.mode = PacketMode::Server;
+        header.stratum = stratum;
+        header.poll = 6;
+        header.reference_id = 0x7f000001;
+        header
+    }
+
+    #[test]
+    fn test_upstream_stratum_ceiling() {
+        let servstate = test_servstate();
+        let mut state = servstate.write().unwrap();
+        let now = SystemTime::now();
+
+        apply_upstream(&mut state, &upstream_header(2), now, 0.0);
+        assert_eq!(state.leap, NoLeap);
+        assert_eq!(state.stratum, 3);
+        assert_eq!(state.poll, 6);
+        assert_eq!(state.refid, 0x7f000001);
+
+        for &stratum in &[15, 16, 255, 0] {
+            apply_upstream(&mut state, &upstream_header(stratum), now, 0.0);
+            assert_eq!(state.leap, LeapState::Unknown);
+            assert_eq!(state.stratum, 16);
+        }
+
+        apply_upstream(&mut state, &upstream_header(14), now, 0.0);
+        assert_eq!(state.leap, NoLeap);
+        assert_eq!(state.stratum, 15);
+    }
+
+    #[test]
+    fn test_root_delay_and_dispersion() {
+        let mut header = upstream_header(2);
+        header.precision = -20;
+        header.root_delay = 0x0000_8000; // 0.5s
+        header.root_dispersion = 0x0000_1000; // 1/16s
+        header.receive_timestamp = 0x0000_0010_0000_0000;
+        header.transmit_timestamp = 0x0000_0010_4000_0000;
+
+        // The upstream took a quarter of a second to answer.
+        assert_eq!(upstream_delay(&header, Duration::from_secs(1)), 0.75);
+        assert_eq!(upstream_delay(&header, Duration::from_millis(100)), 0.0);
+
+        assert_eq!(root_delay(&header, 0.25), 0x0000_c000);
+        // 1/16s, the precision of both clocks and the drift during the round trip, rounded up.
+        assert_eq!(root_dispersion(&header, 0.25), 0x0000_1001);
+
+        let servstate = test_servstate();
+        let mut state = servstate.write().unwrap();
+        let now = SystemTime::now();
+        apply_upstream(&mut state, &header, now, 0.25);
+        assert_eq!(state.root_delay, 0x0000_c000);
+        assert_eq!(state.root_dispersion, 0x0000_1001);
+
+        // The dispersion grows with the time since the measurement.
+        let later = now + Duration::from_secs(1000);
+        assert_eq!(fix_dispersion(0x0001_0000, later, now), 0x0001_03d8);
+        assert_eq!(fix_dispersion(0x0001_0000, now, later), 0x0001_0000);
+    }
+
+    #[test]
+    fn test_refclock() {
+        // The process disciplining the clock last wrote the file 16 seconds ago.
+        let unix_secs = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let updated = SystemTime::UNIX_EPOCH + Duration::new(unix_secs - 16, 500_000_000);
+        let path = env::temp_dir().join("cfnts-test-server-refclock");
+        fs::write(&path, format!("{}.5\n", unix_secs - 16)).unwrap();
+        let servstate = test_servstate();
+        let refid = 0x4750_5300; // "GPS"
+        apply_refclock(&mut servstate.write().unwrap(), refid, read_refclock(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let logger = NullLoggerBuilder.build().unwrap();
+        let query = serialize_header(test_query_header());
+        let listener = test_listener(ListenerKind::Mixed);
+        let resp = response(&query, &test_request(), test_keys(), servstate, logger, &listener);
+        let resp = parse_ntp_packet(&resp.unwrap()).unwrap();
+        assert_eq!(resp.header.leap_indicator, NoLeap);
+        assert_eq!(resp.header.stratum, 1);
+        assert_eq!(resp.header.reference_id, refid);
+        assert_eq!(resp.header.reference_timestamp, ntp_timestamp(updated));
+        assert_eq!(resp.header.root_delay, 0);
+        // The dispersion grew for the 16 seconds since the update.
+        let precision = ntp_short_from_secs(2f64.powi(i32::from(PRECISION)));
+        assert!(resp.header.root_dispersion > precision);
+    }
+
+    #[test]
+    fn test_upstream_poll() {
+        let logger = NullLoggerBuilder.build().unwrap();
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        upstream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let poll = Duration::from_millis(300);
+
+        let servstate = test_servstate();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let refreshed = servstate.clone();
+        thread::spawn(move || refresh_servstate(refreshed, logger, sock, &upstream_addr, poll));
+
+        let mut query_times = Vec::new();
+        let mut buff = [0; 2048];
+        for _ in 0..3 {
+            let (_, client) = upstream.recv_from(&mut buff).unwrap();
+            query_times.push(std::time::Instant::now());
+            upstream.send_to(&serialize_header(upstream_header(15)), client).unwrap();
+        }
+        for pair in query_times.windows(2) {
+            assert!(pair[1] - pair[0] >= poll);
+        }
+        assert_eq!(servstate.read().unwrap().stratum, 16);
     }
 }
@@ -1,19 +1,28 @@
-use slog::{debug, info};
+use lazy_static::lazy_static;
+use prometheus::{opts, register_int_counter_vec, IntCounterVec};
+use slog::{debug, info, warn};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
+use std::io;
 use std::io::{Read, Write};
-use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
 use rustls;
+use rustls::Session;
+use untrusted;
 use webpki;
 use webpki_roots;
 
+use super::proxy::Proxy;
 use super::records;
+use super::transcript::Transcript;
 
 use self::ClientError::*;
-use crate::cookie::NTSKeys;
+use crate::cookie::{NTSKeys, Redacted};
 use crate::nts_ke::records::{
     deserialize,
 
@@ -30,7 +39,6 @@ use crate::nts_ke::records::{
     KeRecordTrait,
     // Enums.
     KnownAeadAlgorithm,
-    KnownNextProtocol,
     NextProtocolRecord,
 
     Party,
@@ -45,28 +53,172 @@ type Cookie = Vec<u8>;
 const DEFAULT_NTP_PORT: u16 = 123;
 const DEFAULT_KE_PORT: u16 = 1234;
 const DEFAULT_SCHEME: u16 = 0;
+/// NTPv4, the protocol we negotiate by default.
+const DEFAULT_NEXT_PROTOCOL: u16 = 0;
 const TIMEOUT: Duration = Duration::from_secs(15);
+/// The longest we wait between two attempts of a key exchange, however the backoff grows.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref WARNING_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "nts_ke_client_warnings_total",
+        "Number of Warning records received from NTS-KE servers, by the name of their code",
+        &["code"]
+    )
+    .unwrap();
+}
+
+/// Where the NTP server or port of a key exchange came from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NegotiationSource {
+    /// The server sent it in an NTPv4 Server Negotiation or Port Negotiation record.
+    Record,
+    /// The server didn't send it, so it's the NTS-KE host or the NTP port.
+    Default,
+}
+
+impl fmt::Display for NegotiationSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NegotiationSource::Record => write!(f, "negotiated"),
+            NegotiationSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// The address family of a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddrFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddrFamily {
+    /// Return the family of the address.
+    pub fn of(addr: &SocketAddr) -> AddrFamily {
+        match addr {
+            SocketAddr::V4(_) => AddrFamily::Ipv4,
+            SocketAddr::V6(_) => AddrFamily::Ipv6,
+        }
+    }
+}
+
+impl fmt::Display for AddrFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddrFamily::Ipv4 => write!(f, "IPv4"),
+            AddrFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// How often to retry a key exchange failing with a transient error, e.g. a refused connection
+/// or a timeout. Other errors, like an invalid certificate or an Error record, are not retried.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// How long to wait before the second attempt.
+    pub initial_backoff: Duration,
+    /// The factor the wait grows by after each attempt. Values below 1 are treated as 1.
+    pub multiplier: f64,
+}
+
+impl RetryPolicy {
+    /// Return the wait after `backoff`, up to `MAX_BACKOFF`.
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        let secs = backoff.as_secs_f64() * self.multiplier.max(1.0);
+        if secs < MAX_BACKOFF.as_secs_f64() {
+            Duration::from_secs_f64(secs)
+        } else {
+            MAX_BACKOFF
+        }
+    }
+}
+
+/// Return true if the error of a key exchange may go away by itself, so the exchange is worth
+/// retrying.
+fn is_transient(error: &(dyn Error + 'static)) -> bool {
+    match error.downcast_ref::<io::Error>() {
+        Some(error) => match error.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::TimedOut
+            // A read timeout shows up as WouldBlock on Unix.
+            | io::ErrorKind::WouldBlock => true,
+            _ => false,
+        },
+        None => false,
+    }
+}
 
 #[derive(Clone, Debug)]
 struct ClientState {
     finished: bool,
     cookies: Vec<Cookie>,
+    /// The next protocol ids we advertised, in order of preference.
+    advertised_protocols: Vec<u16>,
     next_protocols: Vec<u16>,
+    /// The protocol id the keys are exported for, i.e. the protocol we negotiated.
+    next_protocol: u16,
     aead_scheme: u16,
     next_port: u16,
     next_server: String,
-    keys: NTSKeys,
+    next_port_source: NegotiationSource,
+    next_server_source: NegotiationSource,
+    /// The codes of the Warning records, in the order they were received.
+    warnings: Vec<u16>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct NtsKeResult {
     pub cookies: Vec<Cookie>,
     pub next_protocols: Vec<u16>,
     pub aead_scheme: u16,
     pub next_server: String,
     pub next_port: u16,
+    /// Whether `next_server` was negotiated or defaulted to the NTS-KE host.
+    pub next_server_source: NegotiationSource,
+    /// Whether `next_port` was negotiated or defaulted to the NTP port.
+    pub next_port_source: NegotiationSource,
     pub keys: NTSKeys,
+    /// The address family requested in the config, if any. See `ke_addr_family` for the one
+    /// actually used.
     pub use_ipv4: Option<bool>,
+    /// The address family of the connection to the NTS-KE server, which the resolver picks if
+    /// `use_ipv4` is not set. Through a proxy, it's the family of the connection to the proxy.
+    pub ke_addr_family: AddrFamily,
+    /// The local port the NTP queries are sent from, if not an ephemeral one.
+    pub source_port: Option<u16>,
+    /// The codes of the Warning records the server sent. They don't fail the exchange.
+    pub warnings: Vec<u16>,
+    /// Cookies saved from an earlier exchange, to use once `cookies` runs out. They keep the keys
+    /// they were issued with, which are not `keys`.
+    pub cached: Option<CachedCookies>,
+}
+
+// The result is logged in debug mode, so the cookies must not be printed as is.
+impl fmt::Debug for NtsKeResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cookies: Vec<Redacted> =
+            self.cookies.iter().map(|cookie| Redacted(&cookie[..])).collect();
+        f.debug_struct("NtsKeResult")
+            .field("cookies", &cookies)
+            .field("next_protocols", &self.next_protocols)
+            .field("aead_scheme", &self.aead_scheme)
+            .field("next_server", &self.next_server)
+            .field("next_port", &self.next_port)
+            .field("next_server_source", &self.next_server_source)
+            .field("next_port_source", &self.next_port_source)
+            .field("keys", &self.keys)
+            .field("use_ipv4", &self.use_ipv4)
+            .field("ke_addr_family", &self.ke_addr_family)
+            .field("source_port", &self.source_port)
+            .field("warnings", &self.warnings)
+            .field("cached", &self.cached)
+            .finish()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -74,8 +226,23 @@ pub enum ClientError {
     RecordAfterEnd,
     ErrorRecord,
     InvalidRecord,
+    UnknownCriticalRecord,
     NoIpv4AddrFound,
     NoIpv6AddrFound,
+    InvalidHostname(String),
+    KeyExportFailed(String),
+    /// The server chose next protocols none of which we advertised.
+    UnadvertisedNextProtocol(Vec<u16>),
+    /// The server sent an empty AEAD Algorithm Negotiation record, i.e. it supports none of the
+    /// algorithms we advertised.
+    NoAgreedAeadAlgorithm,
+    /// The server didn't send an AEAD Algorithm Negotiation record, so there is no algorithm to
+    /// export the keys for.
+    MissingAeadAlgorithm,
+    /// The server doesn't answer the TLS handshake with TLS.
+    NotTlsEndpoint(String),
+    /// The certificate of the server is valid for none of the accepted names.
+    UnacceptedCertificate(Vec<String>),
 }
 
 impl std::error::Error for ClientError {
@@ -91,10 +258,85 @@ impl std::error::Error for ClientError {
 
 impl std::fmt::Display for ClientError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Client Error")
+        match self {
+            InvalidHostname(host) => write!(f, "server hostname is invalid: {}", host),
+            KeyExportFailed(reason) => write!(f, "failed to export the NTS keys: {}", reason),
+            UnadvertisedNextProtocol(protocol_ids) => write!(
+                f,
+                "server chose next protocols {:?} which were not advertised",
+                protocol_ids,
+            ),
+            NoAgreedAeadAlgorithm => {
+                write!(f, "server supports none of the advertised AEAD algorithms")
+            }
+            MissingAeadAlgorithm => write!(f, "server didn't choose an AEAD algorithm"),
+            NotTlsEndpoint(reason) => write!(
+                f,
+                "{}, the port may not be an NTS-KE endpoint, which speaks TLS",
+                reason,
+            ),
+            UnacceptedCertificate(names) => {
+                write!(f, "server certificate is valid for none of {:?}", names)
+            }
+            _ => write!(f, "Client Error"),
+        }
     }
 }
 
+/// Validate the hostname of the server, which is used for the SNI and the certificate
+/// validation.
+fn dns_name(host: &str) -> Result<webpki::DNSNameRef, ClientError> {
+    webpki::DNSNameRef::try_from_ascii_str(host).map_err(|_| InvalidHostname(host.to_string()))
+}
+
+/// Check that the certificate of the server is valid for at least one of the accepted names.
+/// rustls has already validated it against the trust anchors and the SNI name.
+fn check_accepted_names<T: Session>(
+    session: &T,
+    accepted_names: &[String],
+) -> Result<(), ClientError> {
+    let certs = session.get_peer_certificates().unwrap_or_default();
+    let end_entity = match certs.first() {
+        Some(cert) => webpki::EndEntityCert::from(untrusted::Input::from(&cert.0)),
+        None => return Err(UnacceptedCertificate(accepted_names.to_vec())),
+    };
+    if let Ok(end_entity) = end_entity {
+        for name in accepted_names {
+            if end_entity.verify_is_valid_for_dns_name(dns_name(name)?).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+    Err(UnacceptedCertificate(accepted_names.to_vec()))
+}
+
+/// Export the NTS keys of the next protocol and the AEAD algorithm from the TLS session. NTS
+/// requires TLS 1.3, so the export is refused for an older version, and it fails if the
+/// handshake is not complete.
+fn export_keys<T: Session>(
+    session: &T,
+    protocol_id: u16,
+    aead: KnownAeadAlgorithm,
+) -> Result<NTSKeys, ClientError> {
+    if session.is_handshaking() {
+        return Err(KeyExportFailed(String::from("the TLS handshake is not complete")));
+    }
+    match session.get_protocol_version() {
+        Some(rustls::ProtocolVersion::TLSv1_3) => {}
+        version => {
+            return Err(KeyExportFailed(format!("TLS 1.3 is required, not {:?}", version)));
+        }
+    }
+    records::gen_key(session, protocol_id, aead)
+        .map_err(|error| KeyExportFailed(format!("{:?}", error)))
+}
+
+/// Return the AEAD algorithm the server chose. `process_record` only accepts an algorithm we know,
+/// so it's missing only if the server sent no AEAD Algorithm Negotiation record.
+fn negotiated_aead(state: &ClientState) -> Result<KnownAeadAlgorithm, ClientError> {
+    KnownAeadAlgorithm::from_algorithm_id(state.aead_scheme).ok_or(MissingAeadAlgorithm)
+}
+
 /// Read https://tools.ietf.org/html/draft-ietf-ntp-using-nts-for-ntp-19#section-4
 fn process_record(
     record: records::KeRecord,
@@ -107,177 +349,1636 @@ fn process_record(
     match record {
         KeRecord::EndOfMessage(_) => state.finished = true,
         KeRecord::NextProtocol(record) => {
-            state.next_protocols = record
-                .protocols()
-                .iter()
-                .map(|protocol| protocol.as_protocol_id())
-                .collect();
+            // Keep the full list for diagnostics, even if it's invalid.
+            state.next_protocols = record.protocol_ids().to_vec();
+
+            // The server must choose one of the protocols we advertised.
+            let advertised = &state.advertised_protocols;
+            match state.next_protocols.iter().find(|protocol_id| advertised.contains(protocol_id)) {
+                Some(&protocol_id) => state.next_protocol = protocol_id,
+                None => {
+                    return Err(Box::new(UnadvertisedNextProtocol(state.next_protocols.clone())));
+                }
+            }
         }
         KeRecord::Error(_) => return Err(Box::new(ErrorRecord)),
-        KeRecord::Warning(_) => return Ok(()),
-        KeRecord::AeadAlgorithm(record) => {
-            // TODO: Accessing at index zero can panic.
-            let algorithm = record.algorithms()[0];
-            state.aead_scheme = algorithm.as_algorithm_id();
-
-            if record.algorithms().len() != 1 {
-                return Err(Box::new(InvalidRecord));
+        KeRecord::Warning(record) => {
+            WARNING_COUNTER.with_label_values(&[record.code_name()]).inc();
+            state.warnings.push(record.code());
+        }
+        KeRecord::AeadAlgorithm(record) => match record.algorithm_ids() {
+            // The server answers with an empty record, if there is no algorithm we agree on.
+            [] => return Err(Box::new(NoAgreedAeadAlgorithm)),
+            [algorithm_id] if KnownAeadAlgorithm::from_algorithm_id(*algorithm_id).is_some() => {
+                state.aead_scheme = *algorithm_id;
             }
+            _ => return Err(Box::new(InvalidRecord)),
+        },
+        KeRecord::NewCookie(record) => {
+            // An empty cookie is useless, and sending the same cookie twice would link the
+            // queries, so keep only the distinct non-empty ones.
+            let cookie = record.into_bytes();
+            if !cookie.is_empty() && !state.cookies.contains(&cookie) {
+                state.cookies.push(cookie);
+            }
+        }
+        KeRecord::Server(record) => {
+            state.next_server = record.into_string();
+            state.next_server_source = NegotiationSource::Record;
+        }
+        KeRecord::Port(record) => {
+            state.next_port = record.port();
+            state.next_port_source = NegotiationSource::Record;
         }
-        KeRecord::NewCookie(record) => state.cookies.push(record.into_bytes()),
-        KeRecord::Server(record) => state.next_server = record.into_string(),
-        KeRecord::Port(record) => state.next_port = record.port(),
     }
 
     Ok(())
 }
 
-/// run_nts_client executes the nts client with the config in config file
-pub fn run_nts_ke_client(
+/// Deserialize a whole record received from the server and process it.
+fn process_record_bytes(
     logger: &slog::Logger,
-    client_config: ClientConfig,
-) -> Result<NtsKeResult, Box<dyn Error>> {
+    record_bytes: &[u8],
+    state: &mut ClientState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // `deserialize` has an invariant that the slice needs to be long enough to make it a
+    // valid record, which in this case our slice is exactly as long as specified in the
+    // length field.
+    match deserialize(Party::Client, record_bytes) {
+        Ok(record) => process_record(record, state),
+        Err(DeserializeError::UnknownNotCriticalRecord) => {
+            // If it's not critical, just ignore the error.
+            debug!(logger, "unknown record type");
+            Ok(())
+        }
+        Err(DeserializeError::UnknownCriticalRecord) => {
+            // According to the spec, the client must treat an unrecognized critical record
+            // as an error.
+            debug!(logger, "error: unknown critical record");
+            Err(Box::new(UnknownCriticalRecord))
+        }
+        Err(DeserializeError::Parsing(error)) => {
+            debug!(logger, "error: {}", error);
+            Err(Box::new(InvalidRecord))
+        }
+    }
+}
+
+/// Select the resolved addresses of the family we are allowed to use, keeping the order of the
+/// resolver.
+fn select_addrs<I: Iterator<Item = SocketAddr>>(
+    ip_addrs: I,
+    use_ipv4: Option<bool>,
+) -> Result<Vec<SocketAddr>, ClientError> {
+    let addrs: Vec<SocketAddr> = match use_ipv4 {
+        // mandated to use ipv4
+        Some(true) => ip_addrs.filter(|addr| addr.is_ipv4()).collect(),
+        // mandated to use ipv6
+        Some(false) => ip_addrs.filter(|addr| addr.is_ipv6()).collect(),
+        // sniff whichever one is supported
+        None => ip_addrs.collect(),
+    };
+    if addrs.is_empty() {
+        return Err(match use_ipv4 {
+            Some(false) => NoIpv6AddrFound,
+            _ => NoIpv4AddrFound,
+        });
+    }
+    Ok(addrs)
+}
+
+/// Try to connect to each address in turn until one succeeds. If all of them fail, return the
+/// error of the last one.
+fn connect_any<T, F>(addrs: &[SocketAddr], mut connect: F) -> io::Result<T>
+where
+    F: FnMut(&SocketAddr) -> io::Result<T>,
+{
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no address to connect");
+    for addr in addrs {
+        match connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+/// Build the trust anchors of the client. The trusted certificates, if given, are the only trust
+/// anchors. Otherwise the public roots are used, unless they are disabled in the config.
+fn root_store(
+    logger: &slog::Logger,
+    client_config: &ClientConfig,
+) -> Result<rustls::RootCertStore, Box<dyn Error>> {
+    let mut root_store = rustls::RootCertStore::empty();
+    match client_config.trusted_certs.as_slice() {
+        [] => {
+            if !client_config.use_system_roots {
+                return Err(Box::new(config::ConfigError::Message(String::from(
+                    "a trusted certificate is required when the system roots are disabled",
+                ))));
+            }
+            root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        }
+        certs => {
+            info!(logger, "loading {} custom trust roots", certs.len());
+            for cert in certs {
+                root_store.add(cert)?;
+            }
+        }
+    }
+    Ok(root_store)
+}
+
+/// Send the ClientHello of the session and check that the server starts its answer with a TLS
+/// record, without consuming it. Pointing the client at a plaintext port, e.g. of an HTTP
+/// server, otherwise fails the handshake with an opaque error.
+fn check_tls_endpoint(
+    session: &mut rustls::ClientSession,
+    stream: &mut TcpStream,
+) -> Result<(), Box<dyn Error>> {
+    // A server closing the connection right away may reset it too, depending on the timing.
+    let closed = || Box::new(NotTlsEndpoint(String::from("the server closed the connection")));
+    let is_closed = |error: &io::Error| match error.kind() {
+        io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset => true,
+        _ => false,
+    };
+
+    while session.wants_write() {
+        match session.write_tls(stream) {
+            Err(ref error) if is_closed(error) => return Err(closed()),
+            result => result?,
+        };
+    }
+
+    // A TLS record starts with its content type, handshake or alert, and the major version 3.
+    let mut first_bytes = [0; 16];
+    let length = match stream.peek(&mut first_bytes) {
+        Err(ref error) if is_closed(error) => return Err(closed()),
+        result => result?,
+    };
+    let first_bytes = &first_bytes[..length];
+    if first_bytes.is_empty() {
+        return Err(closed());
+    }
+    let is_tls = (first_bytes[0] == 0x15 || first_bytes[0] == 0x16)
+        && first_bytes.get(1).map_or(true, |&major_version| major_version == 3);
+    if !is_tls {
+        return Err(Box::new(NotTlsEndpoint(format!(
+            "the server answered with plaintext \"{}\"",
+            String::from_utf8_lossy(first_bytes).escape_debug(),
+        ))));
+    }
+    Ok(())
+}
+
+/// A TLS session with an NTS-KE server. Dropping it closes the session cleanly, whether the
+/// exchange succeeded or not: a close_notify alert is sent before the TCP connection is shut
+/// down, as strict servers otherwise log a truncated session.
+struct KeConnection {
+    session: rustls::ClientSession,
+    stream: TcpStream,
+}
+
+impl KeConnection {
+    /// Drive the session over the TCP connection.
+    fn tls_stream(&mut self) -> rustls::Stream<rustls::ClientSession, TcpStream> {
+        rustls::Stream::new(&mut self.session, &mut self.stream)
+    }
+}
+
+impl Drop for KeConnection {
+    fn drop(&mut self) {
+        self.session.send_close_notify();
+        // The connection may be broken already, in which case there's nothing left to close.
+        while self.session.wants_write() {
+            if self.session.write_tls(&mut self.stream).is_err() {
+                break;
+            }
+        }
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// Open the TLS session with the NTS-KE server in the config, and check that the server speaks
+/// TLS. The session still has to be driven, e.g. with `KeConnection::tls_stream`.
+fn connect(
+    logger: &slog::Logger,
+    client_config: &ClientConfig,
+) -> Result<KeConnection, Box<dyn Error>> {
     let mut tls_config = rustls::ClientConfig::new();
     let alpn_proto = String::from("ntske/1");
     let alpn_bytes = alpn_proto.into_bytes();
     tls_config.set_protocols(&[alpn_bytes]);
 
-    match client_config.trusted_cert {
-        Some(cert) => {
-            info!(logger, "loading custom trust root");
-            tls_config.root_store.add(&cert)?;
-        }
-        None => {
-            tls_config
-                .root_store
-                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
-        }
-    }
+    tls_config.root_store = root_store(logger, &client_config)?;
 
     let rc_config = Arc::new(tls_config);
-    let hostname = webpki::DNSNameRef::try_from_ascii_str(client_config.host.as_str())
-        .expect("server hostname is invalid");
+    // The host is only resolved and connected to, if another name is given for the TLS session.
+    let server_name = client_config.sni_name.as_ref().unwrap_or(&client_config.host);
+    let hostname = dns_name(server_name.as_str())?;
     let mut client = rustls::ClientSession::new(&rc_config, hostname);
     debug!(logger, "Connecting");
     let mut port = DEFAULT_KE_PORT;
-    if let Some(p) = client_config.port {
+    if let Some(ref p) = client_config.port {
         port = p.parse::<u16>()?;
     }
 
-    let mut ip_addrs = (client_config.host.as_str(), port).to_socket_addrs()?;
-    let addr;
-    if let Some(use_ipv4) = client_config.use_ipv4 {
-        if use_ipv4 {
-            // mandated to use ipv4
-            addr = ip_addrs.find(|&x| x.is_ipv4());
-            if addr == None {
-                return Err(Box::new(NoIpv4AddrFound));
-            }
-        } else {
-            // mandated to use ipv6
-            addr = ip_addrs.find(|&x| x.is_ipv6());
-            if addr == None {
-                return Err(Box::new(NoIpv6AddrFound));
-            }
+    let mut stream = match client_config.proxy_url {
+        Some(ref url) => {
+            // The proxy resolves the hostname, while the TLS session still validates the
+            // certificate against the real hostname. The address family only applies to the
+            // connection to the proxy.
+            let proxy = Proxy::parse(url)?;
+            debug!(logger, "connecting through proxy {:?}", proxy);
+            proxy.connect(client_config.host.as_str(), port, TIMEOUT, client_config.use_ipv4)?
         }
-    } else {
-        // sniff whichever one is supported
-        addr = ip_addrs.next();
-    }
-    let mut stream = TcpStream::connect_timeout(&addr.unwrap(), TIMEOUT)?;
+        None => {
+            let ip_addrs = (client_config.host.as_str(), port).to_socket_addrs()?;
+            let addrs = select_addrs(ip_addrs, client_config.use_ipv4)?;
+            connect_any(&addrs, |addr| {
+                debug!(logger, "trying {}", addr);
+                TcpStream::connect_timeout(addr, TIMEOUT)
+            })?
+        }
+    };
     stream.set_read_timeout(Some(TIMEOUT))?;
     stream.set_write_timeout(Some(TIMEOUT))?;
+    check_tls_endpoint(&mut client, &mut stream)?;
+    if !client_config.accepted_names.is_empty() {
+        // The certificate is only known once the handshake is over.
+        client.complete_io(&mut stream)?;
+        check_accepted_names(&client, &client_config.accepted_names)?;
+    }
+    Ok(KeConnection {
+        session: client,
+        stream,
+    })
+}
 
-    let mut tls_stream = rustls::Stream::new(&mut client, &mut stream);
+/// Serialize a critical record of the given type whose body is a list of ids. Both the Next
+/// Protocol Negotiation and the AEAD Algorithm Negotiation records are critical.
+///
+/// # Panics
+///
+/// If there are too many ids for the length field.
+///
+fn id_list_record(record_type: u16, ids: &[u16]) -> Vec<u8> {
+    let first_word: u16 = (1 << 15) + record_type;
+    let mut record = Vec::from(&first_word.to_be_bytes()[..]);
+    // A truncated length would make the server read the rest of the body as other records.
+    let length = u16::try_from(ids.len())
+        .ok()
+        .and_then(|length| length.checked_mul(2))
+        .expect("too many ids for a record");
+    record.extend_from_slice(&length.to_be_bytes());
+    for id in ids {
+        record.extend_from_slice(&id.to_be_bytes());
+    }
+    record
+}
 
-    let next_protocol_record = NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]);
-    let aead_record = AeadAlgorithmRecord::from(vec![KnownAeadAlgorithm::AeadAesSivCmac256]);
-    let end_record = EndOfMessageRecord;
+/// Serialize an AEAD Algorithm Negotiation record advertising the given algorithm ids. Unlike
+/// `AeadAlgorithmRecord`, it takes any id, including the ones we don't implement.
+fn aead_record(algorithm_ids: &[u16]) -> Vec<u8> {
+    id_list_record(AeadAlgorithmRecord::record_type(), algorithm_ids)
+}
 
-    tls_stream.write(&serialize(next_protocol_record))?;
-    tls_stream.write(&serialize(aead_record))?;
-    tls_stream.write(&serialize(end_record))?;
-    tls_stream.flush()?;
+/// Serialize a Next Protocol Negotiation record advertising the given protocol ids, in order of
+/// preference. Unlike `NextProtocolRecord`, it takes any id.
+fn next_protocol_record(protocol_ids: &[u16]) -> Vec<u8> {
+    id_list_record(NextProtocolRecord::record_type(), protocol_ids)
+}
+
+/// Serialize the records of the request of the client, advertising the given next protocol ids
+/// and AEAD algorithm ids.
+fn request_records(protocol_ids: &[u16], algorithm_ids: &[u16]) -> Vec<Vec<u8>> {
+    vec![
+        next_protocol_record(protocol_ids),
+        aead_record(algorithm_ids),
+        serialize(EndOfMessageRecord),
+    ]
+}
+
+/// Send the request of the client, advertising the given next protocol ids and AEAD algorithm
+/// ids.
+fn write_request<W: Write>(
+    stream: &mut W,
+    protocol_ids: &[u16],
+    algorithm_ids: &[u16],
+) -> io::Result<()> {
+    write_records(stream, &request_records(protocol_ids, algorithm_ids))
+}
+
+/// Send the serialized records.
+fn write_records<W: Write>(stream: &mut W, records: &[Vec<u8>]) -> io::Result<()> {
+    for record in records {
+        stream.write_all(record)?;
+    }
+    stream.flush()
+}
+
+/// Read a whole record from the server.
+fn read_record<R: Read>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+
+    // We should use `read_exact` here because we always need to read 4 bytes to get the
+    // header.
+    stream.read_exact(&mut header[..])?;
+
+    // Retrieve a body length from the 3rd and 4th bytes of the header.
+    let body_length = u16::from_be_bytes([header[2], header[3]]);
+    let mut body = vec![0; body_length as usize];
+
+    // `read_exact` the length of the body.
+    stream.read_exact(body.as_mut_slice())?;
+
+    // Reconstruct the whole record byte array to let the `records` module deserialize it.
+    let mut record_bytes = Vec::from(&header[..]);
+    record_bytes.append(&mut body);
+    Ok(record_bytes)
+}
+
+/// Send the request and process the records of the response until the End of Message record,
+/// adding every record to the transcript.
+fn exchange<S: Read + Write>(
+    logger: &slog::Logger,
+    stream: &mut S,
+    algorithm_ids: &[u16],
+    state: &mut ClientState,
+    transcript: &mut Transcript,
+) -> Result<(), Box<dyn Error>> {
+    let request = request_records(&state.advertised_protocols, algorithm_ids);
+    for record in &request {
+        transcript.push(Party::Client, record);
+    }
+    write_records(stream, &request)?;
     debug!(logger, "Request transmitted");
-    let keys = records::gen_key(tls_stream.sess).unwrap();
+
+    while !state.finished {
+        let record_bytes = read_record(stream)?;
+        transcript.push(Party::Server, &record_bytes);
+        process_record_bytes(logger, &record_bytes, state)?;
+    }
+    Ok(())
+}
+
+/// run_nts_client executes the nts client with the config in config file
+pub fn run_nts_ke_client(
+    logger: &slog::Logger,
+    client_config: ClientConfig,
+) -> Result<NtsKeResult, Box<dyn Error>> {
+    run_nts_ke_client_with_transcript(logger, client_config, &mut Transcript::default())
+}
+
+/// Run the NTS-KE client like `run_nts_ke_client`, and add every record sent and received to
+/// `transcript`. The records up to a failure are there, even if the exchange fails. If the
+/// exchange is retried, only the records of the last attempt are kept.
+pub fn run_nts_ke_client_with_transcript(
+    logger: &slog::Logger,
+    client_config: ClientConfig,
+    transcript: &mut Transcript,
+) -> Result<NtsKeResult, Box<dyn Error>> {
+    let policy = client_config.retry_policy;
+    let max_attempts = policy.map_or(1, |policy| policy.max_attempts);
+    let mut backoff = policy.map_or(Duration::from_secs(0), |policy| policy.initial_backoff);
+    let mut attempt = 1;
+    loop {
+        transcript.records.clear();
+        match key_exchange(logger, &client_config, transcript) {
+            Err(ref error) if attempt < max_attempts && is_transient(&**error) => {
+                warn!(logger, "key exchange attempt {} failed: {}, retrying in {:?}",
+                      attempt, error, backoff);
+                thread::sleep(backoff);
+                // The policy is set, or there would be a single attempt.
+                backoff = policy.unwrap().next_backoff(backoff);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Run a single attempt of the key exchange.
+fn key_exchange(
+    logger: &slog::Logger,
+    client_config: &ClientConfig,
+    transcript: &mut Transcript,
+) -> Result<NtsKeResult, Box<dyn Error>> {
+    let mut connection = connect(logger, client_config)?;
+    let ke_addr = connection.stream.peer_addr()?;
+    debug!(logger, "connected to {} over {}", ke_addr, AddrFamily::of(&ke_addr));
+    let mut tls_stream = connection.tls_stream();
 
     let mut state = ClientState {
         finished: false,
         cookies: Vec::new(),
+        advertised_protocols: client_config.next_protocols.clone(),
         next_protocols: Vec::new(),
+        next_protocol: DEFAULT_NEXT_PROTOCOL,
         next_server: client_config.host.clone(),
         next_port: DEFAULT_NTP_PORT,
-        keys: keys,
+        next_server_source: NegotiationSource::Default,
+        next_port_source: NegotiationSource::Default,
         aead_scheme: DEFAULT_SCHEME,
+        warnings: Vec::new(),
     };
 
-    while state.finished == false {
-        let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
+    let algorithm_ids = [KnownAeadAlgorithm::AeadAesSivCmac256.as_algorithm_id()];
+    exchange(logger, &mut tls_stream, &algorithm_ids, &mut state, transcript)?;
+    debug!(logger, "saw the end of the response");
+    for code in &state.warnings {
+        warn!(logger, "the server sent warning {}", code);
+    }
+    // A server sending neither record leaves us talking NTP to the NTS-KE host, which surprises
+    // users of servers with a separate NTP endpoint.
+    debug!(logger, "NTP server {} ({}), port {} ({})",
+           state.next_server, state.next_server_source, state.next_port, state.next_port_source);
+
+    // The keys are bound to the negotiated protocol and AEAD algorithm, so they are exported only
+    // now. The handshake is over by the time the response arrives, but make sure it is.
+    let aead = negotiated_aead(&state)?;
+    if tls_stream.sess.is_handshaking() {
+        tls_stream.sess.complete_io(tls_stream.sock)?;
+    }
+    let keys = export_keys(&*tls_stream.sess, state.next_protocol, aead)?;
+
+    Ok(NtsKeResult {
+        aead_scheme: state.aead_scheme,
+        cookies: state.cookies,
+        next_protocols: state.next_protocols,
+        next_server: state.next_server,
+        next_port: state.next_port,
+        next_server_source: state.next_server_source,
+        next_port_source: state.next_port_source,
+        keys: keys,
+        use_ipv4: client_config.use_ipv4,
+        ke_addr_family: AddrFamily::of(&ke_addr),
+        source_port: client_config.source_port,
+        warnings: state.warnings,
+        cached: None,
+    })
+}
+
+/// Cookies saved from an earlier exchange, with the keys of that exchange.
+#[derive(Clone)]
+pub struct CachedCookies {
+    pub keys: NTSKeys,
+    pub cookies: Vec<Cookie>,
+}
+
+// Like the result, the cached cookies are never printed as is.
+impl fmt::Debug for CachedCookies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cookies: Vec<Redacted> =
+            self.cookies.iter().map(|cookie| Redacted(&cookie[..])).collect();
+        f.debug_struct("CachedCookies")
+            .field("keys", &self.keys)
+            .field("cookies", &cookies)
+            .finish()
+    }
+}
+
+/// Run the NTS-KE client like `run_nts_ke_client`, and keep the cached cookies, if any, in the
+/// result next to the fresh ones.
+///
+/// A cookie carries the keys of the exchange that issued it, and the server answers it with
+/// those keys. So the cached cookies can't join the fresh pool: they stay a separate pool with
+/// their own keys, to use once the fresh cookies run out.
+pub fn run_nts_ke_client_with_cookies(
+    logger: &slog::Logger,
+    client_config: ClientConfig,
+    cached: Option<CachedCookies>,
+) -> Result<NtsKeResult, Box<dyn Error>> {
+    let mut result = run_nts_ke_client(logger, client_config)?;
+    if let Some(cached) = cached {
+        add_cached_cookies(logger, &mut result, cached);
+    }
+    Ok(result)
+}
+
+/// Keep the cached cookies in the result, without the ones which are in the fresh pool already.
+fn add_cached_cookies(logger: &slog::Logger, result: &mut NtsKeResult, cached: CachedCookies) {
+    let cookies = dedup_cookies(&result.cookies, cached.cookies);
+    debug!(logger, "keeping {} cached cookies", cookies.len());
+    if !cookies.is_empty() {
+        result.cached = Some(CachedCookies { keys: cached.keys, cookies });
+    }
+}
+
+/// Return the cached cookies which are not in `cookies`, without duplicates, keeping their order.
+fn dedup_cookies(cookies: &[Cookie], cached_cookies: Vec<Cookie>) -> Vec<Cookie> {
+    let mut kept: Vec<Cookie> = Vec::new();
+    for cookie in cached_cookies {
+        if !cookies.contains(&cookie) && !kept.contains(&cookie) {
+            kept.push(cookie);
+        }
+    }
+    kept
+}
+
+/// Run one exchange advertising NTPv4 and only the AEAD algorithm with the given id, and return
+/// whether the server picked it. The records are inspected as raw bytes, so that an algorithm we
+/// don't implement can be recognized too.
+fn probe_aead<S: Read + Write>(stream: &mut S, algorithm_id: u16) -> io::Result<bool> {
+    write_request(stream, &[DEFAULT_NEXT_PROTOCOL], &[algorithm_id])?;
+
+    let mut accepted = false;
+    loop {
+        let record_bytes = read_record(stream)?;
+        let record_type = u16::from_be_bytes([record_bytes[0] & 0x7f, record_bytes[1]]);
+        let body = &record_bytes[HEADER_SIZE..];
+        if record_type == EndOfMessageRecord::record_type() {
+            return Ok(accepted);
+        } else if record_type == records::ErrorRecord::record_type() {
+            return Ok(false);
+        } else if record_type == AeadAlgorithmRecord::record_type() {
+            accepted = body == &algorithm_id.to_be_bytes()[..];
+        }
+    }
+}
 
-        // We should use `read_exact` here because we always need to read 4 bytes to get the
-        // header.
-        if let Err(error) = tls_stream.read_exact(&mut header[..]) {
-            return Err(Box::new(error));
+/// Run `probe` on each candidate and collect the ones it reports as accepted. A failing probe
+/// counts as not accepted.
+fn probe_candidates<F>(logger: &slog::Logger, candidates: &[u16], mut probe: F) -> Vec<u16>
+where
+    F: FnMut(u16) -> Result<bool, Box<dyn Error>>,
+{
+    let mut supported = Vec::new();
+    for &algorithm_id in candidates {
+        match probe(algorithm_id) {
+            Ok(true) => supported.push(algorithm_id),
+            Ok(false) => debug!(logger, "AEAD algorithm {} is not accepted", algorithm_id),
+            Err(error) => {
+                debug!(logger, "probing AEAD algorithm {} failed: {}", algorithm_id, error)
+            }
         }
+    }
+    supported
+}
+
+/// Find out which of the candidate AEAD algorithm ids the NTS-KE server in the config accepts.
+/// It runs a key exchange for each candidate, advertising only that one, so it's meant for
+/// diagnostics rather than regular use.
+pub fn probe_aead_support(
+    logger: &slog::Logger,
+    client_config: &ClientConfig,
+    candidates: &[u16],
+) -> Vec<u16> {
+    probe_candidates(logger, candidates, |algorithm_id| {
+        let mut connection = connect(logger, client_config)?;
+        Ok(probe_aead(&mut connection.tls_stream(), algorithm_id)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Retrieve a body length from the 3rd and 4th bytes of the header.
-        let body_length = u16::from_be_bytes([header[2], header[3]]);
-        let mut body = vec![0; body_length as usize];
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
 
-        // `read_exact` the length of the body.
-        if let Err(error) = tls_stream.read_exact(body.as_mut_slice()) {
-            return Err(Box::new(error));
+    use rustls::internal::pemfile::pkcs8_private_keys;
+
+    use crate::nts_ke::records::{KnownNextProtocol, NewCookieRecord, WarningRecord};
+    use crate::sub_command::client::{load_tls_certs, load_trusted_certs};
+
+    fn test_state() -> ClientState {
+        ClientState {
+            finished: false,
+            cookies: Vec::new(),
+            advertised_protocols: vec![DEFAULT_NEXT_PROTOCOL],
+            next_protocols: Vec::new(),
+            next_protocol: DEFAULT_NEXT_PROTOCOL,
+            aead_scheme: DEFAULT_SCHEME,
+            next_port: DEFAULT_NTP_PORT,
+            next_server: String::from("localhost"),
+            next_port_source: NegotiationSource::Default,
+            next_server_source: NegotiationSource::Default,
+            warnings: Vec::new(),
         }
+    }
 
-        // Reconstruct the whole record byte array to let the `records` module deserialize it.
-        let mut record_bytes = Vec::from(&header[..]);
-        record_bytes.append(&mut body);
+    fn test_result(cookies: Vec<Cookie>, keys: NTSKeys) -> NtsKeResult {
+        NtsKeResult {
+            cookies,
+            next_protocols: vec![DEFAULT_NEXT_PROTOCOL],
+            aead_scheme: DEFAULT_SCHEME,
+            next_server: String::from("localhost"),
+            next_port: DEFAULT_NTP_PORT,
+            next_server_source: NegotiationSource::Default,
+            next_port_source: NegotiationSource::Default,
+            keys,
+            use_ipv4: None,
+            ke_addr_family: AddrFamily::Ipv4,
+            source_port: None,
+            warnings: Vec::new(),
+            cached: None,
+        }
+    }
 
-        // `deserialize` has an invariant that the slice needs to be long enough to make it a
-        // valid record, which in this case our slice is exactly as long as specified in the
-        // length field.
-        match deserialize(Party::Client, record_bytes.as_slice()) {
-            Ok(record) => {
-                let status = process_record(record, &mut state);
-                match status {
-                    Ok(_) => {}
-                    Err(err) => {
-                        return Err(err);
-                    }
+    #[test]
+    fn test_next_protocol_without_ntpv4() {
+        let mut state = test_state();
+        // Only the protocol id 1 which we don't support.
+        let record = NextProtocolRecord::from_bytes(Party::Server, &[0x00, 0x01]).unwrap();
+
+        let error = process_record(KeRecord::NextProtocol(record), &mut state).unwrap_err();
+        match error.downcast_ref::<ClientError>() {
+            Some(UnadvertisedNextProtocol(protocol_ids)) => assert_eq!(protocol_ids, &vec![1]),
+            _ => panic!("a Next Protocol record without NTPv4 must be rejected"),
+        }
+        assert_eq!(error.to_string(), "server chose next protocols [1] which were not advertised");
+        assert_eq!(state.next_protocols, vec![1]);
+    }
+
+    #[test]
+    fn test_next_protocol_with_ntpv4() {
+        let mut state = test_state();
+        let record = NextProtocolRecord::from_bytes(Party::Server, &[0x00, 0x01, 0x00, 0x00])
+            .unwrap();
+
+        process_record(KeRecord::NextProtocol(record), &mut state).unwrap();
+        assert_eq!(state.next_protocols, vec![1, 0]);
+        assert_eq!(state.next_protocol, 0);
+    }
+
+    #[test]
+    fn test_new_cookies() {
+        let mut state = test_state();
+        for cookie in &[vec![1; 4], vec![], vec![2; 4], vec![1; 4], vec![2; 4], vec![3; 4]] {
+            let record = NewCookieRecord::from(cookie.clone());
+            process_record(KeRecord::NewCookie(record), &mut state).unwrap();
+        }
+        assert_eq!(state.cookies, vec![vec![1; 4], vec![2; 4], vec![3; 4]]);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut state = test_state();
+        let before = WARNING_COUNTER.with_label_values(&["other"]).get();
+
+        let mut response = Vec::new();
+        response.append(&mut serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4])));
+        response.append(&mut serialize(WarningRecord::from(7)));
+        response.append(&mut serialize(AeadAlgorithmRecord::from(vec![
+            KnownAeadAlgorithm::AeadAesSivCmac256,
+        ])));
+        response.append(&mut serialize(NewCookieRecord::from(vec![1; 4])));
+        response.append(&mut serialize(EndOfMessageRecord));
+
+        let mut response = &response[..];
+        while !state.finished {
+            let record_bytes = read_record(&mut response).unwrap();
+            process_record_bytes(&logger, &record_bytes, &mut state).unwrap();
+        }
+        assert_eq!(state.warnings, vec![7]);
+        assert_eq!(state.cookies, vec![vec![1; 4]]);
+        // An unknown code is counted as "other", so the server can't make up label values.
+        assert_eq!(WARNING_COUNTER.with_label_values(&["other"]).get(), before + 1);
+        assert_eq!(WARNING_COUNTER.with_label_values(&["7"]).get(), 0);
+    }
+
+    #[test]
+    fn test_request_records() {
+        assert_eq!(next_protocol_record(&[0]), vec![0x80, 0x01, 0x00, 0x02, 0x00, 0x00]);
+        assert_eq!(next_protocol_record(&[0x8001, 0]),
+                   vec![0x80, 0x01, 0x00, 0x04, 0x80, 0x01, 0x00, 0x00]);
+        assert_eq!(aead_record(&[15]), vec![0x80, 0x04, 0x00, 0x02, 0x00, 0x0f]);
+        assert_eq!(aead_record(&[16, 15]), vec![0x80, 0x04, 0x00, 0x04, 0x00, 0x10, 0x00, 0x0f]);
+        assert_eq!(aead_record(&[]), vec![0x80, 0x04, 0x00, 0x00]);
+
+        // The same bytes as the records the server parses.
+        let protocols = NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]);
+        assert_eq!(next_protocol_record(&[0]), serialize(protocols));
+        let algorithms = AeadAlgorithmRecord::from(vec![KnownAeadAlgorithm::AeadAesSivCmac256]);
+        assert_eq!(aead_record(&[15]), serialize(algorithms));
+
+        let mut request = Vec::new();
+        write_request(&mut request, &[0], &[15]).unwrap();
+        assert_eq!(&request[12..], &[0x80, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_too_many_ids() {
+        aead_record(&[15; 0x8000]);
+    }
+
+    #[test]
+    fn test_advertise_next_protocols() {
+        // Only NTPv4.
+        let mut request = Vec::new();
+        write_request(&mut request, &[0], &[15]).unwrap();
+        assert_eq!(&request[..6], &[0x80, 1, 0, 2, 0, 0]);
+
+        let mut state = test_state();
+        let record = NextProtocolRecord::from_bytes(Party::Server, &[0x00, 0x00]).unwrap();
+        process_record(KeRecord::NextProtocol(record), &mut state).unwrap();
+        assert_eq!(state.next_protocol, 0);
+
+        // An extra protocol id preferred over NTPv4. All the ids are sent in order.
+        let mut request = Vec::new();
+        write_request(&mut request, &[0x8001, 0], &[15]).unwrap();
+        assert_eq!(&request[..8], &[0x80, 1, 0, 4, 0x80, 0x01, 0, 0]);
+        let record_bytes = read_record(&mut &request[..]).unwrap();
+        match deserialize(Party::Client, &record_bytes) {
+            Ok(KeRecord::NextProtocol(record)) => {
+                assert_eq!(record.protocol_ids(), &[0x8001, 0][..]);
+            }
+            _ => panic!("the request must start with a Next Protocol record"),
+        }
+
+        // The server may choose either of them.
+        for &chosen in &[0x8001, 0] {
+            let mut state = test_state();
+            state.advertised_protocols = vec![0x8001, 0];
+            let record = NextProtocolRecord::from_bytes(Party::Server, &chosen.to_be_bytes())
+                .unwrap();
+            process_record(KeRecord::NextProtocol(record), &mut state).unwrap();
+            assert_eq!(state.next_protocol, chosen);
+        }
+
+        // But not one we didn't advertise.
+        let mut state = test_state();
+        state.advertised_protocols = vec![0x8001];
+        let record = NextProtocolRecord::from_bytes(Party::Server, &[0x00, 0x00]).unwrap();
+        let error = process_record(KeRecord::NextProtocol(record), &mut state).unwrap_err();
+        match error.downcast_ref::<ClientError>() {
+            Some(UnadvertisedNextProtocol(protocol_ids)) => assert_eq!(protocol_ids, &vec![0]),
+            _ => panic!("unexpected error: {}", error),
+        }
+    }
+
+    fn test_client_config(trusted_certs: Vec<rustls::Certificate>, use_system_roots: bool)
+        -> ClientConfig
+    {
+        ClientConfig {
+            host: String::from("localhost"),
+            sni_name: None,
+            accepted_names: Vec::new(),
+            port: None,
+            trusted_certs,
+            use_system_roots,
+            use_ipv4: None,
+            proxy_url: None,
+            next_protocols: vec![DEFAULT_NEXT_PROTOCOL],
+            source_port: None,
+            retry_policy: None,
+        }
+    }
+
+    #[test]
+    fn test_root_store() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let cert = load_tls_certs(String::from("tests/ca.pem")).unwrap()[0].clone();
+        let public_roots = webpki_roots::TLS_SERVER_ROOTS.0.len();
+
+        // The public roots are used by default.
+        let config = test_client_config(Vec::new(), true);
+        assert_eq!(root_store(&logger, &config).unwrap().len(), public_roots);
+
+        // A trusted certificate is the only trust anchor, whether the public roots are allowed
+        // or not.
+        let config = test_client_config(vec![cert.clone()], true);
+        assert_eq!(root_store(&logger, &config).unwrap().len(), 1);
+        let config = test_client_config(vec![cert], false);
+        assert_eq!(root_store(&logger, &config).unwrap().len(), 1);
+
+        // Without the public roots, a trusted certificate is required.
+        let config = test_client_config(Vec::new(), false);
+        let error = root_store(&logger, &config).unwrap_err();
+        assert!(error.downcast_ref::<config::ConfigError>().is_some());
+    }
+
+    #[test]
+    fn test_trusted_certs_bundle() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let ca = fs::read_to_string("tests/ca.pem").unwrap();
+        let intermediate = fs::read_to_string("tests/intermediate.pem").unwrap();
+
+        // A bundle of two CAs in a single file.
+        let bundle = env::temp_dir().join("cfnts-test-bundle.pem");
+        fs::write(&bundle, format!("{}{}", ca, intermediate)).unwrap();
+        let certs = load_trusted_certs(bundle.to_str().unwrap()).unwrap();
+        fs::remove_file(&bundle).unwrap();
+        assert_eq!(certs.len(), 2);
+        let config = test_client_config(certs.clone(), false);
+        assert_eq!(root_store(&logger, &config).unwrap().len(), 2);
+
+        // The same CAs in a directory, next to a file which is not a certificate.
+        let dir = env::temp_dir().join("cfnts-test-trusted-certs");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ca.pem"), &ca).unwrap();
+        fs::write(dir.join("intermediate.crt"), &intermediate).unwrap();
+        fs::write(dir.join("README"), "not a certificate").unwrap();
+        let dir_certs = load_trusted_certs(dir.to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(dir_certs, certs);
+
+        // A single certificate still works, but nothing at all is an error.
+        assert_eq!(load_trusted_certs("tests/ca.pem").unwrap().len(), 1);
+        load_trusted_certs("tests/cookie.key").unwrap_err();
+        load_trusted_certs("tests/missing.pem").unwrap_err();
+    }
+
+    /// The TLS config of a test server, with a certificate for localhost and bogus.com, among
+    /// others, issued by `tests/ca.pem`.
+    fn test_server_config() -> Arc<rustls::ServerConfig> {
+        let certs = load_tls_certs(String::from("tests/chain.pem")).unwrap();
+        let key_file = fs::File::open("tests/tls-pkcs8.pem").unwrap();
+        let key = pkcs8_private_keys(&mut io::BufReader::new(key_file)).unwrap().remove(0);
+        let mut server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+        server_config.set_single_cert(certs, key).unwrap();
+        server_config.set_protocols(&[Vec::from("ntske/1".as_bytes())]);
+        Arc::new(server_config)
+    }
+
+    #[test]
+    fn test_sni_name() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+
+        // A server doing the handshake on each connection. The certificate is valid for
+        // bogus.com, among others, but not for the IP address we connect to.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut session = rustls::ServerSession::new(&server_config);
+                let _ = session.complete_io(&mut stream);
+            }
+        });
+
+        let handshake = |sni_name: Option<&str>| -> Result<(), Box<dyn Error>> {
+            let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+            let mut client_config = test_client_config(vec![ca], false);
+            client_config.host = addr.ip().to_string();
+            client_config.port = Some(addr.port().to_string());
+            client_config.sni_name = sni_name.map(String::from);
+            let mut connection = connect(&logger, &client_config)?;
+            let KeConnection { ref mut session, ref mut stream } = connection;
+            session.complete_io(stream)?;
+            Ok(())
+        };
+
+        handshake(Some("bogus.com")).unwrap();
+        // The certificate is validated against the SNI name.
+        handshake(Some("example.com")).unwrap_err();
+        // Without an SNI name, the IP address is not a valid hostname.
+        let error = handshake(None).unwrap_err();
+        assert!(error.downcast_ref::<ClientError>().is_some());
+    }
+
+    #[test]
+    fn test_accepted_names() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+
+        // The certificate is valid for localhost and bogus.com, among others.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut session = rustls::ServerSession::new(&server_config);
+                let _ = session.complete_io(&mut stream);
+            }
+        });
+
+        // Return the certificate the server presented once the handshake is over.
+        let handshake = |accepted_names: &[&str]| -> Result<rustls::Certificate, Box<dyn Error>> {
+            let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+            let mut client_config = test_client_config(vec![ca], false);
+            client_config.host = addr.ip().to_string();
+            client_config.port = Some(addr.port().to_string());
+            client_config.sni_name = Some(String::from("localhost"));
+            client_config.accepted_names = accepted_names.iter().map(|&name| name.into()).collect();
+            let mut connection = connect(&logger, &client_config)?;
+            let KeConnection { ref mut session, ref mut stream } = connection;
+            session.complete_io(stream)?;
+            Ok(session.get_peer_certificates().unwrap().remove(0))
+        };
+
+        let leaf = load_tls_certs(String::from("tests/chain.pem")).unwrap().remove(0);
+        assert_eq!(handshake(&[]).unwrap(), leaf);
+        assert_eq!(handshake(&["bogus.com"]).unwrap(), leaf);
+        assert_eq!(handshake(&["example.com", "bogus.com"]).unwrap(), leaf);
+        // The certificate is valid for the SNI name, but not for any accepted name.
+        let error = handshake(&["example.com"]).unwrap_err();
+        match error.downcast_ref::<ClientError>() {
+            Some(UnacceptedCertificate(names)) => assert_eq!(names, &["example.com"]),
+            _ => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 2.0,
+        };
+        assert_eq!(policy.next_backoff(Duration::from_millis(100)), Duration::from_millis(200));
+        assert_eq!(policy.next_backoff(Duration::from_secs(40)), MAX_BACKOFF);
+        let policy = RetryPolicy { multiplier: 0.5, ..policy };
+        assert_eq!(policy.next_backoff(Duration::from_millis(100)), Duration::from_millis(100));
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert!(is_transient(&refused));
+        let invalid_certificate = io::Error::new(io::ErrorKind::InvalidData, "bad certificate");
+        assert!(!is_transient(&invalid_certificate));
+        assert!(!is_transient(&ErrorRecord));
+    }
+
+    #[test]
+    fn test_retry_refused_connection() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+
+        // Find a free port, and leave it closed for the first attempt.
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let listener = TcpListener::bind(addr).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut session = rustls::ServerSession::new(&server_config);
+            let mut tls_stream = rustls::Stream::new(&mut session, &mut stream);
+            while let Ok(record_bytes) = read_record(&mut tls_stream) {
+                if let Ok(KeRecord::EndOfMessage(_)) = deserialize(Party::Client, &record_bytes) {
+                    break;
+                }
+            }
+            let mut response = serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]));
+            response.append(&mut aead_record(&[15]));
+            response.append(&mut serialize(NewCookieRecord::from(vec![0x07; 16])));
+            response.append(&mut serialize(EndOfMessageRecord));
+            tls_stream.write_all(&response).unwrap();
+            tls_stream.flush().unwrap();
+            let aead = KnownAeadAlgorithm::AeadAesSivCmac256;
+            records::gen_key(&session, DEFAULT_NEXT_PROTOCOL, aead).unwrap()
+        });
+
+        let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+        let mut client_config = test_client_config(vec![ca], false);
+        client_config.port = Some(addr.port().to_string());
+        client_config.use_ipv4 = Some(true);
+
+        // Without a retry policy, the refused connection fails the exchange.
+        run_nts_ke_client(&logger, client_config.clone()).unwrap_err();
+
+        client_config.retry_policy = Some(RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(500),
+            multiplier: 2.0,
+        });
+        let result = run_nts_ke_client(&logger, client_config).unwrap();
+        assert_eq!(result.cookies, vec![vec![0x07; 16]]);
+        assert_eq!(result.aead_scheme, 15);
+        // The retried exchange went through a full handshake with the server, so both ends
+        // exported the same keys.
+        let server_keys = server.join().unwrap();
+        assert_eq!(result.keys.c2s, server_keys.c2s);
+        assert_eq!(result.keys.s2c, server_keys.s2c);
+    }
+
+    #[test]
+    fn test_export_keys_before_handshake() {
+        let config = Arc::new(rustls::ClientConfig::new());
+        let session = rustls::ClientSession::new(&config, dns_name("localhost").unwrap());
+
+        let aead = KnownAeadAlgorithm::AeadAesSivCmac256;
+        let error = export_keys(&session, DEFAULT_NEXT_PROTOCOL, aead).unwrap_err();
+        match error {
+            KeyExportFailed(_) => {}
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(
+            error.to_string(),
+            "failed to export the NTS keys: the TLS handshake is not complete",
+        );
+    }
+
+    /// Run an HTTP CONNECT proxy which tunnels `count` connections one after the other to
+    /// `target`, whatever they ask for, like a proxy resolving every name to it. It returns the
+    /// targets the connections asked for.
+    fn mock_connect_proxy(
+        target: SocketAddr,
+        count: usize,
+    ) -> (SocketAddr, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = thread::spawn(move || {
+            let mut requested = Vec::new();
+            for _ in 0..count {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut header = Vec::new();
+                while !header.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0; 1];
+                    stream.read_exact(&mut byte).unwrap();
+                    header.push(byte[0]);
+                }
+                let header = String::from_utf8(header).unwrap();
+                requested.push(String::from(header.split(' ').nth(1).unwrap()));
+
+                // Relay the bytes both ways until both ends are done.
+                let mut upstream = TcpStream::connect(target).unwrap();
+                stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+                let mut client_read = stream.try_clone().unwrap();
+                let mut upstream_write = upstream.try_clone().unwrap();
+                let forward = thread::spawn(move || {
+                    let _ = io::copy(&mut client_read, &mut upstream_write);
+                    let _ = upstream_write.shutdown(Shutdown::Write);
+                });
+                let _ = io::copy(&mut upstream, &mut stream);
+                let _ = stream.shutdown(Shutdown::Write);
+                forward.join().unwrap();
+            }
+            requested
+        });
+        (addr, proxy)
+    }
+
+    #[test]
+    fn test_handshake_through_proxy() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+
+        // A server returning the ALPN protocol of each completed handshake.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut protocols = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut session = rustls::ServerSession::new(&server_config);
+                if session.complete_io(&mut stream).is_ok() && !session.is_handshaking() {
+                    protocols.push(session.get_alpn_protocol().map(String::from));
+                }
+            }
+            protocols
+        });
+        let (proxy_addr, proxy) = mock_connect_proxy(server_addr, 2);
+
+        let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+        let mut client_config = test_client_config(vec![ca], false);
+        client_config.port = Some(server_addr.port().to_string());
+        client_config.proxy_url = Some(format!("http://{}", proxy_addr));
+        let handshake = |client_config: &ClientConfig| -> Result<Option<String>, Box<dyn Error>> {
+            let mut connection = connect(&logger, client_config)?;
+            let KeConnection { ref mut session, ref mut stream } = connection;
+            session.complete_io(stream)?;
+            Ok(session.get_alpn_protocol().map(String::from))
+        };
+
+        // The handshake goes through the tunnel, and negotiates ntske/1.
+        assert_eq!(handshake(&client_config).unwrap(), Some(String::from("ntske/1")));
+
+        // The certificate is still checked against the host we asked the proxy for.
+        client_config.host = String::from("example.com");
+        let error = handshake(&client_config).unwrap_err();
+        assert!(format!("{:?}", error).contains("CertNotValidForName"));
+
+        let port = server_addr.port();
+        let targets = vec![format!("localhost:{}", port), format!("example.com:{}", port)];
+        assert_eq!(proxy.join().unwrap(), targets);
+        assert_eq!(server.join().unwrap(), vec![Some(String::from("ntske/1"))]);
+    }
+
+    #[test]
+    fn test_export_negotiated_aead() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+        let aead = KnownAeadAlgorithm::AeadAesSivCmac256;
+
+        // A server exporting the keys of AEAD_AES_SIV_CMAC_256 once the handshake is over.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut session = rustls::ServerSession::new(&server_config);
+            session.complete_io(&mut stream).unwrap();
+            records::gen_key(&session, DEFAULT_NEXT_PROTOCOL, aead).unwrap()
+        });
+
+        let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+        let mut client_config = test_client_config(vec![ca], false);
+        client_config.host = addr.ip().to_string();
+        client_config.port = Some(addr.port().to_string());
+        client_config.sni_name = Some(String::from("localhost"));
+        let mut connection = connect(&logger, &client_config).unwrap();
+        connection.session.complete_io(&mut connection.stream).unwrap();
+        assert!(!connection.session.is_handshaking());
+        assert_eq!(connection.session.get_alpn_protocol(), Some("ntske/1"));
+
+        // Without an AEAD Algorithm Negotiation record, there is nothing to export the keys for.
+        let mut state = test_state();
+        match negotiated_aead(&state) {
+            Err(MissingAeadAlgorithm) => {}
+            _ => panic!("the keys must not be exported without a negotiated algorithm"),
+        }
+
+        let record = AeadAlgorithmRecord::from(vec![aead]);
+        process_record(KeRecord::AeadAlgorithm(record), &mut state).unwrap();
+        let negotiated = negotiated_aead(&state).unwrap();
+        assert_eq!(negotiated.as_algorithm_id(), 15);
+        let keys = export_keys(&connection.session, DEFAULT_NEXT_PROTOCOL, negotiated).unwrap();
+
+        // The keys have the length of the algorithm, and the server exports the same ones.
+        assert_eq!(keys.c2s.len(), negotiated.key_len());
+        assert_eq!(keys.s2c.len(), negotiated.key_len());
+        assert_ne!(keys.c2s, keys.s2c);
+        assert!(keys.c2s.iter().any(|&byte| byte != 0));
+        let server_keys = server.join().unwrap();
+        assert_eq!(keys.c2s, server_keys.c2s);
+        assert_eq!(keys.s2c, server_keys.s2c);
+    }
+
+    #[test]
+    fn test_invalid_hostname() {
+        dns_name("time.cloudflare.com").unwrap();
+
+        for host in &["", "time..cloudflare.com", "-time.cloudflare.com", "time cloudflare"] {
+            match dns_name(host) {
+                Err(InvalidHostname(ref invalid)) if invalid.as_str() == *host => {},
+                _ => panic!("{:?} must be an invalid hostname", host),
+            }
+        }
+
+        let error = dns_name("time..cloudflare.com").unwrap_err();
+        assert_eq!(error.to_string(), "server hostname is invalid: time..cloudflare.com");
+    }
+
+    #[test]
+    fn test_select_addrs() {
+        let v4: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let v6: SocketAddr = "[::1]:1234".parse().unwrap();
+        let resolved = vec![v6, v4];
+
+        assert_eq!(select_addrs(resolved.clone().into_iter(), None).unwrap(), resolved);
+        assert_eq!(select_addrs(resolved.clone().into_iter(), Some(true)).unwrap(), vec![v4]);
+        assert_eq!(select_addrs(resolved.clone().into_iter(), Some(false)).unwrap(), vec![v6]);
+
+        match select_addrs(vec![v4].into_iter(), Some(false)) {
+            Err(NoIpv6AddrFound) => {},
+            _ => panic!("no IPv6 address must be an error"),
+        }
+        match select_addrs(vec![v6].into_iter(), Some(true)) {
+            Err(NoIpv4AddrFound) => {},
+            _ => panic!("no IPv4 address must be an error"),
+        }
+    }
+
+    #[test]
+    fn test_connect_any() {
+        let live = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live_addr = live.local_addr().unwrap();
+
+        // Nothing listens on this address once the listener is dropped.
+        let dead_addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let connect = |addr: &SocketAddr| TcpStream::connect_timeout(addr, TIMEOUT);
+        let stream = connect_any(&[dead_addr, live_addr], connect).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), live_addr);
+
+        connect_any(&[dead_addr], connect).unwrap_err();
+        connect_any(&[], connect).unwrap_err();
+    }
+
+    #[test]
+    fn test_addr_family() {
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        assert_eq!(AddrFamily::of(&addr), AddrFamily::Ipv4);
+        assert_eq!(AddrFamily::of(&"[::1]:1234".parse().unwrap()), AddrFamily::Ipv6);
+        assert_eq!(AddrFamily::Ipv6.to_string(), "IPv6");
+    }
+
+    #[test]
+    fn test_ke_addr_family_ipv6() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+        let listener = match TcpListener::bind("[::1]:0") {
+            Ok(listener) => listener,
+            // Some containers have no IPv6 loopback. Say that the test didn't run, and fail on
+            // any other error.
+            Err(ref error) if error.kind() == io::ErrorKind::AddrNotAvailable => {
+                eprintln!("test_ke_addr_family_ipv6 skipped, no IPv6 loopback: {}", error);
+                return;
+            }
+            Err(error) => panic!("cannot listen on the IPv6 loopback: {}", error),
+        };
+        let addr = listener.local_addr().unwrap();
+
+        // A server answering the request with NTPv4, AEAD_AES_SIV_CMAC_256 and a cookie.
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut session = rustls::ServerSession::new(&server_config);
+            let mut tls_stream = rustls::Stream::new(&mut session, &mut stream);
+            while let Ok(record_bytes) = read_record(&mut tls_stream) {
+                if let Ok(KeRecord::EndOfMessage(_)) = deserialize(Party::Client, &record_bytes) {
+                    break;
                 }
             }
-            Err(DeserializeError::UnknownNotCriticalRecord) => {
-                // If it's not critical, just ignore the error.
-                debug!(logger, "unknown record type");
+            let mut response = serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4]));
+            response.append(&mut aead_record(&[15]));
+            response.append(&mut serialize(NewCookieRecord::from(vec![0x07; 16])));
+            response.append(&mut serialize(EndOfMessageRecord));
+            tls_stream.write_all(&response).unwrap();
+            tls_stream.flush().unwrap();
+        });
+
+        // Without a preference, the family is the one of the address the exchange went over.
+        let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+        let mut client_config = test_client_config(vec![ca], false);
+        client_config.host = String::from("::1");
+        client_config.sni_name = Some(String::from("localhost"));
+        client_config.port = Some(addr.port().to_string());
+        let result = run_nts_ke_client(&logger, client_config).unwrap();
+        assert_eq!(result.use_ipv4, None);
+        assert_eq!(result.ke_addr_family, AddrFamily::Ipv6);
+        assert_eq!(result.cookies, vec![vec![0x07; 16]]);
+    }
+
+    #[test]
+    fn test_dedup_cookies() {
+        let cookies = vec![vec![1; 4], vec![2; 4]];
+        let cached_cookies = vec![vec![3; 4], vec![2; 4], vec![4; 4], vec![3; 4]];
+        assert_eq!(dedup_cookies(&cookies, cached_cookies), vec![vec![3; 4], vec![4; 4]]);
+        assert_eq!(dedup_cookies(&[], vec![vec![1; 4]]), vec![vec![1; 4]]);
+    }
+
+    #[test]
+    fn test_add_cached_cookies() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let keys = NTSKeys { c2s: [1; 32], s2c: [2; 32] };
+        let mut result = test_result(vec![vec![1; 4]], keys);
+
+        // The cached cookies stay apart from the fresh ones, with the keys they were issued with.
+        let cached_keys = NTSKeys { c2s: [3; 32], s2c: [4; 32] };
+        let cached = CachedCookies { keys: cached_keys, cookies: vec![vec![1; 4], vec![2; 4]] };
+        add_cached_cookies(&logger, &mut result, cached);
+        assert_eq!(result.cookies, vec![vec![1; 4]]);
+        assert_eq!(result.keys.c2s, [1; 32]);
+        let cached = result.cached.clone().unwrap();
+        assert_eq!(cached.cookies, vec![vec![2; 4]]);
+        assert_eq!(cached.keys.c2s, [3; 32]);
+        assert_eq!(cached.keys.s2c, [4; 32]);
+
+        // Nothing is kept if all the cached cookies are fresh ones.
+        let mut result = test_result(vec![vec![1; 4]], keys);
+        let cached = CachedCookies { keys: cached_keys, cookies: vec![vec![1; 4]] };
+        add_cached_cookies(&logger, &mut result, cached);
+        assert!(result.cached.is_none());
+    }
+
+    #[test]
+    fn test_invalid_port_record() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut state = test_state();
+
+        // The Port record is critical when sent by the server.
+        process_record_bytes(&logger, &[0x80, 7, 0, 2, 0x01, 0x7b], &mut state).unwrap();
+        assert_eq!(state.next_port, 379);
+
+        for record_bytes in [&[0x80, 7, 0, 1, 0x7b][..], &[0x80, 7, 0, 2, 0, 0][..]].iter() {
+            let error = process_record_bytes(&logger, record_bytes, &mut state).unwrap_err();
+            match error.downcast_ref::<ClientError>() {
+                Some(InvalidRecord) => {}
+                _ => panic!("unexpected error: {}", error),
             }
-            Err(DeserializeError::UnknownCriticalRecord) => {
-                // TODO: This should propertly handled by sending an Error record.
-                debug!(logger, "error: unknown critical record");
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "unknown critical record",
-                )));
+        }
+        assert_eq!(state.next_port, 379);
+    }
+
+    #[test]
+    fn test_negotiation_sources() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut state = test_state();
+
+        // Only a Port Negotiation record, so the server is still the NTS-KE host.
+        process_record_bytes(&logger, &[0x80, 7, 0, 2, 0x01, 0x7b], &mut state).unwrap();
+        process_record_bytes(&logger, &[0x80, 0, 0, 0], &mut state).unwrap();
+        assert_eq!(state.next_port, 379);
+        assert_eq!(state.next_port_source, NegotiationSource::Record);
+        assert_eq!(state.next_server, "localhost");
+        assert_eq!(state.next_server_source, NegotiationSource::Default);
+        assert_eq!(state.next_port_source.to_string(), "negotiated");
+        assert_eq!(state.next_server_source.to_string(), "default");
+    }
+
+    #[test]
+    fn test_not_tls_endpoint() {
+        let config = Arc::new(rustls::ClientConfig::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A plaintext server answering whatever it receives, and one closing the connection.
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 16];
+            stream.read(&mut request).unwrap();
+            stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").unwrap();
+
+            let (stream, _) = listener.accept().unwrap();
+            drop(stream);
+        });
+
+        let mut session = rustls::ClientSession::new(&config, dns_name("localhost").unwrap());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let error = check_tls_endpoint(&mut session, &mut stream).unwrap_err();
+        match error.downcast_ref::<ClientError>() {
+            Some(NotTlsEndpoint(_)) => {}
+            _ => panic!("unexpected error: {}", error),
+        }
+        assert_eq!(
+            error.to_string(),
+            "the server answered with plaintext \"HTTP/1.1 400 Bad\", the port may not be an \
+             NTS-KE endpoint, which speaks TLS",
+        );
+
+        let mut session = rustls::ClientSession::new(&config, dns_name("localhost").unwrap());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let error = check_tls_endpoint(&mut session, &mut stream).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "the server closed the connection, the port may not be an NTS-KE endpoint, which \
+             speaks TLS",
+        );
+    }
+
+    #[test]
+    fn test_tls_endpoint() {
+        let config = Arc::new(rustls::ClientConfig::new());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The start of a handshake record, which is enough for the check.
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[0x16, 3, 3, 0, 0x7a]).unwrap();
+            // Keep the connection open until the client is done.
+            let mut request = Vec::new();
+            let _ = stream.read_to_end(&mut request);
+        });
+
+        let mut session = rustls::ClientSession::new(&config, dns_name("localhost").unwrap());
+        let mut stream = TcpStream::connect(addr).unwrap();
+        check_tls_endpoint(&mut session, &mut stream).unwrap();
+        // The record is still there for the TLS session.
+        let mut first_bytes = [0; 5];
+        stream.read_exact(&mut first_bytes).unwrap();
+        assert_eq!(first_bytes, [0x16, 3, 3, 0, 0x7a]);
+    }
+
+    #[test]
+    fn test_close_notify_on_error() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A server starting its handshake and hanging up halfway through the first record, which
+        // fails the exchange. It returns everything the client sent.
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[0x16, 3, 3, 0, 0x7a]).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let mut client_config = test_client_config(Vec::new(), true);
+        client_config.port = Some(addr.port().to_string());
+        client_config.use_ipv4 = Some(true);
+        run_nts_ke_client(&logger, client_config).unwrap_err();
+
+        // The ClientHello, then the close_notify alert, which is still in plaintext as the
+        // handshake didn't get far enough to encrypt it.
+        let received = server.join().unwrap();
+        let mut records = Vec::new();
+        let mut rest = &received[..];
+        while rest.len() >= 5 {
+            let length = 5 + u16::from_be_bytes([rest[3], rest[4]]) as usize;
+            records.push((rest[0], rest[5..length].to_vec()));
+            rest = &rest[length..];
+        }
+        assert!(rest.is_empty());
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].0, 0x16);
+        // A warning level close_notify alert.
+        assert_eq!(records[1], (0x15, vec![1, 0]));
+    }
+
+    #[test]
+    fn test_close_notify_after_handshake() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A server completing the handshake and answering the request with an Error record,
+        // which fails the exchange. It returns how the session ended, after the response.
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut session = rustls::ServerSession::new(&server_config);
+            let mut tls_stream = rustls::Stream::new(&mut session, &mut stream);
+            while let Ok(record_bytes) = read_record(&mut tls_stream) {
+                if let Ok(KeRecord::EndOfMessage(_)) = deserialize(Party::Client, &record_bytes) {
+                    break;
+                }
             }
-            Err(DeserializeError::Parsing(error)) => {
-                // TODO: This shouldn't be wrapped as a trait object.
-                debug!(logger, "error: {}", error);
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    error,
-                )));
+            let mut response = serialize(records::ErrorRecord::bad_request());
+            response.append(&mut serialize(EndOfMessageRecord));
+            tls_stream.write_all(&response).unwrap();
+            tls_stream.flush().unwrap();
+            let mut rest = Vec::new();
+            tls_stream.read_to_end(&mut rest).map_err(|error| error.kind())
+        });
+
+        let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+        let mut client_config = test_client_config(vec![ca], false);
+        client_config.port = Some(addr.port().to_string());
+        client_config.use_ipv4 = Some(true);
+        let error = run_nts_ke_client(&logger, client_config).unwrap_err();
+        match error.downcast_ref::<ClientError>() {
+            Some(ErrorRecord) => {}
+            _ => panic!("unexpected error: {}", error),
+        }
+
+        // rustls reports a close_notify as ConnectionAborted, while a TCP connection closed
+        // without one would only read as the end of the stream.
+        assert_eq!(server.join().unwrap(), Err(io::ErrorKind::ConnectionAborted));
+    }
+
+    #[test]
+    fn test_transcript() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut response = vec![
+            serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4])),
+            aead_record(&[15]),
+            serialize(NewCookieRecord::from(vec![0x07; 16])),
+            serialize(records::PortRecord::new(Party::Server, 4123)),
+            serialize(EndOfMessageRecord),
+        ];
+
+        // A mock server sending the response once it has the whole request. It speaks plain TCP,
+        // as the transcript doesn't depend on the TLS session.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let response_bytes = response.concat();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok(record_bytes) = read_record(&mut stream) {
+                if let Ok(KeRecord::EndOfMessage(_)) = deserialize(Party::Client, &record_bytes) {
+                    break;
+                }
             }
+            stream.write_all(&response_bytes).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut state = test_state();
+        let mut transcript = Transcript::default();
+        exchange(&logger, &mut stream, &[15], &mut state, &mut transcript).unwrap();
+        assert_eq!(state.next_port, 4123);
+
+        // The three records of the request, then the ones of the response, in order.
+        let mut expected = request_records(&[DEFAULT_NEXT_PROTOCOL], &[15]);
+        expected.append(&mut response);
+        let records = &transcript.records;
+        assert_eq!(records.len(), 8);
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record.sent_by_client, i < 3);
+            assert_eq!(record.bytes, expected[i]);
         }
+        assert_eq!(records[1].decoded, "critical AEAD Algorithm Negotiation [15]");
+        assert_eq!(records[5].decoded, "New Cookie of 16 bytes");
+        assert_eq!(records[7].decoded, "critical End of Message");
     }
-    debug!(logger, "saw the end of the response");
-    stream.shutdown(Shutdown::Both)?;
 
-    Ok(NtsKeResult {
-        aead_scheme: state.aead_scheme,
-        cookies: state.cookies,
-        next_protocols: state.next_protocols,
-        next_server: state.next_server,
-        next_port: state.next_port,
-        keys: state.keys,
-        use_ipv4: client_config.use_ipv4,
-    })
+    #[test]
+    fn test_probe_aead_support() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let server_config = test_server_config();
+        let candidates = [1, 15, 16, 30];
+
+        // A mock server which only accepts AEAD_AES_SIV_CMAC_256. It returns the ids it was
+        // offered, one per TLS connection.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut offered = Vec::new();
+            for _ in 0..candidates.len() {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut session = rustls::ServerSession::new(&server_config);
+                let mut tls_stream = rustls::Stream::new(&mut session, &mut stream);
+                let mut aead = None;
+                loop {
+                    let record_bytes = read_record(&mut tls_stream).unwrap();
+                    match deserialize(Party::Client, &record_bytes) {
+                        Ok(KeRecord::EndOfMessage(_)) => break,
+                        Ok(KeRecord::AeadAlgorithm(record)) => {
+                            aead = record.algorithm_ids().first().cloned();
+                        }
+                        Ok(_) => {}
+                        Err(_) => {}
+                    }
+                }
+
+                let mut response = Vec::new();
+                if aead == Some(15) {
+                    response.append(&mut serialize(NextProtocolRecord::from(vec![
+                        KnownNextProtocol::Ntpv4,
+                    ])));
+                    response.append(&mut aead_record(&[15]));
+                } else {
+                    response.append(&mut serialize(records::ErrorRecord::bad_request()));
+                }
+                response.append(&mut serialize(EndOfMessageRecord));
+                tls_stream.write_all(&response).unwrap();
+                tls_stream.flush().unwrap();
+                offered.push(aead);
+            }
+            offered
+        });
+
+        let ca = load_tls_certs(String::from("tests/ca.pem")).unwrap().remove(0);
+        let mut client_config = test_client_config(vec![ca], false);
+        client_config.port = Some(addr.port().to_string());
+        client_config.use_ipv4 = Some(true);
+
+        let supported = probe_aead_support(&logger, &client_config, &candidates);
+        assert_eq!(supported, vec![15]);
+        // Every candidate was advertised alone, in its own TLS session.
+        let offered = server.join().unwrap();
+        assert_eq!(offered, vec![Some(1), Some(15), Some(16), Some(30)]);
+    }
+
+    #[test]
+    fn test_unsupported_aead() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        for &with_error in &[false, true] {
+            // A mock server supporting none of the advertised algorithms. It answers with an empty
+            // AEAD Algorithm Negotiation record, followed by an Error record or not, like the
+            // NTS-KE server does, and returns the ids it was offered.
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut offered = Vec::new();
+                loop {
+                    let record_bytes = read_record(&mut stream).unwrap();
+                    match deserialize(Party::Client, &record_bytes) {
+                        Ok(KeRecord::EndOfMessage(_)) => break,
+                        Ok(KeRecord::AeadAlgorithm(record)) => {
+                            assert!(record.algorithms().is_empty());
+                            offered = record.algorithm_ids().to_vec();
+                        }
+                        _ => {}
+                    }
+                }
+
+                let mut response = Vec::new();
+                response.append(&mut serialize(NextProtocolRecord::from(vec![
+                    KnownNextProtocol::Ntpv4,
+                ])));
+                response.append(&mut serialize(AeadAlgorithmRecord::from(vec![])));
+                if with_error {
+                    response.append(&mut serialize(records::ErrorRecord::bad_request()));
+                }
+                response.append(&mut serialize(EndOfMessageRecord));
+                stream.write_all(&response).unwrap();
+                offered
+            });
+
+            // The client advertises only the id 1 which nobody supports.
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let mut state = test_state();
+            let mut transcript = Transcript::default();
+            let error = exchange(&logger, &mut stream, &[1], &mut state, &mut transcript)
+                .unwrap_err();
+            match error.downcast_ref::<ClientError>() {
+                Some(NoAgreedAeadAlgorithm) => {}
+                _ => panic!("an empty AEAD record must fail the exchange, not {}", error),
+            }
+            assert_eq!(error.to_string(), "server supports none of the advertised AEAD algorithms");
+            assert!(state.cookies.is_empty());
+            assert_eq!(server.join().unwrap(), vec![1]);
+        }
+
+        // A server choosing more than one algorithm, or one we don't know, is invalid.
+        for body in &[&[0x00, 0x0f, 0x00, 0x0f][..], &[0x00, 0x01]] {
+            let mut state = test_state();
+            let record = AeadAlgorithmRecord::from_bytes(Party::Server, body).unwrap();
+            let error = process_record(KeRecord::AeadAlgorithm(record), &mut state).unwrap_err();
+            match error.downcast_ref::<ClientError>() {
+                Some(InvalidRecord) => {}
+                _ => panic!("the server must choose exactly one algorithm we know"),
+            }
+        }
+    }
 }
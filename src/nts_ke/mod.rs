@@ -1,3 +1,5 @@
 pub mod client;
+pub mod proxy;
 pub mod records;
 pub mod server;
+pub mod transcript;
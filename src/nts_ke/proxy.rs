@@ -0,0 +1,292 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! Tunneling the NTS-KE connection through an HTTP CONNECT or SOCKS5 proxy.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// The longest response header of an HTTP proxy we are willing to read.
+const MAX_HTTP_HEADER_SIZE: usize = 8192;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTHENTICATION: u8 = 0;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN_NAME: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+/// A proxy, with its address in the `host:port` form.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Proxy {
+    HttpConnect(String),
+    Socks5(String),
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Proxy {
+    /// Parse a proxy url of the form `http://host:port` or `socks5://host:port`.
+    pub fn parse(url: &str) -> io::Result<Proxy> {
+        let invalid = || {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("invalid proxy url: {}", url))
+        };
+
+        let mut parts = url.splitn(2, "://");
+        let scheme = parts.next().ok_or_else(invalid)?;
+        let addr = parts.next().ok_or_else(invalid)?.trim_end_matches('/');
+        if addr.is_empty() || addr.contains('/') {
+            return Err(invalid());
+        }
+
+        match scheme {
+            "http" => Ok(Proxy::HttpConnect(addr.to_string())),
+            "socks5" | "socks5h" => Ok(Proxy::Socks5(addr.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn addr(&self) -> &str {
+        match self {
+            Proxy::HttpConnect(addr) => addr,
+            Proxy::Socks5(addr) => addr,
+        }
+    }
+
+    /// Connect to `host` and `port` through the proxy. The returned stream is connected to the
+    /// target, so it can be used as if it were a direct connection. The proxy resolves the
+    /// target, so the hostname is passed to it as is. If `use_ipv4` is set, only the proxy
+    /// addresses of that family are connected to.
+    pub fn connect(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+        use_ipv4: Option<bool>,
+    ) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no proxy address of the requested family",
+        );
+        let mut stream = None;
+        let addrs = self.addr().to_socket_addrs()?;
+        for addr in addrs.filter(|addr| use_ipv4.map_or(true, |ipv4| addr.is_ipv4() == ipv4)) {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(error) => last_error = error,
+            }
+        }
+        let mut stream = stream.ok_or(last_error)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        match self {
+            Proxy::HttpConnect(_) => http_connect(&mut stream, host, port)?,
+            Proxy::Socks5(_) => socks5_connect(&mut stream, host, port)?,
+        }
+        Ok(stream)
+    }
+}
+
+/// Establish a tunnel with the HTTP CONNECT method. See RFC 7231 Section 4.3.6.
+fn http_connect<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+    let authority = if host.contains(':') {
+        // IPv6 addresses have to be enclosed in brackets.
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    };
+    let request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    // Read the response header one byte at a time, so that we don't consume anything the
+    // target sends after it.
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() == MAX_HTTP_HEADER_SIZE {
+            return Err(invalid_data(String::from("proxy response header is too long")));
+        }
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte)?;
+        header.push(byte[0]);
+    }
+
+    let header = String::from_utf8_lossy(&header);
+    // Safe to unwrap because the header is not empty.
+    let status_line = header.lines().next().unwrap();
+    let mut fields = status_line.split_whitespace();
+    let version = fields.next().unwrap_or("");
+    let status = fields.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") || !status.starts_with('2') || status.len() != 3 {
+        return Err(invalid_data(format!("proxy refused to connect: {}", status_line)));
+    }
+    Ok(())
+}
+
+/// Establish a tunnel with a SOCKS5 proxy that requires no authentication. See RFC 1928.
+fn socks5_connect<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+    // Offer only the "no authentication required" method.
+    stream.write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTHENTICATION])?;
+    stream.flush()?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [SOCKS_VERSION, SOCKS_NO_AUTHENTICATION] {
+        return Err(invalid_data(String::from("proxy requires an authentication")));
+    }
+
+    if host.len() > usize::from(u8::max_value()) {
+        return Err(invalid_data(format!("hostname is too long: {}", host)));
+    }
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_DOMAIN_NAME, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    stream.flush()?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION || reply[1] != 0 {
+        return Err(invalid_data(format!("proxy refused to connect: reply code {}", reply[1])));
+    }
+
+    // Skip the address the proxy bound for us and its port.
+    let addr_len = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN_NAME => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            usize::from(len[0])
+        }
+        _ => return Err(invalid_data(String::from("proxy replied an unknown address type"))),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Proxy::parse("http://proxy.example.com:3128").unwrap(),
+            Proxy::HttpConnect(String::from("proxy.example.com:3128")),
+        );
+        assert_eq!(
+            Proxy::parse("socks5://127.0.0.1:1080/").unwrap(),
+            Proxy::Socks5(String::from("127.0.0.1:1080")),
+        );
+        Proxy::parse("proxy.example.com:3128").unwrap_err();
+        Proxy::parse("https://proxy.example.com:3128").unwrap_err();
+        Proxy::parse("http://").unwrap_err();
+    }
+
+    /// Run a proxy which accepts one connection, checks the handshake with `handshake`, and
+    /// then echoes everything back as if it were the target.
+    fn mock_proxy<F>(handshake: F) -> String
+    where
+        F: FnOnce(&mut TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream);
+            // The client may have given up on the tunnel already.
+            let mut buf = [0; 16];
+            if let Ok(n) = stream.read(&mut buf) {
+                let _ = stream.write_all(&buf[..n]);
+            }
+        });
+        addr
+    }
+
+    fn assert_tunnel(mut stream: TcpStream) {
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn test_http_connect() {
+        let addr = mock_proxy(|stream| {
+            let mut header = Vec::new();
+            while !header.ends_with(b"\r\n\r\n") {
+                let mut byte = [0; 1];
+                stream.read_exact(&mut byte).unwrap();
+                header.push(byte[0]);
+            }
+            assert_eq!(
+                String::from_utf8(header).unwrap(),
+                "CONNECT time.example.com:1234 HTTP/1.1\r\nHost: time.example.com:1234\r\n\r\n",
+            );
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+        });
+
+        let proxy = Proxy::HttpConnect(addr);
+        assert_tunnel(proxy.connect("time.example.com", 1234, TIMEOUT, None).unwrap());
+    }
+
+    #[test]
+    fn test_proxy_family() {
+        let addr = mock_proxy(|stream| {
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+        });
+
+        // The mock proxy only listens on IPv4.
+        let proxy = Proxy::HttpConnect(addr);
+        let error = proxy.connect("time.example.com", 1234, TIMEOUT, Some(false)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_tunnel(proxy.connect("time.example.com", 1234, TIMEOUT, Some(true)).unwrap());
+    }
+
+    #[test]
+    fn test_http_connect_refused() {
+        let addr = mock_proxy(|stream| {
+            stream.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").unwrap();
+        });
+
+        let proxy = Proxy::HttpConnect(addr);
+        proxy.connect("time.example.com", 1234, TIMEOUT, None).unwrap_err();
+    }
+
+    #[test]
+    fn test_socks5_connect() {
+        let addr = mock_proxy(|stream| {
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).unwrap();
+
+            let host = b"time.example.com";
+            let mut request = vec![0; 5 + host.len() + 2];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..5], &[5, 1, 0, 3, host.len() as u8]);
+            assert_eq!(&request[5..5 + host.len()], &host[..]);
+            assert_eq!(&request[5 + host.len()..], &[0x04, 0xd2]);
+            stream.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x04, 0xd2]).unwrap();
+        });
+
+        let proxy = Proxy::Socks5(addr);
+        assert_tunnel(proxy.connect("time.example.com", 1234, TIMEOUT, None).unwrap());
+    }
+}
@@ -20,19 +20,49 @@ impl KnownAeadAlgorithm {
             KnownAeadAlgorithm::AeadAesSivCmac256 => 15,
         }
     }
+
+    /// Return the known algorithm with the given id, if there is one.
+    pub fn from_algorithm_id(algorithm_id: u16) -> Option<KnownAeadAlgorithm> {
+        let algorithm = KnownAeadAlgorithm::AeadAesSivCmac256;
+        if algorithm.as_algorithm_id() == algorithm_id {
+            Some(algorithm)
+        } else {
+            None
+        }
+    }
+
+    /// Return the key length in bytes of the algorithm for each direction.
+    pub fn key_len(&self) -> usize {
+        match self {
+            // AEAD_AES_SIV_CMAC_256 uses a 256-bit key. See RFC 5297.
+            KnownAeadAlgorithm::AeadAesSivCmac256 => 32,
+        }
+    }
 }
 
-pub struct AeadAlgorithmRecord(Vec<KnownAeadAlgorithm>);
+// We keep the raw algorithm ids here, because a client can advertise only the ids we don't know
+// and we still have to answer it.
+pub struct AeadAlgorithmRecord(Vec<u16>);
 
 impl AeadAlgorithmRecord {
-    pub fn algorithms(&self) -> &[KnownAeadAlgorithm] {
+    /// Return the known algorithms in the record. The unknown algorithm ids are skipped.
+    pub fn algorithms(&self) -> Vec<KnownAeadAlgorithm> {
+        self.0.iter()
+            .filter_map(|algorithm_id| KnownAeadAlgorithm::from_algorithm_id(*algorithm_id))
+            .collect()
+    }
+
+    /// Return all the algorithm ids in the record, including the unknown ones.
+    pub fn algorithm_ids(&self) -> &[u16] {
         self.0.as_slice()
     }
 }
 
 impl From<Vec<KnownAeadAlgorithm>> for AeadAlgorithmRecord {
     fn from(algorithms: Vec<KnownAeadAlgorithm>) -> AeadAlgorithmRecord {
-        AeadAlgorithmRecord(algorithms)
+        AeadAlgorithmRecord(
+            algorithms.iter().map(|algorithm| algorithm.as_algorithm_id()).collect()
+        )
     }
 }
 
@@ -57,10 +87,10 @@ impl KeRecordTrait for AeadAlgorithmRecord {
 
     fn into_bytes(self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        for algorithm in self.0.iter() {
+        for algorithm_id in self.0.iter() {
             // The spec said that the protocol id must be in network byte order, so we have to
             // convert it to the big endian order here.
-            let algorithm_bytes = &algorithm.as_algorithm_id().to_be_bytes()[..];
+            let algorithm_bytes = &algorithm_id.to_be_bytes()[..];
 
             bytes.append(&mut Vec::from(algorithm_bytes))
         }
@@ -76,19 +106,36 @@ impl KeRecordTrait for AeadAlgorithmRecord {
                                      must be even."));
         }
 
-        let mut algorithms = Vec::new();
-
-        for word in bytes.chunks_exact(2) {
-            let algorithm_code = u16::from_be_bytes([word[0], word[1]]);
-
-            let algorithm = KnownAeadAlgorithm::AeadAesSivCmac256;
-            if algorithm.as_algorithm_id() == algorithm_code {
-                algorithms.push(algorithm);
-            } else {
-                return Err(String::from("unknown AEAD algorithm id"));
-            }
-        }
+        // The unknown algorithm ids are not an error here. It's up to the receiver to decide what
+        // to do with them.
+        let algorithms = bytes.chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect();
 
         Ok(AeadAlgorithmRecord(algorithms))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_bytes() {
+        // AEAD_AES_SIV_CMAC_256 after the id 1 which we don't know.
+        let record = AeadAlgorithmRecord::from_bytes(Party::Client, &[0x00, 0x01, 0x00, 0x0f])
+            .unwrap();
+        assert_eq!(record.algorithm_ids(), &[1, 15][..]);
+        let algorithms = record.algorithms();
+        assert_eq!(algorithms.len(), 1);
+        assert_eq!(algorithms[0].as_algorithm_id(), 15);
+
+        // Only unknown ids, or none at all.
+        let record = AeadAlgorithmRecord::from_bytes(Party::Client, &[0x00, 0x01]).unwrap();
+        assert!(record.algorithms().is_empty());
+        let record = AeadAlgorithmRecord::from_bytes(Party::Server, &[]).unwrap();
+        assert!(record.algorithm_ids().is_empty());
+
+        AeadAlgorithmRecord::from_bytes(Party::Server, &[0x00]).err().unwrap();
+    }
+}
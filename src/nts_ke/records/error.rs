@@ -10,6 +10,7 @@ use super::Party;
 enum ErrorKind {
     UnrecognizedCriticalRecord,
     BadRequest,
+    InternalServerError,
 }
 
 impl ErrorKind {
@@ -17,12 +18,30 @@ impl ErrorKind {
         match self {
             ErrorKind::UnrecognizedCriticalRecord => 0,
             ErrorKind::BadRequest => 1,
+            ErrorKind::InternalServerError => 2,
         }
     }
 }
 
 pub struct ErrorRecord(ErrorKind);
 
+impl ErrorRecord {
+    /// Create an error record telling the peer that it sent a critical record we don't recognize.
+    pub fn unrecognized_critical_record() -> ErrorRecord {
+        ErrorRecord(ErrorKind::UnrecognizedCriticalRecord)
+    }
+
+    /// Create an error record telling the peer that its request is malformed.
+    pub fn bad_request() -> ErrorRecord {
+        ErrorRecord(ErrorKind::BadRequest)
+    }
+
+    /// Create an error record telling the peer that we failed to answer a valid request.
+    pub fn internal_server_error() -> ErrorRecord {
+        ErrorRecord(ErrorKind::InternalServerError)
+    }
+}
+
 impl KeRecordTrait for ErrorRecord {
     fn critical(&self) -> bool {
         true
@@ -58,6 +77,11 @@ impl KeRecordTrait for ErrorRecord {
             return Ok(ErrorRecord(kind));
         }
 
+        let kind = ErrorKind::InternalServerError;
+        if kind.as_code() == error_code {
+            return Ok(ErrorRecord(kind));
+        }
+
         return Err(String::from("unknown error code"));
     }
 }
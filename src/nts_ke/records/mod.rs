@@ -26,6 +26,8 @@ pub use self::port::*;
 
 use rustls::TLSError;
 
+use std::convert::TryFrom;
+
 use crate::cookie::NTSKeys;
 
 pub const HEADER_SIZE: usize = 4;
@@ -65,6 +67,11 @@ pub trait KeRecordTrait: Sized {
 // ------------------------------------------------------------------------
 
 /// Serialize the record into the network-ready format.
+///
+/// # Panics
+///
+/// If the length of the record is not the length of its body.
+///
 pub fn serialize<T: KeRecordTrait>(record: T) -> Vec<u8> {
     let mut result = Vec::new();
 
@@ -73,10 +80,15 @@ pub fn serialize<T: KeRecordTrait>(record: T) -> Vec<u8> {
     result.append(&mut Vec::from(&first_word.to_be_bytes()[..]));
 
     // The second 16 bits will be the length of the record body.
-    result.append(&mut Vec::from(&record.len().to_be_bytes()[..]));
+    let length = record.len();
+    result.append(&mut Vec::from(&length.to_be_bytes()[..]));
 
-    // The rest is the content of the record.
-    result.append(&mut record.into_bytes());
+    // The rest is the content of the record. A length field which doesn't match it would make
+    // the peer read the following records from the wrong offsets.
+    let mut body = record.into_bytes();
+    assert_eq!(usize::from(length), body.len(),
+               "the length of record type {} doesn't match its body", T::record_type());
+    result.append(&mut body);
 
     result
 }
@@ -94,22 +106,34 @@ pub enum DeserializeError {
 
 /// Deserialize the network bytes into the record.
 ///
-/// # Panics
+/// # Errors
 ///
-/// If slice is shorter than the length specified in the length field.
+/// There is a parsing error if the slice is shorter than the header or than the length specified
+/// in the length field.
 ///
 pub fn deserialize(sender: Party, bytes: &[u8]) -> Result<KeRecord, DeserializeError> {
+    if bytes.len() < HEADER_SIZE {
+        let error = String::from("the record is shorter than its header");
+        return Err(DeserializeError::Parsing(error));
+    }
+
     // The first bit of the first byte is the critical bit.
     let critical = bytes[0] >> 7 == 1;
 
     // The following 15 bits are the record type number.
-    let record_type = u16::from_be_bytes([bytes[0] & 0x7, bytes[1]]);
+    let record_type = u16::from_be_bytes([bytes[0] & 0x7f, bytes[1]]);
 
     // The third and fourth bytes are the body length.
     let length = u16::from_be_bytes([bytes[2], bytes[3]]);
 
     // The body.
-    let body = &bytes[4..4 + usize::from(length)];
+    let body = match bytes.get(HEADER_SIZE..HEADER_SIZE + usize::from(length)) {
+        Some(body) => body,
+        None => return Err(DeserializeError::Parsing(format!(
+            "the record claims a body of {} bytes, but only {} bytes follow the header",
+            length, bytes.len() - HEADER_SIZE
+        ))),
+    };
 
     macro_rules! deserialize_body {
         ( $( ($variant:ident, $record:ident) ),* ) => {
@@ -145,20 +169,170 @@ pub fn deserialize(sender: Party, bytes: &[u8]) -> Result<KeRecord, DeserializeE
     Ok(record)
 }
 
-/// gen_key computes the client and server keys using exporters.
-/// https://tools.ietf.org/html/draft-ietf-ntp-using-nts-for-ntp-18#section-6
-pub fn gen_key<T: rustls::Session>(session: &T) -> Result<NTSKeys, TLSError> {
-    let mut keys: NTSKeys = NTSKeys {
-        c2s: [0; 32],
-        s2c: [0; 32],
-    };
-    let c2s_con = [0, 0, 0, 15, 00];
-    let s2c_con = [0, 0, 0, 15, 01];
+/// Convert the exported keying material of one direction to a key of `NTSKeys`.
+fn key_from_exported(exported: &[u8]) -> Result<[u8; 32], TLSError> {
+    <[u8; 32]>::try_from(exported).map_err(|_| {
+        TLSError::General(format!(
+            "the exported key has {} bytes, but NTSKeys requires 32 bytes", exported.len()
+        ))
+    })
+}
+
+/// The direction byte of the exporter context for the client-to-server key.
+const C2S: u8 = 0;
+/// The direction byte of the exporter context for the server-to-client key.
+const S2C: u8 = 1;
+
+/// Build the per-association context of the exporter: the negotiated Protocol ID, the AEAD
+/// algorithm id, and the direction, with the ids in network byte order.
+/// https://tools.ietf.org/html/rfc8915#section-5.1
+fn exporter_context(protocol_id: u16, aead: KnownAeadAlgorithm, direction: u8) -> [u8; 5] {
+    let protocol = protocol_id.to_be_bytes();
+    let algorithm = aead.as_algorithm_id().to_be_bytes();
+    [protocol[0], protocol[1], algorithm[0], algorithm[1], direction]
+}
+
+/// gen_key computes the client and server keys using exporters for the negotiated next protocol
+/// and AEAD algorithm. The protocol id is the one of NTPv4, i.e. 0, unless another protocol is
+/// ever negotiated. It exports exactly the key length of the algorithm for each direction.
+/// https://tools.ietf.org/html/rfc8915#section-5.1
+pub fn gen_key<T: rustls::Session>(
+    session: &T,
+    protocol_id: u16,
+    aead: KnownAeadAlgorithm,
+) -> Result<NTSKeys, TLSError> {
+    let mut c2s = vec![0; aead.key_len()];
+    let mut s2c = vec![0; aead.key_len()];
+    let c2s_con = exporter_context(protocol_id, aead, C2S);
+    let s2c_con = exporter_context(protocol_id, aead, S2C);
     let context_c2s = Some(&c2s_con[..]);
     let context_s2c = Some(&s2c_con[..]);
     let label = "EXPORTER-network-time-security/1".as_bytes();
-    session.export_keying_material(&mut keys.c2s, label, context_c2s)?;
-    session.export_keying_material(&mut keys.s2c, label, context_s2c)?;
+    session.export_keying_material(&mut c2s, label, context_c2s)?;
+    session.export_keying_material(&mut s2c, label, context_s2c)?;
+
+    Ok(NTSKeys {
+        c2s: key_from_exported(&c2s)?,
+        s2c: key_from_exported(&s2c)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_critical_record() {
+        // The critical bit is set and the record type is 0x4001 which is unknown.
+        let bytes = [0xc0, 0x01, 0x00, 0x02, 0xab, 0xcd];
+        match deserialize(Party::Server, &bytes) {
+            Err(DeserializeError::UnknownCriticalRecord) => {},
+            _ => panic!("an unknown critical record must be an error"),
+        }
+
+        // The same record type without the critical bit.
+        let bytes = [0x40, 0x01, 0x00, 0x02, 0xab, 0xcd];
+        match deserialize(Party::Server, &bytes) {
+            Err(DeserializeError::UnknownNotCriticalRecord) => {},
+            _ => panic!("an unknown non-critical record must be reported as such"),
+        }
+    }
+
+    #[test]
+    fn test_lying_length() {
+        // A Port record claiming a body of four bytes, but carrying only two.
+        let bytes = [0x80, 0x07, 0x00, 0x04, 0x01, 0x7b];
+        match deserialize(Party::Server, &bytes) {
+            Err(DeserializeError::Parsing(error)) => assert_eq!(
+                error, "the record claims a body of 4 bytes, but only 2 bytes follow the header"
+            ),
+            _ => panic!("a record longer than the slice must be an error"),
+        }
 
-    Ok(keys)
+        // Not even a whole header.
+        match deserialize(Party::Server, &bytes[..3]) {
+            Err(DeserializeError::Parsing(_)) => {},
+            _ => panic!("a record shorter than its header must be an error"),
+        }
+    }
+
+    /// A record whose length field lies about its body.
+    struct LyingRecord;
+
+    impl KeRecordTrait for LyingRecord {
+        fn critical(&self) -> bool {
+            true
+        }
+
+        fn record_type() -> u16 {
+            0x4001
+        }
+
+        fn len(&self) -> u16 {
+            4
+        }
+
+        fn into_bytes(self) -> Vec<u8> {
+            vec![0; 2]
+        }
+
+        fn from_bytes(_: Party, _: &[u8]) -> Result<Self, String> {
+            Ok(LyingRecord)
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_serialize_lying_length() {
+        serialize(LyingRecord);
+    }
+
+    #[test]
+    fn test_serialize_critical() {
+        // The critical bit is the first bit of the record, and the length is the body's.
+        assert_eq!(serialize(EndOfMessageRecord), vec![0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(serialize(PortRecord::new(Party::Server, 379)),
+                   vec![0x80, 0x07, 0x00, 0x02, 0x01, 0x7b]);
+        assert_eq!(serialize(PortRecord::new(Party::Client, 379)),
+                   vec![0x00, 0x07, 0x00, 0x02, 0x01, 0x7b]);
+
+        // The critical bit survives a round trip.
+        let bytes = serialize(PortRecord::new(Party::Server, 379));
+        match deserialize(Party::Server, &bytes) {
+            Ok(KeRecord::Port(record)) => assert_eq!(serialize(record), bytes),
+            _ => panic!("a Port record must round-trip"),
+        }
+    }
+
+    #[test]
+    fn test_key_len() {
+        let aead = KnownAeadAlgorithm::AeadAesSivCmac256;
+        assert_eq!(aead.key_len(), 32);
+
+        let keys = NTSKeys {
+            c2s: key_from_exported(&vec![1; aead.key_len()]).unwrap(),
+            s2c: key_from_exported(&vec![2; aead.key_len()]).unwrap(),
+        };
+        assert_eq!(keys.c2s.len(), aead.key_len());
+        assert_eq!(keys.s2c.len(), aead.key_len());
+    }
+
+    #[test]
+    fn test_exporter_context() {
+        // The contexts for AEAD_AES_SIV_CMAC_256 given in RFC 8915 Section 5.1.
+        let aead = KnownAeadAlgorithm::AeadAesSivCmac256;
+        let ntpv4 = KnownNextProtocol::Ntpv4.as_protocol_id();
+        assert_eq!(exporter_context(ntpv4, aead, C2S), [0x00, 0x00, 0x00, 0x0f, 0x00]);
+        assert_eq!(exporter_context(ntpv4, aead, S2C), [0x00, 0x00, 0x00, 0x0f, 0x01]);
+
+        // Keys for another protocol are bound to its id.
+        assert_eq!(exporter_context(0x8001, aead, C2S), [0x80, 0x01, 0x00, 0x0f, 0x00]);
+        assert_ne!(exporter_context(0x8001, aead, C2S), exporter_context(ntpv4, aead, C2S));
+    }
+
+    #[test]
+    fn test_key_len_mismatch() {
+        key_from_exported(&[0; 64]).unwrap_err();
+        key_from_exported(&[0; 16]).unwrap_err();
+    }
 }
@@ -5,12 +5,21 @@
 //! New Cookie record representation.
 
 use std::convert::TryFrom;
+use std::fmt;
 
 use super::KeRecordTrait;
 use super::Party;
 
+use crate::cookie::Redacted;
+
 pub struct NewCookieRecord(Vec<u8>);
 
+impl fmt::Debug for NewCookieRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("NewCookieRecord").field(&Redacted(&self.0)).finish()
+    }
+}
+
 impl From<Vec<u8>> for NewCookieRecord {
     fn from(bytes: Vec<u8>) -> NewCookieRecord {
         NewCookieRecord(bytes)
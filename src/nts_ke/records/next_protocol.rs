@@ -20,19 +20,39 @@ impl KnownNextProtocol {
             KnownNextProtocol::Ntpv4 => 0,
         }
     }
+
+    /// Return the known protocol with the given id, if there is one.
+    pub fn from_protocol_id(protocol_id: u16) -> Option<KnownNextProtocol> {
+        let protocol = KnownNextProtocol::Ntpv4;
+        if protocol.as_protocol_id() == protocol_id {
+            Some(protocol)
+        } else {
+            None
+        }
+    }
 }
 
-pub struct NextProtocolRecord(Vec<KnownNextProtocol>);
+// We keep the raw protocol ids here, because the peer can send the ids we don't know and we
+// still want to report them.
+pub struct NextProtocolRecord(Vec<u16>);
 
 impl NextProtocolRecord {
-    pub fn protocols(&self) -> &[KnownNextProtocol] {
+    /// Return the known protocols in the record. The unknown protocol ids are skipped.
+    pub fn protocols(&self) -> Vec<KnownNextProtocol> {
+        self.0.iter()
+            .filter_map(|protocol_id| KnownNextProtocol::from_protocol_id(*protocol_id))
+            .collect()
+    }
+
+    /// Return all the protocol ids in the record, including the unknown ones.
+    pub fn protocol_ids(&self) -> &[u16] {
         self.0.as_slice()
     }
 }
 
 impl From<Vec<KnownNextProtocol>> for NextProtocolRecord {
     fn from(protocols: Vec<KnownNextProtocol>) -> NextProtocolRecord {
-        NextProtocolRecord(protocols)
+        NextProtocolRecord(protocols.iter().map(|protocol| protocol.as_protocol_id()).collect())
     }
 }
 
@@ -55,10 +75,10 @@ impl KeRecordTrait for NextProtocolRecord {
 
     fn into_bytes(self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        for protocol in self.0.iter() {
+        for protocol_id in self.0.iter() {
             // The spec said that the protocol id must be in network byte order, so we have to
             // convert it to the big endian order here.
-            let protocol_bytes = &protocol.as_protocol_id().to_be_bytes()[..];
+            let protocol_bytes = &protocol_id.to_be_bytes()[..];
 
             bytes.append(&mut Vec::from(protocol_bytes))
         }
@@ -74,18 +94,11 @@ impl KeRecordTrait for NextProtocolRecord {
                                      must be even."));
         }
 
-        let mut protocols = Vec::new();
-
-        for word in bytes.chunks_exact(2) {
-            let protocol_code = u16::from_be_bytes([word[0], word[1]]);
-
-            let protocol = KnownNextProtocol::Ntpv4;
-            if protocol.as_protocol_id() == protocol_code {
-                protocols.push(protocol);
-            } else {
-                return Err(String::from("unknown Next Protocol id"));
-            }
-        }
+        // The unknown protocol ids are not an error here. It's up to the receiver to decide what
+        // to do with them.
+        let protocols = bytes.chunks_exact(2)
+            .map(|word| u16::from_be_bytes([word[0], word[1]]))
+            .collect();
 
         Ok(NextProtocolRecord(protocols))
     }
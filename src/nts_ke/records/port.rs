@@ -52,6 +52,9 @@ impl KeRecordTrait for PortRecord {
             Err(String::from("the body length of Port must be two."))
         } else {
             let port = u16::from_be_bytes([bytes[0], bytes[1]]);
+            if port == 0 {
+                return Err(String::from("the port of Port must not be zero."));
+            }
 
             Ok(PortRecord {
                 sender,
@@ -60,3 +63,18 @@ impl KeRecordTrait for PortRecord {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_bytes() {
+        let record = PortRecord::from_bytes(Party::Server, &[0x01, 0x7b]).unwrap();
+        assert_eq!(record.port(), 379);
+
+        PortRecord::from_bytes(Party::Server, &[0x7b]).err().unwrap();
+        PortRecord::from_bytes(Party::Server, &[0x00, 0x01, 0x7b]).err().unwrap();
+        PortRecord::from_bytes(Party::Server, &[0x00, 0x00]).err().unwrap();
+    }
+}
@@ -7,23 +7,33 @@
 use super::KeRecordTrait;
 use super::Party;
 
-enum WarningKind {
-    // There is currently no warning specified in the spec, but we need to put something here to
-    // make the code compiles. Please remove this Dummy when there is a warning specified in the
-    // spec.
-    Dummy,
-}
+/// The warning codes we know, with their names. The registry of RFC 8915 has none yet.
+const KNOWN_WARNING_CODES: &[(u16, &str)] = &[];
 
-impl WarningKind {
-    fn as_code(&self) -> u16 {
-        match self {
-            // Put the max value for Dummy just to avoid colliding with the future warning code.
-            WarningKind::Dummy => u16::max_value(),
-        }
+/// A Warning record. No warning code is specified yet, so any code is kept as is for the client
+/// to report.
+pub struct WarningRecord(u16);
+
+impl WarningRecord {
+    /// The warning code of the record.
+    pub fn code(&self) -> u16 {
+        self.0
+    }
+
+    /// The name of the warning code if we know it, or "other". Unlike the code, it can label a
+    /// metric, because a server can't make up new names.
+    pub fn code_name(&self) -> &'static str {
+        KNOWN_WARNING_CODES.iter()
+            .find(|(code, _)| *code == self.0)
+            .map_or("other", |&(_, name)| name)
     }
 }
 
-pub struct WarningRecord(WarningKind);
+impl From<u16> for WarningRecord {
+    fn from(code: u16) -> WarningRecord {
+        WarningRecord(code)
+    }
+}
 
 impl KeRecordTrait for WarningRecord {
     fn critical(&self) -> bool {
@@ -39,8 +49,7 @@ impl KeRecordTrait for WarningRecord {
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        let error_code = &self.0.as_code().to_be_bytes()[..];
-        Vec::from(error_code)
+        Vec::from(&self.0.to_be_bytes()[..])
     }
 
     fn from_bytes(_: Party, bytes: &[u8]) -> Result<Self, String> {
@@ -48,14 +57,6 @@ impl KeRecordTrait for WarningRecord {
             return Err(String::from("the body length of Warning must be two."))
         }
 
-        let warning_code = u16::from_be_bytes([bytes[0], bytes[1]]);
-
-        let kind = WarningKind::Dummy;
-        if kind.as_code() == warning_code {
-            return Ok(WarningRecord(kind));
-        }
-
-        return Err(String::from("unknown warning code"))
+        Ok(WarningRecord(u16::from_be_bytes([bytes[0], bytes[1]])))
     }
 }
-
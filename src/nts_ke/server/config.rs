@@ -13,24 +13,135 @@ use sloggers::Build;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::net::SocketAddr;
+use std::str::FromStr;
 
-use crate::cookie::CookieKey;
+use crate::cookie::{warn_if_world_readable, CookieKey, CookieKeySource};
 use crate::error::WrapError;
+use crate::key_rotator::RotationPeriods;
 use crate::metrics::MetricsConfig;
 
+/// The number of cookies the server sends to the client in each exchange, if it's not specified.
+/// According to the spec, if the next protocol is NTPv4, we should send eight cookies.
+pub const DEFAULT_COOKIE_COUNT: u8 = 8;
+
+/// The maximum number of cookies the server sends to the client in each exchange.
+pub const MAX_COOKIE_COUNT: u8 = 8;
+
+/// How the server answers a client advertising only AEAD algorithms we don't support. Either way,
+/// the response has an empty AEAD Algorithm Negotiation record, which tells the client there is
+/// no algorithm we agree on, and no cookies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnsupportedAeadResponse {
+    /// Only the empty AEAD Algorithm Negotiation record.
+    Empty,
+    /// The empty AEAD Algorithm Negotiation record followed by an Error record of Bad Request,
+    /// for clients which only look for an Error record.
+    Error,
+}
+
+impl FromStr for UnsupportedAeadResponse {
+    type Err = std::io::Error;
+
+    fn from_str(name: &str) -> Result<UnsupportedAeadResponse, std::io::Error> {
+        match name {
+            "empty" => Ok(UnsupportedAeadResponse::Empty),
+            "error" => Ok(UnsupportedAeadResponse::Error),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown unsupported AEAD response: {}", name),
+            )),
+        }
+    }
+}
+
 fn get_metrics_config(settings: &config::Config) -> Option<MetricsConfig> {
     let mut metrics = None;
     if let Ok(addr) = settings.get_str("metrics_addr") {
         if let Ok(port) = settings.get_int("metrics_port") {
             metrics = Some(MetricsConfig {
                 port: port as u16,
-                addr
+                addr,
+                token: settings.get_str("metrics_token").ok(),
             });
         }
     }
     return metrics;
 }
 
+/// Check that the metrics listener doesn't take one of the addresses of the NTS-KE server. Both
+/// are TCP listeners, so one of them would fail to bind.
+fn check_metrics_addr(
+    addrs: &[SocketAddr],
+    metrics_config: &Option<MetricsConfig>,
+) -> Result<(), config::ConfigError> {
+    let metrics = match metrics_config {
+        Some(metrics) => metrics,
+        None => return Ok(()),
+    };
+    match addrs.iter().find(|addr| metrics.overlaps(**addr)) {
+        Some(addr) => Err(config::ConfigError::Message(format!(
+            "the addr {} overlaps with the metrics_addr {} and metrics_port {}",
+            addr, metrics.addr, metrics.port
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Read TLS certificates from a file.
+///
+/// # Errors
+///
+/// There will be an error if we cannot open the file or the content is not parsable to get
+/// certificates.
+///
+fn read_tls_certs(filename: &str) -> Result<Vec<Certificate>, std::io::Error> {
+    // Open a file. If there is any error, return it immediately.
+    let file = File::open(filename)?;
+
+    match pemfile::certs(&mut std::io::BufReader::new(file)) {
+        Ok(certs) => Ok(certs),
+        // We don't use Err(_) here because if the error type of `rustls` changes in the
+        // future, we will get noticed.
+        //
+        // The `std::io` module has an error kind of `InvalidData` which is perfectly
+        // suitable for our kind of error.
+        Err(()) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("cannot parse TLS certificates from {}", filename),
+        )),
+    }
+}
+
+/// Read TLS private keys from a file.
+///
+/// # Errors
+///
+/// There will be an error if we cannot open the file or the content is not parsable to get
+/// private keys.
+///
+fn read_tls_secret_keys(
+    logger: &slog::Logger,
+    filename: &str,
+) -> Result<Vec<PrivateKey>, std::io::Error> {
+    // Open a file. If there is any error, return it immediately.
+    let file = File::open(filename)?;
+
+    warn_if_world_readable(logger, filename, &file.metadata()?);
+
+    match pemfile::pkcs8_private_keys(&mut std::io::BufReader::new(file)) {
+        Ok(secret_keys) => Ok(secret_keys),
+        // We don't use Err(_) here because if the error type of `rustls` changes in the
+        // future, we will get noticed.
+        //
+        // The `std::io` module has an error kind of `InvalidData` which is perfectly
+        // suitable for our kind of error.
+        Err(()) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("cannot parse TLS private keys from {}", filename),
+        )),
+    }
+}
+
 /// Configuration for running an NTS-KE server.
 #[derive(Debug)]
 pub struct KeServerConfig {
@@ -41,6 +152,10 @@ pub struct KeServerConfig {
     /// The initial cookie key for the NTS-KE server.
     cookie_key: CookieKey,
 
+    /// Where the cookie key was read from, if anywhere. It's read again when the keys are
+    /// rotated on request.
+    cookie_key_source: Option<CookieKeySource>,
+
     // If you don't to have a timeout, just set it to a very high value.
     timeout: u64,
 
@@ -54,8 +169,28 @@ pub struct KeServerConfig {
 
     pub metrics_config: Option<MetricsConfig>,
     pub next_port: u16,
+
+    /// The number of cookies sent to the client in each exchange. It must be between 1 and
+    /// `MAX_COOKIE_COUNT`.
+    pub cookie_count: u8,
+
+    /// If it's true, the cookies are bound to the subnet of the client. It must be the same as
+    /// in the NTP server, otherwise the NTP server rejects every cookie.
+    pub bind_cookies_to_subnet: bool,
+
+    /// How to answer a client advertising only AEAD algorithms we don't support.
+    pub unsupported_aead_response: UnsupportedAeadResponse,
+
+    /// The rotation of the cookie keys. It must be the same as in the NTP server, otherwise the
+    /// servers look for the keys under different times.
+    pub cookie_rotation: RotationPeriods,
+
     pub tls_certs: Vec<Certificate>,
     pub tls_secret_keys: Vec<PrivateKey>,
+
+    /// The files the TLS certificates and private keys were imported from, if any. They are read
+    /// again when the certificates are reloaded.
+    tls_filenames: Option<(String, String)>,
 }
 
 /// We decided to make KeServerConfig mutable so that you can add more cert, private key, or
@@ -83,6 +218,12 @@ impl KeServerConfig {
 
             tls_certs: Vec::new(),
             tls_secret_keys: Vec::new(),
+            tls_filenames: None,
+            cookie_key_source: None,
+            cookie_count: DEFAULT_COOKIE_COUNT,
+            bind_cookies_to_subnet: false,
+            unsupported_aead_response: UnsupportedAeadResponse::Empty,
+            cookie_rotation: RotationPeriods::default(),
 
             // From parameters.
             cookie_key,
@@ -122,6 +263,11 @@ impl KeServerConfig {
         &self.cookie_key
     }
 
+    /// Return where the cookie key was read from, if anywhere.
+    pub fn cookie_key_source(&self) -> Option<&CookieKeySource> {
+        self.cookie_key_source.as_ref()
+    }
+
     /// Set a new logger to the config.
     pub fn set_logger(&mut self, logger: slog::Logger) {
         self.logger = logger;
@@ -152,28 +298,11 @@ impl KeServerConfig {
     // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
     // method has to be private for now.
     fn import_tls_certs(&mut self, filename: &str) -> Result<(), std::io::Error> {
-        // Open a file. If there is any error, return it immediately.
-        let file = File::open(filename)?;
-
-        match pemfile::certs(&mut std::io::BufReader::new(file)) {
-            Ok(certs) => {
-                // Add all parsed certificates.
-                for cert in certs {
-                    self.add_tls_cert(cert);
-                }
-                // Return success.
-                Ok(())
-            },
-            // We don't use Err(_) here because if the error type of `rustls` changes in the
-            // future, we will get noticed.
-            //
-            // The `std::io` module has an error kind of `InvalidData` which is perfectly
-            // suitable for our kind of error.
-            Err(()) => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("cannot parse TLS certificates from {}", filename),
-            )),
+        // Add all parsed certificates.
+        for cert in read_tls_certs(filename)? {
+            self.add_tls_cert(cert);
         }
+        Ok(())
     }
 
     /// Import TLS private keys from a file.
@@ -186,26 +315,50 @@ impl KeServerConfig {
     // Because the order of `tls_certs` has to correspond to the order of `tls_secret_keys`, this
     // method has to be private for now.
     fn import_tls_secret_keys(&mut self, filename: &str) -> Result<(), std::io::Error> {
-        // Open a file. If there is any error, return it immediately.
-        let file = File::open(filename)?;
-
-        match pemfile::pkcs8_private_keys(&mut std::io::BufReader::new(file)) {
-            Ok(secret_keys) => {
-                // Add all parsed secret keys.
-                for secret_key in secret_keys {
-                    self.add_tls_secret_key(secret_key);
-                }
-                // Return success.
-                Ok(())
-            },
-            // We don't use Err(_) here because if the error type of `rustls` changes in the
-            // future, we will get noticed.
-            //
-            // The `std::io` module has an error kind of `InvalidData` which is perfectly
-            // suitable for our kind of error.
-            Err(()) => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("cannot parse TLS private keys from {}", filename),
+        // Add all parsed secret keys.
+        for secret_key in read_tls_secret_keys(&self.logger, filename)? {
+            self.add_tls_secret_key(secret_key);
+        }
+        Ok(())
+    }
+
+    /// Import TLS certificates and their private keys from files, and remember the files so that
+    /// `read_tls_files` can read them again, e.g. after the certificates are renewed.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if we cannot open the files or the contents are not parsable to
+    /// get certificates and private keys.
+    ///
+    pub fn import_tls_files(
+        &mut self,
+        certs_filename: &str,
+        secret_keys_filename: &str,
+    ) -> Result<(), std::io::Error> {
+        self.import_tls_certs(certs_filename)?;
+        self.import_tls_secret_keys(secret_keys_filename)?;
+        self.tls_filenames =
+            Some((String::from(certs_filename), String::from(secret_keys_filename)));
+        Ok(())
+    }
+
+    /// Read the TLS certificates and private keys again from the files they were imported from.
+    /// The config itself is left as it is.
+    ///
+    /// # Errors
+    ///
+    /// There will be an error if the certificates were not imported from files, or if we cannot
+    /// read them anymore.
+    ///
+    pub fn read_tls_files(&self) -> Result<(Vec<Certificate>, Vec<PrivateKey>), std::io::Error> {
+        match &self.tls_filenames {
+            Some((certs_filename, secret_keys_filename)) => Ok((
+                read_tls_certs(certs_filename)?,
+                read_tls_secret_keys(&self.logger, secret_keys_filename)?,
+            )),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "the TLS certificates were not imported from files",
             )),
         }
     }
@@ -228,6 +381,8 @@ impl KeServerConfig {
     ///
     /// * The next port in the configuration file is a valid `i64` but not a valid `u16`.
     /// * The connection timeout in the configuration file is a valid `i64` but not a valid `u64`.
+    /// * The cookie count in the configuration file is not between 1 and `MAX_COOKIE_COUNT`.
+    /// * One of the addresses in the configuration file overlaps with the metrics address.
     ///
     // Returning a `Message` object here is not a good practice. I will figure out a good practice
     // later.
@@ -277,6 +432,41 @@ impl KeServerConfig {
             },
         };
 
+        // Resolves the number of cookies sent in each exchange.
+        let cookie_count = match settings.get_int("cookie_count") {
+            // If it's a not-found error, we just set it to the default value.
+            Err(config::ConfigError::NotFound(_)) => DEFAULT_COOKIE_COUNT,
+
+            // If it's other error, for example, unparseable error, it means that the user intended
+            // to enter the cookie count but it just fails.
+            Err(error) => return Err(error),
+
+            Ok(val) => {
+                match u8::try_from(val) {
+                    Ok(val) if val >= 1 && val <= MAX_COOKIE_COUNT => val,
+                    _ => {
+                        return Err(config::ConfigError::Message(format!(
+                            "the cookie count must be between 1 and {}", MAX_COOKIE_COUNT
+                        )));
+                    },
+                }
+            },
+        };
+
+        let bind_cookies_to_subnet = match settings.get_bool("bind_cookies_to_subnet") {
+            Err(config::ConfigError::NotFound(_)) => false,
+            Err(error) => return Err(error),
+            Ok(val) => val,
+        };
+
+        let unsupported_aead_response = match settings.get_str("unsupported_aead_response") {
+            Err(config::ConfigError::NotFound(_)) => UnsupportedAeadResponse::Empty,
+            Err(error) => return Err(error),
+            Ok(name) => name.parse().wrap_err()?,
+        };
+
+        let cookie_rotation = RotationPeriods::from_settings(&settings)?;
+
         // Resolves metrics configuration.
         let metrics_config = get_metrics_config(&settings);
 
@@ -288,8 +478,9 @@ impl KeServerConfig {
         let certs_filename = settings.get_str("tls_cert_file")?;
         let secret_keys_filename = settings.get_str("tls_key_file")?;
 
-        let cookie_key_filename = settings.get_str("cookie_key_file")?;
-        let cookie_key = CookieKey::parse(&cookie_key_filename).wrap_err()?;
+        let cookie_key_source = CookieKeySource::from_settings(&settings)?;
+        // The config has no logger yet, so the warnings about the key file go to the global one.
+        let cookie_key = cookie_key_source.load(&slog_scope::logger()).wrap_err()?;
 
         let mut config = KeServerConfig::new(
             timeout,
@@ -298,9 +489,13 @@ impl KeServerConfig {
             metrics_config,
             next_port,
         );
+        config.cookie_count = cookie_count;
+        config.bind_cookies_to_subnet = bind_cookies_to_subnet;
+        config.unsupported_aead_response = unsupported_aead_response;
+        config.cookie_rotation = cookie_rotation;
+        config.cookie_key_source = Some(cookie_key_source);
 
-        config.import_tls_certs(&certs_filename).wrap_err()?;
-        config.import_tls_secret_keys(&secret_keys_filename).wrap_err()?;
+        config.import_tls_files(&certs_filename, &secret_keys_filename).wrap_err()?;
 
         let addrs = settings.get_array("addr")?;
         for addr in addrs {
@@ -308,7 +503,44 @@ impl KeServerConfig {
             let sock_addr = addr.to_string().parse().wrap_err()?;
             config.add_address(sock_addr);
         }
+        check_metrics_addr(config.addrs(), &config.metrics_config)?;
 
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_metrics_addr() {
+        let addrs: Vec<SocketAddr> = vec!["[::]:1234".parse().unwrap()];
+        check_metrics_addr(&addrs, &None).unwrap();
+
+        let metrics_config = |addr: &str, port| Some(MetricsConfig {
+            port,
+            addr: String::from(addr),
+            token: None,
+        });
+        check_metrics_addr(&addrs, &metrics_config("127.0.0.1", 8001)).unwrap();
+
+        match check_metrics_addr(&addrs, &metrics_config("127.0.0.1", 1234)) {
+            Err(config::ConfigError::Message(message)) => assert_eq!(
+                message,
+                "the addr [::]:1234 overlaps with the metrics_addr 127.0.0.1 and metrics_port 1234"
+            ),
+            _ => panic!("the metrics listener must not take the address of the server"),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_aead_response() {
+        assert_eq!("empty".parse::<UnsupportedAeadResponse>().unwrap(),
+                   UnsupportedAeadResponse::Empty);
+        assert_eq!("error".parse::<UnsupportedAeadResponse>().unwrap(),
+                   UnsupportedAeadResponse::Error);
+        let error = "ignore".parse::<UnsupportedAeadResponse>().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -13,36 +13,199 @@ use slog::{debug, error, info};
 use std::sync::{Arc, RwLock};
 use std::io::{Read, Write};
 
-use crate::cookie::{make_cookie, NTSKeys};
+use crate::cookie::{make_cookie_with_context, subnet_context, NTSKeys};
 use crate::key_rotator::KeyRotator;
 use crate::nts_ke::records::gen_key;
-use crate::nts_ke::records::serialize;
+use crate::nts_ke::records::{deserialize, serialize};
 use crate::nts_ke::records::{
     AeadAlgorithmRecord,
     EndOfMessageRecord,
+    ErrorRecord,
     NextProtocolRecord,
     NewCookieRecord,
     PortRecord,
 
+    DeserializeError,
+    KeRecord,
     KnownAeadAlgorithm,
     KnownNextProtocol,
     Party,
+
+    HEADER_SIZE,
 };
 
+use super::config::UnsupportedAeadResponse;
 use super::listener::KeServerListener;
 use super::server::KeServerState;
 
+/// The largest request we buffer. A request is a few small records, so a larger one is either
+/// malformed or an attempt to make us buffer without end.
+const MAX_REQUEST_SIZE: usize = 16384;
+
+/// The result of a successful negotiation with a client.
+#[derive(Clone, Copy)]
+struct Negotiation {
+    /// The next protocol chosen for the client. The keys are exported for it.
+    next_protocol: KnownNextProtocol,
+    /// The AEAD algorithm chosen for the client.
+    aead: KnownAeadAlgorithm,
+}
+
+/// The reason that the negotiation with a client fails.
+#[derive(Debug)]
+enum NegotiationError {
+    /// The client sent a critical record we don't recognize.
+    UnrecognizedCriticalRecord,
+    /// The request is malformed or there is nothing we can agree on.
+    BadRequest(String),
+    /// The client advertised only the AEAD algorithms with these ids, none of which we support.
+    NoAcceptableAead(Vec<u16>),
+}
+
+impl From<&str> for NegotiationError {
+    fn from(message: &str) -> NegotiationError {
+        NegotiationError::BadRequest(String::from(message))
+    }
+}
+
+/// Parse the records of a client request and negotiate the next protocol and the AEAD algorithm.
+///
+/// It returns `Ok(None)` if the request is not complete yet, i.e. we haven't seen the End of
+/// Message record.
+///
+/// # Errors
+///
+/// There will be an error if the request is malformed or there is nothing we can agree on.
+///
+fn negotiate(request: &[u8]) -> Result<Option<Negotiation>, NegotiationError> {
+    if request.len() > MAX_REQUEST_SIZE {
+        return Err(NegotiationError::from("the request is too large"));
+    }
+
+    let mut next_protocols: Option<Vec<KnownNextProtocol>> = None;
+    let mut aead_algorithms: Option<AeadAlgorithmRecord> = None;
+    let mut position = 0;
+
+    loop {
+        let remaining = &request[position..];
+        if remaining.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        // Retrieve a body length from the 3rd and 4th bytes of the header.
+        let body_length = usize::from(u16::from_be_bytes([remaining[2], remaining[3]]));
+        if remaining.len() < HEADER_SIZE + body_length {
+            return Ok(None);
+        }
+
+        // `deserialize` has an invariant that the slice needs to be long enough to make it a
+        // valid record, which in this case our slice is exactly as long as specified in the
+        // length field.
+        let record_bytes = &remaining[..HEADER_SIZE + body_length];
+        position += record_bytes.len();
+
+        match deserialize(Party::Client, record_bytes) {
+            Ok(KeRecord::EndOfMessage(_)) => break,
+            Ok(KeRecord::NextProtocol(record)) => {
+                next_protocols = Some(record.protocols());
+            },
+            Ok(KeRecord::AeadAlgorithm(record)) => {
+                aead_algorithms = Some(record);
+            },
+            // The other records are not expected from the client, but they don't harm us either.
+            Ok(_) => {},
+            // If it's not critical, just ignore the error.
+            Err(DeserializeError::UnknownNotCriticalRecord) => {},
+            Err(DeserializeError::UnknownCriticalRecord) => {
+                return Err(NegotiationError::UnrecognizedCriticalRecord);
+            },
+            Err(DeserializeError::Parsing(error)) => {
+                return Err(NegotiationError::BadRequest(error));
+            },
+        }
+    }
+
+    let next_protocols = next_protocols.ok_or("missing Next Protocol Negotiation record")?;
+    let aead_algorithms = aead_algorithms.ok_or("missing AEAD Algorithm Negotiation record")?;
+
+    // NTPv4 is the only protocol we support.
+    let ntpv4 = KnownNextProtocol::Ntpv4.as_protocol_id();
+    if !next_protocols.iter().any(|protocol| protocol.as_protocol_id() == ntpv4) {
+        return Err(NegotiationError::from("the client doesn't support NTPv4"));
+    }
+
+    // The client lists the algorithms in the order of preference, so we pick the first one we
+    // support.
+    match aead_algorithms.algorithms().first() {
+        Some(aead) => Ok(Some(Negotiation {
+            next_protocol: KnownNextProtocol::Ntpv4,
+            aead: *aead,
+        })),
+        None => Err(NegotiationError::NoAcceptableAead(aead_algorithms.algorithm_ids().to_vec())),
+    }
+}
+
+/// error_response computes the response sent to the client, when the negotiation fails.
+///
+/// If there is no AEAD algorithm we agree on, NTPv4 is still fine, so the response has the Next
+/// Protocol Negotiation record and an empty AEAD Algorithm Negotiation record. It has an Error
+/// record too, if `unsupported_aead_response` asks for it. There are no cookies, as there are no
+/// keys to put in them.
+fn error_response(
+    error: &NegotiationError,
+    unsupported_aead_response: UnsupportedAeadResponse,
+) -> Vec<u8> {
+    let mut response: Vec<u8> = Vec::new();
+
+    let error_record = match error {
+        NegotiationError::UnrecognizedCriticalRecord => {
+            Some(ErrorRecord::unrecognized_critical_record())
+        },
+        NegotiationError::BadRequest(_) => Some(ErrorRecord::bad_request()),
+        NegotiationError::NoAcceptableAead(_) => {
+            response.append(&mut serialize(NextProtocolRecord::from(vec![
+                KnownNextProtocol::Ntpv4,
+            ])));
+            response.append(&mut serialize(AeadAlgorithmRecord::from(vec![])));
+            match unsupported_aead_response {
+                UnsupportedAeadResponse::Empty => None,
+                UnsupportedAeadResponse::Error => Some(ErrorRecord::bad_request()),
+            }
+        },
+    };
+
+    if let Some(error_record) = error_record {
+        response.append(&mut serialize(error_record));
+    }
+    response.append(&mut serialize(EndOfMessageRecord));
+    response
+}
+
+/// The response to a valid request we failed to answer, e.g. because the keys could not be
+/// exported: an Error record of Internal Server Error and End of Message.
+fn internal_error_response() -> Vec<u8> {
+    let mut response = serialize(ErrorRecord::internal_server_error());
+    response.append(&mut serialize(EndOfMessageRecord));
+    response
+}
+
 // response uses the configuration and the keys and computes the response
-// sent to the client.
-fn response(keys: NTSKeys, rotator: &Arc<RwLock<KeyRotator>>, port: u16) -> Vec<u8> {
+// sent to the client. The cookies are bound to `cookie_context`, which is empty unless they are
+// bound to the subnet of the client.
+fn response(
+    keys: NTSKeys,
+    aead: KnownAeadAlgorithm,
+    rotator: &Arc<RwLock<KeyRotator>>,
+    port: u16,
+    cookie_count: u8,
+    cookie_context: &[u8],
+) -> Vec<u8> {
     let mut response: Vec<u8> = Vec::new();
 
     let next_protocol_record = NextProtocolRecord::from(vec![
         KnownNextProtocol::Ntpv4,
     ]);
-    let aead_record = AeadAlgorithmRecord::from(vec![
-        KnownAeadAlgorithm::AeadAesSivCmac256,
-    ]);
+    let aead_record = AeadAlgorithmRecord::from(vec![aead]);
     let port_record = PortRecord::new(Party::Server, port);
     let end_record = EndOfMessageRecord;
 
@@ -53,9 +216,15 @@ fn response(keys: NTSKeys, rotator: &Arc<RwLock<KeyRotator>>, port: u16) -> Vec<
     let (key_id, actual_key) = rotor.latest_key_value();
 
     // According to the spec, if the next protocol is NTPv4, we should send eight cookies to the
-    // client.
-    for _ in 0..8 {
-        let cookie = make_cookie(keys, actual_key.as_ref(), key_id);
+    // client. It's configurable, but eight by default.
+    for _ in 0..cookie_count {
+        let cookie = make_cookie_with_context(
+            keys,
+            aead,
+            actual_key.as_ref(),
+            key_id,
+            cookie_context,
+        );
         let cookie_record = NewCookieRecord::from(cookie);
         response.append(&mut serialize(cookie_record));
     }
@@ -95,6 +264,9 @@ pub struct KeServerConn {
     /// The status of the connection.
     state: KeServerConnState,
 
+    /// The plaintext request received so far.
+    request: Vec<u8>,
+
     /// Logger.
     logger: slog::Logger,
 }
@@ -107,8 +279,10 @@ impl KeServerConn {
     ) -> KeServerConn {
         let server_state = listener.state();
 
-        // Create a TLS session from a server-wide configuration.
-        let tls_session = rustls::ServerSession::new(&server_state.tls_server_config);
+        // Create a TLS session from a server-wide configuration. The session keeps the config
+        // it started with, even if the certificates are reloaded in the meantime.
+        let tls_server_config = server_state.tls_server_config.read().unwrap().clone();
+        let tls_session = rustls::ServerSession::new(&tls_server_config);
         // Create a child logger for the connection.
         let logger = listener.logger().new(slog::o!("client" => listener.addr().to_string()));
 
@@ -120,6 +294,7 @@ impl KeServerConn {
             token,
             logger,
             state: KeServerConnState::Connected,
+            request: Vec::new(),
         }
     }
 
@@ -198,14 +373,44 @@ impl KeServerConn {
                 self.state = KeServerConnState::Opened;
             }
 
-            let keys = gen_key(&self.tls_session).unwrap();
-
             // We have to make sure that the response is not sent yet.
             if self.state == KeServerConnState::Opened {
-                // TODO: Fix unwrap later.
-                self.tls_session
-                    .write_all(&response(keys, &self.server_state.rotator,
-                                         self.server_state.config.next_port)).unwrap();
+                self.request.append(&mut buf);
+
+                let reply = match negotiate(&self.request) {
+                    // The request is not complete yet. Wait for more records.
+                    Ok(None) => return,
+                    Ok(Some(negotiation)) => {
+                        let protocol_id = negotiation.next_protocol.as_protocol_id();
+                        match gen_key(&self.tls_session, protocol_id, negotiation.aead) {
+                            Ok(keys) => {
+                                let config = &self.server_state.config;
+                                let cookie_context = match self.tcp_stream.peer_addr() {
+                                    Ok(addr) if config.bind_cookies_to_subnet => {
+                                        subnet_context(addr.ip())
+                                    },
+                                    _ => Vec::new(),
+                                };
+                                response(keys, negotiation.aead, &self.server_state.rotator,
+                                         config.next_port, config.cookie_count, &cookie_context)
+                            },
+                            Err(error) => {
+                                error!(self.logger, "cannot export the keys: {:?}", error);
+                                internal_error_response()
+                            },
+                        }
+                    },
+                    Err(error) => {
+                        error!(self.logger, "negotiation failed: {:?}", error);
+                        error_response(&error, self.server_state.config.unsupported_aead_response)
+                    },
+                };
+
+                if let Err(error) = self.tls_session.write_all(&reply) {
+                    error!(self.logger, "cannot write the response: {}", error);
+                    self.shutdown();
+                    return;
+                }
                 // Mark that the reponse is sent.
                 self.state = KeServerConnState::ResponseSent;
             }
@@ -262,3 +467,154 @@ impl KeServerConn {
         self.state = KeServerConnState::Closed;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cookie::CookieKey;
+    use crate::key_rotator::KeyId;
+
+    fn client_request() -> Vec<u8> {
+        let mut request = Vec::new();
+        request.append(&mut serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4])));
+        request.append(&mut serialize(AeadAlgorithmRecord::from(vec![
+            KnownAeadAlgorithm::AeadAesSivCmac256,
+        ])));
+        request.append(&mut serialize(EndOfMessageRecord));
+        request
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiation = negotiate(&client_request()).unwrap().unwrap();
+        assert_eq!(negotiation.next_protocol.as_protocol_id(), 0);
+        assert_eq!(negotiation.aead.as_algorithm_id(), 15);
+    }
+
+    #[test]
+    fn test_negotiate_incomplete() {
+        let request = client_request();
+
+        // Missing the End of Message record.
+        assert!(negotiate(&request[..request.len() - HEADER_SIZE]).unwrap().is_none());
+        // Cut in the middle of a record.
+        assert!(negotiate(&request[..HEADER_SIZE + 1]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_negotiate_too_large() {
+        // A record claiming a body of 65535 bytes. The request is incomplete, until it's too
+        // large to wait for.
+        let mut request = vec![0x40, 0x01, 0xff, 0xff];
+        request.resize(MAX_REQUEST_SIZE, 0);
+        assert!(negotiate(&request).unwrap().is_none());
+        request.push(0);
+        match negotiate(&request) {
+            Err(NegotiationError::BadRequest(error)) => {
+                assert_eq!(error, "the request is too large")
+            }
+            _ => panic!("a request larger than MAX_REQUEST_SIZE must be rejected"),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_missing_aead() {
+        let mut request = Vec::new();
+        request.append(&mut serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4])));
+        request.append(&mut serialize(EndOfMessageRecord));
+
+        assert!(negotiate(&request).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_unsupported_aead() {
+        // The client advertises only the AEAD algorithm ids 1 and 16, which we don't support.
+        let mut request = Vec::new();
+        request.append(&mut serialize(NextProtocolRecord::from(vec![KnownNextProtocol::Ntpv4])));
+        request.extend_from_slice(&[0x80, 0x04, 0x00, 0x04, 0x00, 0x01, 0x00, 0x10]);
+        request.append(&mut serialize(EndOfMessageRecord));
+
+        let error = negotiate(&request).err().unwrap();
+        match error {
+            NegotiationError::NoAcceptableAead(ref algorithm_ids) => {
+                assert_eq!(algorithm_ids, &vec![1, 16])
+            },
+            _ => panic!("a request without a supported AEAD algorithm must fail the negotiation"),
+        }
+
+        // NTPv4, an empty AEAD Algorithm Negotiation record and End of Message.
+        let next_protocol = [0x80, 0x01, 0x00, 0x02, 0x00, 0x00];
+        let empty_aead = [0x80, 0x04, 0x00, 0x00];
+        let end_of_message = [0x80, 0x00, 0x00, 0x00];
+        let response = error_response(&error, UnsupportedAeadResponse::Empty);
+        assert_eq!(response, [&next_protocol[..], &empty_aead, &end_of_message].concat());
+
+        // The same with an Error record of Bad Request before End of Message.
+        let bad_request = [0x80, 0x02, 0x00, 0x02, 0x00, 0x01];
+        let response = error_response(&error, UnsupportedAeadResponse::Error);
+        assert_eq!(response,
+                   [&next_protocol[..], &empty_aead, &bad_request, &end_of_message].concat());
+    }
+
+    #[test]
+    fn test_negotiate_unknown_critical_record() {
+        let mut request = Vec::new();
+        // The critical bit is set and the record type is 0x4001 which is unknown.
+        request.extend_from_slice(&[0xc0, 0x01, 0x00, 0x00]);
+        request.append(&mut client_request());
+
+        match negotiate(&request) {
+            Err(NegotiationError::UnrecognizedCriticalRecord) => {},
+            _ => panic!("an unknown critical record must fail the negotiation"),
+        }
+
+        // The server must answer with an Error record of code 0.
+        let response = error_response(&NegotiationError::UnrecognizedCriticalRecord,
+                                      UnsupportedAeadResponse::Empty);
+        assert_eq!(&response[..HEADER_SIZE + 2], &[0x80, 0x02, 0x00, 0x02, 0x00, 0x00][..]);
+    }
+
+    #[test]
+    fn test_internal_error_response() {
+        // An Error record of code 2 and End of Message, which the client can parse.
+        let response = internal_error_response();
+        assert_eq!(response, [0x80, 0x02, 0x00, 0x02, 0x00, 0x02, 0x80, 0x00, 0x00, 0x00]);
+        match deserialize(Party::Server, &response[..HEADER_SIZE + 2]) {
+            Ok(KeRecord::Error(_)) => {},
+            _ => panic!("the response must start with an Error record"),
+        }
+    }
+
+    #[test]
+    fn test_cookie_count() {
+        let rotator = Arc::new(RwLock::new(KeyRotator::with_key(
+            CookieKey::from(&[0x07; 32][..]),
+            KeyId::new(1),
+            &[0x01; 32],
+        )));
+        let keys = NTSKeys {
+            c2s: [1; 32],
+            s2c: [2; 32],
+        };
+
+        for cookie_count in 1..=8 {
+            let bytes = response(keys, KnownAeadAlgorithm::AeadAesSivCmac256, &rotator, 123,
+                                 cookie_count, &[]);
+
+            // Count the New Cookie records in the response.
+            let mut count = 0;
+            let mut position = 0;
+            while position < bytes.len() {
+                let body_length = u16::from_be_bytes([bytes[position + 2], bytes[position + 3]]);
+                let end = position + HEADER_SIZE + usize::from(body_length);
+                if let Ok(KeRecord::NewCookie(_)) = deserialize(Party::Server,
+                                                                 &bytes[position..end]) {
+                    count += 1;
+                }
+                position = end;
+            }
+            assert_eq!(count, cookie_count);
+        }
+    }
+}
@@ -16,6 +16,7 @@ use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use crate::cfsock;
+use crate::error::StartupError;
 
 use super::connection::KeServerConn;
 use super::connection::KeServerConnState;
@@ -65,15 +66,18 @@ impl KeServerListener {
     /// # Errors
     ///
     /// All the errors here are from the kernel which we don't have to know about for now.
-    pub fn bind(addr: SocketAddr, server: &KeServer) -> Result<KeServerListener, std::io::Error> {
+    pub fn bind(addr: SocketAddr, server: &KeServer) -> Result<KeServerListener, StartupError> {
         let state = server.state();
-        let poll = mio::Poll::new()?;
+        // Only the binding itself is a bind error. The rest fails for other reasons, e.g. running
+        // out of file descriptors.
+        let other = |error: std::io::Error| StartupError::Other(Box::new(error));
+        let poll = mio::Poll::new().map_err(other)?;
 
         // Create a listening std tcp listener.
-        let std_tcp_listener = cfsock::tcp_listener(&addr)?;
+        let std_tcp_listener = cfsock::tcp_listener(&addr).map_err(StartupError::Bind)?;
 
         // Transform a std tcp listener to a mio tcp listener.
-        let mio_tcp_listener = TcpListener::from_std(std_tcp_listener)?;
+        let mio_tcp_listener = TcpListener::from_std(std_tcp_listener).map_err(other)?;
 
         // Register for the event that the listener is readable.
         poll.register(
@@ -81,7 +85,7 @@ impl KeServerListener {
             LISTENER_MIO_TOKEN,
             mio::Ready::readable(),
             mio::PollOpt::level(),
-        )?;
+        ).map_err(other)?;
 
         Ok(KeServerListener {
             // Create an `Arc` reference.
@@ -10,6 +10,7 @@ mod listener;
 mod server;
 
 // We expose only two structs: KeServer and KeServerConfig. KeServer is used to run an instant of
-// the NTS-KE server and KeServerConfig is used to instantiate KeServer.
+// the NTS-KE server and KeServerConfig is used to instantiate KeServer. UnsupportedAeadResponse
+// is one of the options of KeServerConfig.
 pub use self::server::KeServer;
-pub use self::config::KeServerConfig;
+pub use self::config::{KeServerConfig, UnsupportedAeadResponse};
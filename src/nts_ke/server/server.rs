@@ -4,13 +4,22 @@
 
 //! NTS-KE server instantiation.
 
-use slog::info;
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 
+use rustls::{Certificate, PrivateKey};
+
+use slog::{error, info};
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
+use crate::error::StartupError;
 use crate::key_rotator::KeyRotator;
 use crate::key_rotator::RotateError;
-use crate::key_rotator::periodic_rotate;
+use crate::key_rotator::{periodic_rotate, rotate_on_sigusr1};
 use crate::metrics;
 
 use super::config::KeServerConfig;
@@ -31,8 +40,13 @@ pub(super) struct KeServerState {
 
     /// TLS server configuration which will be used among listeners.
     // We use `Arc` here so that every thread can read the config, but the drawback of using `Arc`
-    // is that it uses garbage collection.
-    pub(super) tls_server_config: Arc<rustls::ServerConfig>,
+    // is that it uses garbage collection. The `RwLock` lets a reload swap in a new config, while
+    // the connections already open keep the old one.
+    pub(super) tls_server_config: RwLock<Arc<rustls::ServerConfig>>,
+
+    /// Whether the certificates are loaded and all the listeners are bound. This is used by the
+    /// `/healthz` endpoint.
+    pub(super) ready: AtomicBool,
 }
 
 /// NTS-KE server instance.
@@ -54,46 +68,29 @@ impl KeServer {
     /// This doesn't start the server yet. It just makes to the state that it's ready to start.
     /// Please run `start` to start the server.
     pub fn connect(config: KeServerConfig) -> Result<KeServer, RotateError> {
-        let rotator = KeyRotator::connect(
+        let mut rotator = KeyRotator::connect(
             String::from("/nts/nts-keys"),
             String::from(config.memcached_url()),
 
             // We need to clone all of the following properties because the key rotator also
             // has to own them.
             config.cookie_key().clone(),
+            config.cookie_rotation,
             config.logger().clone(),
         )?;
+        rotator.set_master_key_source(config.cookie_key_source().cloned());
 
-        // Putting it in a block just to make it easier to read :)
-        let tls_server_config = {
-            // No client auth for TLS server.
-            let client_auth = rustls::NoClientAuth::new();
-            // TLS server configuration.
-            let mut server_config = rustls::ServerConfig::new(client_auth);
-
-            // We support only TLS1.3
-            server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
-
-            // Set the certificate chain and its corresponding private key.
-            server_config
-                .set_single_cert(
-                    // rustls::ServerConfig wants to own both of them.
-                    config.tls_certs.clone(),
-                    config.tls_secret_keys[0].clone()
-                )
-                .expect("invalid key or certificate");
-
-            // According to the NTS specification, ALPN protocol must be "ntske/1".
-            server_config
-                .set_protocols(&[Vec::from("ntske/1".as_bytes())]);
-
-            server_config
-        };
+        let tls_server_config = tls_server_config(
+            // rustls::ServerConfig wants to own both of them.
+            config.tls_certs.clone(),
+            config.tls_secret_keys[0].clone(),
+        ).expect("invalid key or certificate");
 
         let state = Arc::new(KeServerState {
             config,
             rotator: Arc::new(RwLock::new(rotator)),
-            tls_server_config: Arc::new(tls_server_config),
+            tls_server_config: RwLock::new(Arc::new(tls_server_config)),
+            ready: AtomicBool::new(false),
         });
 
         Ok(KeServer {
@@ -103,7 +100,7 @@ impl KeServer {
     }
 
     /// Start the server.
-    pub fn start(&mut self) -> Result<(), std::io::Error> {
+    pub fn start(&mut self) -> Result<(), StartupError> {
         let logger = self.state.config.logger();
 
         // Side-effect. Logging.
@@ -115,6 +112,11 @@ impl KeServer {
 
         // Create a new thread and periodically rotate the keys.
         periodic_rotate(mutable_rotator);
+        // Operators can force a rotation with SIGUSR1.
+        rotate_on_sigusr1().map_err(|error| StartupError::Other(Box::new(error)))?;
+        // And reload the certificates with SIGHUP, e.g. after they are renewed.
+        reload_tls_on_sighup(self.state.clone())
+            .map_err(|error| StartupError::Other(Box::new(error)))?;
 
         // We need to clone the metrics config here because we need to move it to another thread.
         if let Some(metrics_config) = self.state.config.metrics_config.clone() {
@@ -123,9 +125,18 @@ impl KeServer {
             // Create a child logger to use inside the metric server.
             let log_metrics = logger.new(slog::o!("component" => "metrics"));
 
+            // The server is healthy when all the listeners are bound and the cookie keys keep
+            // rotating.
+            let health: metrics::HealthCheck = {
+                let state = self.state.clone();
+                Arc::new(move || {
+                    state.ready.load(Ordering::SeqCst) && !state.rotator.read().unwrap().is_stale()
+                })
+            };
+
             // Start a metric server.
             std::thread::spawn(move || {
-                metrics::run_metrics(metrics_config, &log_metrics)
+                metrics::run_metrics(metrics_config, health, &log_metrics)
                     .expect("metrics could not be run; starting ntp server failed");
             });
         }
@@ -149,6 +160,9 @@ impl KeServer {
             self.listeners.push(atomic_listener);
         }
 
+        // All the listeners are bound successfully. We are ready to serve traffic.
+        self.state.ready.store(true, Ordering::SeqCst);
+
         // Join handles for the listeners.
         let mut handles = Vec::new();
 
@@ -188,3 +202,149 @@ impl KeServer {
         &self.state
     }
 }
+
+/// Create the TLS server configuration with the certificate chain and its private key.
+fn tls_server_config(
+    certs: Vec<Certificate>,
+    secret_key: PrivateKey,
+) -> Result<rustls::ServerConfig, rustls::TLSError> {
+    // No client auth for TLS server.
+    let client_auth = rustls::NoClientAuth::new();
+    // TLS server configuration.
+    let mut server_config = rustls::ServerConfig::new(client_auth);
+
+    // We support only TLS1.3
+    server_config.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+
+    // Set the certificate chain and its corresponding private key.
+    server_config.set_single_cert(certs, secret_key)?;
+
+    // According to the NTS specification, ALPN protocol must be "ntske/1".
+    server_config.set_protocols(&[Vec::from("ntske/1".as_bytes())]);
+
+    Ok(server_config)
+}
+
+/// Read the TLS certificates and the private key again from their files, and use them for the
+/// new connections. If anything fails, the server keeps the certificates it has.
+fn reload_tls(state: &KeServerState) -> Result<(), Box<dyn Error>> {
+    let (certs, secret_keys) = state.config.read_tls_files()?;
+    let secret_key = secret_keys.into_iter().next().ok_or("no TLS private key found")?;
+    let tls_server_config = tls_server_config(certs, secret_key)?;
+    *state.tls_server_config.write().unwrap() = Arc::new(tls_server_config);
+    Ok(())
+}
+
+/// Whether a reload of the TLS certificates was requested with SIGHUP.
+static TLS_RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_reload_signal(_: libc::c_int) {
+    TLS_RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Reload the TLS certificates whenever the process receives SIGHUP, e.g. with `kill -HUP`. The
+/// reload runs in its own thread, checking for a request every second.
+fn reload_tls_on_sighup(state: Arc<KeServerState>) -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle_reload_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safe because the handler only stores to an atomic.
+    unsafe { sigaction(Signal::SIGHUP, &action) }?;
+
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        if TLS_RELOAD_REQUESTED.swap(false, Ordering::SeqCst) {
+            let logger = state.config.logger();
+            match reload_tls(&state) {
+                Ok(()) => info!(logger, "reloaded the TLS certificates"),
+                Err(error) => error!(logger, "failure to reload the TLS certificates: {}", error),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustls::ResolvesServerCert;
+
+    use std::env;
+    use std::fs;
+
+    use crate::cookie::CookieKey;
+    use crate::key_rotator::KeyId;
+
+    fn test_state(config: KeServerConfig) -> KeServerState {
+        let tls_server_config = match config.tls_secret_keys.first() {
+            Some(secret_key) => tls_server_config(config.tls_certs.clone(), secret_key.clone())
+                .unwrap(),
+            None => rustls::ServerConfig::new(rustls::NoClientAuth::new()),
+        };
+        let rotator = KeyRotator::with_key(CookieKey::from(&[0x07; 32][..]), KeyId::new(1), &[1]);
+        KeServerState {
+            config,
+            rotator: Arc::new(RwLock::new(rotator)),
+            tls_server_config: RwLock::new(Arc::new(tls_server_config)),
+            ready: AtomicBool::new(false),
+        }
+    }
+
+    fn test_config() -> KeServerConfig {
+        KeServerConfig::new(15, CookieKey::from(&[0x07; 32][..]), String::new(), None, 123)
+    }
+
+    /// The certificate chain the server presents in the handshakes with the config.
+    fn presented_chain(tls_server_config: &rustls::ServerConfig) -> Vec<Certificate> {
+        tls_server_config.cert_resolver.resolve(None, &[]).unwrap().cert
+    }
+
+    #[test]
+    fn test_reload_tls() {
+        let certs_path = env::temp_dir().join("cfnts-test-reload-tls.pem");
+        let certs_filename = certs_path.to_str().unwrap();
+        fs::copy("tests/tls.pem", &certs_path).unwrap();
+
+        let mut config = test_config();
+        config.import_tls_files(certs_filename, "tests/tls-pkcs8.pem").unwrap();
+        let old_chain = config.tls_certs.clone();
+        let state = test_state(config);
+        let in_flight = state.tls_server_config.read().unwrap().clone();
+        assert_eq!(presented_chain(&in_flight), old_chain);
+
+        // The certificate is renewed, and now comes with its chain.
+        fs::copy("tests/chain.pem", &certs_path).unwrap();
+        reload_tls(&state).unwrap();
+        fs::remove_file(&certs_path).unwrap();
+
+        // The new handshakes present the new chain, and the connections already open keep the
+        // config they started with.
+        let current = state.tls_server_config.read().unwrap().clone();
+        assert_eq!(presented_chain(&current).len(), 3);
+        assert_ne!(presented_chain(&current), old_chain);
+        assert_eq!(presented_chain(&in_flight), old_chain);
+    }
+
+    #[test]
+    fn test_reload_tls_failure() {
+        let certs_path = env::temp_dir().join("cfnts-test-reload-tls-failure.pem");
+        let certs_filename = certs_path.to_str().unwrap();
+        fs::copy("tests/tls.pem", &certs_path).unwrap();
+
+        let mut config = test_config();
+        config.import_tls_files(certs_filename, "tests/tls-pkcs8.pem").unwrap();
+        let state = test_state(config);
+        let before = state.tls_server_config.read().unwrap().clone();
+
+        // The file is gone, so the server keeps the certificates it has.
+        fs::remove_file(&certs_path).unwrap();
+        reload_tls(&state).unwrap_err();
+        assert!(Arc::ptr_eq(&before, &state.tls_server_config.read().unwrap()));
+
+        // Nor is anything reloaded without the files.
+        reload_tls(&test_state(test_config())).unwrap_err();
+    }
+}
@@ -0,0 +1,133 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! A transcript of the records of a key exchange, for diagnosing interoperability issues.
+
+use std::fmt;
+
+use super::records::{deserialize, DeserializeError, KeRecord, Party, HEADER_SIZE};
+
+/// A record sent or received during a key exchange.
+#[derive(Clone, Debug)]
+pub struct TranscriptRecord {
+    /// Whether the client or the server sent the record.
+    pub sent_by_client: bool,
+    /// The record as it was on the wire.
+    pub bytes: Vec<u8>,
+    /// The decoded record, or why it couldn't be decoded.
+    pub decoded: String,
+}
+
+/// Every record sent and received during a key exchange, in order.
+///
+/// It carries the cookies the server sent, so don't share it with anyone you wouldn't give
+/// them to.
+#[derive(Clone, Debug, Default)]
+pub struct Transcript {
+    pub records: Vec<TranscriptRecord>,
+}
+
+impl Transcript {
+    /// Add a record sent by `sender` to the transcript.
+    pub fn push(&mut self, sender: Party, bytes: &[u8]) {
+        self.records.push(TranscriptRecord {
+            sent_by_client: match sender {
+                Party::Client => true,
+                Party::Server => false,
+            },
+            bytes: bytes.to_vec(),
+            decoded: describe_record(sender, bytes),
+        });
+    }
+}
+
+/// One line per record: the sender, the bytes in hex and the decoded record.
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for record in &self.records {
+            let sender = if record.sent_by_client { "client" } else { "server" };
+            writeln!(f, "{} {} {}", sender, hex::encode(&record.bytes), record.decoded)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decode a record for the transcript. Unlike the client, it shows the ids and codes as they
+/// were sent, even the ones we don't know.
+fn describe_record(sender: Party, bytes: &[u8]) -> String {
+    if bytes.len() < HEADER_SIZE {
+        return String::from("truncated record");
+    }
+    let record_type = u16::from_be_bytes([bytes[0] & 0x7f, bytes[1]]);
+    let critical = if bytes[0] >> 7 == 1 { "critical " } else { "" };
+    let body = &bytes[HEADER_SIZE..];
+    let ids: Vec<u16> = body
+        .chunks(2)
+        .filter(|id| id.len() == 2)
+        .map(|id| u16::from_be_bytes([id[0], id[1]]))
+        .collect();
+
+    let record = match deserialize(sender, bytes) {
+        Ok(KeRecord::EndOfMessage(_)) => String::from("End of Message"),
+        Ok(KeRecord::NextProtocol(record)) => {
+            format!("Next Protocol Negotiation {:?}", record.protocol_ids())
+        }
+        Ok(KeRecord::Error(_)) => format!("Error {}", ids[0]),
+        Ok(KeRecord::Warning(record)) => format!("Warning {}", record.code()),
+        Ok(KeRecord::AeadAlgorithm(record)) => {
+            format!("AEAD Algorithm Negotiation {:?}", record.algorithm_ids())
+        }
+        Ok(KeRecord::NewCookie(_)) => format!("New Cookie of {} bytes", body.len()),
+        Ok(KeRecord::Server(record)) => {
+            format!("NTPv4 Server Negotiation {}", record.into_string())
+        }
+        Ok(KeRecord::Port(record)) => format!("NTPv4 Port Negotiation {}", record.port()),
+        Err(DeserializeError::UnknownCriticalRecord)
+        | Err(DeserializeError::UnknownNotCriticalRecord) => {
+            format!("unknown record type {}", record_type)
+        }
+        Err(DeserializeError::Parsing(error)) => {
+            format!("invalid record of type {}: {}", record_type, error)
+        }
+    };
+    format!("{}{}", critical, record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::nts_ke::records::{serialize, EndOfMessageRecord, PortRecord};
+
+    #[test]
+    fn test_describe_record() {
+        assert_eq!(describe_record(Party::Server, &serialize(EndOfMessageRecord)),
+                   "critical End of Message");
+        assert_eq!(describe_record(Party::Client, &[0x80, 0x01, 0x00, 0x02, 0x00, 0x00]),
+                   "critical Next Protocol Negotiation [0]");
+        assert_eq!(describe_record(Party::Server, &[0x80, 0x02, 0x00, 0x02, 0x00, 0x01]),
+                   "critical Error 1");
+        assert_eq!(describe_record(Party::Server, &[0x80, 0x04, 0x00, 0x02, 0x00, 0x0f]),
+                   "critical AEAD Algorithm Negotiation [15]");
+        assert_eq!(describe_record(Party::Server, &[0x00, 0x05, 0x00, 0x03, 0x01, 0x02, 0x03]),
+                   "New Cookie of 3 bytes");
+        assert_eq!(describe_record(Party::Server, &serialize(PortRecord::new(Party::Server, 123))),
+                   "critical NTPv4 Port Negotiation 123");
+        assert_eq!(describe_record(Party::Server, &[0x40, 0x01, 0x00, 0x00]),
+                   "unknown record type 16385");
+        assert_eq!(describe_record(Party::Server, &[0x80, 0x02, 0x00]), "truncated record");
+        assert!(describe_record(Party::Server, &[0x80, 0x02, 0x00, 0x01, 0x00])
+            .starts_with("critical invalid record of type 2: "));
+    }
+
+    #[test]
+    fn test_display() {
+        let mut transcript = Transcript::default();
+        transcript.push(Party::Client, &serialize(EndOfMessageRecord));
+        transcript.push(Party::Server, &serialize(EndOfMessageRecord));
+        assert_eq!(transcript.to_string(),
+                   "client 80000000 critical End of Message\n\
+                    server 80000000 critical End of Message\n");
+    }
+}
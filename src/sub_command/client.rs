@@ -9,6 +9,7 @@ use slog::debug;
 use std::fs;
 use std::io::BufReader;
 use std::process;
+use std::time::Duration;
 
 use rustls::{
     internal::pemfile::certs,
@@ -16,15 +17,42 @@ use rustls::{
 };
 
 use crate::error::WrapError;
-use crate::ntp::client::run_nts_ntp_client;
-use crate::nts_ke::client::run_nts_ke_client;
+use crate::ntp::client::{
+    run_nts_client_loop, run_nts_ntp_client_with_placeholders, NtpResult, DEFAULT_LOW_WATER_MARK,
+};
+use crate::nts_ke::client::{run_nts_ke_client_with_transcript, NtsKeResult, RetryPolicy};
+use crate::nts_ke::records::KnownNextProtocol;
+use crate::nts_ke::transcript::Transcript;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ClientConfig {
     pub host: String,
+    /// The name sent in the SNI and validated against the certificate, if it's not the host,
+    /// e.g. when the host is an IP address or a load balancer. The host is still the one
+    /// resolved and connected to.
+    pub sni_name: Option<String>,
+    /// If it's not empty, the certificate must also be valid for one of these names, e.g. the
+    /// old and new names of a server during a migration.
+    pub accepted_names: Vec<String>,
     pub port: Option<String>,
-    pub trusted_cert: Option<Certificate>,
-    pub use_ipv4: Option<bool>
+    /// The certificates to trust instead of the public roots, e.g. the CAs of a private PKI. If
+    /// it's empty, the public roots are trusted, unless they are disabled.
+    pub trusted_certs: Vec<Certificate>,
+    /// Whether to trust the public roots when no trusted certificate is given.
+    pub use_system_roots: bool,
+    pub use_ipv4: Option<bool>,
+    /// Connect through an HTTP CONNECT (`http://host:port`) or SOCKS5 (`socks5://host:port`)
+    /// proxy if given.
+    pub proxy_url: Option<String>,
+    /// The next protocol ids to advertise, in order of preference. The server must choose one of
+    /// them.
+    pub next_protocols: Vec<u16>,
+    /// The local port to send the NTP queries from, e.g. for firewalls only letting port 123
+    /// through. An ephemeral port is used if it's not given.
+    pub source_port: Option<u16>,
+    /// How to retry a key exchange failing with a transient error. It's not retried if it's not
+    /// given.
+    pub retry_policy: Option<RetryPolicy>,
 }
 
 pub fn load_tls_certs(path: String) -> Result<Vec<Certificate>, config::ConfigError> {
@@ -34,6 +62,39 @@ pub fn load_tls_certs(path: String) -> Result<Vec<Certificate>, config::ConfigEr
         ))
 }
 
+/// Load the trusted certificates from a PEM file, which may be a bundle of several of them, or
+/// from every `.pem` and `.crt` file of a directory.
+///
+/// # Errors
+///
+/// There will be an error if a file cannot be read or parsed, or there is no certificate at all.
+///
+pub fn load_trusted_certs(path: &str) -> Result<Vec<Certificate>, config::ConfigError> {
+    let mut trusted_certs = Vec::new();
+    if fs::metadata(path).wrap_err()?.is_dir() {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(path).wrap_err()? {
+            let file = entry.wrap_err()?.path();
+            match file.extension().and_then(|extension| extension.to_str()) {
+                Some("pem") | Some("crt") => files.push(file),
+                _ => {}
+            }
+        }
+        // Sort the files, so that the order doesn't depend on the file system.
+        files.sort();
+        for file in files {
+            trusted_certs.append(&mut load_tls_certs(file.to_string_lossy().into_owned())?);
+        }
+    } else {
+        trusted_certs = load_tls_certs(String::from(path))?;
+    }
+
+    if trusted_certs.is_empty() {
+        return Err(config::ConfigError::Message(format!("no certificate found in {}", path)));
+    }
+    Ok(trusted_certs)
+}
+
 /// The entry point of `client`.
 pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
     // This should return the clone of `logger` in the main function.
@@ -45,6 +106,25 @@ pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
         .unwrap();
     let port = matches.value_of("port").map(String::from);
     let cert_file = matches.value_of("cert").map(String::from);
+    let json = matches.is_present("json");
+    let use_system_roots = !matches.is_present("no-system-roots");
+    let proxy_url = matches.value_of("proxy").map(String::from);
+    let sni_name = matches.value_of("sni").map(String::from);
+    let accepted_names = matches
+        .values_of("accept-name")
+        .map(|names| names.map(String::from).collect())
+        .unwrap_or_default();
+    let transcript_file = matches.value_of("transcript").map(String::from);
+    // Clap has already validated that it's a port number.
+    let source_port = matches.value_of("source-port").map(|port| port.parse().unwrap());
+
+    // Clap has already validated that they are numbers.
+    let count: u64 = matches.value_of("count").unwrap_or("1").parse().unwrap();
+    let interval: u64 = matches.value_of("interval").unwrap_or("64").parse().unwrap();
+    // Clap has already validated that it's a fraction.
+    let low_water_mark = matches
+        .value_of("cookie-low-water")
+        .map_or(DEFAULT_LOW_WATER_MARK, |fraction| fraction.parse().unwrap());
 
     // By default, use_ipv4 is None (no preference for using either ipv4 or ipv6
     // so client sniffs which one to use based on support)
@@ -62,40 +142,80 @@ pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
         }
     }
 
-    let mut trusted_cert = None;
-    if let Some(file) = cert_file {
-        if let Ok(certs) = load_tls_certs(file) {
-            trusted_cert = Some(certs[0].clone());
-        }
-    }
+    // Falling back to the public roots would hide the mistake, so a bad path is an error.
+    let trusted_certs = match cert_file {
+        Some(path) => match load_trusted_certs(&path) {
+            Ok(certs) => certs,
+            Err(err) => {
+                eprintln!("cannot load the trusted certificates: {}", err);
+                process::exit(1)
+            }
+        },
+        None => Vec::new(),
+    };
 
     let client_config = ClientConfig {
         host,
+        sni_name,
+        accepted_names,
         port,
-        trusted_cert,
+        trusted_certs,
+        use_system_roots,
         use_ipv4,
+        proxy_url,
+        next_protocols: vec![KnownNextProtocol::Ntpv4.as_protocol_id()],
+        source_port,
+        retry_policy: None,
     };
 
-    let res = run_nts_ke_client(&logger, client_config);
-
-    match res {
-        Err(err) => {
-            eprintln!("failure of tls stage: {}", err);
-            process::exit(1)
+    let key_exchange = || {
+        let mut transcript = Transcript::default();
+        let result =
+            run_nts_ke_client_with_transcript(&logger, client_config.clone(), &mut transcript);
+        // The transcript is written even if the exchange failed, that's when it's most useful.
+        if let Some(ref file) = transcript_file {
+            if let Err(err) = fs::write(file, transcript.to_string()) {
+                eprintln!("cannot write the transcript to {}: {}", file, err);
+            }
         }
-        Ok(_) => {}
-    }
-    let state = res.unwrap();
-    debug!(logger, "running UDP client with state {:x?}", state);
-    let res = run_nts_ntp_client(&logger, state);
-    match res {
-        Err(err) => {
-            eprintln!("failure of client: {}", err);
-            process::exit(1)
+        match result {
+            Err(err) => {
+                eprintln!("failure of tls stage: {}", err);
+                process::exit(1)
+            }
+            Ok(state) => {
+                debug!(logger, "running UDP client with state {:x?}", state);
+                Ok(state)
+            }
         }
-        Ok(result) => {
+    };
+    let query = |state: &mut NtsKeResult, placeholders| {
+        run_nts_ntp_client_with_placeholders(&logger, state, placeholders)
+    };
+    let print_result = |result: &NtpResult| {
+        if json {
+            // Serializing plain data to a string cannot fail.
+            println!("{}", serde_json::to_string(result).unwrap());
+        } else {
             println!("stratum: {:}", result.stratum);
-            println!("offset: {:.6}", result.time_diff);
+            println!("offset: {:.6}", result.offset);
+            println!("delay: {:.6}", result.delay);
+            println!("precision: 2^{}", result.precision);
+            println!("poll: 2^{}", result.poll);
         }
+    };
+
+    let res = run_nts_client_loop(
+        &logger,
+        count,
+        Duration::from_secs(interval),
+        low_water_mark,
+        key_exchange,
+        query,
+        print_result,
+    );
+    if let Err(err) = res {
+        eprintln!("failure of client: {}", err);
+        process::exit(1)
     }
 }
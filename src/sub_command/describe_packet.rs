@@ -0,0 +1,55 @@
+// This file is part of cfnts.
+// Copyright (c) 2019, Cloudflare. All rights reserved.
+// See LICENSE for licensing information.
+
+//! The describe-packet subcommand.
+
+use std::fs;
+use std::process;
+
+use crate::ntp::pcap::analyze_pcap;
+use crate::ntp::protocol::describe_packet;
+
+/// The port of NTP.
+const NTP_PORT: u16 = 123;
+
+/// The entry point of `describe-packet`.
+pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
+    if let Some(filename) = matches.value_of("pcap") {
+        // Clap has already validated that they are port numbers.
+        let ports: Vec<u16> = match matches.values_of("port") {
+            Some(ports) => ports.map(|port| port.parse().unwrap()).collect(),
+            None => vec![NTP_PORT],
+        };
+        match analyze_pcap(filename, &ports) {
+            Ok(summaries) => {
+                for summary in summaries {
+                    println!("{}", summary);
+                }
+            }
+            Err(err) => {
+                eprintln!("cannot read the capture: {}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // The packet is either given in hex on the command line or read raw from a file.
+    let packet = match (matches.value_of("hex"), matches.value_of("file")) {
+        (Some(text), _) => {
+            let text: String = text.split_whitespace().collect();
+            hex::decode(text).map_err(|err| err.to_string())
+        }
+        (None, Some(filename)) => fs::read(filename).map_err(|err| err.to_string()),
+        (None, None) => Err(String::from("please give either a hex packet or a file")),
+    };
+
+    match packet {
+        Ok(packet) => print!("{}", describe_packet(&packet)),
+        Err(err) => {
+            eprintln!("cannot read the packet: {}", err);
+            process::exit(1);
+        }
+    }
+}
@@ -4,8 +4,7 @@
 
 //! The ke-server subcommand.
 
-use std::process;
-
+use crate::error::StartupError;
 use crate::nts_ke::server::{KeServerConfig, KeServer};
 
 /// Get a configuration file path for `ke-server`.
@@ -22,40 +21,22 @@ fn resolve_config_filename<'a>(matches: &clap::ArgMatches<'a>) -> String {
     }
 }
 
-/// The entry point of `ke-server`.
-pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
+/// The entry point of `ke-server`. It only returns if the server failed to start.
+pub fn run<'a>(matches: &clap::ArgMatches<'a>) -> Result<(), StartupError> {
     // This should return the clone of `logger` in the main function.
     let global_logger = slog_scope::logger();
 
     // Get the config file path.
     let filename = resolve_config_filename(&matches);
-    let mut config = match KeServerConfig::parse(&filename) {
-        Ok(val) => val,
-        // If there is an error, display it.
-        Err(err) => {
-            eprintln!("{}", err);
-            process::exit(1);
-        },
-    };
+    let mut config = KeServerConfig::parse(&filename)?;
 
     let logger = global_logger.new(slog::o!("component" => "nts_ke"));
     // Let the parsed config use the child logger of the global logger.
     config.set_logger(logger);
 
     // Try to connect to the Memcached server.
-    let mut server = match KeServer::connect(config) {
-        Ok(server) => server,
-        Err(_error) => {
-            // Disable the log for now because the Error trait is not implemented for
-            // RotateError yet.
-            // eprintln!("starting NTS-KE server failed: {}", error);
-            process::exit(1);
-        }
-    };
+    let mut server = KeServer::connect(config)?;
 
     // Start listening for incoming connections.
-    if let Err(error) = server.start() {
-        eprintln!("starting NTS-KE server failed: {}", error);
-        process::exit(1);
-    }
+    server.start()
 }
@@ -5,5 +5,6 @@
 //! Subcommand collections.
 
 pub mod client;
+pub mod describe_packet;
 pub mod ke_server;
 pub mod ntp_server;
@@ -4,10 +4,10 @@
 
 //! The ntp-server subcommand.
 
-use std::process;
-
+use crate::cfsock::activated_udp_socket;
+use crate::error::StartupError;
 use crate::ntp::server::NtpServerConfig;
-use crate::ntp::server::start_ntp_server;
+use crate::ntp::server::{start_ntp_server, start_ntp_server_with_socket};
 
 /// Get a configuration file path for `ntp-server`.
 ///
@@ -23,28 +23,43 @@ fn resolve_config_filename<'a>(matches: &clap::ArgMatches<'a>) -> String {
     }
 }
 
-/// The entry point of `ntp-server`.
-pub fn run<'a>(matches: &clap::ArgMatches<'a>) {
+/// The entry point of `ntp-server`. It only returns if the server failed to start.
+pub fn run<'a>(matches: &clap::ArgMatches<'a>) -> Result<(), StartupError> {
     // This should return the clone of `logger` in the main function.
     let global_logger = slog_scope::logger();
 
     // Get the config file path.
     let filename = resolve_config_filename(&matches);
-    let mut config = match NtpServerConfig::parse(&filename) {
-        Ok(val) => val,
-        // If there is an error, display it.
-        Err(err) => {
-            eprintln!("{}", err);
-            process::exit(1);
-        },
-    };
+    let mut config = NtpServerConfig::parse(&filename)?;
 
     let logger = global_logger.new(slog::o!("component" => "ntp"));
     // Let the parsed config use the child logger of the global logger.
     config.set_logger(logger);
 
-    if let Err(err) = start_ntp_server(config) {
-        eprintln!("starting NTP server failed: {}", err);
-        process::exit(1);
+    // A socket passed by socket activation replaces the addresses of the config.
+    match activated_udp_socket().map_err(StartupError::Bind)? {
+        Some(socket) => start_ntp_server_with_socket(config, socket),
+        None => start_ntp_server(config),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cmd::create_clap_command;
+
+    use std::env;
+
+    #[test]
+    fn test_config_error() {
+        let path = env::temp_dir().join("cfnts-test-missing-ntp-server.config");
+        let matches = create_clap_command()
+            .get_matches_from(vec!["cfnts", "ntp-server", "-f", path.to_str().unwrap()]);
+        let matches = matches.subcommand_matches("ntp-server").unwrap();
+        match run(matches) {
+            Err(StartupError::Config(_)) => {}
+            result => panic!("expected a config error, got {:?}", result),
+        }
     }
 }
@@ -0,0 +1,99 @@
+#!/usr/bin/env python3
+# This file is part of cfnts.
+# Copyright (c) 2019, Cloudflare. All rights reserved.
+# See LICENSE for licensing information.
+#
+# Generate the packet captures in this directory from the regression fixtures in ../fixtures.
+#
+# ntp.pcap is a classic little-endian pcap of Ethernet frames over IPv4: the NTS request and
+# response of the fixtures on port 123, a DNS query which isn't NTP, and a truncated NTP packet.
+# ntp.pcapng is a big-endian pcapng of Ethernet frames over IPv6, carrying a plain NTP request
+# behind a VLAN tag.
+
+import os
+import struct
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+CLIENT = bytes([0x02, 0, 0, 0, 0, 1])
+SERVER = bytes([0x02, 0, 0, 0, 0, 2])
+
+
+def fixture(name):
+    with open(os.path.join(HERE, "..", "fixtures", name)) as f:
+        return bytes.fromhex("".join(f.read().split()))
+
+
+def udp(src_port, dst_port, payload):
+    # A zero checksum is allowed over IPv4, and nobody checks it here over IPv6.
+    return struct.pack(">HHHH", src_port, dst_port, 8 + len(payload), 0) + payload
+
+
+def ipv4(src, dst, payload):
+    header = struct.pack(">BBHHHBBH4s4s", 0x45, 0, 20 + len(payload), 0, 0x4000, 64, 17, 0,
+                         bytes(src), bytes(dst))
+    return header + payload
+
+
+def ipv6(src, dst, payload):
+    header = struct.pack(">IHBB16s16s", 6 << 28, len(payload), 17, 64, bytes(src), bytes(dst))
+    return header + payload
+
+
+def ethernet(src, dst, ethertype, payload, vlan=None):
+    frame = dst + src
+    if vlan is not None:
+        frame += struct.pack(">HH", 0x8100, vlan)
+    return frame + struct.pack(">H", ethertype) + payload
+
+
+def pcap(frames):
+    data = struct.pack("<IHHiIII", 0xa1b2c3d4, 2, 4, 0, 0, 65535, 1)
+    for i, frame in enumerate(frames):
+        data += struct.pack("<IIII", 1500000000 + i, 0, len(frame), len(frame)) + frame
+    return data
+
+
+def block(block_type, body):
+    body += bytes(-len(body) % 4)
+    length = struct.pack(">I", len(body) + 12)
+    return struct.pack(">I", block_type) + length + body + length
+
+
+def pcapng(frames):
+    data = block(0x0a0d0d0a, struct.pack(">IHHq", 0x1a2b3c4d, 1, 0, -1))
+    data += block(1, struct.pack(">HHI", 1, 0, 65535))
+    for frame in frames:
+        data += block(6, struct.pack(">IIIII", 0, 0, 0, len(frame), len(frame)) + frame)
+    return data
+
+
+def ntp_pcap():
+    client = [192, 0, 2, 1]
+    server = [192, 0, 2, 123]
+    request = fixture("client-request.hex")
+    response = fixture("server-response.hex")
+    dns_query = bytes([0x12, 0x34, 0x01, 0x00]) + bytes(8)
+    return pcap([
+        ethernet(CLIENT, SERVER, 0x0800, ipv4(client, server, udp(50000, 123, request))),
+        ethernet(SERVER, CLIENT, 0x0800, ipv4(server, client, udp(123, 50000, response))),
+        ethernet(CLIENT, SERVER, 0x0800, ipv4(client, server, udp(50001, 53, dns_query))),
+        ethernet(CLIENT, SERVER, 0x0800, ipv4(client, server, udp(50002, 123, request[:20]))),
+    ])
+
+
+def ntp_pcapng():
+    client = [0x20, 0x01, 0x0d, 0xb8] + [0] * 11 + [1]
+    server = [0x20, 0x01, 0x0d, 0xb8] + [0] * 11 + [0x7b]
+    # A plain request: leap indicator 0, version 4, client mode.
+    request = struct.pack(">BBbbIII4Q", 0x23, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0123456789abcdef)
+    return pcapng([
+        ethernet(CLIENT, SERVER, 0x86dd, ipv6(client, server, udp(50000, 123, request)), vlan=7),
+    ])
+
+
+if __name__ == "__main__":
+    with open(os.path.join(HERE, "ntp.pcap"), "wb") as f:
+        f.write(ntp_pcap())
+    with open(os.path.join(HERE, "ntp.pcapng"), "wb") as f:
+        f.write(ntp_pcapng())
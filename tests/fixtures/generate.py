@@ -0,0 +1,112 @@
+#!/usr/bin/env python3
+# This file is part of cfnts.
+# Copyright (c) 2019, Cloudflare. All rights reserved.
+# See LICENSE for licensing information.
+#
+# Generate the regression fixtures in this directory. They are not third-party test vectors: the
+# packets are written by this script, modeled on the extension order and lengths chrony and ntpsec
+# use (RFC 8915 Section 5.7), not captured from them. They are encrypted with the AES-SIV
+# implementation of the `cryptography` package rather than our own, so that a bug in our AEAD
+# usage doesn't cancel itself out in the tests.
+#
+# The keys are fixed: C2S is 0x00..0x1f and S2C is 0x20..0x3f.
+#
+# The sntp-* fixtures are plain packets in the shape SNTP clients (RFC 4330) send and accept.
+#
+# Interop vectors are still missing: packets captured from chrony and ntpsec clients and servers,
+# with the keys exported by the key exchange they follow, e.g. from a chrony built with key
+# logging. They should include a request with several cookies and one with placeholders. Until
+# they are committed next to these fixtures, the tests only guard our own wire format.
+
+import struct
+
+from cryptography.hazmat.primitives.ciphers.aead import AESSIV
+
+C2S = bytes(range(0x00, 0x20))
+S2C = bytes(range(0x20, 0x40))
+
+UNIQUE_IDENTIFIER = 0x0104
+NTS_COOKIE = 0x0204
+NTS_COOKIE_PLACEHOLDER = 0x0304
+NTS_AUTHENTICATOR = 0x0404
+
+
+def header(mode, stratum, transmit):
+    # Leap indicator 0, version 4.
+    first = (4 << 3) | mode
+    return struct.pack(
+        ">BBbbIII4Q", first, stratum, 0, -20, 0, 0, 0, 0, 0, 0, transmit
+    )
+
+
+def extension(ext_type, contents):
+    assert len(contents) % 4 == 0
+    return struct.pack(">HH", ext_type, len(contents) + 4) + contents
+
+
+def authenticator(key, nonce, authenticated, plaintext):
+    ciphertext = AESSIV(key).encrypt(plaintext, [authenticated, nonce])
+    contents = struct.pack(">HH", len(nonce), len(ciphertext)) + nonce + ciphertext
+    return extension(NTS_AUTHENTICATOR, contents)
+
+
+def write(name, packet):
+    text = packet.hex()
+    with open(name, "w") as f:
+        for i in range(0, len(text), 64):
+            f.write(text[i:i + 64] + "\n")
+
+
+def client_request():
+    # A request with one cookie and two placeholders asking for two more cookies, with an empty
+    # encrypted part, as chrony sends after losing two responses.
+    cookie = bytes([0xc0]) * 104
+    packet = header(3, 0, 0xe1d2c3b4a5968778)
+    packet += extension(UNIQUE_IDENTIFIER, bytes([0x11]) * 32)
+    packet += extension(NTS_COOKIE, cookie)
+    packet += extension(NTS_COOKIE_PLACEHOLDER, bytes(len(cookie)))
+    packet += extension(NTS_COOKIE_PLACEHOLDER, bytes(len(cookie)))
+    packet += authenticator(C2S, bytes([0xa0]) * 16, packet, b"")
+    return packet
+
+
+def server_response():
+    # A response carrying three new cookies in the encrypted part, as ntpsec sends to a request
+    # with two placeholders.
+    cookies = b"".join(extension(NTS_COOKIE, bytes([0xc1 + i]) * 104) for i in range(3))
+    packet = header(4, 2, 0xe1d2c3b4a5968779)
+    packet += extension(UNIQUE_IDENTIFIER, bytes([0x11]) * 32)
+    packet += authenticator(S2C, bytes([0xb0]) * 16, packet, cookies)
+    return packet
+
+
+def sntp_request():
+    # A minimal SNTP request: everything is zero but the version, the mode and the transmit
+    # timestamp (RFC 4330 Section 5).
+    first = (4 << 3) | 3
+    return struct.pack(">BBbbIII4Q", first, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xe1d2c3b4a5968770)
+
+
+def sntp_request_v3():
+    # An NTPv3 request with the leap indicator of an unsynchronized clock, a poll and a
+    # precision, like ntpdate sends.
+    first = (3 << 6) | (3 << 3) | 3
+    return struct.pack(">BBbbIII4Q", first, 0, 6, -6, 0x10000, 0x10000, 0, 0, 0, 0,
+                       0xe1d2c3b4a5968771)
+
+
+def sntp_response():
+    # A stratum 2 response to sntp_request, echoing its transmit timestamp as the origin
+    # timestamp, which every SNTP client accepts.
+    first = (4 << 3) | 4
+    return struct.pack(">BBbbIII4Q", first, 2, 6, -20, 0x120, 0x340, 0xc0000201,
+                       0xe1d2c3b0a5968770, 0xe1d2c3b4a5968770, 0xe1d2c3b4a6000000,
+                       0xe1d2c3b4a6000100)
+
+
+if __name__ == "__main__":
+    write("client-request.hex", client_request())
+    write("server-response.hex", server_response())
+    write("sntp-request.hex", sntp_request())
+    write("sntp-request-v3.hex", sntp_request_v3())
+    write("sntp-response.hex", sntp_response())
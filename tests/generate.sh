@@ -1,4 +1,48 @@
-cfssl gencert -config=int-config.json -ca=ca.pem -ca-key=ca-key.pem intermediate.json | cfssljson -bare intermediate
-cfssl gencert -ca intermediate.pem -ca-key intermediate-key.pem test.json | cfssljson -bare tls
+#!/bin/sh
+# Generate the test CA, intermediate and TLS certificates in this directory. They are valid for
+# 100 years, so that the tests doing TLS handshakes don't start failing when they expire.
+set -e
+cd "$(dirname "$0")"
+
+DAYS=36500
+
+# The root CA.
+openssl ecparam -name prime256v1 -genkey -noout -out ca-key.pem
+openssl req -new -key ca-key.pem -out ca.csr \
+    -subj "/C=US/ST=CA/L=San Francisco/CN=localhost"
+openssl x509 -req -in ca.csr -signkey ca-key.pem -out ca.pem -days $DAYS -sha256 \
+    -extfile /dev/stdin <<EOF
+basicConstraints = critical, CA:true
+keyUsage = critical, keyCertSign, cRLSign
+subjectKeyIdentifier = hash
+EOF
+
+# The intermediate CA, which can only issue end-entity certificates.
+openssl ecparam -name prime256v1 -genkey -noout -out intermediate-key.pem
+openssl req -new -key intermediate-key.pem -out intermediate.csr \
+    -subj "/C=US/ST=California/L=San Francisco/O=HappyCert, Inc./OU=HappyCert Intermediate/CN=(dev use only)"
+openssl x509 -req -in intermediate.csr -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+    -out intermediate.pem -days $DAYS -sha256 -extfile /dev/stdin <<EOF
+basicConstraints = critical, CA:true, pathlen:0
+keyUsage = critical, digitalSignature, keyCertSign, cRLSign
+subjectKeyIdentifier = hash
+authorityKeyIdentifier = keyid
+EOF
+
+# The certificate of the test server.
+openssl ecparam -name prime256v1 -genkey -noout -out tls-key.pem
+openssl req -new -key tls-key.pem -out tls.csr \
+    -subj "/C=US/ST=CA/L=San Francisco/O=Cloudflare test/OU=Crypto team/CN=localhost"
+openssl x509 -req -in tls.csr -CA intermediate.pem -CAkey intermediate-key.pem -CAcreateserial \
+    -out tls.pem -days $DAYS -sha256 -extfile /dev/stdin <<EOF
+basicConstraints = critical, CA:false
+keyUsage = critical, digitalSignature, keyEncipherment
+extendedKeyUsage = serverAuth, clientAuth
+subjectKeyIdentifier = hash
+authorityKeyIdentifier = keyid
+subjectAltName = DNS:server, DNS:localhost, DNS:bogus.com, DNS:*.localhost
+EOF
+
 openssl pkcs8 -topk8 -nocrypt -in tls-key.pem -out tls-pkcs8.pem
 cat tls.pem intermediate.pem ca.pem > chain.pem
+rm -f ca.srl intermediate.srl